@@ -0,0 +1,61 @@
+/*
+ * The C interface of trayme, for apps that supervise commands with it without
+ * running the trayme binary. Link against the trayme library built by
+ * `cargo build --release`. The functions are documented in src/ffi.rs.
+ *
+ * Embedded instances show no tray icon and no notifications, as if
+ * `--headless` was given: the tray needs an event loop on the main thread,
+ * which belongs to the app, and the app is told about every event through the
+ * callback to show the instance in its own window or tray icon. Options that
+ * only work with a tray, like `--watch-inbox`, make trayme_start fail.
+ */
+
+#ifndef TRAYME_H
+#define TRAYME_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An instance started by trayme_start. */
+typedef struct trayme_instance trayme_instance;
+
+/*
+ * Called with every event of an instance after it started, as a line of JSON,
+ * and the user_data given to trayme_start. It's called on the instance's
+ * thread, and event is only valid during the call.
+ */
+typedef void (*trayme_event_callback)(const char *event, void *user_data);
+
+/*
+ * Starts supervising a command, the way `trayme <ARGS>` does but without a
+ * tray, with the count arguments in arguments. Returns the instance once the
+ * process was started, or NULL if it couldn't be, putting the reason in *error
+ * if error isn't NULL. callback may be NULL.
+ */
+trayme_instance *trayme_start(const char *const *arguments, size_t count,
+                              trayme_event_callback callback, void *user_data,
+                              char **error);
+
+/*
+ * The status of instance as JSON, as the `status` command of the control
+ * socket answers, or NULL if it can no longer tell.
+ */
+char *trayme_status(const trayme_instance *instance);
+
+/*
+ * Stops the process of instance, if it's still running, and frees instance.
+ * Returns the exit code of the process, or -1 if it has none.
+ */
+int trayme_stop(trayme_instance *instance);
+
+/* Frees a string returned by trayme. */
+void trayme_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TRAYME_H */
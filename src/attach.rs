@@ -0,0 +1,179 @@
+use std::{
+    fmt,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::menu::{IsMenuItem, MenuEvent, MenuEventReceiver, MenuItem, PredefinedMenuItem};
+
+use crate::{
+    build_tray, build_tray_menu, display, icon, menu_text,
+    notify::show_notification,
+    pidwatch::{WatchedExit, WatchedProcess},
+    stop::STOP_GRACE_PERIOD,
+};
+
+/// How often the watched process is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The menu items of the tray of `trayme attach`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VariantArray)]
+enum AttachMessage {
+    Kill,
+    StopWatching,
+}
+
+impl fmt::Display for AttachMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachMessage::Kill => write!(f, "Kill"),
+            AttachMessage::StopWatching => write!(f, "Stop Watching"),
+        }
+    }
+}
+
+impl FromStr for AttachMessage {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Kill" => Ok(AttachMessage::Kill),
+            "Stop Watching" => Ok(AttachMessage::StopWatching),
+            _ => Err(strum::ParseError::VariantNotFound),
+        }
+    }
+}
+
+/// The tray of `trayme attach`: a process trayme didn't start, watched until it exits.
+struct AttachTray {
+    process: WatchedProcess,
+    /// What the tooltip and notifications call the process.
+    name: String,
+    last_check: Option<Instant>,
+}
+
+impl AttachTray {
+    fn tick(&mut self, menu_channel: &MenuEventReceiver) -> anyhow::Result<ControlFlow> {
+        if self
+            .last_check
+            .is_none_or(|at| at.elapsed() >= POLL_INTERVAL)
+        {
+            self.last_check = Some(Instant::now());
+            if let Some(exit) = self.process.try_wait()? {
+                self.exited(exit);
+                return Ok(ControlFlow::Exit);
+            }
+        }
+
+        if let Ok(event) = menu_channel.try_recv() {
+            debug!("{event:?}");
+            let Ok(msg) = AttachMessage::from_str(&event.id().0) else {
+                warn!("Ignoring unknown menu item '{}'", event.id().0);
+                return Ok(ControlFlow::Poll);
+            };
+            match msg {
+                AttachMessage::Kill => {
+                    if let Err(e) = self.kill() {
+                        error!("{e:#}");
+                        show_notification("Failed to kill the process", &format!("{e:#}"));
+                    }
+                }
+                AttachMessage::StopWatching => {
+                    info!("No longer watching PID {}", self.process.pid());
+                    return Ok(ControlFlow::Exit);
+                }
+            }
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+
+    /// Asks the process to stop, and kills it if it's still running after
+    /// [`STOP_GRACE_PERIOD`]. Its exit is noticed by the next tick.
+    fn kill(&self) -> anyhow::Result<()> {
+        info!("Stopping PID {}", self.process.pid());
+        self.process.terminate()?;
+        let start = Instant::now();
+        while start.elapsed() < STOP_GRACE_PERIOD {
+            if self.process.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        warn!("PID {} still running, killing it", self.process.pid());
+        self.process.kill()
+    }
+
+    fn exited(&self, exit: WatchedExit) {
+        let pid = self.process.pid();
+        info!("PID {pid} {exit}");
+        show_notification(
+            "Process exited",
+            &format!("{} (PID {pid}) {exit}", self.name),
+        );
+    }
+}
+
+/// Shows a tray icon for the running process with the ID `pid`, which can kill it, until the
+/// process exits or the user stops watching it. Nothing is started.
+///
+/// # Arguments
+///
+/// * `pid` - The process to watch.
+/// * `name` - What the tooltip calls the process. Defaults to its command line.
+///
+/// # Errors
+///
+/// An error is returned if there is no such process, it cannot be watched, or the tray icon
+/// cannot be built.
+pub fn run_attached(pid: u32, name: Option<String>) -> anyhow::Result<()> {
+    let process = WatchedProcess::open(pid)?;
+    let command = process.describe();
+    let name = name
+        .or_else(|| command.clone())
+        .unwrap_or_else(|| format!("PID {pid}"));
+    info!("Watching PID {pid} ({name})");
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(AttachMessage::VARIANTS)?;
+    let header = MenuItem::new(
+        menu_text(&format!(
+            "PID {pid}: {}",
+            command.as_deref().unwrap_or("unknown command")
+        )),
+        false,
+        None,
+    );
+    menu.prepend_items(&[&header as &dyn IsMenuItem, &PredefinedMenuItem::separator()])?;
+    let tooltip = format!("trayme attach: {}", menu_text(&name));
+    let mut tray = Some(build_tray(&tooltip, menu, icon::identicon(&name)?)?);
+    let menu_channel = MenuEvent::receiver();
+    let mut attach_tray = AttachTray {
+        process,
+        name,
+        last_check: None,
+    };
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        if tray.is_none() {
+            return;
+        }
+        match attach_tray.tick(menu_channel) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
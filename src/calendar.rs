@@ -0,0 +1,627 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{
+    DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc,
+    Weekday,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+
+/// How often the calendar is read again.
+const REFRESH_INTERVAL: Duration = Duration::from_mins(5);
+
+/// How long fetching a calendar from a URL may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often whether it's busy is worked out again, which goes through every event.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How many days a recurring event's occurrences are searched through, as a bound on events
+/// that repeat forever.
+const MAX_SEARCH_DAYS: i64 = 366 * 30;
+
+/// Where a calendar is read from: a local ICS file, or an `http`, `https`, or `webcal` URL, such
+/// as the secret address most calendar services export a calendar at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CalendarSource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl FromStr for CalendarSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("webcal://") {
+            return Ok(Self::Url(format!("https://{rest}")));
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Self::Url(s.to_string()));
+        }
+        std::path::absolute(s)
+            .map(Self::File)
+            .map_err(|e| format!("invalid path '{s}': {e}"))
+    }
+}
+
+impl TryFrom<String> for CalendarSource {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CalendarSource> for String {
+    fn from(source: CalendarSource) -> Self {
+        source.to_string()
+    }
+}
+
+impl fmt::Display for CalendarSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl CalendarSource {
+    /// Reads the calendar's ICS text.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be read or the URL cannot be fetched.
+    fn read(&self) -> anyhow::Result<String> {
+        match self {
+            Self::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display())),
+            Self::Url(url) => ureq::get(url)
+                .timeout(FETCH_TIMEOUT)
+                .call()
+                .with_context(|| format!("Failed to fetch {url}"))?
+                .into_string()
+                .with_context(|| format!("Failed to read {url}")),
+        }
+    }
+}
+
+/// A busy block of the calendar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Busy {
+    /// The event's title.
+    pub summary: String,
+    /// When the block ends.
+    pub until: DateTime<Local>,
+}
+
+/// A calendar that is kept up to date in the background, to tell whether the user is in a
+/// meeting. Events marked as free (`TRANSP:TRANSPARENT`) and cancelled ones don't count.
+pub struct BusyCalendar {
+    updates: Receiver<Vec<Event>>,
+    events: Vec<Event>,
+    /// The last answer of [`BusyCalendar::busy`] and when it was worked out.
+    checked: Option<(Instant, Option<Busy>)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl BusyCalendar {
+    /// Starts reading the calendar in the background, every [`REFRESH_INTERVAL`]. Until it was
+    /// read for the first time, and while it can't be, the last events read are used.
+    pub fn watch(source: CalendarSource) -> Self {
+        Self::with_clock(source, Arc::new(SystemClock))
+    }
+
+    /// Like [`BusyCalendar::watch`], with the time coming from `clock`.
+    pub fn with_clock(source: CalendarSource, clock: Arc<dyn Clock>) -> Self {
+        let (sender, updates) = mpsc::channel();
+        thread::spawn(move || refresh(&source, &sender));
+        Self {
+            updates,
+            events: Vec::new(),
+            checked: None,
+            clock,
+        }
+    }
+
+    /// The busy block going on right now, if any.
+    pub fn busy(&mut self) -> Option<Busy> {
+        let mut updated = false;
+        while let Ok(events) = self.updates.try_recv() {
+            self.events = events;
+            updated = true;
+        }
+        if let Some((at, busy)) = &self.checked {
+            if !updated && self.clock.instant().duration_since(*at) < CHECK_INTERVAL {
+                return busy.clone();
+            }
+        }
+        let now = self.clock.now();
+        let busy = self
+            .events
+            .iter()
+            .filter_map(|event| event.busy_at(now))
+            .max_by_key(|busy| busy.until);
+        self.checked = Some((self.clock.instant(), busy.clone()));
+        busy
+    }
+}
+
+/// Reads the calendar every [`REFRESH_INTERVAL`] until the [`BusyCalendar`] is dropped, sending
+/// the events on `updates`.
+fn refresh(source: &CalendarSource, updates: &Sender<Vec<Event>>) {
+    let mut failing = false;
+    loop {
+        match source.read().and_then(|ics| parse(&ics)) {
+            Ok(events) => {
+                if failing {
+                    info!("Read calendar {source} again");
+                }
+                failing = false;
+                debug!("Read {} events from {source}", events.len());
+                if updates.send(events).is_err() {
+                    return;
+                }
+            }
+            // warned about once, it's probably just offline
+            Err(e) if failing => debug!("{e:#}"),
+            Err(e) => {
+                warn!("Failed to read calendar {source}: {e:#}");
+                failing = true;
+            }
+        }
+        thread::sleep(REFRESH_INTERVAL);
+    }
+}
+
+/// How often a recurring event repeats, as far as its `RRULE` is understood.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    /// On the given days of the week, or the weekday of the first occurrence if there are none.
+    Weekly(Option<[bool; 7]>),
+    /// On the day of the month of the first occurrence.
+    Monthly,
+    /// On the day and month of the first occurrence.
+    Yearly,
+}
+
+/// The understood parts of an `RRULE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<DateTime<Local>>,
+}
+
+/// A `VEVENT`, in local time.
+#[derive(Debug, Clone)]
+struct Event {
+    uid: Option<String>,
+    summary: String,
+    start: DateTime<Local>,
+    duration: TimeDelta,
+    rule: Option<Rule>,
+    /// Occurrences that were removed, or moved and are events of their own.
+    exceptions: Vec<DateTime<Local>>,
+    /// The occurrence of a recurring event this event replaces.
+    replaces: Option<DateTime<Local>>,
+}
+
+impl Event {
+    /// The busy block of this event going on at `at`, if any.
+    fn busy_at(&self, at: DateTime<Local>) -> Option<Busy> {
+        let start = match self.rule {
+            Some(rule) => self.occurrence_around(rule, at)?,
+            None => Some(self.start).filter(|start| *start <= at)?,
+        };
+        let until = start + self.duration;
+        (at < until).then(|| Busy {
+            summary: self.summary.clone(),
+            until,
+        })
+    }
+
+    /// The latest occurrence that starts at or before `at`, among those that could still be
+    /// going on.
+    fn occurrence_around(&self, rule: Rule, at: DateTime<Local>) -> Option<DateTime<Local>> {
+        let first = self.start.date_naive();
+        let last = at.date_naive();
+        // with a count, every occurrence from the first on has to be counted
+        let from = if rule.count.is_some() {
+            first
+        } else {
+            let reach = self.duration.num_days() + 1;
+            first.max(last - TimeDelta::days(reach))
+        };
+        let days = (last - from).num_days();
+        if days < 0 || (last - first).num_days() > MAX_SEARCH_DAYS {
+            return None;
+        }
+        let mut found = None;
+        let mut seen = 0;
+        for day in from.iter_days().take(usize::try_from(days).ok()? + 1) {
+            if !repeats_on(rule, first, day) {
+                continue;
+            }
+            seen += 1;
+            if rule.count.is_some_and(|count| seen > count) {
+                break;
+            }
+            let Some(start) = local(day.and_time(self.start.time())) else {
+                continue;
+            };
+            if start > at || rule.until.is_some_and(|until| start > until) {
+                break;
+            }
+            if !self.exceptions.contains(&start) {
+                found = Some(start);
+            }
+        }
+        found
+    }
+}
+
+/// Whether a rule starting on `first` has an occurrence on `day`.
+fn repeats_on(rule: Rule, first: NaiveDate, day: NaiveDate) -> bool {
+    let interval = i64::from(rule.interval.max(1));
+    match rule.frequency {
+        Frequency::Daily => (day - first).num_days() % interval == 0,
+        Frequency::Weekly(days) => {
+            let week_start = |date: NaiveDate| {
+                date - TimeDelta::days(i64::from(date.weekday().num_days_from_monday()))
+            };
+            let weeks = (week_start(day) - week_start(first)).num_days() / 7;
+            let on_day = match days {
+                Some(days) => days[day.weekday().num_days_from_monday() as usize],
+                None => day.weekday() == first.weekday(),
+            };
+            weeks % interval == 0 && on_day
+        }
+        Frequency::Monthly => {
+            let months = i64::from(day.year() - first.year()) * 12 + i64::from(day.month())
+                - i64::from(first.month());
+            day.day() == first.day() && months % interval == 0
+        }
+        Frequency::Yearly => {
+            (day.month(), day.day()) == (first.month(), first.day())
+                && i64::from(day.year() - first.year()) % interval == 0
+        }
+    }
+}
+
+fn local(time: NaiveDateTime) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&time).earliest()
+}
+
+/// Parses the events of an ICS calendar. Times with a `TZID` are taken to be in the local time
+/// zone, since there's no time zone database to convert them with, which is right for a work
+/// calendar in the user's own time zone. Recurring events are understood for daily, weekly,
+/// monthly, and yearly rules with `INTERVAL`, `COUNT`, `UNTIL`, and `BYDAY` for weekly ones.
+/// Other rules only count their first occurrence.
+///
+/// # Errors
+///
+/// An error is returned if the text isn't a calendar.
+fn parse(ics: &str) -> anyhow::Result<Vec<Event>> {
+    if !ics.trim_start().starts_with("BEGIN:VCALENDAR") {
+        bail!("Not an iCalendar file");
+    }
+    let mut events = Vec::new();
+    let mut current: Option<Vec<(String, String, String)>> = None;
+    for line in unfold(ics) {
+        let Some((name, params, value)) = split_property(&line) else {
+            continue;
+        };
+        match (name.as_str(), value.as_str()) {
+            ("BEGIN", "VEVENT") => current = Some(Vec::new()),
+            ("END", "VEVENT") => {
+                if let Some(properties) = current.take() {
+                    match parse_event(&properties) {
+                        Ok(Some(event)) => events.push(event),
+                        Ok(None) => {}
+                        Err(e) => debug!("Skipping event: {e:#}"),
+                    }
+                }
+            }
+            _ => {
+                if let Some(properties) = current.as_mut() {
+                    properties.push((name, params, value));
+                }
+            }
+        }
+    }
+    // moved occurrences are events of their own, which replace the original one
+    let mut replaced: HashMap<String, Vec<DateTime<Local>>> = HashMap::new();
+    for event in &events {
+        if let (Some(uid), Some(at)) = (&event.uid, event.replaces) {
+            replaced.entry(uid.clone()).or_default().push(at);
+        }
+    }
+    for event in &mut events {
+        if event.replaces.is_some() {
+            continue;
+        }
+        if let Some(at) = event.uid.as_ref().and_then(|uid| replaced.get(uid)) {
+            event.exceptions.extend(at);
+        }
+    }
+    Ok(events)
+}
+
+/// Joins the lines that continue on the next one, which start with a space or tab.
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Splits `NAME;PARAM=VALUE:VALUE` into its name, parameters, and value. Parameters may quote
+/// colons.
+fn split_property(line: &str) -> Option<(String, String, String)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((
+        name.to_ascii_uppercase(),
+        params.to_string(),
+        value.to_string(),
+    ))
+}
+
+/// Builds an event from its properties. Returns `None` for events that don't make the user
+/// busy.
+fn parse_event(properties: &[(String, String, String)]) -> anyhow::Result<Option<Event>> {
+    let get = |name: &str| {
+        properties
+            .iter()
+            .find(|(n, _, _)| n == name)
+            .map(|(_, params, value)| (params.as_str(), value.as_str()))
+    };
+    if get("TRANSP").is_some_and(|(_, v)| v.eq_ignore_ascii_case("TRANSPARENT"))
+        || get("STATUS").is_some_and(|(_, v)| v.eq_ignore_ascii_case("CANCELLED"))
+    {
+        return Ok(None);
+    }
+    let (params, value) = get("DTSTART").context("No DTSTART")?;
+    let (start, all_day) = parse_time(params, value)?;
+    let duration = if let Some((params, value)) = get("DTEND") {
+        parse_time(params, value)?.0 - start
+    } else if let Some((_, value)) = get("DURATION") {
+        parse_duration(value)?
+    } else if all_day {
+        TimeDelta::days(1)
+    } else {
+        TimeDelta::zero()
+    };
+    let mut exceptions = Vec::new();
+    for (_, params, value) in properties.iter().filter(|(n, _, _)| n == "EXDATE") {
+        for value in value.split(',') {
+            exceptions.push(parse_time(params, value)?.0);
+        }
+    }
+    Ok(Some(Event {
+        uid: get("UID").map(|(_, v)| v.to_string()),
+        summary: get("SUMMARY").map_or_else(|| "Busy".to_string(), |(_, v)| unescape(v)),
+        start,
+        duration,
+        rule: get("RRULE").and_then(|(_, v)| parse_rule(v)),
+        exceptions,
+        replaces: get("RECURRENCE-ID")
+            .map(|(params, value)| parse_time(params, value))
+            .transpose()?
+            .map(|(at, _)| at),
+    }))
+}
+
+/// Parses a date (`20241014`) or time (`20241014T090000`, with `Z` for UTC). Returns the time
+/// and whether it was a date, which starts at midnight.
+fn parse_time(params: &str, value: &str) -> anyhow::Result<(DateTime<Local>, bool)> {
+    let value = value.trim();
+    let date_only = params
+        .split(';')
+        .any(|p| p.eq_ignore_ascii_case("VALUE=DATE"))
+        || !value.contains('T');
+    if date_only {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d")
+            .with_context(|| format!("Invalid date '{value}'"))?;
+        let time = local(date.and_time(NaiveTime::MIN)).context("Invalid local time")?;
+        return Ok((time, true));
+    }
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .with_context(|| format!("Invalid time '{value}'"))?;
+    let time = if utc {
+        Utc.from_utc_datetime(&time).with_timezone(&Local)
+    } else {
+        local(time).context("Invalid local time")?
+    };
+    Ok((time, false))
+}
+
+/// Parses a duration such as `PT1H30M` or `P1D`.
+fn parse_duration(value: &str) -> anyhow::Result<TimeDelta> {
+    let invalid = || format!("Invalid duration '{value}'");
+    let rest = value
+        .trim()
+        .trim_start_matches('+')
+        .strip_prefix('P')
+        .with_context(invalid)?;
+    let mut total = TimeDelta::zero();
+    let mut number = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' {
+            continue;
+        }
+        let n: i64 = number.parse().with_context(invalid)?;
+        number.clear();
+        total += match c {
+            'W' => TimeDelta::weeks(n),
+            'D' => TimeDelta::days(n),
+            'H' => TimeDelta::hours(n),
+            'M' => TimeDelta::minutes(n),
+            'S' => TimeDelta::seconds(n),
+            _ => bail!(invalid()),
+        };
+    }
+    Ok(total)
+}
+
+/// The understood parts of an `RRULE`, or `None` if it can't be followed.
+fn parse_rule(value: &str) -> Option<Rule> {
+    let parts: HashMap<_, _> = value
+        .split(';')
+        .filter_map(|part| part.split_once('='))
+        .map(|(key, value)| (key.to_ascii_uppercase(), value))
+        .collect();
+    let by_day = match parts.get("BYDAY") {
+        Some(days) => {
+            let mut set = [false; 7];
+            for day in days.split(',') {
+                // offsets like 2TU only make sense for monthly rules
+                let weekday = match day {
+                    "MO" => Weekday::Mon,
+                    "TU" => Weekday::Tue,
+                    "WE" => Weekday::Wed,
+                    "TH" => Weekday::Thu,
+                    "FR" => Weekday::Fri,
+                    "SA" => Weekday::Sat,
+                    "SU" => Weekday::Sun,
+                    _ => return unsupported(value),
+                };
+                set[weekday.num_days_from_monday() as usize] = true;
+            }
+            Some(set)
+        }
+        None => None,
+    };
+    let frequency = match *parts.get("FREQ")? {
+        "DAILY" if by_day.is_none() => Frequency::Daily,
+        "WEEKLY" => Frequency::Weekly(by_day),
+        "MONTHLY" if by_day.is_none() => Frequency::Monthly,
+        "YEARLY" if by_day.is_none() => Frequency::Yearly,
+        _ => return unsupported(value),
+    };
+    if parts.keys().any(|key| {
+        !matches!(
+            key.as_str(),
+            "FREQ" | "INTERVAL" | "COUNT" | "UNTIL" | "BYDAY" | "WKST"
+        )
+    }) {
+        return unsupported(value);
+    }
+    let until = match parts.get("UNTIL") {
+        // a date includes the occurrences on it
+        Some(until) => match parse_time("", until).ok()? {
+            (until, true) => Some(until + TimeDelta::days(1) - TimeDelta::seconds(1)),
+            (until, false) => Some(until),
+        },
+        None => None,
+    };
+    Some(Rule {
+        frequency,
+        interval: parts
+            .get("INTERVAL")
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1),
+        count: parts.get("COUNT").and_then(|n| n.parse().ok()),
+        until,
+    })
+}
+
+fn unsupported(rule: &str) -> Option<Rule> {
+    debug!("Only counting the first occurrence of RRULE:{rule}");
+    None
+}
+
+/// Undoes the escaping of text values.
+fn unescape(value: &str) -> String {
+    value
+        .replace("\\n", " ")
+        .replace("\\N", " ")
+        .replace("\\,", ",")
+        .replace("\\;", ";")
+        .replace("\\\\", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn busy_follows_the_clock() {
+        let path = std::env::temp_dir().join(format!(
+            "{}-calendar-{}.ics",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nSUMMARY:Standup\r\nDTSTART:20240304T100000\r\n\
+             DTEND:20240304T103000\r\nRRULE:FREQ=WEEKLY;BYDAY=MO,WE\r\nEND:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+        )
+        .unwrap();
+        // a Wednesday, in the first occurrence's second week
+        let start = Local.with_ymd_and_hms(2024, 3, 13, 9, 50, 0).unwrap();
+        let clock = FakeClock::at(start);
+        let mut calendar =
+            BusyCalendar::with_clock(CalendarSource::File(path), Arc::new(clock.clone()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calendar.events.is_empty() {
+            assert!(Instant::now() < deadline, "the calendar was never read");
+            assert_eq!(calendar.busy(), None);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        clock.advance(TimeDelta::minutes(15));
+        let busy = calendar.busy().unwrap();
+        assert_eq!(busy.summary, "Standup");
+        assert_eq!(busy.until, start + TimeDelta::minutes(40));
+        // the answer is kept for a while, even once the event is over
+        clock.advance(TimeDelta::minutes(24) + TimeDelta::seconds(55));
+        assert!(calendar.busy().is_some());
+        clock.advance(TimeDelta::seconds(10));
+        assert!(calendar.busy().is_some());
+        clock.advance(TimeDelta::seconds(10));
+        assert_eq!(calendar.busy(), None);
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{child::ChildProcess, cli::InstanceArgs, parse, supervisor::CommandSpec};
+
+/// How often the memory of a command with `--max-memory` is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An amount of memory, written as bytes with an optional binary unit, e.g. `512M` or `1.5G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(pub u64);
+
+impl fmt::Display for ByteSize {
+    /// Writes the size with the largest unit it's a whole number of, so that it parses back to
+    /// the same size.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (unit, shift) in [("T", 40), ("G", 30), ("M", 20), ("K", 10)] {
+            if self.0 != 0 && self.0.is_multiple_of(1 << shift) {
+                return write!(f, "{}{unit}", self.0 >> shift);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse::byte_size(s)
+            .filter(|bytes| *bytes > 0)
+            .map(Self)
+            .ok_or_else(|| format!("expected a size such as 512M or 2G, got '{s}'"))
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(size: ByteSize) -> Self {
+        size.to_string()
+    }
+}
+
+/// What happens when a command goes over `--max-memory`, on top of the notification.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimitAction {
+    /// It's stopped, and restarted only if the restart policy says so.
+    #[default]
+    Kill,
+    /// It's restarted once the restart backoff is over, whatever the restart policy.
+    Restart,
+    /// Nothing, the limit is only a threshold to be told about.
+    Notify,
+}
+
+/// The memory and CPU caps of a command, see `--max-memory` and `--cpu-limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceCaps {
+    pub max_memory: Option<u64>,
+    /// A share of one CPU, which may be above 100 for commands that use several.
+    pub cpu_percent: Option<u32>,
+    pub action: LimitAction,
+}
+
+impl ResourceCaps {
+    /// The caps of `instance`, or `None` if it has none.
+    pub fn new(instance: &InstanceArgs) -> Option<Self> {
+        let caps = Self {
+            max_memory: instance.max_memory.map(|size| size.0),
+            cpu_percent: instance.cpu_limit,
+            action: instance.on_limit.unwrap_or_default(),
+        };
+        (caps.max_memory.is_some() || caps.cpu_percent.is_some()).then_some(caps)
+    }
+}
+
+/// Returns the spec that runs the command of `spec` in a cgroup of its own with its caps, with
+/// `systemd-run --user --scope` on Linux. Without `--user` services, and elsewhere, the caps are
+/// enforced by [`CapWatch`] instead, and `None` is returned.
+pub fn apply(spec: &CommandSpec) -> Option<CommandSpec> {
+    platform::wrap(&spec.caps?, spec)
+}
+
+/// Watches the memory a capped command uses, and keeps it to its caps where [`apply`] didn't:
+/// with a job object on Windows, which its child processes are in too. On Linux it reads the
+/// counters of the command's cgroup, elsewhere it adds up the memory of its process group every
+/// few seconds, so short spikes can go unnoticed, and `--cpu-limit` isn't enforced.
+#[derive(Debug)]
+pub struct CapWatch {
+    caps: ResourceCaps,
+    watch: platform::Watch,
+    last_check: Instant,
+    /// Whether the command is over the limit, so that crossing it is only reported once.
+    over: bool,
+}
+
+impl CapWatch {
+    /// Starts watching `child`, which was spawned with the caps.
+    pub fn attach(caps: ResourceCaps, child: &ChildProcess) -> Self {
+        Self {
+            caps,
+            watch: platform::Watch::attach(&caps, child),
+            last_check: Instant::now(),
+            over: false,
+        }
+    }
+
+    /// What happens when the command goes over its memory limit.
+    pub fn action(&self) -> LimitAction {
+        self.caps.action
+    }
+
+    /// Returns how much memory the command uses if it just went over `--max-memory`, when it's
+    /// time for a check.
+    pub fn check(&mut self) -> Option<u64> {
+        let limit = self.caps.max_memory?;
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let (used, exceeded) = self.watch.sample(&self.caps)?;
+        if exceeded && !self.over {
+            self.over = true;
+            return Some(used);
+        }
+        // it has to go back down a bit before it's reported again
+        if !exceeded && used < limit / 10 * 9 {
+            self.over = false;
+        }
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{
+        ffi::OsString,
+        fs,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+        sync::OnceLock,
+    };
+
+    use log::{debug, warn};
+
+    use super::{LimitAction, ResourceCaps};
+    use crate::{child::ChildProcess, supervisor::CommandSpec};
+
+    /// The variables `systemd-run` finds the user's service manager with.
+    const BUS_VARS: &[&str] = &["XDG_RUNTIME_DIR", "DBUS_SESSION_BUS_ADDRESS"];
+
+    /// Whether the user's service manager can start scopes with cgroup v2 controllers, which is
+    /// checked once by starting an empty one.
+    fn scopes_available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE.get_or_init(|| {
+            if fs::metadata("/sys/fs/cgroup/cgroup.controllers").is_err() {
+                debug!("cgroup v2 isn't mounted");
+                return false;
+            }
+            let probe = Command::new("systemd-run")
+                .args(["--user", "--scope", "--quiet", "--", "true"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            match probe {
+                Ok(status) if status.success() => true,
+                Ok(status) => {
+                    debug!("systemd-run --user can't start scopes: {status}");
+                    false
+                }
+                Err(e) => {
+                    debug!("Failed to run systemd-run: {e}");
+                    false
+                }
+            }
+        })
+    }
+
+    pub fn wrap(caps: &ResourceCaps, spec: &CommandSpec) -> Option<CommandSpec> {
+        if !scopes_available() {
+            debug!("Can't start a cgroup for the caps, checking the memory of the command instead");
+            if caps.cpu_percent.is_some() {
+                warn!("--cpu-limit needs systemd --user services on Linux, see --cpu-throttle");
+            }
+            return None;
+        }
+        let mut cmd: Vec<OsString> = ["systemd-run", "--user", "--scope", "--quiet", "--collect"]
+            .map(OsString::from)
+            .into();
+        if let Some(bytes) = caps.max_memory {
+            // MemoryHigh slows the command down rather than killing it
+            let property = match caps.action {
+                LimitAction::Notify => "MemoryHigh",
+                LimitAction::Kill | LimitAction::Restart => "MemoryMax",
+            };
+            cmd.push("-p".into());
+            cmd.push(format!("{property}={bytes}").into());
+        }
+        if let Some(percent) = caps.cpu_percent {
+            cmd.push("-p".into());
+            cmd.push(format!("CPUQuota={percent}%").into());
+        }
+        cmd.push("--".into());
+        cmd.extend(spec.cmd.iter().cloned());
+        let mut capped = spec.clone();
+        capped.cmd = cmd;
+        if let Some(env) = capped.env.as_mut() {
+            for name in BUS_VARS {
+                if let (false, Ok(value)) = (env.contains_key(*name), std::env::var(name)) {
+                    env.insert((*name).to_string(), value);
+                }
+            }
+        }
+        Some(capped)
+    }
+
+    #[derive(Debug)]
+    pub enum Watch {
+        /// The command has a scope of its own, whose counters say when it went over.
+        Scope {
+            pid: u32,
+            events: u64,
+        },
+        Sampled(super::sampled::Group),
+    }
+
+    impl Watch {
+        pub fn attach(_: &ResourceCaps, child: &ChildProcess) -> Self {
+            if scopes_available() {
+                Self::Scope {
+                    pid: child.id(),
+                    events: 0,
+                }
+            } else {
+                Self::Sampled(super::sampled::Group::new(child))
+            }
+        }
+
+        /// How much memory the command uses, and whether it went over its limit since the last
+        /// sample.
+        pub fn sample(&mut self, caps: &ResourceCaps) -> Option<(u64, bool)> {
+            match self {
+                Self::Scope { pid, events } => {
+                    let dir = scope_dir(*pid)?;
+                    let used = fs::read_to_string(dir.join("memory.current")).ok()?;
+                    let used = used.trim().parse().ok()?;
+                    let counters = fs::read_to_string(dir.join("memory.events")).ok()?;
+                    // `high` counts the times it went over MemoryHigh, `max` over MemoryMax
+                    let counter = match caps.action {
+                        LimitAction::Notify => "high",
+                        LimitAction::Kill | LimitAction::Restart => "max",
+                    };
+                    let count = counters
+                        .lines()
+                        .filter_map(|line| line.split_once(' '))
+                        .find(|(name, _)| *name == counter)
+                        .and_then(|(_, count)| count.trim().parse().ok())?;
+                    let exceeded = count > *events;
+                    *events = count;
+                    Some((used, exceeded))
+                }
+                Self::Sampled(group) => group.sample(caps),
+            }
+        }
+    }
+
+    /// The cgroup directory of the scope `pid` is in, once `systemd-run` moved it there.
+    fn scope_dir(pid: u32) -> Option<PathBuf> {
+        let cgroup = |path: &str| {
+            fs::read_to_string(path)
+                .ok()?
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+        };
+        let theirs = cgroup(&format!("/proc/{pid}/cgroup"))?;
+        // until then, it's still in trayme's
+        let scope = Path::new(theirs.trim_start_matches('/'));
+        if Some(&theirs) == cgroup("/proc/self/cgroup").as_ref()
+            || scope.extension().is_none_or(|ext| ext != "scope")
+        {
+            return None;
+        }
+        Some(Path::new("/sys/fs/cgroup").join(scope))
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod platform {
+    use log::warn;
+
+    use super::ResourceCaps;
+    use crate::{child::ChildProcess, supervisor::CommandSpec};
+
+    pub fn wrap(caps: &ResourceCaps, _: &CommandSpec) -> Option<CommandSpec> {
+        if caps.cpu_percent.is_some() {
+            warn!("--cpu-limit isn't supported on this platform, see --cpu-throttle");
+        }
+        None
+    }
+
+    pub use super::sampled::Group as Watch;
+
+    impl Watch {
+        pub fn attach(_: &ResourceCaps, child: &ChildProcess) -> Self {
+            Self::new(child)
+        }
+    }
+}
+
+/// The memory of a process group, for platforms where the kernel can't keep count of it.
+#[cfg(unix)]
+mod sampled {
+    use std::process::Command;
+
+    use log::debug;
+
+    use super::ResourceCaps;
+    use crate::child::ChildProcess;
+
+    #[derive(Debug)]
+    pub struct Group {
+        pgid: u32,
+    }
+
+    impl Group {
+        /// The group the child leads, see `ProcessTree`.
+        pub fn new(child: &ChildProcess) -> Self {
+            Self { pgid: child.id() }
+        }
+
+        pub fn sample(&mut self, caps: &ResourceCaps) -> Option<(u64, bool)> {
+            let output = match Command::new("ps").args(["-A", "-o", "pgid=,rss="]).output() {
+                Ok(output) if output.status.success() => output,
+                Ok(output) => {
+                    debug!("ps failed: {}", output.status);
+                    return None;
+                }
+                Err(e) => {
+                    debug!("Failed to run ps: {e}");
+                    return None;
+                }
+            };
+            let pgid = self.pgid.to_string();
+            let kib: u64 = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    (fields.next()? == pgid).then(|| fields.next()?.parse::<u64>().ok())?
+                })
+                .sum();
+            let used = kib * 1024;
+            Some((used, caps.max_memory.is_some_and(|limit| used > limit)))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{io, os::windows::io::AsRawHandle, ptr};
+
+    use anyhow::Context;
+    use log::{debug, warn};
+
+    use super::{LimitAction, ResourceCaps};
+    use crate::{child::ChildProcess, supervisor::CommandSpec};
+
+    type Handle = *mut std::ffi::c_void;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_extended_limit_information
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    const JOB_OBJECT_LIMIT_JOB_MEMORY: u32 = 0x200;
+    // https://learn.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_cpu_rate_control_information
+    const JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION: i32 = 15;
+    const JOB_OBJECT_CPU_RATE_CONTROL_ENABLE: u32 = 0x1;
+    const JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP: u32 = 0x4;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimits {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        counts: [u64; 6],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimits {
+        basic: BasicLimits,
+        io: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[repr(C)]
+    struct CpuRateControl {
+        control_flags: u32,
+        /// The share of all CPUs in hundredths of a percent.
+        cpu_rate: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *const std::ffi::c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(
+            job: Handle,
+            class: i32,
+            info: *const std::ffi::c_void,
+            length: u32,
+        ) -> i32;
+        fn QueryInformationJobObject(
+            job: Handle,
+            class: i32,
+            info: *mut std::ffi::c_void,
+            length: u32,
+            returned: *mut u32,
+        ) -> i32;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    pub fn wrap(_: &ResourceCaps, _: &CommandSpec) -> Option<CommandSpec> {
+        None
+    }
+
+    #[derive(Debug)]
+    pub struct Watch {
+        /// `None` if the job couldn't be set up, in which case the caps aren't enforced.
+        job: Option<Handle>,
+    }
+
+    impl Watch {
+        pub fn attach(caps: &ResourceCaps, child: &ChildProcess) -> Self {
+            match create_job(caps, child) {
+                Ok(job) => Self { job: Some(job) },
+                Err(e) => {
+                    warn!("{e:#}, running without --max-memory and --cpu-limit");
+                    Self { job: None }
+                }
+            }
+        }
+
+        /// The most memory the job used so far, and whether that reached the limit. The peak
+        /// never goes down, so going over is reported once per run.
+        pub fn sample(&mut self, caps: &ResourceCaps) -> Option<(u64, bool)> {
+            let mut limits = ExtendedLimits::default();
+            // SAFETY: the job handle is open until the watch is dropped, and `limits` lives for
+            // the duration of the call
+            let queried = unsafe {
+                QueryInformationJobObject(
+                    self.job?,
+                    JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                    ptr::addr_of_mut!(limits).cast(),
+                    u32::try_from(std::mem::size_of::<ExtendedLimits>()).ok()?,
+                    ptr::null_mut(),
+                )
+            };
+            if queried == 0 {
+                debug!(
+                    "Failed to query the memory of the job: {}",
+                    io::Error::last_os_error()
+                );
+                return None;
+            }
+            let peak = u64::try_from(limits.peak_job_memory_used).ok()?;
+            Some((peak, caps.max_memory.is_some_and(|limit| peak >= limit)))
+        }
+    }
+
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            if let Some(job) = self.job {
+                // SAFETY: the handle was created by CreateJobObjectW and is only closed here.
+                // The limits stay on the processes in it until they exit.
+                unsafe {
+                    CloseHandle(job);
+                }
+            }
+        }
+    }
+
+    /// Creates a job with the caps, and puts `child` in it. Processes the child starts from then
+    /// on are in it too.
+    fn create_job(caps: &ResourceCaps, child: &ChildProcess) -> anyhow::Result<Handle> {
+        let mut limits = ExtendedLimits::default();
+        if let Some(bytes) = caps
+            .max_memory
+            .filter(|_| caps.action != LimitAction::Notify)
+        {
+            limits.basic.limit_flags = JOB_OBJECT_LIMIT_JOB_MEMORY;
+            limits.job_memory_limit = usize::try_from(bytes).unwrap_or(usize::MAX);
+        }
+        // the rate is shared by all CPUs, but --cpu-limit is a share of one of them
+        let cpus = std::thread::available_parallelism().map_or(1, usize::get);
+        let cpus = u32::try_from(cpus).unwrap_or(u32::MAX);
+        let rate = caps.cpu_percent.map(|percent| CpuRateControl {
+            control_flags: JOB_OBJECT_CPU_RATE_CONTROL_ENABLE
+                | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+            cpu_rate: (percent.saturating_mul(100) / cpus).clamp(1, 10_000),
+        });
+        // SAFETY: all pointers are valid for the duration of the calls, and the job handle is
+        // closed when the watch is dropped
+        unsafe {
+            let job = CreateJobObjectW(ptr::null(), ptr::null());
+            if job.is_null() {
+                return Err(io::Error::last_os_error())
+                    .context("Failed to create a job object for the caps");
+            }
+            let set_up = SetInformationJobObject(
+                job,
+                JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                ptr::addr_of!(limits).cast(),
+                u32::try_from(std::mem::size_of::<ExtendedLimits>())?,
+            ) != 0
+                && rate.as_ref().map_or(true, |rate| {
+                    SetInformationJobObject(
+                        job,
+                        JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION,
+                        ptr::from_ref(rate).cast(),
+                        u32::try_from(std::mem::size_of::<CpuRateControl>()).unwrap_or(u32::MAX),
+                    ) != 0
+                })
+                && AssignProcessToJobObject(job, child.as_raw_handle().cast()) != 0;
+            if !set_up {
+                let e = io::Error::last_os_error();
+                CloseHandle(job);
+                return Err(e).context("Failed to cap the process");
+            }
+            debug!("Capped PID {} with a job object", child.id());
+            Ok(job)
+        }
+    }
+}
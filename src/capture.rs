@@ -0,0 +1,469 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{ChildStderr, ChildStdout},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, info, warn};
+
+/// How long [`LogCapture::finish`] waits for the last output to be written. Output can keep
+/// coming after the child exited if it left processes behind that hold its stdout open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often writing to the log's real location is retried while the output goes to a fallback
+/// file.
+const RESTORE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A change in where the output is written, to let the user know.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkEvent {
+    /// The log file couldn't be written anymore, e.g. because the drive it's on was unmounted,
+    /// so the output goes to `fallback` in the temp directory until it can be written again.
+    FellBack { log: PathBuf, fallback: PathBuf },
+    /// The log file can be written again and has everything written to the fallback file.
+    Restored { log: PathBuf },
+}
+
+/// The file the output currently goes to, shared by the reader threads.
+struct Sink {
+    file: File,
+    /// The log file, even while the output goes to a fallback file.
+    path: PathBuf,
+    fallback: Option<Fallback>,
+    events: mpsc::Sender<SinkEvent>,
+}
+
+/// A file in the temp directory the output goes to while the log file can't be written.
+struct Fallback {
+    path: PathBuf,
+    last_attempt: Instant,
+}
+
+impl Sink {
+    fn new(file: File, path: &Path, events: mpsc::Sender<SinkEvent>) -> Self {
+        Self {
+            file,
+            path: path.to_path_buf(),
+            fallback: None,
+            events,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        if self.fallback.is_some() {
+            self.try_restore(false);
+        }
+        let Err(e) = self.file.write_all(buf) else {
+            return;
+        };
+        if self.fallback.is_some() {
+            warn!("Failed to write output to the fallback file: {e}");
+            return;
+        }
+        warn!("Failed to write output to {}: {e}", self.path.display());
+        match self.fall_back() {
+            Ok(()) => {
+                if let Err(e) = self.file.write_all(buf) {
+                    warn!("Failed to write output to the fallback file: {e}");
+                }
+            }
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+
+    /// Sends the output to a file in the temp directory.
+    fn fall_back(&mut self) -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(concat!(env!("CARGO_PKG_NAME"), "-fallback"));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(self.path.file_name().unwrap_or_default());
+        self.file = open_log(&path)?;
+        warn!("Writing output to {} for now", path.display());
+        let _ = self.events.send(SinkEvent::FellBack {
+            log: self.path.clone(),
+            fallback: path.clone(),
+        });
+        self.fallback = Some(Fallback {
+            path,
+            last_attempt: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Moves the output back to the log file if it can be written again, copying over what was
+    /// written to the fallback file. Unless `now` is set, this is only tried every
+    /// [`RESTORE_INTERVAL`].
+    fn try_restore(&mut self, now: bool) {
+        let Some(fallback) = self.fallback.as_mut() else {
+            return;
+        };
+        if !now && fallback.last_attempt.elapsed() < RESTORE_INTERVAL {
+            return;
+        }
+        fallback.last_attempt = Instant::now();
+        let restored = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut log| {
+                let mut written = File::open(&fallback.path)?;
+                io::copy(&mut written, &mut log)?;
+                Ok(log)
+            });
+        let log = match restored {
+            Ok(log) => log,
+            Err(e) => {
+                debug!("{} still can't be written: {e}", self.path.display());
+                return;
+            }
+        };
+        self.file = log;
+        if let Err(e) = std::fs::remove_file(&fallback.path) {
+            warn!("Failed to remove {}: {e}", fallback.path.display());
+        }
+        self.fallback = None;
+        info!("Writing output to {} again", self.path.display());
+        let _ = self.events.send(SinkEvent::Restored {
+            log: self.path.clone(),
+        });
+    }
+}
+
+/// Copies the child's stdout and stderr into its log file from background threads. Unlike
+/// handing the child the file directly, this lets the log be swapped for a new one while the
+/// child keeps running.
+pub struct LogCapture {
+    sink: Arc<Mutex<Sink>>,
+    bytes: Arc<AtomicU64>,
+    readers: Vec<JoinHandle<()>>,
+    events: mpsc::Receiver<SinkEvent>,
+    events_tx: mpsc::Sender<SinkEvent>,
+    /// Copies of the read ends of the child's stdout and stderr, for [`LogCapture::hand_off`].
+    #[cfg(unix)]
+    pipes: Option<[OwnedFd; 2]>,
+}
+
+impl LogCapture {
+    /// Starts copying the child's output into `file`, which is at `path`. If the file can't be
+    /// written anymore, the output goes to the temp directory until it can be again (see
+    /// [`SinkEvent`]).
+    pub fn start(file: File, path: &Path, stdout: ChildStdout, stderr: ChildStderr) -> Self {
+        #[cfg(unix)]
+        let pipes = stdout
+            .as_fd()
+            .try_clone_to_owned()
+            .and_then(|stdout| Ok([stdout, stderr.as_fd().try_clone_to_owned()?]))
+            .map_err(|e| warn!("Failed to keep the output pipes, they can't be handed off: {e}"))
+            .ok();
+        let (events_tx, events) = mpsc::channel();
+        let sink = Arc::new(Mutex::new(Sink::new(file, path, events_tx.clone())));
+        let bytes = Arc::new(AtomicU64::new(0));
+        let readers = vec![
+            spawn_reader(stdout, Arc::clone(&sink), Arc::clone(&bytes)),
+            spawn_reader(stderr, Arc::clone(&sink), Arc::clone(&bytes)),
+        ];
+        Self {
+            sink,
+            bytes,
+            readers,
+            events,
+            events_tx,
+            #[cfg(unix)]
+            pipes,
+        }
+    }
+
+    /// Hands the output over to a `trayme relay-output` process in its own process group, which
+    /// keeps appending it to the log once trayme exits, so that the child can outlive trayme
+    /// without writing into closed pipes.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the output was handed off before, or the relay cannot be started.
+    #[cfg(unix)]
+    pub fn hand_off(&mut self) -> anyhow::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let [stdout, stderr] = self
+            .pipes
+            .take()
+            .context("The output can't be handed off")?;
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        let path = sink
+            .fallback
+            .as_ref()
+            .map_or(&sink.path, |fallback| &fallback.path)
+            .clone();
+        // the relay appends, so what is still copied here must not overwrite it
+        sink.file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to reopen {}", path.display()))?;
+        let exe = std::env::current_exe().context("Failed to get the path of trayme")?;
+        let relay = std::process::Command::new(exe)
+            .arg("relay-output")
+            .arg(&path)
+            // the relay reads the child's stdout from its stdin and its stderr from its stdout
+            .stdin(stdout)
+            .stdout(stderr)
+            .stderr(std::process::Stdio::null())
+            .process_group(0)
+            .spawn()
+            .context("Failed to start the output relay")?;
+        info!(
+            "Output of the detached process goes through PID {}",
+            relay.id()
+        );
+        Ok(())
+    }
+
+    /// The read ends of the child's stdout and stderr, for handing them to the trayme on disk,
+    /// see [`crate::handover`]. `None` if the output was handed off.
+    #[cfg(unix)]
+    pub fn pipes(&self) -> Option<[BorrowedFd<'_>; 2]> {
+        self.pipes
+            .as_ref()
+            .map(|[stdout, stderr]| [stdout.as_fd(), stderr.as_fd()])
+    }
+
+    /// Returns the changes in where the output goes since the last call.
+    pub fn events(&self) -> Vec<SinkEvent> {
+        self.events.try_iter().collect()
+    }
+
+    /// How many bytes of output were written so far, across all rotated files.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sends all further output to a new file at `path` and returns the path of the old one.
+    /// Output is never split mid-chunk between the two files.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the new file cannot be created. The output keeps going to the old
+    /// one in that case.
+    pub fn rotate(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let file = open_log(path)?;
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        let old = std::mem::replace(&mut *sink, Sink::new(file, path, self.events_tx.clone()));
+        info!("Rotated log {} -> {}", old.path.display(), path.display());
+        Ok(old.path)
+    }
+
+    /// Waits up to [`DRAIN_TIMEOUT`] for the reader threads to write the rest of the output, and
+    /// moves the output from the fallback file to the log file if it can. Call this once the
+    /// child exited.
+    pub fn finish(&mut self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while self.readers.iter().any(|reader| !reader.is_finished()) {
+            if Instant::now() >= deadline {
+                debug!("Output still open after the process exited, not waiting for it");
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+        self.sink
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .try_restore(true);
+    }
+}
+
+/// Copies everything read from `pipe` into the sink until the pipe is closed.
+fn spawn_reader(
+    mut pipe: impl Read + Send + 'static,
+    sink: Arc<Mutex<Sink>>,
+    bytes: Arc<AtomicU64>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let read = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to read output: {e}");
+                    break;
+                }
+            };
+            sink.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write(&buf[..read]);
+            bytes.fetch_add(read as u64, Ordering::Relaxed);
+        }
+    })
+}
+
+/// Appends the output of a detached child to `log` until the child closes its end of the pipes,
+/// as the `relay-output` process [`LogCapture::hand_off`] starts. The pipes are stdin (the child's
+/// stdout) and stdout (its stderr).
+///
+/// # Errors
+///
+/// An error is returned if the log cannot be opened.
+#[cfg(unix)]
+pub fn relay(log: &Path) -> anyhow::Result<()> {
+    use std::os::fd::FromRawFd;
+
+    let file = OpenOptions::new()
+        .append(true)
+        .open(log)
+        .with_context(|| format!("Failed to open output file {}", log.display()))?;
+    let (events, _) = mpsc::channel();
+    let sink = Arc::new(Mutex::new(Sink::new(file, log, events)));
+    let bytes = Arc::new(AtomicU64::new(0));
+    // SAFETY: hand_off passes the pipes as fds 0 and 1, and nothing else in this process uses
+    // them
+    let pipes = [0, 1].map(|fd| File::from(unsafe { OwnedFd::from_raw_fd(fd) }));
+    let readers: Vec<_> = pipes
+        .into_iter()
+        .map(|pipe| spawn_reader(pipe, Arc::clone(&sink), Arc::clone(&bytes)))
+        .collect();
+    for reader in readers {
+        let _ = reader.join();
+    }
+    Ok(())
+}
+
+/// Creates (or truncates) a log file.
+///
+/// # Errors
+///
+/// An error is returned if the file cannot be created.
+pub fn open_log(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Failed to open output file {}", path.display()))
+}
+
+/// Returns the path a rotated log is compressed to, e.g. `run.log.gz` for `run.log`.
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// Compresses a rotated log with gzip in the background and deletes the original once done. If
+/// compressing fails, the original is kept.
+pub fn compress_in_background(path: PathBuf) {
+    std::thread::spawn(move || match compress(&path) {
+        Ok(compressed) => info!("Compressed {}", compressed.display()),
+        Err(e) => warn!("{e:#}"),
+    });
+}
+
+fn compress(path: &Path) -> anyhow::Result<PathBuf> {
+    let compressed = compressed_path(path);
+    let mut input =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let output = File::create(&compressed)
+        .with_context(|| format!("Failed to create {}", compressed.display()))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .with_context(|| format!("Failed to compress {}", path.display()))?;
+    std::fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    Ok(compressed)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    /// A directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-{name}-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Waits until `capture` wrote `bytes` in total.
+    fn wait_for(capture: &LogCapture, bytes: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while capture.bytes_written() < bytes {
+            assert!(Instant::now() < deadline, "output never arrived");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn rotates_without_losing_output() {
+        let dir = test_dir("rotate");
+        let first = dir.join("run.log");
+        let second = dir.join("run.1.log");
+        let mut child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut capture = LogCapture::start(
+            open_log(&first).unwrap(),
+            &first,
+            child.stdout.take().unwrap(),
+            child.stderr.take().unwrap(),
+        );
+
+        stdin.write_all(b"before\n").unwrap();
+        wait_for(&capture, 7);
+        assert_eq!(capture.rotate(&second).unwrap(), first);
+        stdin.write_all(b"after\n").unwrap();
+        drop(stdin);
+        child.wait().unwrap();
+        capture.finish();
+
+        assert_eq!(capture.bytes_written(), 13);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "before\n");
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "after\n");
+        assert!(capture.events().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn compresses_rotated_logs() {
+        let dir = test_dir("compress");
+        let log = dir.join("run.log");
+        std::fs::write(&log, "output\n".repeat(100)).unwrap();
+        compress(&log).unwrap();
+
+        let compressed = compressed_path(&log);
+        assert_eq!(compressed, dir.join("run.log.gz"));
+        assert!(!log.exists());
+        let mut text = String::new();
+        GzDecoder::new(File::open(&compressed).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "output\n".repeat(100));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,97 @@
+use std::ffi::OsString;
+
+/// The separator between the steps of a chain on the command line, see `--then`.
+pub const SEPARATOR: &str = "--then";
+
+/// Commands that run one after another as one run of an instance, e.g. a build, then its tests,
+/// then a deploy. See `--then`.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    /// Every step, the first one included.
+    steps: Vec<Vec<OsString>>,
+    /// The index of the step that runs.
+    current: usize,
+    continue_on_failure: bool,
+    /// How many steps of this pass through the chain failed.
+    failures: usize,
+}
+
+impl Chain {
+    /// Creates the chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `first` - The command of the first step.
+    /// * `then` - The commands of the steps after it.
+    /// * `continue_on_failure` - Whether a failed step is followed by the next one anyway.
+    pub fn new(first: Vec<OsString>, then: Vec<Vec<OsString>>, continue_on_failure: bool) -> Self {
+        let mut steps = vec![first];
+        steps.extend(then);
+        Self {
+            steps,
+            current: 0,
+            continue_on_failure,
+            failures: 0,
+        }
+    }
+
+    /// Counts the step that ended, and returns the command of the next one if the chain goes
+    /// on.
+    ///
+    /// # Arguments
+    ///
+    /// * `success` - Whether the step exited successfully.
+    pub fn advance(&mut self, success: bool) -> Option<&[OsString]> {
+        if !success {
+            self.failures += 1;
+        }
+        if (!success && !self.continue_on_failure) || self.current + 1 >= self.steps.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(&self.steps[self.current])
+    }
+
+    /// Starts over at the first step, returning its command, e.g. for a restart.
+    pub fn reset(&mut self) -> &[OsString] {
+        self.current = 0;
+        self.failures = 0;
+        &self.steps[0]
+    }
+
+    /// The step that runs and how many there are, counting from 1, e.g. for the tooltip.
+    pub fn position(&self) -> (usize, usize) {
+        (self.current + 1, self.steps.len())
+    }
+
+    /// How the pass through the chain went, for the notification once it ended, or `None` if
+    /// every step succeeded.
+    pub fn summary(&self) -> Option<String> {
+        let (step, total) = self.position();
+        if step < total {
+            Some(format!("Stopped after step {step} of {total} failed"))
+        } else if self.failures > 0 {
+            Some(format!("{} of {total} steps failed", self.failures))
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits a command line into the commands of its steps at each `--then`. Returns `None` if a
+/// step is empty.
+pub fn split(cmd: Vec<OsString>) -> Option<(Vec<OsString>, Vec<Vec<OsString>>)> {
+    let mut steps = vec![Vec::new()];
+    for arg in cmd {
+        if arg == SEPARATOR {
+            steps.push(Vec::new());
+        } else if let Some(step) = steps.last_mut() {
+            step.push(arg);
+        }
+    }
+    if steps.iter().any(Vec::is_empty) {
+        return None;
+    }
+    let first = steps.remove(0);
+    Some((first, steps))
+}
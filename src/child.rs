@@ -0,0 +1,139 @@
+use std::{
+    io,
+    process::{Child, ChildStdin},
+};
+
+#[cfg(windows)]
+use std::{
+    os::windows::io::{AsRawHandle, RawHandle},
+    process::ExitStatus,
+};
+
+/// The process of a run. It was spawned by this trayme or, on Unix, adopted from the trayme that
+/// handed the instance over to this one (see [`crate::handover`]), which spawned it from this
+/// very process before replacing itself with this binary. Either way it's a child of trayme's,
+/// so it's reaped like one.
+#[derive(Debug)]
+pub struct ChildProcess {
+    inner: Inner,
+    /// The write end of the process' stdin, see [`crate::supervisor::Supervisor::send_line`].
+    pub stdin: Option<ChildStdin>,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Spawned(Child),
+    /// Known by its PID only, since [`Child`] can't be made from one.
+    #[cfg(unix)]
+    Adopted(u32),
+}
+
+impl ChildProcess {
+    /// The process with the ID `pid`, which must be a child of this process that hasn't been
+    /// reaped yet.
+    #[cfg(unix)]
+    pub fn adopt(pid: u32, stdin: Option<ChildStdin>) -> Self {
+        Self {
+            inner: Inner::Adopted(pid),
+            stdin,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match &self.inner {
+            Inner::Spawned(child) => child.id(),
+            #[cfg(unix)]
+            Inner::Adopted(pid) => *pid,
+        }
+    }
+
+    /// Kills the process, like [`Child::kill`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be killed.
+    pub fn kill(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Spawned(child) => child.kill(),
+            #[cfg(unix)]
+            Inner::Adopted(pid) => {
+                let pid = libc::pid_t::try_from(*pid).map_err(io::Error::other)?;
+                // SAFETY: kill has no memory safety preconditions
+                if unsafe { libc::kill(pid, libc::SIGKILL) } == 0 {
+                    return Ok(());
+                }
+                let err = io::Error::last_os_error();
+                // like Child::kill, which doesn't fail for a process that was reaped
+                if err.raw_os_error() == Some(libc::ESRCH) {
+                    return Ok(());
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Like [`Child::try_wait`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process' status cannot be queried.
+    #[cfg(windows)]
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        let Inner::Spawned(child) = &mut self.inner;
+        child.try_wait()
+    }
+
+    /// Like [`Child::wait`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be waited on.
+    #[cfg(windows)]
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let Inner::Spawned(child) = &mut self.inner;
+        child.wait()
+    }
+}
+
+impl From<Child> for ChildProcess {
+    fn from(mut child: Child) -> Self {
+        let stdin = child.stdin.take();
+        Self {
+            inner: Inner::Spawned(child),
+            stdin,
+        }
+    }
+}
+
+/// Windows has no way to adopt processes, so every process has the handle of its [`Child`].
+#[cfg(windows)]
+impl AsRawHandle for ChildProcess {
+    fn as_raw_handle(&self) -> RawHandle {
+        let Inner::Spawned(child) = &self.inner;
+        child.as_raw_handle()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    #[test]
+    fn adopted_children_are_killed_and_reaped_like_spawned_ones() {
+        let spawned = Command::new("sleep")
+            .arg("30")
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = spawned.id();
+        // what's left of the child once the trayme that spawned it exec'd the next one
+        std::mem::forget(spawned);
+        let mut adopted = ChildProcess::adopt(pid, None);
+        assert_eq!(adopted.id(), pid);
+        adopted.kill().unwrap();
+        let (status, _) = crate::usage::wait(&mut adopted).unwrap();
+        assert!(!status.success());
+    }
+}
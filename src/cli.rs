@@ -0,0 +1,940 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand, ValueEnum, ValueHint};
+
+use regex::Regex;
+
+use crate::{
+    calendar::CalendarSource,
+    caps::{ByteSize, LimitAction},
+    config::ProfileRef,
+    confirm::ConfirmMethod,
+    display::DisplayBackend,
+    dotenv,
+    envprovider::EnvProvider,
+    exitcode::ErrorFormat,
+    health::Threshold,
+    limits::ResourceLimit,
+    logout::OnLogout,
+    notify::{NotifyEvent, NotifyUrgency},
+    ping::PingUrl,
+    priority::Priority,
+    restart::RestartPolicy,
+    schedule::{Constraints, CronExpr, MissedPolicy, OverlapPolicy, Schedule, TimeWindow},
+    selflog::Verbosity,
+    sleep::OnWake,
+    statusline::BarFormat,
+    stop::StopStrategy,
+    tempdir::TempCleanup,
+    token::Scope,
+    waitfor::PortAddr,
+};
+
+/// The exit codes of trayme itself, see [`crate::exitcode::ErrorKind`].
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Any other error
+  2  Invalid command line
+  3  Invalid config file or profile, or no such profile
+  4  The command couldn't be started, its --pre-check failed, or what it waits for with
+     --wait-for-* didn't become available
+  5  There is no display or tray to show the icon in
+  6  The instance to control isn't running
+
+Once the command ends by itself and isn't restarted, trayme exits with the command's exit code
+instead, or with 128 plus the signal's number if a signal killed it (Unix). It exits with 0 if
+the command was killed from the tray or over IPC, or left running.";
+
+/// Runs any command-line command in the system tray. This is meant for long-running
+/// background processes that the user wants to keep running without having to keep a
+/// terminal window open, but it'll work with any command.
+#[derive(Debug, Parser)]
+#[command(
+    trailing_var_arg = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    about,
+    version,
+    author,
+    after_long_help = EXIT_CODES
+)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    pub subcommand: Option<CliSubcommand>,
+    /// Which display protocol the tray uses on Linux. Try `x11` if the tray fails to show up on
+    /// a Wayland compositor; `trayme doctor` shows what the session supports.
+    #[arg(long, value_enum, global = true, default_value_t)]
+    pub display_backend: DisplayBackend,
+    /// How the error trayme exits with is printed: `json` prints a line with its kind, exit code,
+    /// message, and causes to stderr, for scripts. See `--help` for the exit codes.
+    #[arg(long, value_enum, global = true, default_value_t)]
+    pub error_format: ErrorFormat,
+    /// Logs everything that goes into spawning the command: each argument exactly, the program
+    /// and interpreter it resolves to, the working directory, the environment's differences from
+    /// trayme's, and how its streams are set up. Failed spawns get the OS error code and what it
+    /// usually means, for when "Failed to spawn command" isn't enough.
+    #[arg(long, global = true)]
+    pub trace_spawn: bool,
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+/// Arguments for running a command in the tray. These are used when no subcommand is given.
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// The urgency of failure notifications. Start and exit notifications are never shown with
+    /// more than normal urgency.
+    #[arg(long, value_enum, default_value_t = NotifyUrgency::Normal)]
+    pub notify_urgency: NotifyUrgency,
+    /// A custom sound to play for an event, as EVENT=SOUND (e.g. failure=dialog-error). Sound names
+    /// are platform-specific. Can be given multiple times.
+    #[arg(long, value_name = "EVENT=SOUND", value_parser = parse_event_sound)]
+    pub notify_sound: Vec<(NotifyEvent, String)>,
+    /// The working directory of the command. Defaults to trayme's.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, value_parser = parse_dir)]
+    pub cwd: Option<PathBuf>,
+    /// Sets a variable in the command's environment, over those of `--env-file`. Can be given
+    /// multiple times.
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub env: Vec<(String, String)>,
+    /// Sets the variables in a dotenv file (`KEY=VALUE` lines, `#` comments) in the command's
+    /// environment. Can be given multiple times, with later files winning.
+    #[arg(
+        long = "env-file",
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        value_parser = parse_env_file
+    )]
+    pub env_files: Vec<BTreeMap<String, String>>,
+    /// Starts the command with only the variables of `--env` and `--env-file` instead of
+    /// inheriting trayme's environment, for services that should run the same wherever they're
+    /// started from. Programs are still looked up in trayme's `PATH` unless `--env` sets one.
+    #[arg(long)]
+    pub clear_env: bool,
+    /// Runs the command as one string with `$SHELL -c` (`cmd /C` on Windows), so that pipes,
+    /// globs, and `&&` work, e.g. `trayme --shell 'make && ./serve | tee out.log'`. Its logs are
+    /// named after the command line.
+    #[arg(long)]
+    pub shell: bool,
+    #[command(flatten)]
+    pub instance: InstanceArgs,
+    /// The command to run. Its arguments are passed on as they are, including ones that aren't valid
+    /// Unicode. More commands can follow it, each after `--then`, e.g. `trayme -- make --then
+    /// ./deploy.sh`: they run one after another once the one before succeeded, as one run that
+    /// restarts from the first, with the tooltip showing the step that runs.
+    #[arg(required = true, value_hint = ValueHint::CommandWithArguments, num_args = 1..)]
+    pub cmd: Vec<OsString>,
+    // TODO: customize tray icon via cli (e.g. tooltip, icon, etc.)
+}
+
+/// Options for how an instance runs and how other trayme processes can reach it.
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)] // independent command line flags
+pub struct InstanceArgs {
+    /// The name of this instance, used to control it from other trayme processes. Defaults to the
+    /// program name.
+    #[arg(long, value_parser = parse_instance_name)]
+    pub name: Option<String>,
+    /// Runs without a tray icon or notifications, e.g. as a system service. Use `trayme tray
+    /// <NAME>` to show a tray icon for it from a desktop session.
+    #[arg(long)]
+    pub headless: bool,
+    /// Waits for the desktop's tray to come up before showing the icon, for up to TIMEOUT
+    /// (default 60s), for autostart entries that start before the panel does. Linux only.
+    #[arg(
+        long,
+        value_name = "TIMEOUT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "60s",
+        value_parser = humantime::parse_duration,
+        conflicts_with = "headless"
+    )]
+    pub wait_for_tray: Option<Duration>,
+    /// What happens to the command when the desktop session ends: `kill` stops it first, while
+    /// `detach` leaves it running with its output still logged (Unix only, its stdin is closed).
+    /// Without this, trayme doesn't handle the end of the session at all.
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub on_logout: Option<OnLogout>,
+    /// Leaves the command running whenever trayme exits without being asked to kill it, e.g. when
+    /// it's interrupted or the session ends, like the Detach item of the tray menu does. Its
+    /// output keeps going to the log on Unix.
+    #[arg(long)]
+    pub detach_on_exit: bool,
+    /// Keeps the tray up once the command exited on its own, with the icon showing how it ended
+    /// and Relaunch and Quit in the menu, rather than taking the tray with it. Killing the
+    /// instance still ends it.
+    #[arg(long, conflicts_with = "headless")]
+    pub stay_open: bool,
+    /// The address of the control socket. Defaults to a random port on localhost.
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:0")]
+    pub listen: SocketAddr,
+    /// How to stop the command when it's killed from the tray or over IPC. The process is killed
+    /// outright if it doesn't exit in time. Defaults to picking the best one for the program.
+    #[arg(long, value_enum)]
+    pub stop_strategy: Option<StopStrategy>,
+    /// How long the command gets to exit after each step of the stop strategy (e.g. SIGTERM)
+    /// before the next one is tried, and finally before it's killed outright. Defaults to 2s.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub kill_timeout: Option<Duration>,
+    /// Tags this instance for filtering with `trayme ls/down --tag` and grouping in the aggregator
+    /// tray. With `up`, selects every profile with the tag instead. Can be given multiple times.
+    #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+    /// Marks the instance unhealthy when too many lines of its output match this regex (see
+    /// `--threshold`), even if the process keeps running.
+    #[arg(long, value_name = "PATTERN")]
+    pub unhealthy_if: Option<Regex>,
+    /// How many matching lines within how long make the instance unhealthy, as COUNT/WINDOW.
+    /// Defaults to 10/60s.
+    #[arg(long, value_name = "COUNT/WINDOW", requires = "unhealthy_if")]
+    pub threshold: Option<Threshold>,
+    /// Restarts the process when it becomes unhealthy.
+    #[arg(long, requires = "unhealthy_if")]
+    pub restart_on_unhealthy: bool,
+    /// Starts the command again when it exits on its own: `on-failure` only when it fails,
+    /// `always` whenever it does. Being stopped with SIGHUP, SIGINT, SIGTERM, or SIGPIPE isn't a
+    /// failure. The tray stays up in between.
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub restart_policy: Option<RestartPolicy>,
+    /// How long the first of several restarts in a row waits. The wait doubles with every
+    /// further one, up to 5m, and starts over once a run lasts a minute. Defaults to 1s.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub restart_backoff: Option<Duration>,
+    /// Gives up after this many restarts in a row, except in maintenance mode. Unlimited by
+    /// default.
+    #[arg(long, value_name = "COUNT")]
+    pub max_restarts: Option<u32>,
+    /// Stops the process once it ran this long (e.g. `30m`), with its stop strategy. This counts
+    /// as a failure, so it's restarted if `--restart-policy` says so, and the instance ends
+    /// otherwise. The status submenu shows the time left.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
+    /// Pings a dead man's switch check around each run, the way healthchecks.io expects:
+    /// `<URL>/start` when the process starts, `<URL>` when it exits cleanly, and `<URL>/fail`
+    /// when it fails or times out. The service alerts once the pings stop, even if the machine
+    /// or trayme is down.
+    #[arg(long, value_name = "URL")]
+    pub ping_url: Option<PingUrl>,
+    /// Restarts the process after it ran this long (e.g. `6h`), to cycle one that leaks before
+    /// it gets bad. Counted from the start of each run.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub restart_every: Option<Duration>,
+    /// Restarts the process at the times matching a cron expression (minute hour day month
+    /// weekday), e.g. `'0 3 * * *'`. With `--restart-every` too, whichever comes first applies.
+    #[arg(long, value_name = "EXPR")]
+    pub restart_cron: Option<CronExpr>,
+    /// Runs this shell command instead once the command failed `--fallback-after` times in a
+    /// row, e.g. a stable build when the nightly one keeps crashing. The tray says so while it
+    /// runs, and restarting from the tray tries the command again. Restarts on failure unless
+    /// `--restart-policy` says otherwise.
+    #[arg(long, value_name = "CMD")]
+    pub fallback: Option<String>,
+    /// How many failures in a row switch to `--fallback`. Defaults to 3.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        requires = "fallback",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub fallback_after: Option<u32>,
+    /// What happens to the process once the machine wakes from sleep, which is logged either
+    /// way: `nothing`, `restart`, or `healthcheck` to run `--wake-check` and restart it if the
+    /// check fails, e.g. for SSH tunnels that die across sleep. Sleeps shorter than a minute
+    /// aren't noticed.
+    #[arg(long, value_enum, value_name = "ACTION")]
+    pub on_wake: Option<OnWake>,
+    /// The shell command that checks on the process after a wake, e.g. `nc -z localhost 8080`,
+    /// with the command's working directory and environment. It's stopped after 30 seconds.
+    /// Implies `--on-wake healthcheck` unless that says otherwise.
+    #[arg(long, value_name = "CMD")]
+    pub wake_check: Option<String>,
+    /// How long maintenance mode lasts when it's turned on from the tray menu or with `trayme
+    /// maintenance` without `--for`. Defaults to 30m.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub maintenance_duration: Option<Duration>,
+    /// Includes the peak memory, CPU time, and log size of the run in the exit notification. These
+    /// are recorded in the run history either way.
+    #[arg(long)]
+    pub verbose_exit: bool,
+    /// Shows the progress the command reports in its output, with an estimate of the time left, in
+    /// the tray icon, tooltip, and status menu. The first capture group (or the whole match) must
+    /// be a percentage, e.g. `(\d+)%`.
+    #[arg(long, value_name = "PATTERN")]
+    pub progress_regex: Option<Regex>,
+    /// Shows a notification with the first line the command writes that isn't blank, such as
+    /// `Listening on http://localhost:3000`, and keeps it in the status menu.
+    #[arg(long)]
+    pub first_output_notify: bool,
+    /// Runs the command in a development environment: `nix` (the Nix shell of the working
+    /// directory), `venv:PATH` (a Python virtual environment), or `asdf`.
+    #[arg(long, value_name = "PROVIDER")]
+    pub env_provider: Option<EnvProvider>,
+    /// Runs the command in this time zone by setting `TZ`, e.g. `UTC` or `Europe/Berlin`. On
+    /// Windows only programs using the C runtime's time functions honor it, and only with POSIX
+    /// style zones such as `UTC` or `EST5EDT`.
+    #[arg(long, value_name = "ZONE")]
+    pub tz: Option<String>,
+    /// Runs the command with this locale by setting `LANG` and `LC_ALL`, e.g. `C.UTF-8` or
+    /// `en_US.UTF-8`. Windows has no per-process locale, so there only programs that read these
+    /// variables (such as Python or Git) honor it.
+    #[arg(long, value_name = "LOCALE")]
+    pub locale: Option<String>,
+    /// Sets a resource limit of the command as RESOURCE=SOFT[:HARD], e.g. `nofile=8192` for dev
+    /// servers that run out of file descriptors. RESOURCE is one of nofile, nproc, core, stack,
+    /// memlock, as, cpu, or fsize, and either limit may be `unlimited`. Only root can raise the
+    /// hard limit, so the soft one is kept below it otherwise. Ignored on Windows, which has no
+    /// such limits. Can be given multiple times.
+    #[arg(long = "ulimit", value_name = "RESOURCE=SOFT[:HARD]")]
+    pub ulimits: Vec<ResourceLimit>,
+    /// Runs this shell command before the command is started or restarted, and doesn't start it
+    /// unless it succeeds, e.g. to check that a VPN is up. Its output is shown in the
+    /// notification if it fails. It runs with the command's working directory and environment,
+    /// and is stopped after 30 seconds.
+    #[arg(long, value_name = "CMD")]
+    pub pre_check: Option<String>,
+    /// Holds the start back until at least this much memory is free, e.g. `1G`, waiting like
+    /// the `--wait-for-*` options do. Restarts are put off until it is too, and checked again
+    /// every 10 seconds, rather than restarting a command that ran out of memory into the same
+    /// shortage.
+    #[arg(long, value_name = "SIZE")]
+    pub require_free_mem: Option<ByteSize>,
+    /// Holds starts and restarts back until at least this much disk space is free on the
+    /// filesystem of the working directory, the same way as `--require-free-mem`.
+    #[arg(long, value_name = "SIZE")]
+    pub require_free_disk: Option<ByteSize>,
+    /// Runs the command with administrator rights, after a prompt to allow it: pkexec (or `sudo
+    /// -A` with `SUDO_ASKPASS` set) on Linux, an administrator password dialog on macOS, or UAC
+    /// on Windows. trayme itself keeps running as the user. Only what `--env`, `--tz`, and
+    /// `--locale` set is passed on, and not at all on Windows, where the command gets a console of
+    /// its own and its output isn't logged. trayme can't stop a process of the administrator's
+    /// itself, so stopping it, be it with Kill, `--timeout`, `--max-memory`, or a restart, asks
+    /// for administrator rights again.
+    #[arg(long)]
+    pub elevate: bool,
+    /// Gives each run a temporary directory of its own, which `TMPDIR`, `TEMP`, and `TMP` point
+    /// the command to, and which "Open Temp Dir" opens. Once the run is over, it's deleted
+    /// (`delete`, the default), kept if the run failed or was killed (`keep-failed`), or kept
+    /// (`keep`). Runs that are left running when trayme exits keep theirs.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "CLEANUP",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "delete"
+    )]
+    pub temp_dir: Option<TempCleanup>,
+    /// Runs this shell command whenever the command starts, including restarts, e.g. to
+    /// register it somewhere. Hooks run in the background with the command's working directory
+    /// and environment, plus `TRAYME_EVENT`, `TRAYME_INSTANCE`, `TRAYME_RUN_ID`, `TRAYME_PID`, and
+    /// `TRAYME_LOG_FILE`. They're stopped after a minute, and one that fails is only logged.
+    #[arg(long, value_name = "CMD")]
+    pub on_start: Option<String>,
+    /// Runs this shell command whenever a run ends, whether it exited, failed, or was stopped,
+    /// with `TRAYME_EXIT_CODE` and `TRAYME_EXIT_STATUS` on top of what `--on-start` gets, e.g. to
+    /// clean up after it. Runs that are left running when trayme exits don't run it.
+    #[arg(long, value_name = "CMD")]
+    pub on_exit: Option<String>,
+    /// Runs this shell command whenever a run fails or times out, after `--on-exit`, with the
+    /// same variables.
+    #[arg(long, value_name = "CMD")]
+    pub on_failure: Option<String>,
+    /// Starts the command only once this port accepts connections, e.g. `5432` or `db:5432`
+    /// (`[HOST:]PORT`, on localhost by default). The tooltip shows what's waited for. Only the
+    /// first start waits. Can be given multiple times.
+    #[arg(long, value_name = "[HOST:]PORT")]
+    pub wait_for_port: Vec<PortAddr>,
+    /// Starts the command only once this file exists, e.g. one that another program creates
+    /// when it's ready. Can be given multiple times.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    pub wait_for_file: Vec<PathBuf>,
+    /// Starts the command only once this shell command succeeds, e.g. `pg_isready`. It's run
+    /// every second with the command's working directory and environment, and counts as failed
+    /// if it takes longer than 5 seconds. Can be given multiple times.
+    #[arg(long, value_name = "CMD")]
+    pub wait_for_cmd: Vec<OsString>,
+    /// How long the `--wait-for-*` options wait before giving up, in which case the command isn't
+    /// started at all. Defaults to 1m.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub wait_timeout: Option<Duration>,
+    /// Puts the tray icon up right away but only starts the command after this long, e.g. `30s`,
+    /// with a countdown in the tooltip. Start Now in the menu starts it early. The `--wait-for-*`
+    /// options start waiting once the delay is over. Restarts aren't delayed.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub delay: Option<Duration>,
+    /// Keeps the command to this percentage of one CPU by pausing it regularly (or with a job
+    /// object on Windows), for platforms without cgroups.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..100))]
+    pub cpu_throttle: Option<u8>,
+    /// Caps the memory of the command and the processes it starts, e.g. `512M` or `2G`: in a
+    /// cgroup of its own started with `systemd-run --user` on Linux, or a job object on Windows.
+    /// Elsewhere, or without systemd, its memory is checked every few seconds instead. Going over
+    /// it is notified about, and what else happens is up to `--on-limit`.
+    #[arg(long, value_name = "SIZE")]
+    pub max_memory: Option<ByteSize>,
+    /// Caps the CPU the command and the processes it starts use to this percentage of one CPU,
+    /// which can be above 100 for several, the same way as `--max-memory`. Not supported on
+    /// macOS, where `--cpu-throttle` does something similar.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub cpu_limit: Option<u32>,
+    /// What happens when the command goes over `--max-memory`: it's killed (`kill`, the default,
+    /// after which the restart policy applies), restarted after the restart backoff whatever the
+    /// policy (`restart`), or only notified about (`notify`), in which case its memory on Linux is
+    /// reclaimed more aggressively rather than capped.
+    #[arg(long, value_enum, value_name = "ACTION")]
+    pub on_limit: Option<LimitAction>,
+    /// Runs the command at a lower priority so that it doesn't compete with foreground work:
+    /// `below-normal` (nice 10 on Unix) or `idle` (nice 19), which the processes it starts
+    /// inherit. It can be changed from the tray's "Priority" submenu while the command runs.
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    pub priority: Option<Priority>,
+    /// Shows a glyph for the instance's state (● running, ▲ unhealthy) next to the tray icon, for
+    /// trays that don't show icon changes well. The tooltip always starts with it.
+    #[arg(long)]
+    pub status_glyphs: bool,
+    /// Compresses the old log file with gzip when the log is rotated, from the tray or with the
+    /// `rotate-log` control command.
+    #[arg(long)]
+    pub compress_rotated_logs: bool,
+    /// Leaves Kill, Restart and Restart With Arguments out of the tray menu and refuses `trayme
+    /// down` and `trayme self-restart`, so that the instance can't be stopped by accident, e.g. on
+    /// a shared machine. `trayme down --force` still stops it, given the owner secret or an `admin`
+    /// token.
+    #[arg(long)]
+    pub no_kill_menu: bool,
+    /// Hides the windows of a GUI command as it opens them, and adds Show Window and Hide Window
+    /// to the tray menu. Needs xdotool and X11 on Linux.
+    #[arg(long)]
+    pub start_hidden: bool,
+    /// Shows and hides the command's windows when the tray icon is clicked. The menu opens with
+    /// a right click instead.
+    #[arg(long)]
+    pub click_to_toggle: bool,
+    /// Asks for confirmation before the instance is killed from the tray menu.
+    #[arg(long)]
+    pub protected: bool,
+    /// How a protected instance's kill is confirmed. Defaults to typing its name.
+    #[arg(long, value_enum)]
+    pub confirm: Option<ConfirmMethod>,
+    #[command(flatten)]
+    pub constraints: ConstraintArgs,
+    /// Goes on with the next `--then` step when one fails, rather than ending the run with it.
+    /// The run then counts as failed only if the last step did.
+    #[arg(long)]
+    pub continue_on_failure: bool,
+    /// Runs the command once for every file that appears in this folder, as a "hot folder" for
+    /// converters and importers, rather than keeping it running. `{file}` in any argument is
+    /// replaced with the new file's path, which is appended as the last argument if there's no
+    /// `{file}`. Once its run is over, the file is moved to the folder's `done/` or `failed/`
+    /// subfolder depending on how the run went. Files already in the folder are processed too,
+    /// and new ones once they stop growing. The tray shows the runs in progress, the queue, and how
+    /// many files were processed. With `--headless`, the folder is watched until trayme is
+    /// interrupted.
+    #[arg(
+        long,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+        value_parser = parse_dir
+    )]
+    pub watch_inbox: Option<PathBuf>,
+    /// How many runs of `--watch-inbox` may be in progress at once. Further files wait in a queue,
+    /// which is shown in the tray menu, where queued runs can be cancelled. Defaults to 1.
+    #[arg(long, value_name = "N")]
+    pub max_concurrent: Option<NonZeroUsize>,
+    /// The commands that run after the command, one after another. Set from the `--then`s of the
+    /// command line or a profile's `then`.
+    #[arg(skip)]
+    pub then: Vec<Vec<OsString>>,
+    /// The profile this instance was started from, if any. Set by `up`.
+    #[arg(skip)]
+    pub profile: Option<ProfileRef>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliSubcommand {
+    /// Lists previous runs recorded in the run history, most recent first.
+    History,
+    /// Reproduces a previous run exactly, using its recorded command, environment, and working
+    /// directory.
+    Rerun {
+        /// The ID of the run to reproduce, as shown by `history`.
+        run_id: String,
+        #[command(flatten)]
+        instance: InstanceArgs,
+    },
+    /// Shows a tray icon for an instance running in another process, such as a headless service,
+    /// and controls it over IPC. Closing this tray leaves the instance running.
+    Tray {
+        /// The instance name, or the HOST:PORT address of its control socket. If omitted, one tray
+        /// is shown for all running instances, grouped by tag.
+        instance: Option<String>,
+    },
+    /// Checks whether the session supports the tray and notifications, whether the config file,
+    /// logs directory, control socket, and autostart entries are usable, and prints hints on how
+    /// to fix any problems.
+    Doctor,
+    /// Prints the events of instances, such as starts, exits, and health changes, from the event
+    /// log. With `--follow`, streams them from the running instances as they happen instead, e.g.
+    /// for a status bar widget.
+    Events {
+        /// The instance names or control socket addresses to print events of. Defaults to all
+        /// instances.
+        instances: Vec<String>,
+        /// Streams new events, including those of instances that start later, until interrupted.
+        #[arg(short, long)]
+        follow: bool,
+        /// Prints each event as a line of JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints a summary of the running instances for a status bar, for sessions with a bar but
+    /// without a usable tray.
+    Statusline {
+        /// The bar to format the summary for.
+        #[arg(long, value_enum)]
+        format: BarFormat,
+        /// Keeps running and prints a new summary whenever an instance changes state.
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Prints the end of trayme's own log, which every instance writes to, e.g. to find out why
+    /// an option doesn't do what it should. Debug builds log to stdout instead.
+    SelfLogs {
+        /// Keeps printing what's logged until interrupted.
+        #[arg(short, long)]
+        follow: bool,
+        /// How many of the last lines are printed.
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// Makes the given running instances log at LEVEL first, without restarting them. It
+        /// lasts until they exit, or until it's set to `reset`.
+        #[arg(long, value_enum, value_name = "LEVEL", requires = "names")]
+        level: Option<Verbosity>,
+        /// The names of the instances whose level is changed.
+        #[arg(requires = "level")]
+        names: Vec<String>,
+    },
+    /// Lists running instances.
+    Ls {
+        /// Only lists instances with this tag. Can be given multiple times.
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
+    /// Stops running instances.
+    Down {
+        /// The names of the instances to stop.
+        #[arg(required_unless_present = "tags")]
+        names: Vec<String>,
+        /// Stops every instance with this tag. Can be given multiple times.
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Stops instances even if they were started with `--no-kill-menu`.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Restarts running instances with the trayme on disk without stopping their processes, e.g.
+    /// after trayme was updated. Each instance replaces itself with the new binary, started with
+    /// the same arguments, which takes the process and its log over and supervises it from there
+    /// with the instance's options. Only on Unix.
+    SelfRestart {
+        /// The names of the instances to hand over.
+        #[arg(required_unless_present = "tags")]
+        names: Vec<String>,
+        /// Hands over every instance with this tag. Can be given multiple times.
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
+    /// Turns maintenance mode on or off for running instances. While it's on, health checks and
+    /// the restarts that come with them are suspended and no notifications are sent, e.g. while
+    /// working on the service. It turns itself off once the duration runs out.
+    Maintenance {
+        /// The names of the instances.
+        #[arg(required_unless_present = "tags")]
+        names: Vec<String>,
+        /// Also every instance with this tag. Can be given multiple times.
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// How long maintenance mode lasts. Defaults to the instance's `--maintenance-duration`.
+        #[arg(long = "for", value_name = "DURATION", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+        /// Turns maintenance mode off instead.
+        #[arg(long, conflicts_with = "duration")]
+        off: bool,
+    },
+    /// Shows a tray icon for a process that's already running, e.g. one started before trayme,
+    /// without starting anything. The tray can kill it, and goes away once it exits.
+    Attach {
+        /// The ID of the process.
+        #[arg(long)]
+        pid: u32,
+        /// What the tooltip calls the process. Defaults to its command line.
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Attaches the terminal to a running instance: its output is shown as it's written, and every
+    /// line typed is sent to its stdin. This is what the "Console…" tray menu item opens.
+    Console {
+        /// The instance name, or the HOST:PORT address of its control socket.
+        instance: String,
+    },
+    /// Writes lines to the stdin of a running instance, e.g. commands for a program that reads
+    /// them from its console. This is what the "Send Input…" tray menu item does.
+    Send {
+        /// The instance name, or the HOST:PORT address of its control socket.
+        instance: String,
+        /// The lines to send, in order. Lines are read from stdin until it's closed if none are
+        /// given.
+        lines: Vec<String>,
+    },
+    /// Runs profiles from the config file. Each instance is named after its profile unless
+    /// `--name` is given. A single named profile runs in this process, while several profiles or
+    /// profiles selected with `--tag` are started in the background, one process each.
+    Up {
+        /// The names of the profiles to run.
+        #[arg(required_unless_present = "tags")]
+        profiles: Vec<String>,
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[command(flatten)]
+        instance: InstanceArgs,
+    },
+    /// Runs a command whenever text matching a pattern is copied to the clipboard, e.g.
+    /// `trayme clip --pattern 'https://(www\.)?youtube\.com/\S+' yt-dlp {clip}`. Runs beyond
+    /// `--max-concurrent` are queued. The tray shows the runs in progress and the queue.
+    #[command(trailing_var_arg = true)]
+    Clip {
+        /// The pattern copied text must match. Only the matching part is used.
+        #[arg(long, value_name = "PATTERN")]
+        pattern: Regex,
+        #[command(flatten)]
+        queue: QueueArgs,
+        /// The command to run. `{clip}` in any argument is replaced with the matched text, which
+        /// is appended as the last argument if there's no `{clip}`.
+        #[arg(required = true, value_hint = ValueHint::CommandWithArguments, num_args = 1..)]
+        cmd: Vec<OsString>,
+    },
+    /// Prints a systemd user unit that starts a profile with the graphical session, e.g.
+    /// `trayme systemd-unit web > ~/.config/systemd/user/trayme-web.service`. The unit is
+    /// `Type=notify`, so it's only ready once the tray icon and the process are both up.
+    #[cfg(target_os = "linux")]
+    SystemdUnit {
+        /// The profile to start.
+        profile: String,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Shows a small always-on-top window and runs a command for every file dropped onto it, e.g.
+    /// `trayme drop ffmpeg -i {file} {file}.mp3`. Runs beyond `--max-concurrent` are queued. The
+    /// window can be hidden and shown again from the tray. Windows and macOS only.
+    #[cfg(any(windows, target_os = "macos"))]
+    #[command(trailing_var_arg = true)]
+    Drop {
+        #[command(flatten)]
+        queue: QueueArgs,
+        /// The command to run. `{file}` in any argument is replaced with the dropped file's path,
+        /// which is appended as the last argument if there's no `{file}`.
+        #[arg(required = true, value_hint = ValueHint::CommandWithArguments, num_args = 1..)]
+        cmd: Vec<OsString>,
+    },
+    /// Runs a command on a schedule, e.g. `trayme schedule --cron '0 3 * * *' backup.sh`. Runs
+    /// that were due while the machine was asleep or off are caught up on according to
+    /// `--missed`. The tray shows the next run and can start one right away.
+    #[command(trailing_var_arg = true)]
+    Schedule {
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+        #[command(flatten)]
+        queue: QueueArgs,
+        /// The command to run. `{time}` in any argument is replaced with the time the run was
+        /// due, as YYYY-MM-DD HH:MM.
+        #[arg(required = true, value_hint = ValueHint::CommandWithArguments, num_args = 1..)]
+        cmd: Vec<OsString>,
+    },
+    /// Bundles the profiles in the config file, and the Windows services installed for them, into
+    /// one file for moving to another machine or sharing a team's setup. Environment variables
+    /// that look secret are left out.
+    ExportSetup {
+        /// The file to write.
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Adds the profiles from a file written by `export-setup` to the config file, and installs
+    /// its services on Windows.
+    ImportSetup {
+        /// The file to read.
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Replaces profiles that already exist instead of skipping them.
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Moves the data directory, where logs and the run history are kept, to the layout this
+    /// version of trayme uses. This happens by itself when trayme starts, unless instances are
+    /// running at the time.
+    Migrate {
+        /// Prints what would be moved and updated without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manages Windows services that run a profile headless.
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Manages tokens for the control sockets of instances, e.g. a read-only one for a status bar
+    /// widget. Once a token exists, every client has to present one (trayme's own use the
+    /// user's owner secret, or the token in `TRAYME_TOKEN`).
+    Token {
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+    /// Keeps logging the output of a process detached by `--on-logout detach`. Started by
+    /// trayme, not meant to be run by hand.
+    #[cfg(unix)]
+    #[command(hide = true)]
+    RelayOutput { log: PathBuf },
+}
+
+impl CliSubcommand {
+    /// Whether the data directory is migrated to the current layout before the subcommand runs,
+    /// see [`crate::layout::upgrade`]. `migrate` does it itself, and the output relay runs while
+    /// the instance it was started by exits.
+    pub fn upgrades_layout(&self) -> bool {
+        match self {
+            CliSubcommand::Migrate { .. } => false,
+            #[cfg(unix)]
+            CliSubcommand::RelayOutput { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Options for modes that start a run per trigger, such as `clip`.
+#[derive(Debug, Args)]
+pub struct QueueArgs {
+    /// How many runs may be in progress at once. Further runs wait in a queue, which is shown in
+    /// the tray menu, where queued runs can be cancelled.
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub max_concurrent: NonZeroUsize,
+}
+
+/// Options for when `schedule` runs its command.
+#[derive(Debug, Args)]
+pub struct ScheduleArgs {
+    /// Runs the command at a fixed interval (e.g. `30m`), counted from when it was first
+    /// scheduled.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        required_unless_present = "cron",
+        conflicts_with = "cron"
+    )]
+    pub every: Option<Duration>,
+    /// Runs the command at the times matching a cron expression (minute hour day month weekday),
+    /// e.g. `'*/15 9-17 * * mon-fri'`.
+    #[arg(long, value_name = "EXPR")]
+    pub cron: Option<CronExpr>,
+    /// What to do about runs that were due while the machine was asleep or trayme wasn't
+    /// running.
+    #[arg(long, value_enum, default_value_t)]
+    pub missed: MissedPolicy,
+    /// What to do when a run is due while the previous one is still going.
+    #[arg(long, value_enum, default_value_t)]
+    pub overlap: OverlapPolicy,
+    /// Wakes the machine from sleep for each run, where the OS lets trayme schedule a wake: with
+    /// a systemd timer on Linux, a scheduled task on Windows, and `pmset` on macOS (as root
+    /// only). Runs the machine sleeps through anyway are caught up on according to `--missed`.
+    #[arg(long)]
+    pub wake: bool,
+    /// The name of the schedule, which its state is saved under so that missed runs are known
+    /// across restarts. Defaults to the program name.
+    #[arg(long, value_parser = parse_instance_name)]
+    pub name: Option<String>,
+    /// Alerts when no run has succeeded for this long (e.g. `26h` for a daily backup), once until
+    /// the next run succeeds: a dead man's switch. The notifications of runs starting and
+    /// exiting successfully are left out, so that only failures and this alert are shown.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub expect_success_within: Option<Duration>,
+    /// Pings a dead man's switch check around each run, like `--ping-url` of an instance, so
+    /// that runs which stop coming are noticed off the machine too.
+    #[arg(long, value_name = "URL")]
+    pub ping_url: Option<PingUrl>,
+    #[command(flatten)]
+    pub constraints: ConstraintArgs,
+}
+
+impl ScheduleArgs {
+    pub fn schedule(&self) -> Schedule {
+        match (&self.cron, self.every) {
+            (Some(expr), _) => Schedule::Cron(expr.clone()),
+            (None, Some(interval)) => Schedule::Every(interval),
+            (None, None) => unreachable!("clap requires --every or --cron"),
+        }
+    }
+}
+
+/// Limits on when a command starts. Instances that aren't allowed to start exit right away, and
+/// scheduled runs that aren't allowed are dropped.
+#[derive(Debug, Default, Args)]
+pub struct ConstraintArgs {
+    /// Only starts from Monday to Friday.
+    #[arg(long)]
+    pub only_weekdays: bool,
+    /// Never starts on these dates, as comma-separated YYYY-MM-DD.
+    #[arg(long, value_name = "DATES", value_delimiter = ',')]
+    pub not_on: Vec<NaiveDate>,
+    /// Only starts at these times of day, as HH:MM-HH:MM (e.g. 08:00-18:00, or 22:00-06:00 across
+    /// midnight). Can be given multiple times.
+    #[arg(long = "window", value_name = "HH:MM-HH:MM")]
+    pub windows: Vec<TimeWindow>,
+    /// Keeps out of the way of meetings in this calendar, an ICS file or URL (`webcal://` too),
+    /// read every 5 minutes: scheduled runs due during a busy event start once it's over, and
+    /// an instance is paused while one is going on. Events marked free don't count.
+    #[arg(long, value_name = "FILE|URL")]
+    pub busy_calendar: Option<CalendarSource>,
+}
+
+impl ConstraintArgs {
+    pub fn constraints(&self) -> Constraints {
+        Constraints {
+            only_weekdays: self.only_weekdays,
+            not_on: self.not_on.clone(),
+            windows: self.windows.clone(),
+            busy_calendar: self.busy_calendar.clone(),
+        }
+    }
+}
+
+/// Options for finding the config file.
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    /// The config file to read profiles from. Defaults to trayme/config.toml in the user's config
+    /// directory.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+}
+
+#[cfg(windows)]
+#[derive(Debug, Subcommand)]
+pub enum ServiceAction {
+    /// Registers a service that runs the profile headless. The service is named
+    /// `trayme-<PROFILE>`.
+    Install {
+        /// The name of the profile to run.
+        profile: String,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// The address of the service's control socket. Give a fixed port to show a tray icon for
+        /// the service with `trayme tray <ADDR>`.
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:0")]
+        listen: SocketAddr,
+        /// Starts the service automatically at boot instead of on demand.
+        #[arg(long)]
+        auto_start: bool,
+    },
+    /// Starts an installed service.
+    Start {
+        /// The name of the profile the service was installed for.
+        profile: String,
+    },
+    /// Stops a running service, killing its process.
+    Stop {
+        /// The name of the profile the service was installed for.
+        profile: String,
+    },
+    /// Stops and removes an installed service.
+    Uninstall {
+        /// The name of the profile the service was installed for.
+        profile: String,
+    },
+    /// The entry point used by the service control manager. Not meant to be run by hand.
+    #[command(hide = true)]
+    Run {
+        profile: String,
+        #[arg(long)]
+        config: PathBuf,
+        #[arg(long)]
+        listen: SocketAddr,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenAction {
+    /// Creates a token and prints its secret. Send it as `token <SECRET> <COMMAND>` on the
+    /// control socket.
+    Create {
+        /// A name for the token, to revoke it by.
+        name: String,
+        /// What the token allows: `read` only the status, `control` controlling the process, and
+        /// `admin` also stopping instances started with `--no-kill-menu`.
+        #[arg(long, value_enum, default_value_t)]
+        scope: Scope,
+    },
+    /// Lists the tokens.
+    Ls,
+    /// Deletes a token, so that it's refused from then on.
+    Revoke {
+        /// The name of the token.
+        name: String,
+    },
+}
+
+fn parse_event_sound(s: &str) -> Result<(NotifyEvent, String), String> {
+    let (event, sound) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected EVENT=SOUND, got '{s}'"))?;
+    let event = NotifyEvent::from_str(event, true)?;
+    Ok((event, sound.to_string()))
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{s}'"))?;
+    dotenv::check_name(key).map_err(|e| e.to_string())?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_env_file(s: &str) -> Result<BTreeMap<String, String>, String> {
+    dotenv::load(Path::new(s)).map_err(|e| format!("{e:#}"))
+}
+
+/// Parses a directory that has to exist, made absolute so that the run record says where the
+/// command ran.
+fn parse_dir(s: &str) -> Result<PathBuf, String> {
+    let dir = std::path::absolute(s).map_err(|e| format!("invalid path '{s}': {e}"))?;
+    if !dir.is_dir() {
+        return Err(format!("'{}' is not a directory", dir.display()));
+    }
+    Ok(dir)
+}
+
+fn parse_tag(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(char::is_whitespace) {
+        return Err(format!("'{s}' is not a valid tag"));
+    }
+    Ok(s.to_string())
+}
+
+pub fn parse_instance_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(['/', '\\']) {
+        return Err(format!("'{s}' is not a valid instance name"));
+    }
+    Ok(s.to_string())
+}
@@ -0,0 +1,283 @@
+use std::{
+    ffi::OsString,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, error, info};
+use regex::Regex;
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::menu::{CheckMenuItem, IsMenuItem, PredefinedMenuItem};
+
+use crate::{
+    build_tray, build_tray_menu, display, icon,
+    notify::Notifier,
+    traybackend::{SystemTray, TrayBackend},
+    trigger::{QueueMessage, QueueStatus, RunQueue},
+};
+
+/// How often the clipboard is checked for new text.
+const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The placeholder in the command template that is replaced with the matched text.
+pub const CLIP_PLACEHOLDER: &str = "{clip}";
+
+/// The menu item that pauses and resumes watching the clipboard.
+const WATCH_ID: &str = "Watch Clipboard";
+
+/// Clipboard tools tried, in order, to read the clipboard on Linux and the BSDs.
+#[cfg(all(unix, not(target_os = "macos")))]
+const CLIPBOARD_TOOLS: &[(&str, &[&str])] = &[
+    ("wl-paste", &["--no-newline", "--type", "text"]),
+    ("xclip", &["-selection", "clipboard", "-out"]),
+    ("xsel", &["--clipboard", "--output"]),
+];
+
+/// Reads the text currently on the clipboard. Returns `None` if the clipboard is empty or holds
+/// something other than text.
+///
+/// # Errors
+///
+/// An error is returned if the clipboard cannot be accessed at all, e.g. because no clipboard
+/// tool is installed.
+pub fn read_clipboard() -> anyhow::Result<Option<String>> {
+    #[cfg(windows)]
+    {
+        win::read_text().context("Failed to read clipboard")
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let output = std::process::Command::new("pbpaste")
+            .output()
+            .context("Failed to run pbpaste")?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+            .filter(|text| !text.is_empty()))
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        for (tool, args) in CLIPBOARD_TOOLS {
+            let output = match std::process::Command::new(tool).args(*args).output() {
+                Ok(output) => output,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to run {tool}")),
+            };
+            // these tools fail when the clipboard is empty
+            return Ok(output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+                .filter(|text| !text.is_empty()));
+        }
+        anyhow::bail!("No clipboard tool found (install wl-clipboard, xclip, or xsel)")
+    }
+}
+
+/// Watches the clipboard for text matching a pattern.
+#[derive(Debug)]
+pub struct ClipboardWatcher {
+    pattern: Regex,
+    last: Option<String>,
+    last_check: Instant,
+}
+
+impl ClipboardWatcher {
+    /// Starts watching. Whatever is on the clipboard already is ignored.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the clipboard cannot be read.
+    pub fn new(pattern: Regex) -> anyhow::Result<Self> {
+        Ok(Self {
+            pattern,
+            last: read_clipboard()?,
+            last_check: Instant::now(),
+        })
+    }
+
+    /// Returns the matching part of the clipboard if new text was copied since the last check and
+    /// it matches the pattern. Copying the same text twice in a row only matches once.
+    pub fn poll(&mut self) -> Option<String> {
+        if self.last_check.elapsed() < CLIPBOARD_POLL_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let text = match read_clipboard() {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("{e:#}");
+                return None;
+            }
+        };
+        if text == self.last {
+            return None;
+        }
+        self.last.clone_from(&text);
+        let found = self.pattern.find(text.as_deref()?)?;
+        Some(found.as_str().to_string())
+    }
+}
+
+/// The tray of `trayme clip`: a clipboard watcher feeding a run queue.
+struct ClipTray {
+    watcher: ClipboardWatcher,
+    queue: RunQueue,
+    status: QueueStatus,
+    watch_item: CheckMenuItem,
+    tooltip: String,
+}
+
+impl ClipTray {
+    fn tick(&mut self, tray: &mut dyn TrayBackend) -> anyhow::Result<ControlFlow> {
+        if self.watch_item.is_checked() {
+            if let Some(clip) = self.watcher.poll() {
+                self.queue.push(clip);
+            }
+        }
+        self.queue.poll()?;
+        self.status.update(&self.queue)?;
+        let running = self.queue.running().count();
+        let tooltip = if running > 0 {
+            format!("trayme clip: {running} running")
+        } else if self.watch_item.is_checked() {
+            "trayme clip: watching".to_string()
+        } else {
+            "trayme clip: paused".to_string()
+        };
+        if tooltip != self.tooltip {
+            tray.set_tooltip(&tooltip)?;
+            self.tooltip = tooltip;
+        }
+
+        if let Some(id) = tray.menu_event() {
+            if id == WATCH_ID {
+                // check items toggle themselves, so there's nothing else to do
+                info!("Watching clipboard: {}", self.watch_item.is_checked());
+                return Ok(ControlFlow::Poll);
+            }
+            return self.queue.handle_menu_event(&id);
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+}
+
+/// Runs `cmd` whenever text matching `pattern` is copied, until the user quits from the tray.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern copied text must match. The matching part is filled into `cmd`.
+/// * `cmd` - The command template. `{clip}` is replaced with the matched text.
+/// * `notifier` - Used for the notifications of each run.
+/// * `max_concurrent` - How many runs may be in progress at once.
+///
+/// # Errors
+///
+/// An error is returned if the clipboard cannot be read or the tray icon cannot be built.
+pub fn run_clipboard_trigger(
+    pattern: Regex,
+    cmd: Vec<OsString>,
+    notifier: Notifier,
+    max_concurrent: NonZeroUsize,
+) -> anyhow::Result<()> {
+    let watcher = ClipboardWatcher::new(pattern)?;
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(QueueMessage::VARIANTS)?;
+    let status = QueueStatus::new();
+    let watch_item = CheckMenuItem::with_id(WATCH_ID, WATCH_ID, true, true, None);
+    menu.prepend_items(&[
+        &status.running as &dyn IsMenuItem,
+        &status.queued,
+        &watch_item,
+        &PredefinedMenuItem::separator(),
+    ])?;
+    let tooltip = "trayme clip: watching".to_string();
+    let mut tray = Some(SystemTray::new(build_tray(
+        &tooltip,
+        menu,
+        icon::identicon(&tooltip)?,
+    )?));
+    let mut clip_tray = ClipTray {
+        watcher,
+        queue: RunQueue::new(cmd, CLIP_PLACEHOLDER, notifier, max_concurrent),
+        status,
+        watch_item,
+        tooltip,
+    };
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        let Some(icon) = tray.as_mut() else {
+            return;
+        };
+        match clip_tray.tick(icon) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = clip_tray.queue.stop();
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
+
+#[cfg(windows)]
+mod win {
+    use std::{ffi::c_void, io};
+
+    type Bool = i32;
+
+    const CF_UNICODETEXT: u32 = 13;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn OpenClipboard(owner: *mut c_void) -> Bool;
+        fn CloseClipboard() -> Bool;
+        fn GetClipboardData(format: u32) -> *mut c_void;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GlobalLock(mem: *mut c_void) -> *mut c_void;
+        fn GlobalUnlock(mem: *mut c_void) -> Bool;
+    }
+
+    /// Reads the text on the clipboard, if there is any.
+    pub fn read_text() -> io::Result<Option<String>> {
+        // SAFETY: the clipboard data is only read while it's locked and the clipboard is open
+        unsafe {
+            if OpenClipboard(std::ptr::null_mut()) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let handle = GetClipboardData(CF_UNICODETEXT);
+            let text = if handle.is_null() {
+                None
+            } else {
+                let data = GlobalLock(handle).cast::<u16>();
+                if data.is_null() {
+                    None
+                } else {
+                    let mut len = 0;
+                    while *data.add(len) != 0 {
+                        len += 1;
+                    }
+                    let text = String::from_utf16_lossy(std::slice::from_raw_parts(data, len));
+                    GlobalUnlock(handle);
+                    Some(text)
+                }
+            };
+            CloseClipboard();
+            Ok(text.filter(|text| !text.is_empty()))
+        }
+    }
+}
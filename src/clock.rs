@@ -0,0 +1,68 @@
+use std::{fmt, time::Instant};
+
+use chrono::{DateTime, Local};
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Where the time comes from, so that what follows the clock can be tested without waiting for
+/// it.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time on the clock.
+    fn now(&self) -> DateTime<Local>;
+
+    /// The current monotonic time, for measuring how long something took. It stands still while
+    /// the machine sleeps, except on Windows.
+    fn instant(&self) -> Instant;
+}
+
+/// The system's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it's told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct FakeClock(Arc<Mutex<(DateTime<Local>, Instant)>>);
+
+#[cfg(test)]
+impl FakeClock {
+    /// A clock stopped at `time`.
+    pub fn at(time: DateTime<Local>) -> Self {
+        Self(Arc::new(Mutex::new((time, Instant::now()))))
+    }
+
+    /// Moves the clock forward by `by` while the machine is awake.
+    pub fn advance(&self, by: chrono::Duration) {
+        let mut time = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        time.0 += by;
+        time.1 += by.to_std().expect("the clock only moves forward");
+    }
+
+    /// Moves the time of day forward by `by` while the monotonic time stands still, like a sleep
+    /// of the machine does.
+    pub fn sleep(&self, by: chrono::Duration) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).0 += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Local> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).0
+    }
+
+    fn instant(&self) -> Instant {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).1
+    }
+}
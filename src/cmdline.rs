@@ -0,0 +1,80 @@
+use std::ffi::OsString;
+
+use anyhow::bail;
+
+/// Joins a command into one line that [`split`] turns back into the same arguments. Only the
+/// arguments that need it are quoted, so that the line reads like one typed into a shell. An
+/// argument that isn't valid Unicode can't be typed and is made readable the lossy way.
+pub fn join(cmd: &[OsString]) -> String {
+    cmd.iter()
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-_./:=,+@%^".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Splits a command line into arguments the way a POSIX shell would, without expanding
+/// anything: whitespace separates arguments, single quotes take everything literally, and
+/// double quotes and backslashes escape the characters after them.
+///
+/// # Errors
+///
+/// An error is returned for an unterminated quote or a trailing backslash.
+pub fn split(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    // `None` between arguments, so that `''` still makes an empty one
+    let mut arg: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // only these are escaped inside double quotes
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => bail!("unterminated double quote"),
+                        },
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => bail!("trailing backslash"),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
@@ -0,0 +1,855 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use regex::Regex;
+
+use crate::{
+    caps::{ByteSize, LimitAction},
+    cli::InstanceArgs,
+    confirm::ConfirmMethod,
+    envprovider::EnvProvider,
+    exitcode::{ErrorKind, WithKind},
+    health::Threshold,
+    limits::ResourceLimit,
+    logout::OnLogout,
+    notify::{Notifier, NotifyEvent, NotifyUrgency},
+    notifyroute::NotifyRoute,
+    osargs,
+    ping::PingUrl,
+    priority::Priority,
+    restart::RestartPolicy,
+    schedule::{Constraints, CronExpr},
+    sleep::OnWake,
+    stop::StopStrategy,
+    supervisor::CommandSpec,
+    tempdir::TempCleanup,
+    waitfor::PortAddr,
+};
+
+/// The trayme configuration file. Profiles are stored as `[profiles.<name>]` tables:
+///
+/// ```toml
+/// [profiles.web]
+/// cmd = ["npm", "run", "dev"]
+/// cwd = "C:/projects/web"
+/// env = { PORT = "8080" }
+/// notify_urgency = "critical"
+/// stop_strategy = "ctrl-break"
+/// tags = ["work"]
+/// unhealthy_if = "ERROR"
+/// threshold = "10/60s"
+///
+/// [profiles.api]
+/// extends = "web"
+/// cmd = ["npm", "run", "api"]
+/// env = { PORT = "8081" }
+/// ```
+///
+/// On Windows, which ignores `#!` lines, scripts are run with the interpreter their line names,
+/// looked up in `PATH` or in the `[interpreters]` table:
+///
+/// ```toml
+/// [interpreters]
+/// python3 = ["py", "-3"]
+/// bash = ["C:/Program Files/Git/bin/bash.exe"]
+/// ```
+///
+/// A profile that `extends` another one gets all of its settings, except the ones it sets itself.
+/// Tables like `env` are merged, so `api` above has every variable of `web` but its own `PORT`.
+///
+/// Administrators can provide profiles in a system-wide config file (see [`system_config_path`]).
+/// A user's profile of the same name is merged over it the same way, unless the system profile
+/// sets `locked = true`, in which case the user's is ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// The profiles as they're written in the file.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// What the interpreters that `#!` lines name are run with on Windows, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub interpreters: BTreeMap<String, Vec<String>>,
+    /// The profiles with their `extends` resolved, see [`Config::profile`].
+    #[serde(skip)]
+    resolved: BTreeMap<String, Profile>,
+}
+
+/// A named command, along with everything needed to run it the same way every time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)] // mirrors the command line flags
+pub struct Profile {
+    /// Keeps users from overriding this profile. Only has an effect in the system config.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub locked: bool,
+    /// The profile this one is based on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// The command (with args) to run. Profiles that are only extended can leave it out. An
+    /// argument that isn't valid Unicode is written as `{ bytes = [...] }` on Unix, or
+    /// `{ wide = [...] }` with UTF-16 code units on Windows.
+    #[serde(default, with = "osargs", skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<OsString>,
+    /// The working directory of the command. Defaults to trayme's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Variables to set in addition to the inherited environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub notify_urgency: NotifyUrgency,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub notify_sounds: HashMap<NotifyEvent, String>,
+    /// Where notifications are sent, see [`NotifyRoute`]. Only to the desktop if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<NotifyRoute>,
+    /// Tags for selecting several profiles at once, e.g. with `trayme up --tag <TAG>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Overrides how the command is stopped. Defaults to picking one automatically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_strategy: Option<StopStrategy>,
+    /// See `--kill-timeout`, e.g. `"10s"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_timeout: Option<String>,
+    /// See `--unhealthy-if`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unhealthy_if: Option<String>,
+    /// See `--threshold`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<Threshold>,
+    /// See `--restart-on-unhealthy`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub restart_on_unhealthy: bool,
+    /// See `--restart-policy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    /// See `--restart-backoff`, e.g. `"5s"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_backoff: Option<String>,
+    /// See `--max-restarts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_restarts: Option<u32>,
+    /// See `--timeout`, e.g. `"30m"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    /// See `--ping-url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_url: Option<PingUrl>,
+    /// See `--restart-every`, e.g. `"6h"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_every: Option<String>,
+    /// See `--restart-cron`, e.g. `"0 3 * * *"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_cron: Option<String>,
+    /// See `--fallback`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    /// See `--fallback-after`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_after: Option<u32>,
+    /// See `--on-wake`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_wake: Option<OnWake>,
+    /// See `--wake-check`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wake_check: Option<String>,
+    /// See `--maintenance-duration`, e.g. `"1h"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_duration: Option<String>,
+    /// See `--progress-regex`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress_regex: Option<String>,
+    /// See `--first-output-notify`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub first_output_notify: bool,
+    /// See `--env-provider`, e.g. `"venv:.venv"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_provider: Option<EnvProvider>,
+    /// See `--tz`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tz: Option<String>,
+    /// See `--locale`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// See `--ulimit`, e.g. `["nofile=8192"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ulimits: Vec<ResourceLimit>,
+    /// See `--pre-check`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_check: Option<String>,
+    /// The commands that run after `cmd`, one after another, see `--then`, e.g.
+    /// `[["cargo", "test"], ["./deploy.sh"]]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub then: Vec<Vec<String>>,
+    /// See `--continue-on-failure`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub continue_on_failure: bool,
+    /// See `--watch-inbox`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watch_inbox: Option<PathBuf>,
+    /// See `--max-concurrent`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<NonZeroUsize>,
+    /// See `--require-free-mem`, e.g. `"1G"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_free_mem: Option<ByteSize>,
+    /// See `--require-free-disk`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_free_disk: Option<ByteSize>,
+    /// See `--elevate`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub elevate: bool,
+    /// See `--temp-dir`, e.g. `"keep-failed"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<TempCleanup>,
+    /// See `--on-start`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_start: Option<String>,
+    /// See `--on-exit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_exit: Option<String>,
+    /// See `--on-failure`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<String>,
+    /// See `--wait-for-port`, e.g. `["5432"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wait_for_port: Vec<PortAddr>,
+    /// See `--wait-for-file`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wait_for_file: Vec<PathBuf>,
+    /// See `--wait-for-cmd`.
+    #[serde(default, with = "osargs", skip_serializing_if = "Vec::is_empty")]
+    pub wait_for_cmd: Vec<OsString>,
+    /// See `--wait-timeout`, e.g. `"2m"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_timeout: Option<String>,
+    /// See `--delay`, e.g. `"30s"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<String>,
+    /// See `--cpu-throttle`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_throttle: Option<u8>,
+    /// See `--max-memory`, e.g. `"512M"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<ByteSize>,
+    /// See `--cpu-limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_limit: Option<u32>,
+    /// See `--on-limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_limit: Option<LimitAction>,
+    /// See `--priority`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// See `--on-logout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_logout: Option<OnLogout>,
+    /// See `--detach-on-exit`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detach_on_exit: bool,
+    /// See `--stay-open`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stay_open: bool,
+    /// See `--status-glyphs`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub status_glyphs: bool,
+    /// See `--no-kill-menu`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_kill_menu: bool,
+    /// See `--start-hidden`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub start_hidden: bool,
+    /// See `--click-to-toggle`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub click_to_toggle: bool,
+    /// See `--protected`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+    /// See `--confirm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm: Option<ConfirmMethod>,
+    /// When the profile may start. See `--only-weekdays`, `--not-on`, and `--window`.
+    #[serde(default, skip_serializing_if = "Constraints::is_empty")]
+    pub constraints: Constraints,
+}
+
+/// Where a running instance's profile came from, so that changes can be written back to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileRef {
+    pub name: String,
+    pub config: PathBuf,
+}
+
+impl Config {
+    /// Loads the configuration file, layered over the system config if there is one. A missing
+    /// file is treated as an empty configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to load.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file or the system config exists but cannot be read or parsed,
+    /// or a profile extends one that doesn't exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Self::parse(&contents, system_profiles()?)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Parses the contents of a config file, without touching the file system.
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - The contents of the file.
+    /// * `system` - The `[profiles]` table of the system config, if there is one. The profiles
+    ///   in `contents` are layered over it.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if `contents` isn't a valid config, or a profile extends one that
+    /// doesn't exist.
+    pub fn parse(contents: &str, system: Option<toml::Table>) -> anyhow::Result<Self> {
+        let raw: toml::Table = toml::from_str(contents)?;
+        let profiles = match system {
+            Some(system) => layer_profiles(system, &profiles_table(&raw)),
+            None => profiles_table(&raw),
+        };
+        let resolved = resolve_profiles(&profiles)?;
+        let mut config: Self = toml::Value::Table(raw).try_into()?;
+        config.resolved = resolved;
+        Ok(config)
+    }
+
+    /// Writes the configuration back to `path`. Comments and formatting in the file are not
+    /// preserved.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the configuration cannot be serialized or the file cannot be
+    /// written.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let contents = toml::to_string_pretty(self).context("Failed to serialize config")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The names of all profiles, including the ones from the system config.
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        self.resolved.keys().map(String::as_str)
+    }
+
+    /// Returns the profile named `name`, with the settings it inherits through `extends`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if there is no such profile.
+    pub fn profile(&self, name: &str) -> anyhow::Result<&Profile> {
+        let profile = match self.resolved.get(name) {
+            Some(profile) if profile.cmd.is_empty() => {
+                Err(anyhow!("Profile '{name}' has an empty cmd"))
+            }
+            Some(profile) => Ok(profile),
+            None => Err(anyhow!("No profile named '{name}'")),
+        };
+        profile.with_kind(ErrorKind::Config)
+    }
+
+    /// Selects the profiles named in `names` plus every profile with any of `tags`, sorted by
+    /// name.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a selected profile doesn't exist or is invalid, or if nothing was
+    /// selected.
+    pub fn select(
+        &self,
+        names: &[String],
+        tags: &[String],
+    ) -> anyhow::Result<Vec<(&str, &Profile)>> {
+        for name in names {
+            self.profile(name)?;
+        }
+        let selected = self
+            .resolved
+            .iter()
+            .filter(|(name, profile)| {
+                // base profiles pass their tags on without being started themselves
+                let tagged =
+                    !profile.cmd.is_empty() && profile.tags.iter().any(|t| tags.contains(t));
+                names.contains(name) || tagged
+            })
+            .map(|(name, _)| Ok((name.as_str(), self.profile(name)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if selected.is_empty() {
+            bail!("No profiles are tagged {}", tags.join(" or "));
+        }
+        Ok(selected)
+    }
+}
+
+impl Profile {
+    /// Builds the spec to spawn this profile's command with.
+    pub fn to_spec(&self) -> CommandSpec {
+        CommandSpec {
+            cmd: self.cmd.clone(),
+            cwd: self.cwd.clone(),
+            env: None,
+            env_overrides: self.env.clone(),
+            env_provider: None,
+            tz: None,
+            locale: None,
+            ulimits: Vec::new(),
+            pre_check: None,
+            shell: false,
+            elevate: false,
+            temp_dir: None,
+            interpreters: BTreeMap::new(),
+            caps: None,
+        }
+    }
+
+    /// Fills in the instance options that weren't given on the command line from this profile.
+    /// The instance is named after the profile and gets its tags.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the profile's `unhealthy_if` or `progress_regex` pattern, or its
+    /// `kill_timeout`, `restart_backoff`, `timeout`, `restart_every`, `restart_cron`,
+    /// `maintenance_duration`, `wait_timeout`, or `delay`, is invalid.
+    pub fn apply_to(&self, name: &str, instance: &mut InstanceArgs) -> anyhow::Result<()> {
+        instance.name.get_or_insert_with(|| name.to_string());
+        instance.stop_strategy = instance.stop_strategy.or(self.stop_strategy);
+        if instance.kill_timeout.is_none() {
+            instance.kill_timeout = self
+                .kill_timeout
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid kill_timeout in profile '{name}'"))?;
+        }
+        instance.tags.clone_from(&self.tags);
+        if instance.unhealthy_if.is_none() {
+            instance.unhealthy_if = self
+                .unhealthy_if
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid unhealthy_if pattern in profile '{name}'"))?;
+        }
+        instance.threshold = instance.threshold.or(self.threshold);
+        instance.restart_on_unhealthy |= self.restart_on_unhealthy;
+        self.apply_restarts_to(name, instance)?;
+        if instance.maintenance_duration.is_none() {
+            instance.maintenance_duration = self
+                .maintenance_duration
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid maintenance_duration in profile '{name}'"))?;
+        }
+        if instance.env_provider.is_none() {
+            instance.env_provider.clone_from(&self.env_provider);
+        }
+        if instance.tz.is_none() {
+            instance.tz.clone_from(&self.tz);
+        }
+        if instance.locale.is_none() {
+            instance.locale.clone_from(&self.locale);
+        }
+        if instance.ulimits.is_empty() {
+            instance.ulimits.clone_from(&self.ulimits);
+        }
+        self.apply_startup_to(instance);
+        self.apply_waits_to(name, instance)?;
+        instance.cpu_throttle = instance.cpu_throttle.or(self.cpu_throttle);
+        instance.max_memory = instance.max_memory.or(self.max_memory);
+        instance.cpu_limit = instance.cpu_limit.or(self.cpu_limit);
+        instance.on_limit = instance.on_limit.or(self.on_limit);
+        instance.priority = instance.priority.or(self.priority);
+        instance.on_logout = instance.on_logout.or(self.on_logout);
+        instance.detach_on_exit |= self.detach_on_exit;
+        instance.stay_open |= self.stay_open;
+        instance.status_glyphs |= self.status_glyphs;
+        instance.first_output_notify |= self.first_output_notify;
+        instance.no_kill_menu |= self.no_kill_menu;
+        instance.start_hidden |= self.start_hidden;
+        instance.click_to_toggle |= self.click_to_toggle;
+        instance.protected |= self.protected;
+        instance.confirm = instance.confirm.or(self.confirm);
+        if instance.progress_regex.is_none() {
+            instance.progress_regex = self
+                .progress_regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid progress_regex pattern in profile '{name}'"))?;
+        }
+        let constraints = &mut instance.constraints;
+        constraints.only_weekdays |= self.constraints.only_weekdays;
+        constraints.not_on.extend(&self.constraints.not_on);
+        if constraints.windows.is_empty() {
+            constraints.windows.clone_from(&self.constraints.windows);
+        }
+        if constraints.busy_calendar.is_none() {
+            constraints
+                .busy_calendar
+                .clone_from(&self.constraints.busy_calendar);
+        }
+        Ok(())
+    }
+
+    /// Fills in the options that decide whether and how the command is started, for
+    /// [`Profile::apply_to`].
+    fn apply_startup_to(&self, instance: &mut InstanceArgs) {
+        if instance.pre_check.is_none() {
+            instance.pre_check.clone_from(&self.pre_check);
+        }
+        if instance.then.is_empty() {
+            instance.then = self
+                .then
+                .iter()
+                .cloned()
+                .map(osargs::from_strings)
+                .collect();
+        }
+        instance.continue_on_failure |= self.continue_on_failure;
+        if instance.watch_inbox.is_none() {
+            instance.watch_inbox.clone_from(&self.watch_inbox);
+        }
+        instance.max_concurrent = instance.max_concurrent.or(self.max_concurrent);
+        instance.require_free_mem = instance.require_free_mem.or(self.require_free_mem);
+        instance.require_free_disk = instance.require_free_disk.or(self.require_free_disk);
+        instance.elevate |= self.elevate;
+        instance.temp_dir = instance.temp_dir.or(self.temp_dir);
+        for (hook, configured) in [
+            (&mut instance.on_start, &self.on_start),
+            (&mut instance.on_exit, &self.on_exit),
+            (&mut instance.on_failure, &self.on_failure),
+        ] {
+            if hook.is_none() {
+                hook.clone_from(configured);
+            }
+        }
+    }
+
+    /// Fills in the `--wait-for-*` options and `--delay`, for [`Profile::apply_to`].
+    fn apply_waits_to(&self, name: &str, instance: &mut InstanceArgs) -> anyhow::Result<()> {
+        if instance.wait_for_port.is_empty() {
+            instance.wait_for_port.clone_from(&self.wait_for_port);
+        }
+        if instance.wait_for_file.is_empty() {
+            instance.wait_for_file.clone_from(&self.wait_for_file);
+        }
+        if instance.wait_for_cmd.is_empty() {
+            instance.wait_for_cmd.clone_from(&self.wait_for_cmd);
+        }
+        if instance.wait_timeout.is_none() {
+            instance.wait_timeout = self
+                .wait_timeout
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid wait_timeout in profile '{name}'"))?;
+        }
+        if instance.delay.is_none() {
+            instance.delay = self
+                .delay
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid delay in profile '{name}'"))?;
+        }
+        Ok(())
+    }
+
+    /// Fills in the options that decide when the process is restarted or stopped, for
+    /// [`Profile::apply_to`].
+    fn apply_restarts_to(&self, name: &str, instance: &mut InstanceArgs) -> anyhow::Result<()> {
+        instance.restart_policy = instance.restart_policy.or(self.restart_policy);
+        if instance.restart_backoff.is_none() {
+            instance.restart_backoff = self
+                .restart_backoff
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid restart_backoff in profile '{name}'"))?;
+        }
+        instance.max_restarts = instance.max_restarts.or(self.max_restarts);
+        if instance.timeout.is_none() {
+            instance.timeout = self
+                .timeout
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .with_context(|| format!("Invalid timeout in profile '{name}'"))?;
+        }
+        if instance.ping_url.is_none() {
+            instance.ping_url.clone_from(&self.ping_url);
+        }
+        let (every, cron) = self
+            .planned_restarts()
+            .with_context(|| format!("Invalid profile '{name}'"))?;
+        instance.restart_every = instance.restart_every.or(every);
+        if instance.restart_cron.is_none() {
+            instance.restart_cron = cron;
+        }
+        if instance.fallback.is_none() {
+            instance.fallback.clone_from(&self.fallback);
+        }
+        instance.fallback_after = instance.fallback_after.or(self.fallback_after);
+        instance.on_wake = instance.on_wake.or(self.on_wake);
+        if instance.wake_check.is_none() {
+            instance.wake_check.clone_from(&self.wake_check);
+        }
+        Ok(())
+    }
+
+    /// Parses `restart_every` and `restart_cron`.
+    ///
+    /// # Errors
+    ///
+    /// An error naming the setting is returned if either is invalid.
+    pub fn planned_restarts(&self) -> anyhow::Result<(Option<Duration>, Option<CronExpr>)> {
+        let every = self
+            .restart_every
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()
+            .context("Invalid restart_every")?;
+        let cron = self
+            .restart_cron
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(anyhow::Error::msg)
+            .context("Invalid restart_cron")?;
+        Ok((every, cron))
+    }
+
+    /// Builds a notifier with this profile's notification settings.
+    pub fn notifier(&self) -> Notifier {
+        let mut notifier = Notifier::new(self.notify_urgency, self.notify_sounds.clone());
+        notifier.set_routes(self.notify.clone());
+        notifier
+    }
+}
+
+/// Returns the path of the default configuration file, which is `trayme/config.toml` in the
+/// platform's config directory.
+///
+/// # Errors
+///
+/// An error is returned if the config directory cannot be determined.
+pub fn config_path() -> anyhow::Result<PathBuf> {
+    let mut path = dirs::config_dir().context("Failed to get config directory")?;
+    path.push(env!("CARGO_PKG_NAME"));
+    path.push("config.toml");
+    Ok(path)
+}
+
+/// Returns the path of the system-wide config file, which administrators can use to provide
+/// profiles to every user: `/etc/trayme/config.toml` on Linux,
+/// `/Library/Application Support/trayme/config.toml` on macOS, and
+/// `%ProgramData%\trayme\config.toml` on Windows.
+pub fn system_config_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("ProgramData")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from("/Library/Application Support")
+    } else {
+        PathBuf::from("/etc")
+    };
+    Some(dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+}
+
+/// Loads the config file.
+///
+/// # Arguments
+///
+/// * `path` - The config file to read. If `None`, the default one is used (see [`config_path`]).
+///
+/// # Errors
+///
+/// An error is returned if the config file cannot be loaded.
+pub fn load_config(path: Option<&Path>) -> anyhow::Result<Config> {
+    let config = match path {
+        Some(path) => Config::load(path),
+        None => config_path().and_then(|path| Config::load(&path)),
+    };
+    config.with_kind(ErrorKind::Config)
+}
+
+/// Replaces the `env` table of a profile in its config file.
+///
+/// # Arguments
+///
+/// * `profile` - The profile to update.
+/// * `env` - The new variables.
+///
+/// # Errors
+///
+/// An error is returned if the config file cannot be loaded or saved, or if the profile no longer
+/// exists.
+pub fn save_profile_env(
+    profile: &ProfileRef,
+    env: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut config = Config::load(&profile.config)?;
+    // variables that are the same as in the extended profile stay inherited
+    let inherited = config
+        .profiles
+        .get(&profile.name)
+        .and_then(|entry| entry.extends.as_ref())
+        .and_then(|base| config.resolved.get(base))
+        .map(|base| base.env.clone())
+        .unwrap_or_default();
+    let Some(entry) = config.profiles.get_mut(&profile.name) else {
+        bail!(
+            "No profile named '{}' in {}",
+            profile.name,
+            profile.config.display()
+        );
+    };
+    entry.env = env
+        .iter()
+        .filter(|(name, value)| inherited.get(*name) != Some(*value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    config.save(&profile.config)
+}
+
+/// Resolves the `extends` of every profile in the raw `[profiles]` table.
+///
+/// # Errors
+///
+/// An error is returned if a profile extends one that doesn't exist, profiles extend each other
+/// in a cycle, or a profile is invalid.
+fn resolve_profiles(profiles: &toml::Table) -> anyhow::Result<BTreeMap<String, Profile>> {
+    profiles
+        .keys()
+        .map(|name| {
+            let resolved = resolve_profile(profiles, name, &mut Vec::new())?;
+            let profile = toml::Value::Table(resolved)
+                .try_into()
+                .with_context(|| format!("Invalid profile '{name}'"))?;
+            Ok((name.clone(), profile))
+        })
+        .collect()
+}
+
+fn profiles_table(raw: &toml::Table) -> toml::Table {
+    raw.get("profiles")
+        .and_then(toml::Value::as_table)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Reads the `[profiles]` table of the system config, if there is one.
+///
+/// # Errors
+///
+/// An error is returned if the system config exists but cannot be read or parsed.
+fn system_profiles() -> anyhow::Result<Option<toml::Table>> {
+    let Some(path) = system_config_path() else {
+        return Ok(None);
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let raw: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("Invalid system config file {}", path.display()))?;
+    Ok(Some(profiles_table(&raw)))
+}
+
+/// Merges the user's profiles over the system's, except for the system's locked ones.
+fn layer_profiles(mut system: toml::Table, user: &toml::Table) -> toml::Table {
+    for (name, profile) in user {
+        let mut profile = profile.clone();
+        if let Some(profile) = profile.as_table_mut() {
+            // only the system config can lock profiles
+            profile.remove("locked");
+        }
+        match system.get_mut(name) {
+            Some(toml::Value::Table(base)) => {
+                if base.get("locked").and_then(toml::Value::as_bool) == Some(true) {
+                    warn!("Ignoring your profile '{name}', the system config doesn't allow changing it");
+                } else if let toml::Value::Table(profile) = &profile {
+                    merge_table(base, profile);
+                }
+            }
+            _ => {
+                system.insert(name.clone(), profile);
+            }
+        }
+    }
+    system
+}
+
+/// Merges the profile `name` over the profiles it extends. `chain` holds the profiles that
+/// extend it, to detect cycles.
+fn resolve_profile<'a>(
+    profiles: &'a toml::Table,
+    name: &'a str,
+    chain: &mut Vec<&'a str>,
+) -> anyhow::Result<toml::Table> {
+    if chain.contains(&name) {
+        chain.push(name);
+        bail!(
+            "Profiles extend each other in a cycle: {}",
+            chain.join(" -> ")
+        );
+    }
+    let own = match profiles.get(name) {
+        Some(toml::Value::Table(own)) => own,
+        Some(_) => bail!("Profile '{name}' is not a table"),
+        None => bail!(
+            "Profile '{}' extends '{name}', which doesn't exist",
+            chain.last().copied().unwrap_or_default()
+        ),
+    };
+    let Some(base) = own.get("extends") else {
+        return Ok(own.clone());
+    };
+    let base = base
+        .as_str()
+        .with_context(|| format!("The extends of profile '{name}' must be a profile name"))?;
+    chain.push(name);
+    let mut merged = resolve_profile(profiles, base, chain)?;
+    merge_table(&mut merged, own);
+    Ok(merged)
+}
+
+/// Sets every value of `over` in `base`, merging tables that are in both.
+fn merge_table(base: &mut toml::Table, over: &toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_table(base, over),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
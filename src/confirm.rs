@@ -0,0 +1,242 @@
+use std::{
+    process::Command,
+    sync::mpsc::{self, TryRecvError},
+    thread,
+};
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use anyhow::bail;
+use anyhow::Context;
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::notify::show_notification;
+
+/// How the user confirms stopping a protected instance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfirmMethod {
+    /// Typing the instance's name into a dialog.
+    #[default]
+    Phrase,
+    /// Authenticating with the OS: polkit on Linux, UAC on Windows, and an administrator prompt
+    /// (which takes Touch ID where it's set up) on macOS.
+    OsAuth,
+}
+
+/// Keeps actions such as Kill on a protected instance from happening until the user confirms
+/// them. The dialog runs in the background, so the tray keeps working while it's open.
+#[derive(Debug)]
+pub struct Protection<A> {
+    method: ConfirmMethod,
+    /// The open confirmation and the action it's for.
+    pending: Option<(mpsc::Receiver<bool>, A)>,
+}
+
+impl<A> Protection<A> {
+    pub fn new(method: ConfirmMethod) -> Self {
+        Self {
+            method,
+            pending: None,
+        }
+    }
+
+    /// Asks the user to confirm `action` on the instance `name`. Does nothing if a confirmation
+    /// is already open.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The instance's name, which is what has to be typed to confirm.
+    /// * `verb` - What the action does, as in "Type its name to kill it".
+    /// * `action` - The action, returned by [`Protection::confirmed`] once it's confirmed.
+    pub fn ask(&mut self, name: &str, verb: &str, action: A) {
+        if self.pending.is_some() {
+            debug!("Confirmation already open");
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let method = self.method;
+        let name = name.to_string();
+        let verb = verb.to_string();
+        thread::spawn(move || {
+            let confirmed = match method {
+                ConfirmMethod::Phrase => {
+                    let prompt = format!("'{name}' is protected. Type its name to {verb} it:");
+                    ask_phrase(&prompt, "").map(|answer| answer.trim() == name)
+                }
+                ConfirmMethod::OsAuth => authenticate(),
+            };
+            let confirmed = confirmed.unwrap_or_else(|e| {
+                warn!("{e:#}");
+                show_notification("Failed to confirm", &format!("{e:#}"));
+                false
+            });
+            let _ = tx.send(confirmed);
+        });
+        self.pending = Some((rx, action));
+    }
+
+    /// Returns the action asked for last once, when the user confirmed it.
+    pub fn confirmed(&mut self) -> Option<A> {
+        let (pending, _) = self.pending.as_ref()?;
+        let confirmed = match pending.try_recv() {
+            Ok(confirmed) => confirmed,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => false,
+        };
+        let (_, action) = self.pending.take()?;
+        info!("Protected action confirmed: {confirmed}");
+        confirmed.then_some(action)
+    }
+}
+
+/// Asks for a line of text, e.g. a new name, in a dialog that runs in the background like
+/// [`Protection`]'s.
+#[derive(Debug, Default)]
+pub struct TextPrompt {
+    pending: Option<mpsc::Receiver<String>>,
+}
+
+impl TextPrompt {
+    /// Shows the dialog with `prompt`, with `default` filled in as the answer. Does nothing if
+    /// it's already open.
+    pub fn ask(&mut self, prompt: &str, default: &str) {
+        if self.pending.is_some() {
+            debug!("Prompt already open");
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let prompt = prompt.to_string();
+        let default = default.to_string();
+        thread::spawn(move || {
+            let answer = ask_phrase(&prompt, &default).unwrap_or_else(|e| {
+                warn!("{e:#}");
+                show_notification("Failed to ask", &format!("{e:#}"));
+                String::new()
+            });
+            let _ = tx.send(answer);
+        });
+        self.pending = Some(rx);
+    }
+
+    /// Returns the answer once, when the dialog was closed. Cancelling answers an empty string.
+    pub fn answer(&mut self) -> Option<String> {
+        let answer = match self.pending.as_ref()?.try_recv() {
+            Ok(answer) => answer,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => String::new(),
+        };
+        self.pending = None;
+        Some(answer)
+    }
+}
+
+/// Shows a dialog asking for a line of text and returns it. Cancelling returns an empty string.
+///
+/// # Arguments
+///
+/// * `prompt` - What the dialog asks for.
+/// * `default` - The answer filled in when the dialog opens.
+///
+/// # Errors
+///
+/// An error is returned if no dialog could be shown.
+fn ask_phrase(prompt: &str, default: &str) -> anyhow::Result<String> {
+    #[cfg(windows)]
+    {
+        let script = format!(
+            "Add-Type -AssemblyName Microsoft.VisualBasic; \
+             [Microsoft.VisualBasic.Interaction]::InputBox('{}', 'trayme', '{}')",
+            prompt.replace('\'', "''"),
+            default.replace('\'', "''")
+        );
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-Command", &script])
+            .output()
+            .context("Failed to show confirmation dialog")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let script = format!(
+            "text returned of (display dialog \"{}\" default answer \"{}\" with title \"trayme\")",
+            escape(prompt),
+            escape(default)
+        );
+        let output = Command::new("osascript")
+            .args(["-e", &script])
+            .output()
+            .context("Failed to show confirmation dialog")?;
+        // cancelling fails with a non-zero exit code and no output
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        let dialogs: [(&str, &[&str]); 2] = [
+            (
+                "zenity",
+                &[
+                    "--entry",
+                    "--title",
+                    "trayme",
+                    "--text",
+                    prompt,
+                    "--entry-text",
+                    default,
+                ],
+            ),
+            (
+                "kdialog",
+                &["--title", "trayme", "--inputbox", prompt, default],
+            ),
+        ];
+        for (program, args) in dialogs {
+            match Command::new(program).args(args).output() {
+                Ok(output) => return Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+                Err(e) => debug!("Failed to run {program}: {e}"),
+            }
+        }
+        bail!("No dialog program found (install zenity or kdialog)")
+    }
+}
+
+/// Asks the OS to authenticate the user, as it would before an administrative action. Returns
+/// whether that succeeded.
+///
+/// # Errors
+///
+/// An error is returned if the authentication prompt could not be shown.
+fn authenticate() -> anyhow::Result<bool> {
+    #[cfg(windows)]
+    let mut command = {
+        let mut command = Command::new("powershell");
+        command.args([
+            "-NoProfile",
+            "-Command",
+            "Start-Process cmd.exe -ArgumentList '/c','exit' -Verb RunAs -Wait",
+        ]);
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = {
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e",
+            "do shell script \"true\" with prompt \"trayme wants to stop a protected \
+             instance.\" with administrator privileges",
+        ]);
+        command
+    };
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let mut command = {
+        let mut command = Command::new("pkexec");
+        command.arg("true");
+        command
+    };
+    let status = command
+        .status()
+        .context("Failed to show authentication prompt")?;
+    Ok(status.success())
+}
@@ -0,0 +1,211 @@
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::Path,
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use log::{debug, warn};
+
+use crate::{
+    ipc::{self, ControlCommand, ControlResponse},
+    output::{detect_level, OutputTail},
+    registry,
+};
+
+/// How often the console checks the log file for new output.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// Terminal emulators tried, in order, to open a console on Linux and the BSDs. Each runs the
+/// command given after its arguments.
+#[cfg(all(unix, not(target_os = "macos")))]
+const TERMINALS: &[(&str, &[&str])] = &[
+    ("x-terminal-emulator", &["-e"]),
+    ("gnome-terminal", &["--"]),
+    ("konsole", &["-e"]),
+    ("xfce4-terminal", &["-x"]),
+    ("alacritty", &["-e"]),
+    ("kitty", &[]),
+    ("xterm", &["-e"]),
+];
+
+/// Opens a terminal window running `trayme console <target>`.
+///
+/// # Arguments
+///
+/// * `target` - The instance name, or the address of its control socket.
+///
+/// # Errors
+///
+/// An error is returned if no terminal could be opened.
+pub fn open_console(target: &str) -> anyhow::Result<()> {
+    let exe = std::env::current_exe().context("Failed to get trayme's path")?;
+    debug!("Opening console for '{target}'");
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#flags
+        const CREATE_NEW_CONSOLE: u32 = 0x0000_0010;
+
+        Command::new(exe)
+            .args(["console", target])
+            .creation_flags(CREATE_NEW_CONSOLE)
+            .spawn()
+            .context("Failed to open console")?;
+        Ok(())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let script = format!(
+            "tell application \"Terminal\" to do script \"{} console {}\"",
+            exe.display(),
+            target
+        );
+        Command::new("osascript")
+            .args(["-e", &script])
+            .spawn()
+            .context("Failed to open console")?;
+        Ok(())
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        // $TERMINAL is a loose convention, so only the usual `-e` flag is assumed for it
+        let custom = std::env::var("TERMINAL").ok();
+        let candidates = custom
+            .as_deref()
+            .map(|terminal| (terminal, &["-e"][..]))
+            .into_iter()
+            .chain(TERMINALS.iter().copied());
+        for (terminal, args) in candidates {
+            let spawned = Command::new(terminal)
+                .args(args)
+                .arg(&exe)
+                .args(["console", target])
+                .spawn();
+            match spawned {
+                Ok(_) => return Ok(()),
+                Err(e) => debug!("Failed to run {terminal}: {e}"),
+            }
+        }
+        bail!("No terminal emulator found (set $TERMINAL to choose one)")
+    }
+}
+
+/// Attaches the current terminal to a running instance: the instance's output is followed and
+/// every line typed is sent to its stdin. Returns once stdin is closed.
+///
+/// # Arguments
+///
+/// * `target` - The instance name, or the address of its control socket.
+///
+/// # Errors
+///
+/// An error is returned if the instance cannot be reached.
+pub fn run_console(target: &str) -> anyhow::Result<()> {
+    #[cfg(windows)]
+    attach_console();
+
+    let addr = registry::resolve(target)?;
+    let status = match ipc::request(addr, &ControlCommand::Status)? {
+        ControlResponse::Status(status) => status,
+        response => bail!("Unexpected status response: {response:?}"),
+    };
+    println!(
+        "Connected to '{}' (PID {}). Lines typed here are sent to its stdin.",
+        status.name, status.pid
+    );
+
+    thread::spawn(move || {
+        if let Err(e) = follow(&status.log_file) {
+            warn!("Stopped following {}: {e}", status.log_file.display());
+        }
+    });
+
+    for line in io::stdin().lock().lines() {
+        let line = line.context("Failed to read stdin")?;
+        match ipc::request(addr, &ControlCommand::Send(line))? {
+            ControlResponse::Error { message } => eprintln!("trayme: {message}"),
+            ControlResponse::Ok | ControlResponse::Status(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes lines to the stdin of a running instance.
+///
+/// # Arguments
+///
+/// * `target` - The instance name, or the address of its control socket.
+/// * `lines` - The lines to send. When empty, they're read from stdin until it's closed.
+///
+/// # Errors
+///
+/// An error is returned if the instance cannot be reached, or a line could not be written to
+/// its stdin, e.g. because the process isn't running.
+pub fn send_lines(target: &str, lines: &[String]) -> anyhow::Result<()> {
+    let addr = registry::resolve(target)?;
+    let send = |line: String| match ipc::request(addr, &ControlCommand::Send(line))? {
+        ControlResponse::Error { message } => bail!("{message}"),
+        ControlResponse::Ok | ControlResponse::Status(_) => Ok(()),
+    };
+    if lines.is_empty() {
+        for line in io::stdin().lock().lines() {
+            send(line.context("Failed to read stdin")?)?;
+        }
+    } else {
+        lines.iter().cloned().try_for_each(send)?;
+    }
+    Ok(())
+}
+
+/// Prints everything written to `path` to stdout, forever. Lines are colored by their log level
+/// when stdout is a terminal.
+fn follow(path: &Path) -> io::Result<()> {
+    let mut output = OutputTail::open(path)?;
+    let colored = io::stdout().is_terminal();
+    loop {
+        let lines = output.read_lines()?;
+        if lines.is_empty() {
+            thread::sleep(FOLLOW_INTERVAL);
+            continue;
+        }
+        let mut stdout = io::stdout().lock();
+        for line in lines {
+            match detect_level(&line).filter(|_| colored) {
+                Some(level) => writeln!(stdout, "{}{line}{ANSI_RESET}", level.ansi_color())?,
+                None => writeln!(stdout, "{line}")?,
+            }
+        }
+    }
+}
+
+/// Release builds use the GUI subsystem and start without a console, so one is allocated here.
+/// This is a no-op when there already is one. ANSI colors are enabled on the console as well.
+#[cfg(windows)]
+fn attach_console() {
+    // https://learn.microsoft.com/en-us/windows/console/setconsolemode
+    const STD_OUTPUT_HANDLE: u32 = -11_i32 as u32;
+    const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AllocConsole() -> i32;
+        fn GetStdHandle(std_handle: u32) -> *mut std::ffi::c_void;
+        fn GetConsoleMode(handle: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+        fn SetConsoleMode(handle: *mut std::ffi::c_void, mode: u32) -> i32;
+    }
+    // SAFETY: these calls have no preconditions and fail harmlessly
+    unsafe {
+        AllocConsole();
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) != 0 {
+            SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use regex::{Regex, RegexSet};
+
+/// How much of the end of the log is searched for a backtrace.
+const TAIL_BYTES: u64 = 64 * 1024;
+
+/// Backtraces longer than this are cut off, keeping their start.
+const MAX_LINES: usize = 100;
+
+/// Lines that start a crash report, per runtime: Rust panics, Python tracebacks, Go panics, Java
+/// exceptions, and sanitizer reports.
+const START_PATTERNS: &[&str] = &[
+    r"^thread '.*' panicked at",
+    r"^Traceback \(most recent call last\):",
+    r"^(panic|fatal error): ",
+    r"^Exception in thread ",
+    r"^==\d+==ERROR: \w+Sanitizer",
+];
+
+/// A crash report found at the end of a run's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    /// The whole report, as printed.
+    pub text: String,
+    /// The line (or two) saying what went wrong, e.g. the panic message.
+    pub summary: String,
+}
+
+/// Searches the end of a log file for the last crash report in it.
+///
+/// # Errors
+///
+/// An error is returned if the log file cannot be read.
+pub fn find_in_log(path: &Path) -> io::Result<Option<Backtrace>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_BYTES)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(extract(&String::from_utf8_lossy(&tail)))
+}
+
+/// Extracts the last crash report from a run's output. The report runs from its first line to
+/// the end of the output, since a crash is the last thing a process prints.
+pub fn extract(output: &str) -> Option<Backtrace> {
+    let starts = RegexSet::new(START_PATTERNS).expect("backtrace patterns are valid");
+    // a JS error line directly followed by a stack frame
+    let js_error = Regex::new(r"^\w*Error\b").expect("backtrace patterns are valid");
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let start = (0..lines.len()).rev().find(|&i| {
+        starts.is_match(lines[i])
+            || (js_error.is_match(lines[i])
+                && lines
+                    .get(i + 1)
+                    .is_some_and(|next| next.trim_start().starts_with("at ")))
+    })?;
+    let mut block = &lines[start..];
+    while let [rest @ .., last] = block {
+        if !last.trim().is_empty() {
+            break;
+        }
+        block = rest;
+    }
+    let block = &block[..block.len().min(MAX_LINES)];
+
+    let first = block[0];
+    let summary = if first.starts_with("Traceback") {
+        // Python prints the exception last
+        block
+            .iter()
+            .rev()
+            .find(|line| !line.starts_with(char::is_whitespace))
+            .copied()
+            .unwrap_or(first)
+            .to_string()
+    } else if first.ends_with(':') && block.len() > 1 {
+        // newer Rust versions print the panic message on its own line
+        format!("{first} {}", block[1].trim())
+    } else {
+        first.to_string()
+    };
+    Some(Backtrace {
+        text: block.join("\n"),
+        summary,
+    })
+}
@@ -0,0 +1,151 @@
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use tao::event_loop::{EventLoop, EventLoopBuilder};
+
+use crate::exitcode::{ErrorKind, WithKind};
+
+/// The variable GTK reads its backend from. tao and tray-icon use GTK on Linux.
+#[cfg(all(unix, not(target_os = "macos")))]
+const GDK_BACKEND: &str = "GDK_BACKEND";
+
+static BACKEND: OnceLock<DisplayBackend> = OnceLock::new();
+
+/// Which display protocol trayme's tray and windows use on Linux. The other platforms only have
+/// one, so this is ignored there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DisplayBackend {
+    /// Whatever the session uses.
+    #[default]
+    Auto,
+    /// X11, which also works through `XWayland` on most Wayland compositors.
+    X11,
+    Wayland,
+}
+
+impl DisplayBackend {
+    /// The value of `GDK_BACKEND` that selects this backend.
+    pub fn gdk_name(self) -> Option<&'static str> {
+        match self {
+            DisplayBackend::Auto => None,
+            DisplayBackend::X11 => Some("x11"),
+            DisplayBackend::Wayland => Some("wayland"),
+        }
+    }
+}
+
+/// Picks the backend used by [`build_event_loop`]. Only the first call has an effect.
+pub fn select(backend: DisplayBackend) {
+    let _ = BACKEND.set(backend);
+}
+
+/// The backend picked with [`select`].
+pub fn selected() -> DisplayBackend {
+    BACKEND.get().copied().unwrap_or_default()
+}
+
+/// Creates the event loop with the selected display backend. The choice only applies to trayme:
+/// the environment is restored afterwards, so children still pick their own backend.
+pub fn build_event_loop() -> anyhow::Result<EventLoop<()>> {
+    if detect_session() == Session::None {
+        // the event loop would panic instead
+        return Err(anyhow!(
+            "There is no display to show the tray on, use --headless to run without one"
+        ))
+        .with_kind(ErrorKind::TrayUnavailable);
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if let Some(name) = selected().gdk_name() {
+        log::debug!("Using display backend {name}");
+        let previous = std::env::var_os(GDK_BACKEND);
+        std::env::set_var(GDK_BACKEND, name);
+        let event_loop = EventLoopBuilder::new().build();
+        match previous {
+            Some(previous) => std::env::set_var(GDK_BACKEND, previous),
+            None => std::env::remove_var(GDK_BACKEND),
+        }
+        return Ok(event_loop);
+    }
+    Ok(EventLoopBuilder::new().build())
+}
+
+/// The kind of graphical session trayme runs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Session {
+    X11,
+    Wayland,
+    /// Windows or macOS.
+    Native,
+    /// No display at all, e.g. over SSH or in a system service.
+    None,
+}
+
+/// Detects the current session from the environment.
+pub fn detect_session() -> Session {
+    if cfg!(any(windows, target_os = "macos")) {
+        return Session::Native;
+    }
+    let has = |name: &str| std::env::var_os(name).is_some_and(|value| !value.is_empty());
+    if has("WAYLAND_DISPLAY") {
+        Session::Wayland
+    } else if has("DISPLAY") {
+        Session::X11
+    } else {
+        Session::None
+    }
+}
+
+/// The bus name of the `StatusNotifierItem` host, which shows tray icons on Linux.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub const TRAY_BUS_NAME: &str = "org.kde.StatusNotifierWatcher";
+
+/// Whether a name is owned on the D-Bus session bus, e.g. whether a tray host or notification
+/// daemon is running. Returns `None` if the bus can't be reached.
+///
+/// # Errors
+///
+/// An error is returned if dbus-send cannot be run.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn bus_name_owned(name: &str) -> std::io::Result<Option<bool>> {
+    Ok(ask_bus("NameHasOwner", name)?.map(|reply| reply.contains("boolean true")))
+}
+
+/// The unique name of the connection that owns a name on the D-Bus session bus, e.g. `:1.42`,
+/// which changes when the owner restarts. Returns `None` if the name has no owner or the bus
+/// can't be reached.
+///
+/// # Errors
+///
+/// An error is returned if dbus-send cannot be run.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn bus_name_owner(name: &str) -> std::io::Result<Option<String>> {
+    let Some(reply) = ask_bus("GetNameOwner", name)? else {
+        return Ok(None);
+    };
+    // the reply's last line is `   string ":1.42"`
+    Ok(reply
+        .split_once("string \"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(owner, _)| owner.to_string()))
+}
+
+/// Calls a method of the bus itself that takes a name, returning the reply as dbus-send prints
+/// it, or `None` if the call failed.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn ask_bus(method: &str, name: &str) -> std::io::Result<Option<String>> {
+    let output = std::process::Command::new("dbus-send")
+        .args([
+            "--session",
+            "--print-reply",
+            "--dest=org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            &format!("org.freedesktop.DBus.{method}"),
+            &format!("string:{name}"),
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
@@ -0,0 +1,376 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, TcpListener},
+    path::{Path, PathBuf},
+};
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use std::process::Command;
+
+use anyhow::bail;
+
+use crate::{
+    config,
+    display::{self, DisplayBackend, Session},
+    get_logs_dir,
+};
+
+/// How a check turned out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Works, but some features won't.
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Outcome::Pass => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        })
+    }
+}
+
+/// The result of one check, with a hint on how to fix it if it didn't pass.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        outcome: Outcome,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            outcome,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:<4}] {}: {}", self.outcome, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the session and the files trayme uses are set up properly and prints the
+/// results.
+///
+/// # Errors
+///
+/// An error is returned if any check failed.
+pub fn run() -> anyhow::Result<()> {
+    let mut checks = display_checks();
+    checks.extend([
+        config_check(),
+        logs_dir_check(),
+        control_socket_check(),
+        autostart_check(),
+    ]);
+    for check in &checks {
+        println!("{check}");
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.outcome == Outcome::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+    Ok(())
+}
+
+/// Checks the display, tray, and notification support of the session.
+fn display_checks() -> Vec<Check> {
+    let session = display::detect_session();
+    let backend = display::selected();
+    let mut checks = vec![display_check(session, backend)];
+    if session == Session::Native {
+        checks.push(Check::pass("Tray", "supported natively"));
+        checks.push(Check::pass("Notifications", "supported natively"));
+        return checks;
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        checks.push(bus_check(
+            "Tray",
+            display::TRAY_BUS_NAME,
+            Outcome::Fail,
+            "there is no StatusNotifierItem host, so the tray icon won't show",
+            "Enable a system tray in your panel or bar (e.g. waybar's \"tray\" module), or on \
+             GNOME install the AppIndicator extension",
+        ));
+        checks.push(bus_check(
+            "Notifications",
+            "org.freedesktop.Notifications",
+            Outcome::Warn,
+            "no notification daemon is running, so notifications are only logged",
+            "Run a notification daemon such as mako, dunst, or swaync",
+        ));
+        checks.push(window_control_check(session, backend));
+    }
+    checks
+}
+
+fn display_check(session: Session, backend: DisplayBackend) -> Check {
+    const NAME: &str = "Display";
+    match (session, backend) {
+        (Session::Native, _) => Check::pass(NAME, "native"),
+        (Session::None, _) => Check::problem(
+            NAME,
+            Outcome::Fail,
+            "neither WAYLAND_DISPLAY nor DISPLAY is set",
+            "Run trayme from a graphical session, or use --headless with `trayme tray` from one",
+        ),
+        (Session::X11, DisplayBackend::Wayland) => Check::problem(
+            NAME,
+            Outcome::Fail,
+            "--display-backend wayland was given, but this is an X11 session",
+            "Use --display-backend auto",
+        ),
+        (Session::Wayland, DisplayBackend::X11) if std::env::var_os("DISPLAY").is_none() => {
+            Check::problem(
+                NAME,
+                Outcome::Fail,
+                "--display-backend x11 was given, but XWayland isn't running (DISPLAY isn't set)",
+                "Enable XWayland in your compositor, or use --display-backend auto",
+            )
+        }
+        (Session::Wayland, DisplayBackend::X11) => Check::pass(NAME, "Wayland session, using X11"),
+        (Session::Wayland, _) => Check::pass(NAME, "Wayland session"),
+        (Session::X11, _) => Check::pass(NAME, "X11 session"),
+    }
+}
+
+/// Checks whether a D-Bus name is owned on the session bus.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn bus_check(
+    name: &'static str,
+    bus_name: &str,
+    outcome: Outcome,
+    missing: &str,
+    hint: &str,
+) -> Check {
+    match display::bus_name_owned(bus_name) {
+        Ok(None) => Check::problem(
+            name,
+            Outcome::Fail,
+            "the session bus can't be reached",
+            "Make sure DBUS_SESSION_BUS_ADDRESS is set, as it is in a normal desktop session",
+        ),
+        Ok(Some(true)) => Check::pass(name, format!("{bus_name} is available")),
+        Ok(Some(false)) => Check::problem(name, outcome, missing, hint),
+        Err(_) => Check::problem(
+            name,
+            Outcome::Warn,
+            "dbus-send isn't installed, so this can't be checked",
+            "Install dbus (which provides dbus-send) to run this check",
+        ),
+    }
+}
+
+/// Checks what `--start-hidden` and `--click-to-toggle` need.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn window_control_check(session: Session, backend: DisplayBackend) -> Check {
+    const NAME: &str = "Window control";
+    if session == Session::Wayland && backend != DisplayBackend::X11 {
+        return Check::problem(
+            NAME,
+            Outcome::Warn,
+            "Wayland doesn't let trayme show and hide other apps' windows",
+            "--start-hidden only works for apps running under XWayland",
+        );
+    }
+    match Command::new("xdotool").arg("version").output() {
+        Ok(_) => Check::pass(NAME, "xdotool is available"),
+        Err(_) => Check::problem(
+            NAME,
+            Outcome::Warn,
+            "xdotool isn't installed, so --start-hidden won't work",
+            "Install xdotool",
+        ),
+    }
+}
+
+fn config_check() -> Check {
+    const NAME: &str = "Config";
+    let path = match config::config_path() {
+        Ok(path) => path,
+        Err(e) => {
+            return Check::problem(
+                NAME,
+                Outcome::Fail,
+                format!("{e:#}"),
+                "Pass the config file with --config",
+            )
+        }
+    };
+    let system = config::system_config_path().filter(|system| system.exists());
+    if !path.exists() && system.is_none() {
+        return Check::pass(NAME, format!("no config file at {}", path.display()));
+    }
+    match config::Config::load(&path) {
+        Ok(config) => {
+            let sources = match system {
+                Some(system) => format!("{} and {}", path.display(), system.display()),
+                None => path.display().to_string(),
+            };
+            let detail = format!("{} profiles from {sources}", config.profile_names().count());
+            Check::pass(NAME, detail)
+        }
+        Err(e) => Check::problem(
+            NAME,
+            Outcome::Fail,
+            format!("{e:#}"),
+            "Fix the error in the config file",
+        ),
+    }
+}
+
+/// Checks that logs, the run history, and the instance registry can be written.
+fn logs_dir_check() -> Check {
+    const NAME: &str = "Logs directory";
+    let dir = match get_logs_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            return Check::problem(
+                NAME,
+                Outcome::Fail,
+                format!("{e:#}"),
+                "Make sure your home directory exists and is writable",
+            )
+        }
+    };
+    let probe = dir.join(".doctor");
+    let written = std::fs::write(&probe, b"");
+    let _ = std::fs::remove_file(&probe);
+    match written {
+        Ok(()) => Check::pass(NAME, format!("{} is writable", dir.display())),
+        Err(e) => Check::problem(
+            NAME,
+            Outcome::Fail,
+            format!("{} isn't writable: {e}", dir.display()),
+            format!(
+                "Check the owner and permissions of {} (e.g. after running trayme with sudo)",
+                dir.display()
+            ),
+        ),
+    }
+}
+
+/// Checks that a control socket can be bound, which `down`, `console`, and `tray` rely on.
+fn control_socket_check() -> Check {
+    const NAME: &str = "Control socket";
+    match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)) {
+        Ok(_) => Check::pass(NAME, "can listen on localhost"),
+        Err(e) => Check::problem(
+            NAME,
+            Outcome::Fail,
+            format!("can't listen on localhost: {e}"),
+            "Allow trayme to listen on localhost in your firewall or sandbox, otherwise \
+             instances can't be controlled from other trayme processes",
+        ),
+    }
+}
+
+/// Checks that autostart entries starting trayme point to a trayme binary that still exists.
+fn autostart_check() -> Check {
+    const NAME: &str = "Autostart";
+    let Some(dir) = autostart_dir() else {
+        return Check::pass(NAME, "no autostart directory on this platform");
+    };
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Check::pass(NAME, format!("{} doesn't exist", dir.display()));
+    };
+    let mut found = 0;
+    let mut broken = Vec::new();
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        let Ok(contents) = std::fs::read(&path) else {
+            continue;
+        };
+        let contents = String::from_utf8_lossy(&contents);
+        let binaries = referenced_binaries(&contents);
+        if binaries.is_empty() {
+            continue;
+        }
+        found += 1;
+        if !binaries.iter().any(|binary| binary.exists()) {
+            broken.push(path);
+        }
+    }
+    if broken.is_empty() {
+        return Check::pass(
+            NAME,
+            format!("{found} entries starting trayme in {}", dir.display()),
+        );
+    }
+    let broken: Vec<_> = broken
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    Check::problem(
+        NAME,
+        Outcome::Warn,
+        format!(
+            "these entries start a trayme that doesn't exist: {}",
+            broken.join(", ")
+        ),
+        match std::env::current_exe() {
+            Ok(exe) => format!("Point them to {}", exe.display()),
+            Err(_) => "Point them to where trayme is installed now".to_string(),
+        },
+    )
+}
+
+/// Where the platform looks for programs to start on login.
+fn autostart_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        Some(dirs::data_dir()?.join(r"Microsoft\Windows\Start Menu\Programs\Startup"))
+    } else if cfg!(target_os = "macos") {
+        Some(dirs::home_dir()?.join("Library/LaunchAgents"))
+    } else {
+        Some(dirs::config_dir()?.join("autostart"))
+    }
+}
+
+/// The absolute paths to a trayme binary in an autostart entry, such as a `.desktop` file's
+/// `Exec` line or a launch agent's `ProgramArguments`.
+fn referenced_binaries(contents: &str) -> Vec<PathBuf> {
+    contents
+        .split(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>' | '=' | '\0'))
+        .map(Path::new)
+        .filter(|path| {
+            path.is_absolute()
+                && path
+                    .file_stem()
+                    .is_some_and(|stem| stem == env!("CARGO_PKG_NAME"))
+        })
+        .map(Path::to_path_buf)
+        .collect()
+}
@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Context};
+
+/// The variables a clean environment keeps, see `--clear-env`. Windows programs can't load
+/// system libraries without `SystemRoot`, so even a clean environment needs it.
+#[cfg(windows)]
+const KEPT: &[&str] = &["SystemRoot"];
+#[cfg(not(windows))]
+const KEPT: &[&str] = &[];
+
+/// Reads a dotenv file, see [`parse`].
+///
+/// # Errors
+///
+/// An error is returned if the file cannot be read or isn't valid.
+pub fn load(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&contents).with_context(|| format!("Invalid env file {}", path.display()))
+}
+
+/// Parses the contents of a dotenv file: one `KEY=VALUE` per line, optionally preceded by
+/// `export`, with blank lines and lines starting with `#` ignored. Values may be quoted, with
+/// single quotes taking the value literally and double quotes allowing `\n`, `\t`, `\"`, and
+/// `\\`. A `#` after whitespace starts a comment in unquoted values. Variables aren't expanded.
+///
+/// # Errors
+///
+/// An error naming the line is returned for a line without `=`, an invalid name, or an
+/// unterminated quote.
+pub fn parse(contents: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut vars = BTreeMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) =
+            parse_line(line).with_context(|| format!("Line {}: {line}", number + 1))?;
+        vars.insert(key, value);
+    }
+    Ok(vars)
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let Some((key, value)) = line.split_once('=') else {
+        bail!("expected KEY=VALUE");
+    };
+    let key = key.trim_end();
+    check_name(key)?;
+    let value = value.trim_start();
+    let value = if let Some(quoted) = value.strip_prefix('\'') {
+        let Some((value, _)) = quoted.split_once('\'') else {
+            bail!("unterminated single quote");
+        };
+        value.to_string()
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        unescape(quoted)?
+    } else {
+        let end = value.find(" #").or_else(|| value.find("\t#"));
+        value[..end.unwrap_or(value.len())].trim_end().to_string()
+    };
+    Ok((key.to_string(), value))
+}
+
+/// The value of a double-quoted string, up to the closing quote.
+fn unescape(quoted: &str) -> anyhow::Result<String> {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok(value),
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\')) => value.push(c),
+                // kept as is, like most dotenv implementations do
+                Some(c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    bail!("unterminated double quote")
+}
+
+/// Checks that `key` can be the name of an environment variable everywhere.
+///
+/// # Errors
+///
+/// An error is returned if it's empty or contains `=`, whitespace, or a NUL.
+pub fn check_name(key: &str) -> anyhow::Result<()> {
+    if key.is_empty() || key.contains(|c: char| c == '=' || c == '\0' || c.is_whitespace()) {
+        bail!("'{key}' is not a valid variable name");
+    }
+    Ok(())
+}
+
+/// The environment `--clear-env` starts from: empty but for the few variables the platform
+/// can't do without, taken from trayme's.
+pub fn clean() -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter(|(key, _)| KEPT.iter().any(|kept| key.eq_ignore_ascii_case(kept)))
+        .collect()
+}
@@ -0,0 +1,159 @@
+use std::{ffi::OsString, num::NonZeroUsize, path::Path};
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use strum::VariantArray;
+use tao::{
+    dpi::LogicalSize,
+    event::{Event, WindowEvent},
+    event_loop::ControlFlow,
+    window::{Window, WindowBuilder},
+};
+use tray_icon::menu::{
+    CheckMenuItem, IsMenuItem, MenuEvent, MenuEventReceiver, PredefinedMenuItem,
+};
+
+use crate::{
+    build_tray, build_tray_menu, display, icon,
+    notify::{show_notification, Notifier},
+    osargs,
+    supervisor::program_name,
+    trigger::{QueueMessage, QueueStatus, RunQueue},
+};
+
+/// The placeholder in the command template that is replaced with the dropped file's path.
+pub const FILE_PLACEHOLDER: &str = "{file}";
+
+/// The menu item that shows and hides the drop window.
+const SHOW_WINDOW_ID: &str = "Show Drop Window";
+
+/// The size of the drop window, in logical pixels.
+const WINDOW_SIZE: f64 = 160.0;
+
+/// The tray of `trayme drop`: a small always-on-top window feeding a run queue.
+struct DropTray {
+    window: Window,
+    queue: RunQueue,
+    status: QueueStatus,
+    show_item: CheckMenuItem,
+}
+
+impl DropTray {
+    /// Queues a run for a dropped file.
+    fn drop_file(&mut self, path: &Path) {
+        if let Some(path) = path.to_str() {
+            self.queue.push(path.to_string());
+        } else {
+            warn!("Ignoring non-UTF-8 path {}", path.display());
+            show_notification(
+                "File ignored",
+                &format!("{} is not valid UTF-8", path.display()),
+            );
+        }
+    }
+
+    fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::DroppedFile(path) => self.drop_file(path),
+            WindowEvent::CloseRequested => {
+                // closing the window only hides it, the tray keeps running
+                self.window.set_visible(false);
+                self.show_item.set_checked(false);
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, menu_channel: &MenuEventReceiver) -> anyhow::Result<ControlFlow> {
+        self.queue.poll()?;
+        self.status.update(&self.queue)?;
+
+        if let Ok(event) = menu_channel.try_recv() {
+            debug!("{event:?}");
+
+            if event.id().0 == SHOW_WINDOW_ID {
+                // check items toggle themselves
+                let visible = self.show_item.is_checked();
+                info!("Drop window visible: {visible}");
+                self.window.set_visible(visible);
+                return Ok(ControlFlow::Poll);
+            }
+            return self.queue.handle_menu_event(&event.id().0);
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+}
+
+/// Shows a small always-on-top window and runs `cmd` for every file dropped onto it, until the
+/// user quits from the tray. The window can be hidden and shown again from the tray.
+///
+/// # Arguments
+///
+/// * `cmd` - The command template. `{file}` is replaced with the path of the dropped file.
+/// * `notifier` - Used for the notifications of each run.
+/// * `max_concurrent` - How many runs may be in progress at once.
+///
+/// # Errors
+///
+/// An error is returned if the window or the tray icon cannot be built.
+pub fn run_drop_window(
+    cmd: Vec<OsString>,
+    notifier: Notifier,
+    max_concurrent: NonZeroUsize,
+) -> anyhow::Result<()> {
+    let event_loop = display::build_event_loop()?;
+
+    let window = WindowBuilder::new()
+        .with_title(format!(
+            "Drop files to run {}",
+            program_name(&cmd[0].to_string_lossy())
+        ))
+        .with_inner_size(LogicalSize::new(WINDOW_SIZE, WINDOW_SIZE))
+        .with_resizable(false)
+        .with_always_on_top(true)
+        .build(&event_loop)
+        .context("Failed to build drop window")?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(QueueMessage::VARIANTS)?;
+    let status = QueueStatus::new();
+    let show_item = CheckMenuItem::with_id(SHOW_WINDOW_ID, SHOW_WINDOW_ID, true, true, None);
+    menu.prepend_items(&[
+        &status.running as &dyn IsMenuItem,
+        &status.queued,
+        &show_item,
+        &PredefinedMenuItem::separator(),
+    ])?;
+    let tooltip = format!("trayme drop: {}", osargs::display(&cmd));
+    let mut tray = Some(build_tray(&tooltip, menu, icon::identicon(&tooltip)?)?);
+    let menu_channel = MenuEvent::receiver();
+    let mut drop_tray = DropTray {
+        window,
+        queue: RunQueue::new(cmd, FILE_PLACEHOLDER, notifier, max_concurrent),
+        status,
+        show_item,
+    };
+
+    event_loop.run(move |event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        if tray.is_none() {
+            return;
+        }
+        if let Event::WindowEvent { event, .. } = &event {
+            drop_tray.handle_window_event(event);
+        }
+        match drop_tray.tick(menu_channel) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = drop_tray.queue.stop();
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
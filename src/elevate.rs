@@ -0,0 +1,348 @@
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+
+use crate::supervisor::CommandSpec;
+
+/// Returns the spec that runs the command of `spec` with administrator rights, after the user
+/// agreed to it in the platform's prompt. trayme itself keeps running as the user and watches
+/// the elevated command like any other, but has to stop it through [`Elevation`].
+///
+/// * Linux and the BSDs: `pkexec`, or `sudo -A` when `SUDO_ASKPASS` is set. Both reset the
+///   environment, so the variables trayme sets on top of it are passed on with `env`.
+/// * macOS: `osascript`'s `do shell script … with administrator privileges`, which only hands
+///   over the command's output once it exits.
+/// * Windows: a UAC prompt through `Start-Process -Verb RunAs`, which runs the command in a
+///   console of its own, so its output isn't logged and the variables trayme sets aren't passed
+///   on.
+///
+/// # Errors
+///
+/// An error is returned if the working directory cannot be determined.
+pub fn apply(spec: &CommandSpec) -> anyhow::Result<CommandSpec> {
+    let mut elevated = spec.clone();
+    elevated.cmd = platform::wrap(spec)?;
+    Ok(elevated)
+}
+
+/// Stops the processes of an elevated command. They don't run as the user, so trayme can't
+/// signal them itself, and asks for administrator rights again to do it with the tool the command
+/// was started with.
+#[derive(Debug, Clone)]
+pub struct Elevation {
+    /// `SUDO_ASKPASS`, if the command was started with `sudo -A`, which it's stopped with too.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    askpass: Option<std::ffi::OsString>,
+}
+
+impl Elevation {
+    /// How the command of `spec` is stopped, if it's elevated.
+    pub fn of(spec: &CommandSpec) -> Option<Self> {
+        spec.elevate.then(|| Self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            askpass: platform::askpass(spec),
+        })
+    }
+
+    /// Sends `signal` to the process group `pgid`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the user didn't allow it, or the group cannot be signalled.
+    #[cfg(unix)]
+    pub fn signal(&self, pgid: libc::pid_t, signal: libc::c_int) -> anyhow::Result<()> {
+        let kill = [
+            "kill".to_string(),
+            format!("-{signal}"),
+            "--".to_string(),
+            format!("-{pgid}"),
+        ];
+        run(platform::command(self, &kill))
+            .with_context(|| format!("Failed to signal process group {pgid}"))
+    }
+
+    /// Kills the process `pid` and the processes it started, which are the elevated process the
+    /// `Start-Process` wrapper started and what that started in turn.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the user didn't allow it, or the processes cannot be killed.
+    #[cfg(windows)]
+    #[allow(clippy::unused_self)] // every elevated process is killed the same way on Windows
+    pub fn kill_tree(&self, pid: u32) -> anyhow::Result<()> {
+        run(platform::kill_tree(pid))
+            .with_context(|| format!("Failed to kill the tree of PID {pid}"))
+    }
+}
+
+/// Runs `command`, which asks the user for administrator rights, and waits for it.
+fn run(mut command: Command) -> anyhow::Result<()> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .context("Failed to run the elevation tool")?;
+    if !status.success() {
+        bail!("The elevation tool failed: {status}");
+    }
+    Ok(())
+}
+
+/// The arguments that make `env` run the command of `spec` with the variables trayme sets on top
+/// of the environment.
+#[cfg(unix)]
+fn env_args(spec: &CommandSpec) -> impl Iterator<Item = std::ffi::OsString> + '_ {
+    std::iter::once("--".into())
+        .chain(
+            spec.overrides()
+                .into_iter()
+                .map(|(key, value)| format!("{key}={value}").into()),
+        )
+        .chain(spec.cmd.iter().cloned())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::{ffi::OsString, process::Command};
+
+    use anyhow::Context;
+
+    use super::{env_args, Elevation};
+    use crate::supervisor::CommandSpec;
+
+    pub fn wrap(spec: &CommandSpec) -> anyhow::Result<Vec<OsString>> {
+        let mut cmd: Vec<OsString> = if askpass(spec).is_some() {
+            // sudo keeps the working directory
+            ["sudo", "-A", "--", "env"].map(OsString::from).into()
+        } else {
+            // pkexec starts in the target user's home directory instead
+            let cwd = match &spec.cwd {
+                Some(cwd) => cwd.clone(),
+                None => std::env::current_dir().context("Failed to get current directory")?,
+            };
+            let mut chdir = OsString::from("--chdir=");
+            chdir.push(cwd);
+            vec!["pkexec".into(), "env".into(), chdir]
+        };
+        cmd.extend(env_args(spec));
+        Ok(cmd)
+    }
+
+    /// The `SUDO_ASKPASS` the command of `spec` is started with, which sudo reads from its own
+    /// environment.
+    pub fn askpass(spec: &CommandSpec) -> Option<OsString> {
+        if let Some(askpass) = spec.overrides().get("SUDO_ASKPASS") {
+            return Some(askpass.into());
+        }
+        match &spec.env {
+            Some(env) => env.get("SUDO_ASKPASS").map(Into::into),
+            None => std::env::var_os("SUDO_ASKPASS"),
+        }
+    }
+
+    /// Runs `args` as root the way the command was started.
+    pub fn command(elevation: &Elevation, args: &[String]) -> Command {
+        let mut command;
+        if let Some(askpass) = &elevation.askpass {
+            command = Command::new("sudo");
+            command.args(["-A", "--"]).env("SUDO_ASKPASS", askpass);
+        } else {
+            command = Command::new("pkexec");
+        }
+        command.args(args);
+        command
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{ffi::OsString, process::Command};
+
+    use anyhow::Context;
+
+    use super::{env_args, Elevation};
+    use crate::{envprovider::shell_quote, supervisor::CommandSpec};
+
+    pub fn wrap(spec: &CommandSpec) -> anyhow::Result<Vec<OsString>> {
+        let cwd = match &spec.cwd {
+            Some(cwd) => cwd.clone(),
+            None => std::env::current_dir().context("Failed to get current directory")?,
+        };
+        let mut script = format!(
+            "cd {} && exec env",
+            shell_quote(cwd.as_os_str()).to_string_lossy()
+        );
+        for arg in env_args(spec) {
+            script.push(' ');
+            script.push_str(&shell_quote(&arg).to_string_lossy());
+        }
+        Ok(vec![
+            "osascript".into(),
+            "-e".into(),
+            administrator_script(&script).into(),
+        ])
+    }
+
+    /// Runs `args` as root the way the command was started.
+    pub fn command(_: &Elevation, args: &[String]) -> Command {
+        let mut command = Command::new("osascript");
+        command.args(["-e", &administrator_script(&args.join(" "))]);
+        command
+    }
+
+    /// The AppleScript that runs the shell `script` with administrator privileges.
+    fn administrator_script(script: &str) -> String {
+        let script = script.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("do shell script \"{script}\" with administrator privileges")
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{ffi::OsString, process::Command};
+
+    use anyhow::Context;
+
+    use crate::supervisor::CommandSpec;
+
+    pub fn wrap(spec: &CommandSpec) -> anyhow::Result<Vec<OsString>> {
+        let cwd = match &spec.cwd {
+            Some(cwd) => cwd.clone(),
+            None => std::env::current_dir().context("Failed to get current directory")?,
+        };
+        let (program, args) = spec.cmd.split_first().context("The command is empty")?;
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| quote_arg(&arg.to_string_lossy()))
+            .collect();
+        let mut script = format!(
+            "$p = Start-Process -Verb RunAs -Wait -PassThru -FilePath {} -WorkingDirectory {}",
+            quote_ps(&program.to_string_lossy()),
+            quote_ps(&cwd.to_string_lossy())
+        );
+        if !args.is_empty() {
+            script.push_str(" -ArgumentList ");
+            script.push_str(&quote_ps(&args.join(" ")));
+        }
+        // waits for the elevated process, and passes its exit code on
+        script.push_str("; exit $p.ExitCode");
+        Ok([
+            "powershell",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &script,
+        ]
+        .map(OsString::from)
+        .into())
+    }
+
+    /// Kills the tree of `pid` with `taskkill` in a UAC prompt. The processes `Start-Process`
+    /// starts elevated count as the children of the process that started them.
+    pub fn kill_tree(pid: u32) -> Command {
+        let mut command = Command::new("powershell");
+        command.args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &format!(
+                "$p = Start-Process -Verb RunAs -Wait -PassThru -WindowStyle Hidden -FilePath \
+                 taskkill -ArgumentList '/T /F /PID {pid}'; exit $p.ExitCode"
+            ),
+        ]);
+        command
+    }
+
+    /// Quotes `s` as a PowerShell string that nothing in is expanded.
+    fn quote_ps(s: &str) -> String {
+        format!("'{}'", s.replace('\'', "''"))
+    }
+
+    /// Quotes `arg` for a command line the way the C runtime splits it back up.
+    fn quote_arg(arg: &str) -> String {
+        if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+            return arg.to_string();
+        }
+        let mut quoted = String::from('"');
+        let mut backslashes = 0;
+        // backslashes are only special in front of a quote, where they're doubled
+        for c in arg.chars() {
+            match c {
+                '\\' => {
+                    backslashes += 1;
+                    quoted.push('\\');
+                }
+                '"' => {
+                    quoted.push_str(&"\\".repeat(backslashes + 1));
+                    quoted.push('"');
+                    backslashes = 0;
+                }
+                _ => {
+                    backslashes = 0;
+                    quoted.push(c);
+                }
+            }
+        }
+        quoted.push_str(&"\\".repeat(backslashes));
+        quoted.push('"');
+        quoted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ffi::OsStr};
+
+    use super::*;
+
+    fn strings(args: &[std::ffi::OsString]) -> Vec<&str> {
+        args.iter().map(|arg| arg.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn runs_the_command_through_pkexec_or_sudo() {
+        let mut spec = CommandSpec {
+            cwd: Some("/srv".into()),
+            env: Some(BTreeMap::new()),
+            env_overrides: [("GREETING".to_string(), "hi".to_string())].into(),
+            elevate: true,
+            ..CommandSpec::of(&["server", "--port=1"])
+        };
+        assert_eq!(
+            strings(&apply(&spec).unwrap().cmd),
+            [
+                "pkexec",
+                "env",
+                "--chdir=/srv",
+                "--",
+                "GREETING=hi",
+                "server",
+                "--port=1"
+            ]
+        );
+        let command = platform::command(&Elevation::of(&spec).unwrap(), &["kill".to_string()]);
+        assert_eq!(command.get_program(), "pkexec");
+
+        spec.env_overrides
+            .insert("SUDO_ASKPASS".to_string(), "/usr/bin/ask".to_string());
+        assert_eq!(
+            strings(&apply(&spec).unwrap().cmd)[..4],
+            ["sudo", "-A", "--", "env"]
+        );
+        // it's stopped with sudo too, which needs the askpass program as well
+        let command = platform::command(&Elevation::of(&spec).unwrap(), &["kill".to_string()]);
+        assert_eq!(command.get_program(), "sudo");
+        assert!(command.get_envs().any(
+            |(name, value)| name == "SUDO_ASKPASS" && value == Some(OsStr::new("/usr/bin/ask"))
+        ));
+    }
+
+    #[test]
+    fn only_elevated_commands_are_stopped_through_the_tool() {
+        let mut spec = CommandSpec::of(&["server"]);
+        assert!(Elevation::of(&spec).is_none());
+        spec.elevate = true;
+        assert!(Elevation::of(&spec).is_some());
+    }
+}
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Context;
+
+use crate::get_logs_dir;
+
+/// Parts of variable names that mark their values as secret, compared case-insensitively.
+const SECRET_MARKERS: &[&str] = &[
+    "SECRET",
+    "TOKEN",
+    "PASSWORD",
+    "PASSWD",
+    "KEY",
+    "CREDENTIAL",
+    "AUTH",
+    "PRIVATE",
+];
+
+/// Shown instead of the values of secret variables.
+const MASK: &str = "********";
+
+/// How long a secret value must be to be masked by [`redact`].
+const MIN_REDACTED_LEN: usize = 4;
+
+/// How the environment a child received differs from trayme's own, which is usually what
+/// "works in my shell but not under trayme" comes down to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvDiff {
+    /// Variables the child has but trayme doesn't.
+    pub injected: BTreeMap<String, String>,
+    /// Variables trayme has but the child doesn't, with trayme's values.
+    pub removed: BTreeMap<String, String>,
+    /// Variables both have with different values, as (trayme's, the child's).
+    pub overridden: BTreeMap<String, (String, String)>,
+}
+
+impl EnvDiff {
+    /// Compares trayme's environment (`parent`) with the one a child received (`child`).
+    pub fn between(parent: &BTreeMap<String, String>, child: &BTreeMap<String, String>) -> Self {
+        let mut diff = Self::default();
+        for (name, value) in child {
+            match parent.get(name) {
+                None => {
+                    diff.injected.insert(name.clone(), value.clone());
+                }
+                Some(old) if old != value => {
+                    diff.overridden
+                        .insert(name.clone(), (old.clone(), value.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, value) in parent {
+            if !child.contains_key(name) {
+                diff.removed.insert(name.clone(), value.clone());
+            }
+        }
+        diff
+    }
+
+    /// Compares trayme's current environment with `child`. Variables that aren't valid Unicode
+    /// are left out, as they are in the run history.
+    pub fn from_current(child: &BTreeMap<String, String>) -> Self {
+        let parent = std::env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .collect();
+        Self::between(&parent, child)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.injected.is_empty() && self.removed.is_empty() && self.overridden.is_empty()
+    }
+}
+
+/// Lists the differences one per line: `+` for injected, `-` for removed, and `~` for overridden
+/// variables. Secret values are masked.
+impl fmt::Display for EnvDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "The environment is the same as trayme's.");
+        }
+        for (name, value) in &self.injected {
+            writeln!(f, "+ {name}={}", shown(name, value))?;
+        }
+        for (name, value) in &self.removed {
+            writeln!(f, "- {name}={}", shown(name, value))?;
+        }
+        for (name, (old, new)) in &self.overridden {
+            writeln!(f, "~ {name}={} -> {}", shown(name, old), shown(name, new))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a variable's value should be masked, judging by its name.
+pub fn is_secret(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    SECRET_MARKERS.iter().any(|marker| upper.contains(marker))
+}
+
+/// Masks the values of the secret variables of `env` wherever they appear in `text`, e.g. in
+/// output that's sent elsewhere. Values shorter than [`MIN_REDACTED_LEN`] are left alone, since
+/// they'd mask too much.
+pub fn redact(text: &str, env: &BTreeMap<String, String>) -> String {
+    let mut secrets: Vec<&str> = env
+        .iter()
+        .filter(|(name, value)| is_secret(name) && value.len() >= MIN_REDACTED_LEN)
+        .map(|(_, value)| value.as_str())
+        .collect();
+    // longer values first, in case one contains another
+    secrets.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    secrets
+        .into_iter()
+        .fold(text.to_string(), |text, secret| text.replace(secret, MASK))
+}
+
+fn shown<'a>(name: &str, value: &'a str) -> &'a str {
+    if is_secret(name) {
+        MASK
+    } else {
+        value
+    }
+}
+
+/// Writes a report of how the environment of the instance `name` differs from trayme's and
+/// opens it with the default text viewer.
+///
+/// # Errors
+///
+/// An error is returned if the report cannot be written or opened.
+pub fn open_report(name: &str, child: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let dir = get_logs_dir()?.join("env");
+    std::fs::create_dir_all(&dir).context("Failed to create environment directory")?;
+    let path = dir.join(format!("{name}-diff.txt"));
+    let report = format!(
+        "# How the environment of '{name}' differs from trayme's\n\
+         # + injected, - removed, ~ overridden (trayme's value -> the process')\n\n{}",
+        EnvDiff::from_current(child)
+    );
+    std::fs::write(&path, report).with_context(|| format!("Failed to write {}", path.display()))?;
+    open::that(&path).context("Failed to open environment diff")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn redacts_the_values_of_secret_variables() {
+        let env = env(&[
+            ("API_KEY", "abcd1234"),
+            ("DB_PASSWORD", "abcd1234xyz"),
+            ("github_token", "ghp_42"),
+            ("SHORT_SECRET", "abc"),
+            ("HOME", "/home/me"),
+        ]);
+        let text = "connecting as abcd1234xyz with abcd1234 and ghp_42 from /home/me, abc";
+        // the longer value that contains another is masked as a whole
+        assert_eq!(
+            redact(text, &env),
+            "connecting as ******** with ******** and ******** from /home/me, abc"
+        );
+        assert_eq!(redact("nothing to hide", &env), "nothing to hide");
+    }
+
+    #[test]
+    fn diffs_mask_secrets() {
+        let parent = env(&[("PATH", "/bin"), ("TERM", "xterm"), ("AUTH_TOKEN", "old")]);
+        let child = env(&[
+            ("PATH", "/usr/bin"),
+            ("PORT", "8080"),
+            ("AUTH_TOKEN", "new"),
+        ]);
+        let diff = EnvDiff::between(&parent, &child);
+        assert_eq!(diff.injected, env(&[("PORT", "8080")]));
+        assert_eq!(diff.removed, env(&[("TERM", "xterm")]));
+        assert_eq!(
+            diff.to_string(),
+            "+ PORT=8080\n- TERM=xterm\n~ AUTH_TOKEN=******** -> ********\n~ PATH=/bin -> \
+             /usr/bin\n"
+        );
+        assert!(EnvDiff::between(&parent, &parent).is_empty());
+    }
+}
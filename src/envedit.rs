@@ -0,0 +1,122 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ProfileRef, get_logs_dir};
+
+/// How often the environment file is checked for changes while it's open.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const HEADER: &str = "\
+# The variables trayme injects into the child's environment, on top of the inherited ones.
+# Edit the values under [env] and save this file. Changes take effect on the next restart.
+# Set save_to_profile = true to also write them back to the profile in the config file
+# (comments and formatting in the config file are not preserved).
+
+";
+
+/// The contents of the environment file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnvFile {
+    #[serde(default)]
+    pub save_to_profile: bool,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Lets the user edit an instance's injected environment variables in their text editor. The
+/// variables are written to a TOML file, which is watched for changes while the instance runs.
+#[derive(Debug)]
+pub struct EnvEditor {
+    path: PathBuf,
+    profile: Option<ProfileRef>,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl EnvEditor {
+    /// Creates an editor for the instance named `name`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the instance.
+    /// * `profile` - The profile the instance was started from. Without one, edits can't be
+    ///   saved back.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the logs directory cannot be determined.
+    pub fn new(name: &str, profile: Option<ProfileRef>) -> anyhow::Result<Self> {
+        let path = get_logs_dir()?.join("env").join(format!("{name}.toml"));
+        Ok(Self {
+            path,
+            profile,
+            modified: None,
+            last_check: Instant::now(),
+        })
+    }
+
+    pub fn profile(&self) -> Option<&ProfileRef> {
+        self.profile.as_ref()
+    }
+
+    /// Writes `env` to the environment file and opens it with the default editor.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be written or opened.
+    pub fn open(&mut self, env: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        let file = EnvFile {
+            save_to_profile: false,
+            env: env.clone(),
+        };
+        let contents = toml::to_string_pretty(&file).context("Failed to serialize environment")?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create environment directory")?;
+        }
+        std::fs::write(&self.path, format!("{HEADER}{contents}"))
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.modified = Some(self.mtime()?);
+        open::that(&self.path).context("Failed to open environment file")
+    }
+
+    /// Returns the edited file if it was saved since the last call. Only checks once per
+    /// [`CHECK_INTERVAL`], and never before [`EnvEditor::open`] was called.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be read or parsed.
+    pub fn poll(&mut self) -> anyhow::Result<Option<EnvFile>> {
+        let Some(modified) = self.modified else {
+            return Ok(None);
+        };
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return Ok(None);
+        }
+        self.last_check = Instant::now();
+        let mtime = self.mtime()?;
+        if mtime == modified {
+            return Ok(None);
+        }
+        // recorded before parsing so that an invalid file is only reported once per save
+        self.modified = Some(mtime);
+        debug!("{} changed", self.path.display());
+        let contents = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let file = toml::from_str(&contents)
+            .with_context(|| format!("Invalid environment file {}", self.path.display()))?;
+        Ok(Some(file))
+    }
+
+    fn mtime(&self) -> anyhow::Result<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to check {}", self.path.display()))
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::supervisor::CommandSpec;
+
+/// Where the scripts of a Python virtual environment are.
+#[cfg(windows)]
+const VENV_BIN: &str = "Scripts";
+#[cfg(not(windows))]
+const VENV_BIN: &str = "bin";
+
+/// A development environment the command is run in, so that it doesn't have to be wrapped in a
+/// shell script that activates it first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum EnvProvider {
+    /// The Nix shell of the working directory: `nix develop` if it has a `flake.nix`, and
+    /// `nix-shell` otherwise.
+    Nix,
+    /// The Python virtual environment at the path, relative to the working directory.
+    Venv(PathBuf),
+    /// The tool versions asdf selects for the working directory.
+    Asdf,
+}
+
+impl EnvProvider {
+    /// Returns the spec that runs `spec` inside this environment. Nix and asdf wrap the command,
+    /// while a virtual environment is activated the way its activation script does it.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the working directory cannot be determined, or the virtual
+    /// environment doesn't exist.
+    pub fn apply(&self, spec: &CommandSpec) -> anyhow::Result<CommandSpec> {
+        let cwd = match &spec.cwd {
+            Some(cwd) => cwd.clone(),
+            None => std::env::current_dir().context("Failed to get current directory")?,
+        };
+        let mut spec = spec.clone();
+        match self {
+            EnvProvider::Nix if cwd.join("flake.nix").exists() => {
+                spec.cmd = wrap(&["nix", "develop", "--command"], &spec.cmd);
+            }
+            EnvProvider::Nix => {
+                let mut script = OsString::new();
+                for (i, arg) in spec.cmd.iter().enumerate() {
+                    if i > 0 {
+                        script.push(" ");
+                    }
+                    script.push(shell_quote(arg));
+                }
+                spec.cmd = wrap(&["nix-shell", "--run"], &[script]);
+            }
+            EnvProvider::Asdf => spec.cmd = wrap(&["asdf", "exec"], &spec.cmd),
+            EnvProvider::Venv(venv) => activate_venv(&mut spec, &cwd.join(venv))?,
+        }
+        Ok(spec)
+    }
+}
+
+impl FromStr for EnvProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("venv", "")) => Err("expected venv:PATH".to_string()),
+            Some(("venv", path)) => Ok(EnvProvider::Venv(PathBuf::from(path))),
+            None if s == "nix" => Ok(EnvProvider::Nix),
+            None if s == "asdf" => Ok(EnvProvider::Asdf),
+            None if s == "venv" => Err("expected venv:PATH".to_string()),
+            _ => Err(format!("expected nix, venv:PATH, or asdf, got '{s}'")),
+        }
+    }
+}
+
+impl TryFrom<String> for EnvProvider {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EnvProvider> for String {
+    fn from(provider: EnvProvider) -> Self {
+        provider.to_string()
+    }
+}
+
+impl fmt::Display for EnvProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvProvider::Nix => write!(f, "nix"),
+            EnvProvider::Venv(path) => write!(f, "venv:{}", path.display()),
+            EnvProvider::Asdf => write!(f, "asdf"),
+        }
+    }
+}
+
+fn wrap(wrapper: &[&str], cmd: &[OsString]) -> Vec<OsString> {
+    wrapper
+        .iter()
+        .map(OsString::from)
+        .chain(cmd.iter().cloned())
+        .collect()
+}
+
+/// Quotes `arg` for a POSIX shell, which is what `nix-shell --run` hands its command to. On
+/// Unix the bytes are kept as they are, since the shell doesn't care whether they're UTF-8.
+#[cfg(unix)]
+pub fn shell_quote(arg: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let mut quoted = vec![b'\''];
+    for &byte in arg.as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(br"'\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
+
+/// Quotes `arg` for a POSIX shell, which is what `nix-shell --run` hands its command to.
+#[cfg(not(unix))]
+pub fn shell_quote(arg: &OsStr) -> OsString {
+    format!("'{}'", arg.to_string_lossy().replace('\'', r"'\''")).into()
+}
+
+/// Does what a virtual environment's activation script does: sets `VIRTUAL_ENV`, puts its
+/// scripts first on `PATH`, and unsets `PYTHONHOME`.
+fn activate_venv(spec: &mut CommandSpec, venv: &Path) -> anyhow::Result<()> {
+    let bin = venv.join(VENV_BIN);
+    if !bin.is_dir() {
+        bail!(
+            "{} is not a virtual environment (it has no {VENV_BIN} directory)",
+            venv.display()
+        );
+    }
+    let inherited = |name: &str| match &spec.env {
+        Some(env) => env.get(name).map(OsString::from),
+        None => std::env::var_os(name),
+    };
+    let path = spec
+        .env_overrides
+        .get("PATH")
+        .map(OsString::from)
+        .or_else(|| inherited("PATH"))
+        .unwrap_or_default();
+    let path = std::env::join_paths(std::iter::once(bin).chain(std::env::split_paths(&path)))
+        .context("Failed to add the virtual environment to PATH")?;
+    let venv = venv.to_string_lossy().into_owned();
+    let path = path.to_string_lossy().into_owned();
+    if inherited("PYTHONHOME").is_some() {
+        let env = spec.env.get_or_insert_with(|| {
+            std::env::vars_os()
+                .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+                .collect()
+        });
+        env.remove("PYTHONHOME");
+    }
+    spec.env_overrides.remove("PYTHONHOME");
+    spec.env_overrides.insert("VIRTUAL_ENV".to_string(), venv);
+    spec.env_overrides.insert("PATH".to_string(), path);
+    Ok(())
+}
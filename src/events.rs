@@ -0,0 +1,245 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    get_logs_dir,
+    ipc::{self, ControlResponse},
+    notify::NotifyEvent,
+    registry,
+};
+
+/// How often following events looks for instances that started since it last looked.
+const DISCOVER_INTERVAL: Duration = Duration::from_secs(2);
+
+/// One line of the event log, which records every lifecycle and health event of every instance
+/// so that they can be looked at later or picked up by other tools.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub at: DateTime<Local>,
+    /// The name of the instance the event happened to.
+    pub instance: String,
+    /// The run of the instance that was current at the time.
+    pub run_id: String,
+    pub event: NotifyEvent,
+    /// The same text as the notification.
+    pub message: String,
+    /// The crash report found at the end of the output, for failures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+}
+
+impl EventRecord {
+    /// Appends this event to the event log.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the event log cannot be written.
+    pub fn append(&self) -> anyhow::Result<()> {
+        let path = events_path()?;
+        let mut line = serde_json::to_string(self).context("Failed to serialize event")?;
+        line.push('\n');
+        // a single write, so lines from concurrent instances don't interleave
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// The path of the event log, `events.jsonl` in the logs directory. Each line is a JSON
+/// [`EventRecord`].
+///
+/// # Errors
+///
+/// An error is returned if the logs directory cannot be determined.
+pub fn events_path() -> anyhow::Result<PathBuf> {
+    Ok(get_logs_dir()?.join("events.jsonl"))
+}
+
+/// One line per event: the time, the instance, the event, and its message.
+impl fmt::Display for EventRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = self
+            .event
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        write!(
+            f,
+            "{} {} {event}: {}",
+            self.at.format("%Y-%m-%d %H:%M:%S"),
+            self.instance,
+            self.message
+        )
+    }
+}
+
+/// Prints the events recorded in the event log, or with `follow`, the events of running
+/// instances as they happen, received over their control sockets.
+///
+/// # Arguments
+///
+/// * `targets` - The instance names or control socket addresses to print events of. Every
+///   instance if empty, including ones that start while following.
+/// * `follow` - Whether to stream new events instead of printing past ones.
+/// * `json` - Whether to print each event as a line of JSON, as in the event log.
+///
+/// # Errors
+///
+/// An error is returned if the event log or the instance registry cannot be read.
+pub fn print(targets: &[String], follow: bool, json: bool) -> anyhow::Result<()> {
+    let print_record = |record: &EventRecord, raw: &str| {
+        if json {
+            println!("{raw}");
+        } else {
+            println!("{record}");
+        }
+    };
+    if !follow {
+        let path = events_path()?;
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            match serde_json::from_str::<EventRecord>(&line) {
+                Ok(record) if targets.is_empty() || targets.contains(&record.instance) => {
+                    print_record(&record, &line);
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Skipping invalid event: {e}"),
+            }
+        }
+        return Ok(());
+    }
+
+    follow_instances(targets, |followed| {
+        if let Followed::Event(record, raw) = followed {
+            print_record(&record, &raw);
+        }
+        Ok(())
+    })
+}
+
+/// Something that happened while following instances with [`follow_instances`].
+pub enum Followed {
+    /// Started following an instance.
+    Joined,
+    /// An event of a followed instance, along with its line of JSON.
+    Event(EventRecord, String),
+    /// Stopped following an instance, usually because it exited.
+    Left,
+}
+
+/// Follows the events of running instances over their control sockets until interrupted,
+/// passing everything that happens to `on_followed`. Following stops once it returns an error.
+///
+/// # Arguments
+///
+/// * `targets` - The instance names or control socket addresses to follow. Every instance if
+///   empty, including ones that start while following.
+/// * `on_followed` - Called on the current thread for each event and each instance that is
+///   joined or left.
+///
+/// # Errors
+///
+/// An error is returned if the instance registry cannot be read or `on_followed` returns one.
+pub fn follow_instances(
+    targets: &[String],
+    mut on_followed: impl FnMut(Followed) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut following = HashSet::new();
+    loop {
+        let current = if targets.is_empty() {
+            registry::list()?
+                .into_iter()
+                .map(|instance| instance.name)
+                .collect()
+        } else {
+            targets.to_vec()
+        };
+        for target in current {
+            if following.contains(&target) {
+                continue;
+            }
+            // not running yet, tried again on the next round
+            let Ok(addr) = registry::resolve(&target) else {
+                continue;
+            };
+            match ipc::subscribe(addr) {
+                Ok(stream) => {
+                    debug!("Following events of '{target}'");
+                    following.insert(target.clone());
+                    on_followed(Followed::Joined)?;
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        let result = forward(stream, &tx);
+                        let _ = tx.send(Received::Ended(target, result));
+                    });
+                }
+                Err(e) => debug!("Failed to follow '{target}': {e:#}"),
+            }
+        }
+        let deadline = Instant::now() + DISCOVER_INTERVAL;
+        while let Ok(received) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            match received {
+                Received::Event(record, raw) => on_followed(Followed::Event(record, raw))?,
+                Received::Ended(target, result) => {
+                    if let Err(e) = result {
+                        warn!("Stopped following '{target}': {e:#}");
+                    }
+                    following.remove(&target);
+                    on_followed(Followed::Left)?;
+                }
+            }
+        }
+    }
+}
+
+/// What the threads following the instances send to the one calling back.
+enum Received {
+    Event(EventRecord, String),
+    /// The instance closed the stream, usually because it exited.
+    Ended(String, anyhow::Result<()>),
+}
+
+/// Sends the events of one subscription on until the instance closes it.
+fn forward(mut stream: BufReader<impl Read>, tx: &mpsc::Sender<Received>) -> anyhow::Result<()> {
+    let mut line = String::new();
+    while stream.read_line(&mut line)? > 0 {
+        let Ok(record) = serde_json::from_str::<EventRecord>(line.trim_end()) else {
+            // the subscription was refused with a regular response
+            stream.read_to_string(&mut line)?;
+            if let Ok(ControlResponse::Error { message }) = toml::from_str(&line) {
+                bail!(message);
+            }
+            bail!("Unexpected response: {line}");
+        };
+        let raw = line.trim_end().to_string();
+        if tx.send(Received::Event(record, raw)).is_err() {
+            break;
+        }
+        line.clear();
+    }
+    Ok(())
+}
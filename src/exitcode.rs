@@ -0,0 +1,120 @@
+use std::{error::Error as StdError, fmt, process::ExitCode};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// What kind of failure made trayme exit, which decides its exit code. The codes are listed in
+/// `trayme --help`, so scripts can rely on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// Anything not covered by the other kinds.
+    Other,
+    /// The config file or a profile in it is invalid, or the profile doesn't exist.
+    Config,
+    /// The command couldn't be started, including when its `--pre-check` failed.
+    Spawn,
+    /// There is no display or tray to show the icon in.
+    TrayUnavailable,
+    /// The instance to control isn't running.
+    NotFound,
+}
+
+impl ErrorKind {
+    /// The exit code of this kind of failure. 2 is left to clap, which exits with it for invalid
+    /// command lines.
+    pub fn code(self) -> u8 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Config => 3,
+            ErrorKind::Spawn => 4,
+            ErrorKind::TrayUnavailable => 5,
+            ErrorKind::NotFound => 6,
+        }
+    }
+}
+
+/// How trayme prints the error it exits with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// The error and its causes, for people.
+    #[default]
+    Text,
+    /// A line of JSON with the kind, exit code, message, and causes, for scripts.
+    Json,
+}
+
+/// An error marked with its [`ErrorKind`]. It reads exactly like the error it wraps, so marking
+/// an error doesn't change what the user sees.
+#[derive(Debug)]
+struct Classified {
+    kind: ErrorKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.error, f)
+    }
+}
+
+impl StdError for Classified {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Marks the error of a result with the kind of failure it is.
+pub trait WithKind<T> {
+    /// Marks the error, if any, as `kind`. Context added afterwards keeps the mark.
+    ///
+    /// # Errors
+    ///
+    /// The error of `self`, marked.
+    fn with_kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T> WithKind<T> for anyhow::Result<T> {
+    fn with_kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|error| Classified { kind, error }.into())
+    }
+}
+
+/// The kind of `error`: that of the outermost mark in its chain, or [`ErrorKind::Other`].
+fn kind_of(error: &anyhow::Error) -> ErrorKind {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Classified>())
+        .map_or(ErrorKind::Other, |classified| classified.kind)
+}
+
+/// What `--error-format json` prints.
+#[derive(Serialize)]
+struct ErrorReport {
+    kind: ErrorKind,
+    code: u8,
+    message: String,
+    causes: Vec<String>,
+}
+
+/// Prints the error trayme exits with to stderr in `format` and returns the exit code for it.
+pub fn report(error: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let kind = kind_of(error);
+    match format {
+        // the same as returning the error from main
+        ErrorFormat::Text => eprintln!("Error: {error:?}"),
+        ErrorFormat::Json => {
+            let report = ErrorReport {
+                kind,
+                code: kind.code(),
+                message: error.to_string(),
+                causes: error.chain().skip(1).map(ToString::to_string).collect(),
+            };
+            match serde_json::to_string(&report) {
+                Ok(line) => eprintln!("{line}"),
+                Err(_) => eprintln!("Error: {error:?}"),
+            }
+        }
+    }
+    ExitCode::from(kind.code())
+}
@@ -0,0 +1,411 @@
+//! The C interface, for apps that supervise commands with trayme without running the `trayme`
+//! binary, e.g. Electron or .NET apps. `include/trayme.h` declares it.
+//!
+//! An embedded instance is what `trayme <ARGS>` would run, with the restart policy, health
+//! checks, logs, history, and control socket that come with it, so that it's listed by
+//! `trayme ls` and can be controlled with `trayme down` and the like too. It runs on a thread of
+//! its own without a tray and tells the app what happens through a callback instead of
+//! notifications. The tray needs an event loop on the main thread, which the app owns, and the
+//! app shows the instance in its own window or tray icon instead, so `--headless` is implied and
+//! tray-only options like `--watch-inbox` are refused.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString, OsString},
+    ptr, slice,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use log::{error, warn};
+
+use crate::{
+    cli::CliArgs, events::EventRecord, ipc::ControlServer, layout, run_args_spec, start_instance,
+    supervisor::Supervisor, waitfor::Waiter, HEADLESS_POLL_INTERVAL,
+};
+
+/// Called with every event of an instance after it started as a line of JSON (see
+/// [`EventRecord`]) and the `user_data` given to [`trayme_start`]. It's called on the instance's
+/// thread, and the string is only valid during the call.
+pub type EventCallback = Option<unsafe extern "C" fn(event: *const c_char, user_data: *mut c_void)>;
+
+/// An instance started by [`trayme_start`].
+pub struct Instance {
+    requests: Sender<Request>,
+    thread: JoinHandle<Option<i32>>,
+}
+
+/// What the app asks of the instance's thread.
+enum Request {
+    /// Answers with the status as JSON, see [`crate::ipc::InstanceStatus`].
+    Status(Sender<String>),
+    Stop,
+}
+
+/// The callback the app gave [`trayme_start`], with its user data.
+struct Callback {
+    callback: unsafe extern "C" fn(*const c_char, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// the app vouches for using the callback and its data on the instance's thread, see trayme_start
+unsafe impl Send for Callback {}
+
+impl Callback {
+    fn call(&self, record: &EventRecord) {
+        let event = match serde_json::to_string(record) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Failed to serialize event: {e}");
+                return;
+            }
+        };
+        // JSON escapes NULs, so this always succeeds
+        let Ok(event) = CString::new(event) else {
+            return;
+        };
+        unsafe { (self.callback)(event.as_ptr(), self.user_data) };
+    }
+}
+
+impl Instance {
+    /// Starts supervising `trayme <ARGS>` on a thread of its own. Returns once the process was
+    /// started.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the arguments are invalid or the instance cannot be started.
+    fn start(args: Vec<OsString>, callback: Option<Callback>) -> anyhow::Result<Self> {
+        let (started_sender, started) = mpsc::channel();
+        let (requests, received) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("trayme".to_string())
+            .spawn(move || {
+                let (supervisor, control) = match start_embedded(args) {
+                    Ok(instance) => instance,
+                    Err(e) => {
+                        let _ = started_sender.send(Err(e));
+                        return None;
+                    }
+                };
+                let _ = started_sender.send(Ok(()));
+                supervise(supervisor, &control, callback, &received)
+            })
+            .context("Failed to start the instance's thread")?;
+        match started.recv() {
+            Ok(Ok(())) => Ok(Self { requests, thread }),
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            }
+            Err(_) => bail!("The instance's thread ended before the process started"),
+        }
+    }
+
+    /// The status of the instance as JSON, or `None` if its thread ended, e.g. because
+    /// supervising the process failed.
+    fn status(&self) -> Option<String> {
+        let (sender, status) = mpsc::channel();
+        self.requests.send(Request::Status(sender)).ok()?;
+        status.recv().ok()
+    }
+
+    /// Stops the process, if it's still running, and returns its exit code.
+    fn stop(self) -> Option<i32> {
+        let _ = self.requests.send(Request::Stop);
+        self.thread.join().ok().flatten()
+    }
+}
+
+/// Starts the instance of `trayme <ARGS>` without a tray.
+///
+/// # Errors
+///
+/// An error is returned if the arguments are invalid, they name a subcommand or options that
+/// need a tray of trayme's own, or the instance cannot be started.
+fn start_embedded(args: Vec<OsString>) -> anyhow::Result<(Supervisor, ControlServer)> {
+    let args = CliArgs::try_parse_from(std::iter::once(OsString::from("trayme")).chain(args))?;
+    if args.subcommand.is_some() {
+        bail!("Only commands can be embedded, not subcommands");
+    }
+    let (spec, mut notifier, mut instance) = run_args_spec(args.run)?;
+    if instance.watch_inbox.is_some() {
+        bail!("--watch-inbox can't be embedded");
+    }
+    instance.headless = true;
+    // the app is told through the callback and notifies the user if it wants to
+    notifier.mute();
+    layout::upgrade();
+    if let Some(mut waiter) = Waiter::new(&instance) {
+        waiter.wait(&spec, &notifier)?;
+    }
+    let (mut supervisor, control) = start_instance(spec, notifier, &instance)?;
+    // replacing trayme would replace the app
+    supervisor.set_handover_allowed(false);
+    Ok((supervisor, control))
+}
+
+/// Supervises the process until the app stops the instance, answering the app and the control
+/// socket in between. Returns the exit code of the process.
+fn supervise(
+    mut supervisor: Supervisor,
+    control: &ControlServer,
+    callback: Option<Callback>,
+    requests: &Receiver<Request>,
+) -> Option<i32> {
+    if let Some(callback) = callback {
+        supervisor.set_event_callback(move |record| callback.call(record));
+    }
+    loop {
+        if let Some(request) = control.try_recv() {
+            supervisor.handle_request(request);
+        }
+        match requests.try_recv() {
+            Ok(Request::Status(sender)) => match serde_json::to_string(&supervisor.status()) {
+                Ok(status) => {
+                    let _ = sender.send(status);
+                }
+                Err(e) => warn!("Failed to serialize status: {e}"),
+            },
+            // the app let go of the instance without stopping it, which only a panic does
+            Ok(Request::Stop) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+        if let Err(e) = supervisor.poll() {
+            error!("{e:#}");
+            break;
+        }
+        thread::sleep(HEADLESS_POLL_INTERVAL);
+    }
+    if !supervisor.is_finished() {
+        if let Err(e) = supervisor.kill() {
+            error!("{e:#}");
+        }
+    }
+    supervisor.exit_code()
+}
+
+/// The argument `arg` as the OS gets it. Only Unix has arguments that aren't Unicode, elsewhere
+/// they're read as UTF-8.
+fn os_string(arg: &CStr) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        std::ffi::OsStr::from_bytes(arg.to_bytes()).to_os_string()
+    }
+    #[cfg(not(unix))]
+    {
+        OsString::from(arg.to_string_lossy().into_owned())
+    }
+}
+
+/// Hands `string` to the app, which frees it with [`trayme_string_free`].
+fn into_c_string(string: &str) -> *mut c_char {
+    CString::new(string.replace('\0', " ")).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Starts supervising a command, the way `trayme <ARGS>` does but without a tray. Returns the
+/// instance once the process was started, or null if it couldn't be.
+///
+/// # Arguments
+///
+/// * `arguments` - The arguments trayme would get after its own name, e.g. `--restart-policy`,
+///   `always`, `--`, `server`.
+/// * `count` - How many arguments there are.
+/// * `callback` - Called with every event of the instance after it started, see
+///   [`EventCallback`]. May be null.
+/// * `user_data` - Passed on to `callback`.
+/// * `error` - Where the reason the instance couldn't be started is put, for the app to free
+///   with [`trayme_string_free`]. May be null.
+///
+/// # Safety
+///
+/// `arguments` must point to `count` NUL-terminated strings, and `error` must be null or valid for
+/// writes. `callback` is called with `user_data` on another thread until [`trayme_stop`]
+/// returns, so both must stay usable from there until then.
+#[no_mangle]
+pub unsafe extern "C" fn trayme_start(
+    arguments: *const *const c_char,
+    count: usize,
+    callback: EventCallback,
+    user_data: *mut c_void,
+    error: *mut *mut c_char,
+) -> *mut Instance {
+    let parsed: Option<Vec<_>> = if count == 0 {
+        Some(Vec::new())
+    } else if arguments.is_null() {
+        None
+    } else {
+        slice::from_raw_parts(arguments, count)
+            .iter()
+            .map(|&arg| (!arg.is_null()).then(|| os_string(CStr::from_ptr(arg))))
+            .collect()
+    };
+    let started = match parsed {
+        Some(args) => Instance::start(
+            args,
+            callback.map(|callback| Callback {
+                callback,
+                user_data,
+            }),
+        ),
+        None => Err(anyhow::anyhow!("The arguments can't be null")),
+    };
+    match started {
+        Ok(instance) => Box::into_raw(Box::new(instance)),
+        Err(e) => {
+            if !error.is_null() {
+                *error = into_c_string(&format!("{e:#}"));
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The status of `instance` as JSON, as the `status` command of the control socket answers, for
+/// the app to free with [`trayme_string_free`]. Null if `instance` is, or it can no longer tell, e.g. because
+/// supervising the process failed.
+///
+/// # Safety
+///
+/// `instance` must be null or have been returned by [`trayme_start`] and not stopped yet.
+#[no_mangle]
+pub unsafe extern "C" fn trayme_status(instance: *const Instance) -> *mut c_char {
+    match instance.as_ref().and_then(Instance::status) {
+        Some(status) => into_c_string(&status),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Stops the process of `instance`, if it's still running, and frees `instance`. Returns the
+/// exit code of the process, or -1 if it has none, e.g. because it was killed.
+///
+/// # Safety
+///
+/// `instance` must be null or have been returned by [`trayme_start`] and not stopped yet.
+#[no_mangle]
+pub unsafe extern "C" fn trayme_stop(instance: *mut Instance) -> c_int {
+    if instance.is_null() {
+        return -1;
+    }
+    Box::from_raw(instance).stop().unwrap_or(-1)
+}
+
+/// Frees a string returned by trayme.
+///
+/// # Safety
+///
+/// `string` must be null or have been returned by trayme and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn trayme_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        ipc::{InstanceStatus, ProcessState},
+        notify::NotifyEvent,
+    };
+
+    /// Keeps the events in the `Mutex<Vec<EventRecord>>` that `user_data` points to.
+    unsafe extern "C" fn keep(event: *const c_char, user_data: *mut c_void) {
+        let events = &*user_data.cast::<Mutex<Vec<EventRecord>>>();
+        let event = CStr::from_ptr(event).to_str().unwrap();
+        events
+            .lock()
+            .unwrap()
+            .push(serde_json::from_str(event).unwrap());
+    }
+
+    fn start(args: &[&str], events: &Mutex<Vec<EventRecord>>) -> Result<*mut Instance, String> {
+        let args: Vec<_> = args.iter().map(|arg| CString::new(*arg).unwrap()).collect();
+        let pointers: Vec<_> = args.iter().map(|arg| arg.as_ptr()).collect();
+        let mut error = ptr::null_mut();
+        let instance = unsafe {
+            trayme_start(
+                pointers.as_ptr(),
+                pointers.len(),
+                Some(keep),
+                ptr::from_ref(events).cast_mut().cast(),
+                &raw mut error,
+            )
+        };
+        if instance.is_null() {
+            let message = unsafe { CStr::from_ptr(error) }
+                .to_str()
+                .unwrap()
+                .to_string();
+            unsafe { trayme_string_free(error) };
+            return Err(message);
+        }
+        Ok(instance)
+    }
+
+    fn status(instance: *const Instance) -> InstanceStatus {
+        let status = unsafe { trayme_status(instance) };
+        assert!(!status.is_null());
+        let parsed = serde_json::from_str(unsafe { CStr::from_ptr(status) }.to_str().unwrap());
+        unsafe { trayme_string_free(status) };
+        parsed.unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn supervises_embedded_commands() {
+        let events = Mutex::new(Vec::new());
+        let instance = start(
+            &["--name", "embedded", "--", "sh", "-c", "sleep 0.2; exit 3"],
+            &events,
+        )
+        .unwrap();
+        assert_eq!(status(instance).name, "embedded");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while status(instance).state == ProcessState::Running {
+            assert!(Instant::now() < deadline, "the process never exited");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(unsafe { trayme_stop(instance) }, 3);
+        let events = events.into_inner().unwrap();
+        assert!(
+            events
+                .iter()
+                .any(|record| record.event == NotifyEvent::Failure && record.instance == "embedded"),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn reports_why_it_cant_start() {
+        let events = Mutex::new(Vec::new());
+        let error = start(&["--no-such-option", "--", "true"], &events).unwrap_err();
+        assert!(error.contains("--no-such-option"), "{error}");
+        let error = start(&["history"], &events).unwrap_err();
+        assert!(error.contains("subcommand"), "{error}");
+        assert!(unsafe { trayme_status(ptr::null()) }.is_null());
+        assert_eq!(unsafe { trayme_stop(ptr::null_mut()) }, -1);
+    }
+
+    #[test]
+    fn header_declares_the_interface() {
+        let header = include_str!("../include/trayme.h");
+        for function in [
+            "trayme_start(",
+            "trayme_status(",
+            "trayme_stop(",
+            "trayme_string_free(",
+        ] {
+            assert!(header.contains(function), "{function} isn't declared");
+        }
+    }
+}
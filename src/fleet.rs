@@ -0,0 +1,499 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use log::info;
+
+use crate::{
+    cli::InstanceArgs,
+    display,
+    ipc::{self, ControlCommand, ControlResponse},
+    osargs, registry,
+    selflog::Verbosity,
+    spawntrace,
+};
+
+/// How long the trayme an instance was handed over to gets to answer on the control socket.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prints the running instances with any of `tags` (or all of them) to stdout.
+///
+/// # Errors
+///
+/// An error is returned if the registry cannot be read.
+pub fn print_instances(tags: &[String]) -> anyhow::Result<()> {
+    for instance in registry::list()? {
+        if !instance.has_any_tag(tags) {
+            continue;
+        }
+        println!(
+            "{}  PID {}  {}  [{}]  {}",
+            instance.name,
+            instance.pid,
+            instance.addr,
+            instance.tags.join(", "),
+            osargs::display(&instance.cmd)
+        );
+    }
+    Ok(())
+}
+
+/// Stops the named instances and every running instance with any of `tags`. With `force`,
+/// instances started with `--no-kill-menu` are stopped too.
+///
+/// # Errors
+///
+/// An error is returned if any of the instances couldn't be stopped. The others are still
+/// stopped.
+pub fn stop_instances(names: &[String], tags: &[String], force: bool) -> anyhow::Result<()> {
+    let command = if force {
+        ControlCommand::ForceKill
+    } else {
+        ControlCommand::Kill
+    };
+    let targets = targets(names, tags)?;
+    let mut failed = 0;
+    for name in &targets {
+        let result =
+            registry::lookup(name).and_then(|instance| ipc::request(instance.addr, &command));
+        match result {
+            Ok(ControlResponse::Error { message }) => {
+                eprintln!("Failed to stop '{name}': {message}");
+                failed += 1;
+            }
+            Ok(_) => println!("Stopped '{name}'"),
+            Err(e) => {
+                eprintln!("Failed to stop '{name}': {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} instances could not be stopped",
+            targets.len()
+        );
+    }
+    Ok(())
+}
+
+/// Hands the named instances and every running instance with any of `tags` over to the trayme on
+/// disk, see [`crate::supervisor::Supervisor::hand_over`], and waits for each to answer again.
+///
+/// # Errors
+///
+/// An error is returned if any of the instances couldn't be handed over or didn't answer within
+/// [`HANDOVER_TIMEOUT`]. The others are still handed over.
+pub fn hand_over_instances(names: &[String], tags: &[String]) -> anyhow::Result<()> {
+    let targets = targets(names, tags)?;
+    let mut failed = 0;
+    for name in &targets {
+        let result = registry::lookup(name)
+            .and_then(|instance| ipc::request(instance.addr, &ControlCommand::Handover));
+        match result {
+            Ok(ControlResponse::Error { message }) => {
+                eprintln!("Failed to hand over '{name}': {message}");
+                failed += 1;
+            }
+            Ok(_) if answers_within(name, HANDOVER_TIMEOUT) => println!("Handed '{name}' over"),
+            Ok(_) => {
+                eprintln!("'{name}' was handed over, but the new trayme isn't answering");
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to hand over '{name}': {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} instances could not be handed over",
+            targets.len()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the instance named `name` answers on its control socket within `timeout`.
+fn answers_within(name: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if registry::lookup(name).is_ok_and(|instance| instance.is_alive()) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+/// Turns maintenance mode on for the named instances and every running instance with any of
+/// `tags`, or off with `off`. See [`crate::supervisor::Supervisor::start_maintenance`].
+///
+/// # Arguments
+///
+/// * `names` - The names of the instances.
+/// * `tags` - Selects every running instance with any of these tags too.
+/// * `duration` - How long maintenance mode lasts. Defaults to each instance's own.
+/// * `off` - Whether to turn maintenance mode off instead.
+///
+/// # Errors
+///
+/// An error is returned if the mode couldn't be changed for any of the instances. The others
+/// are still changed.
+pub fn set_maintenance(
+    names: &[String],
+    tags: &[String],
+    duration: Option<Duration>,
+    off: bool,
+) -> anyhow::Result<()> {
+    let command = if off {
+        ControlCommand::EndMaintenance
+    } else {
+        ControlCommand::StartMaintenance(duration)
+    };
+    let targets = targets(names, tags)?;
+    let mut failed = 0;
+    for name in &targets {
+        let result =
+            registry::lookup(name).and_then(|instance| ipc::request(instance.addr, &command));
+        match result {
+            Ok(ControlResponse::Status(status)) => match status.maintenance_until {
+                Some(until) => println!(
+                    "'{name}' is in maintenance mode until {}",
+                    until.format("%H:%M")
+                ),
+                None => println!("'{name}' is out of maintenance mode"),
+            },
+            Ok(ControlResponse::Error { message }) => {
+                eprintln!("Failed to change maintenance mode of '{name}': {message}");
+                failed += 1;
+            }
+            Ok(response) => {
+                eprintln!("Unexpected response from '{name}': {response:?}");
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to change maintenance mode of '{name}': {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} instances could not be changed",
+            targets.len()
+        );
+    }
+    Ok(())
+}
+
+/// Changes how verbose trayme's own log is in the named instances, see
+/// [`crate::selflog::set_verbosity`].
+///
+/// # Errors
+///
+/// An error is returned if the level couldn't be changed for any of the instances. The others
+/// are still changed.
+pub fn set_log_level(names: &[String], verbosity: Verbosity) -> anyhow::Result<()> {
+    let command = ControlCommand::LogLevel(verbosity);
+    let mut failed = 0;
+    for name in names {
+        let result =
+            registry::lookup(name).and_then(|instance| ipc::request(instance.addr, &command));
+        match result {
+            Ok(ControlResponse::Ok) => info!("Changed the log level of '{name}'"),
+            Ok(ControlResponse::Error { message }) => {
+                eprintln!("Failed to change the log level of '{name}': {message}");
+                failed += 1;
+            }
+            Ok(response) => {
+                eprintln!("Unexpected response from '{name}': {response:?}");
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to change the log level of '{name}': {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} instances could not be changed", names.len());
+    }
+    Ok(())
+}
+
+/// The named instances followed by every running instance with any of `tags`.
+fn targets(names: &[String], tags: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut targets: Vec<_> = names.to_vec();
+    if !tags.is_empty() {
+        targets.extend(
+            registry::list()?
+                .into_iter()
+                .filter(|instance| instance.has_any_tag(tags) && !names.contains(&instance.name))
+                .map(|instance| instance.name),
+        );
+    }
+    Ok(targets)
+}
+
+/// Starts each of the given profiles in its own background trayme process.
+///
+/// # Arguments
+///
+/// * `profiles` - The names of the profiles to start.
+/// * `config` - The config file the profiles came from, passed on to the new processes.
+/// * `instance` - Instance options to pass on. Options that only make sense for a single instance
+///   (`--name`, a fixed `--listen` port) are rejected.
+///
+/// # Errors
+///
+/// An error is returned if the options can't be shared between instances or a process can't be
+/// spawned.
+pub fn spawn_profiles(
+    profiles: &[&str],
+    config: Option<&Path>,
+    instance: &InstanceArgs,
+) -> anyhow::Result<()> {
+    if instance.name.is_some() || instance.listen.port() != 0 {
+        bail!("--name and --listen can only be used when starting a single profile");
+    }
+    let exe = std::env::current_exe().context("Failed to get trayme's path")?;
+    for profile in profiles {
+        let mut command = Command::new(&exe);
+        command.args(["up", profile]);
+        if let Some(config) = config {
+            command.arg("--config").arg(config);
+        }
+        pass_on(&mut command, instance);
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start profile '{profile}'"))?;
+        info!("Started profile '{profile}' (PID {})", child.id());
+        println!("Started '{profile}'");
+    }
+    Ok(())
+}
+
+/// Adds the options of `instance` that every spawned instance shares to `command`.
+fn pass_on(command: &mut Command, instance: &InstanceArgs) {
+    if instance.headless {
+        command.arg("--headless");
+    }
+    if let Some(timeout) = instance.wait_for_tray {
+        command.arg(format!(
+            "--wait-for-tray={}",
+            humantime::format_duration(timeout)
+        ));
+    }
+    if let Some(backend) = display::selected().to_possible_value() {
+        command.args(["--display-backend", backend.get_name()]);
+    }
+    if spawntrace::enabled() {
+        command.arg("--trace-spawn");
+    }
+    if let Some(duration) = instance.maintenance_duration {
+        command.arg(format!(
+            "--maintenance-duration={}",
+            humantime::format_duration(duration)
+        ));
+    }
+    if instance.verbose_exit {
+        command.arg("--verbose-exit");
+    }
+    if instance.compress_rotated_logs {
+        command.arg("--compress-rotated-logs");
+    }
+    if let Some(provider) = &instance.env_provider {
+        command.arg("--env-provider").arg(provider.to_string());
+    }
+    if let Some(tz) = &instance.tz {
+        command.arg("--tz").arg(tz);
+    }
+    if let Some(locale) = &instance.locale {
+        command.arg("--locale").arg(locale);
+    }
+    pass_on_startup(command, instance);
+    pass_on_resources(command, instance);
+    if let Some(policy) = instance.on_logout.and_then(|p| p.to_possible_value()) {
+        command.args(["--on-logout", policy.get_name()]);
+    }
+    if instance.detach_on_exit {
+        command.arg("--detach-on-exit");
+    }
+    if instance.stay_open {
+        command.arg("--stay-open");
+    }
+    if instance.first_output_notify {
+        command.arg("--first-output-notify");
+    }
+    if instance.status_glyphs {
+        command.arg("--status-glyphs");
+    }
+    if instance.no_kill_menu {
+        command.arg("--no-kill-menu");
+    }
+    if instance.start_hidden {
+        command.arg("--start-hidden");
+    }
+    if instance.click_to_toggle {
+        command.arg("--click-to-toggle");
+    }
+    if instance.protected {
+        command.arg("--protected");
+    }
+    if let Some(method) = instance.confirm.and_then(|m| m.to_possible_value()) {
+        command.args(["--confirm", method.get_name()]);
+    }
+    if instance.constraints.only_weekdays {
+        command.arg("--only-weekdays");
+    }
+    for date in &instance.constraints.not_on {
+        command.arg("--not-on").arg(date.to_string());
+    }
+    for window in &instance.constraints.windows {
+        command.arg("--window").arg(window.to_string());
+    }
+    if let Some(calendar) = &instance.constraints.busy_calendar {
+        command.arg("--busy-calendar").arg(calendar.to_string());
+    }
+    if let Some(strategy) = instance.stop_strategy.and_then(|s| s.to_possible_value()) {
+        command.args(["--stop-strategy", strategy.get_name()]);
+    }
+    pass_on_restarts(command, instance);
+    if let Some(timeout) = instance.kill_timeout {
+        command.arg(format!(
+            "--kill-timeout={}",
+            humantime::format_duration(timeout)
+        ));
+    }
+}
+
+/// Adds the options of `instance` that limit the resources of the command to `command`.
+fn pass_on_resources(command: &mut Command, instance: &InstanceArgs) {
+    for limit in &instance.ulimits {
+        command.arg("--ulimit").arg(limit.to_string());
+    }
+    if let Some(percent) = instance.cpu_throttle {
+        command.arg("--cpu-throttle").arg(percent.to_string());
+    }
+    if let Some(size) = instance.max_memory {
+        command.arg("--max-memory").arg(size.to_string());
+    }
+    if let Some(percent) = instance.cpu_limit {
+        command.arg("--cpu-limit").arg(percent.to_string());
+    }
+    if let Some(action) = instance.on_limit.and_then(|a| a.to_possible_value()) {
+        command.args(["--on-limit", action.get_name()]);
+    }
+    if let Some(priority) = instance.priority.and_then(|p| p.to_possible_value()) {
+        command.args(["--priority", priority.get_name()]);
+    }
+}
+
+/// Adds the options of `instance` that decide whether and how the command is started to
+/// `command`.
+fn pass_on_startup(command: &mut Command, instance: &InstanceArgs) {
+    if let Some(check) = &instance.pre_check {
+        command.arg("--pre-check").arg(check);
+    }
+    if instance.continue_on_failure {
+        command.arg("--continue-on-failure");
+    }
+    if let Some(dir) = &instance.watch_inbox {
+        command.arg("--watch-inbox").arg(dir);
+    }
+    if let Some(max) = instance.max_concurrent {
+        command.arg(format!("--max-concurrent={max}"));
+    }
+    if let Some(size) = instance.require_free_mem {
+        command.arg("--require-free-mem").arg(size.to_string());
+    }
+    if let Some(size) = instance.require_free_disk {
+        command.arg("--require-free-disk").arg(size.to_string());
+    }
+    if instance.elevate {
+        command.arg("--elevate");
+    }
+    if let Some(cleanup) = instance.temp_dir.and_then(|c| c.to_possible_value()) {
+        command.arg(format!("--temp-dir={}", cleanup.get_name()));
+    }
+    for (option, hook) in [
+        ("--on-start", &instance.on_start),
+        ("--on-exit", &instance.on_exit),
+        ("--on-failure", &instance.on_failure),
+    ] {
+        if let Some(hook) = hook {
+            command.arg(option).arg(hook);
+        }
+    }
+    for addr in &instance.wait_for_port {
+        command.arg("--wait-for-port").arg(addr.to_string());
+    }
+    for path in &instance.wait_for_file {
+        command.arg("--wait-for-file").arg(path);
+    }
+    for cmd in &instance.wait_for_cmd {
+        command.arg("--wait-for-cmd").arg(cmd);
+    }
+    if let Some(timeout) = instance.wait_timeout {
+        command.arg(format!(
+            "--wait-timeout={}",
+            humantime::format_duration(timeout)
+        ));
+    }
+    if let Some(delay) = instance.delay {
+        command.arg(format!("--delay={}", humantime::format_duration(delay)));
+    }
+}
+
+/// Adds the options of `instance` that decide how the process is restarted to `command`.
+fn pass_on_restarts(command: &mut Command, instance: &InstanceArgs) {
+    if let Some(policy) = instance.restart_policy.and_then(|p| p.to_possible_value()) {
+        command.args(["--restart-policy", policy.get_name()]);
+    }
+    if let Some(backoff) = instance.restart_backoff {
+        command.arg(format!(
+            "--restart-backoff={}",
+            humantime::format_duration(backoff)
+        ));
+    }
+    if let Some(max) = instance.max_restarts {
+        command.arg("--max-restarts").arg(max.to_string());
+    }
+    if let Some(timeout) = instance.timeout {
+        command.arg(format!("--timeout={}", humantime::format_duration(timeout)));
+    }
+    if let Some(url) = &instance.ping_url {
+        command.arg("--ping-url").arg(url.to_string());
+    }
+    if let Some(every) = instance.restart_every {
+        command.arg(format!(
+            "--restart-every={}",
+            humantime::format_duration(every)
+        ));
+    }
+    if let Some(cron) = &instance.restart_cron {
+        command.arg("--restart-cron").arg(cron.to_string());
+    }
+    if let Some(fallback) = &instance.fallback {
+        command.arg("--fallback").arg(fallback);
+    }
+    if let Some(after) = instance.fallback_after {
+        command.arg("--fallback-after").arg(after.to_string());
+    }
+    if let Some(action) = instance.on_wake.and_then(|a| a.to_possible_value()) {
+        command.args(["--on-wake", action.get_name()]);
+    }
+    if let Some(check) = &instance.wake_check {
+        command.arg("--wake-check").arg(check);
+    }
+}
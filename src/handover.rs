@@ -0,0 +1,209 @@
+use std::{
+    convert::Infallible,
+    ffi::OsString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::PathBuf,
+    process::{ChildStderr, ChildStdin, ChildStdout, Command},
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{capture::LogCapture, child::ChildProcess, history::RunRecord, osargs};
+
+/// The variable that passes the [`Handover`] to the next trayme, as JSON.
+const HANDOVER_VAR: &str = "TRAYME_HANDOVER";
+
+/// What the trayme on disk needs to take the run of an instance over from this one, see
+/// `trayme self-restart`. It execs that trayme from this process, so the process stays a child
+/// of trayme's, and its pipes are passed on as open file descriptors.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handover {
+    pub pid: u32,
+    /// The run, whose record the history has.
+    pub run_id: String,
+    /// The command the instance runs now, which differs from its arguments after Restart With
+    /// Arguments or while it runs its fallback.
+    #[serde(with = "osargs")]
+    pub cmd: Vec<OsString>,
+    pub paused: bool,
+    stdin: Option<RawFd>,
+    stdout: RawFd,
+    stderr: RawFd,
+}
+
+impl Handover {
+    /// Describes the run of `child`, whose output `capture` logs.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the output was handed off, so its pipes are gone.
+    pub fn new(
+        child: &ChildProcess,
+        capture: &LogCapture,
+        record: &RunRecord,
+        cmd: &[OsString],
+        paused: bool,
+    ) -> anyhow::Result<Self> {
+        let [stdout, stderr] = capture
+            .pipes()
+            .context("The output of the process can't be handed over")?;
+        Ok(Self {
+            pid: child.id(),
+            run_id: record.id.clone(),
+            cmd: cmd.to_vec(),
+            paused,
+            stdin: child.stdin.as_ref().map(AsRawFd::as_raw_fd),
+            stdout: stdout.as_raw_fd(),
+            stderr: stderr.as_raw_fd(),
+        })
+    }
+
+    /// Replaces this trayme with the one on disk, started with the same arguments, which takes
+    /// the run over (see [`take`]). `before` is called right before, once nothing but the exec
+    /// itself can fail anymore.
+    ///
+    /// Returns only if the trayme on disk couldn't be started, with the reason. This trayme can
+    /// go on as before then.
+    pub fn exec(&self, before: impl FnOnce()) -> anyhow::Error {
+        let Err(e) = self.try_exec(before);
+        e
+    }
+
+    fn try_exec(&self, before: impl FnOnce()) -> anyhow::Result<Infallible> {
+        let exe = installed_exe()?;
+        if !exe.is_file() {
+            bail!("{} doesn't exist anymore", exe.display());
+        }
+        let state = serde_json::to_string(self).context("Failed to serialize the handover")?;
+        let fds = self.fds();
+        for (passed, &fd) in fds.iter().enumerate() {
+            if let Err(e) = set_inherited(fd, true) {
+                for &fd in &fds[..passed] {
+                    let _ = set_inherited(fd, false);
+                }
+                return Err(e).context("Failed to pass the pipes of the process on");
+            }
+        }
+        let mut command = Command::new(&exe);
+        command
+            .args(std::env::args_os().skip(1))
+            .env(HANDOVER_VAR, state);
+        before();
+        info!("Handing PID {} over to {}", self.pid, exe.display());
+        let err = command.exec();
+        for fd in fds {
+            let _ = set_inherited(fd, false);
+        }
+        Err(err).with_context(|| format!("Failed to start {}", exe.display()))
+    }
+
+    /// Takes the pipes of the process over from the trayme that handed it over.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if they aren't open in this trayme.
+    pub fn into_pipes(self) -> anyhow::Result<(Option<ChildStdin>, ChildStdout, ChildStderr)> {
+        let stdin = self.stdin.map(adopt_fd).transpose()?.map(ChildStdin::from);
+        Ok((
+            stdin,
+            adopt_fd(self.stdout)?.into(),
+            adopt_fd(self.stderr)?.into(),
+        ))
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        self.stdin
+            .into_iter()
+            .chain([self.stdout, self.stderr])
+            .collect()
+    }
+}
+
+/// Whether this trayme was started by [`Handover::exec`] and has yet to [`take`] the run over.
+pub fn pending() -> bool {
+    std::env::var_os(HANDOVER_VAR).is_some()
+}
+
+/// The run this trayme was started to take over by [`Handover::exec`], if any. It's only
+/// returned once, so that the processes trayme starts from then on don't get it.
+pub fn take() -> Option<Handover> {
+    let state = std::env::var_os(HANDOVER_VAR)?;
+    std::env::remove_var(HANDOVER_VAR);
+    serde_json::from_str(&state.to_string_lossy())
+        .map_err(|e| warn!("Starting the command anew, the handover is invalid: {e}"))
+        .ok()
+}
+
+/// The path of trayme on disk. On Linux a binary that was replaced while it ran has ` (deleted)`
+/// after its path, which is dropped to get the binary that replaced it.
+fn installed_exe() -> anyhow::Result<PathBuf> {
+    let exe = std::env::current_exe().context("Failed to get trayme's path")?;
+    Ok(
+        match exe
+            .to_str()
+            .and_then(|path| path.strip_suffix(" (deleted)"))
+        {
+            Some(path) => PathBuf::from(path),
+            None => exe,
+        },
+    )
+}
+
+/// Takes `fd`, which the trayme that handed over left open, and closes it on exec again.
+fn adopt_fd(fd: RawFd) -> anyhow::Result<OwnedFd> {
+    set_inherited(fd, false).with_context(|| format!("Pipe {fd} wasn't handed over"))?;
+    // SAFETY: the descriptor is open, and nothing else owns it since it was inherited
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Sets whether `fd` stays open when trayme execs another binary.
+fn set_inherited(fd: RawFd, inherited: bool) -> io::Result<()> {
+    let flags = if inherited { 0 } else { libc::FD_CLOEXEC };
+    // SAFETY: fcntl has no memory safety preconditions, and fails for descriptors that aren't open
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::fd::IntoRawFd,
+    };
+
+    use super::*;
+
+    #[test]
+    fn passes_pipes_on() {
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let handover = Handover {
+            pid: 1,
+            run_id: "run".to_string(),
+            cmd: vec!["server".into()],
+            paused: true,
+            stdin: None,
+            stdout: OwnedFd::from(writer.try_clone().unwrap()).into_raw_fd(),
+            stderr: OwnedFd::from(writer).into_raw_fd(),
+        };
+        let state = serde_json::to_string(&handover).unwrap();
+        let handover: Handover = serde_json::from_str(&state).unwrap();
+        assert_eq!(handover.cmd, ["server"]);
+        assert!(handover.paused);
+        let (stdin, stdout, stderr) = handover.into_pipes().unwrap();
+        assert!(stdin.is_none());
+        let mut stdout = std::fs::File::from(OwnedFd::from(stdout));
+        stdout.write_all(b"out").unwrap();
+        drop((stdout, stderr));
+        let mut read = String::new();
+        reader.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "out");
+    }
+}
@@ -0,0 +1,213 @@
+use std::{path::Path, time::Duration};
+
+use log::warn;
+
+use crate::{cli::InstanceArgs, usage::format_bytes};
+
+/// How long a restart waits before the free memory and disk space are checked again.
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The free memory and disk space a command needs before it's started or restarted, see
+/// `--require-free-mem` and `--require-free-disk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headroom {
+    memory: Option<u64>,
+    disk: Option<u64>,
+}
+
+impl Headroom {
+    /// The headroom `instance` needs, or `None` if it doesn't need any.
+    pub fn new(instance: &InstanceArgs) -> Option<Self> {
+        let headroom = Self {
+            memory: instance.require_free_mem.map(|size| size.0),
+            disk: instance.require_free_disk.map(|size| size.0),
+        };
+        (headroom.memory.is_some() || headroom.disk.is_some()).then_some(headroom)
+    }
+
+    /// What there isn't enough of for people to read, with `dir` on the filesystem the disk space
+    /// is checked on, or `None` if there's enough of everything.
+    pub fn shortfall(&self, dir: &Path) -> Option<String> {
+        let missing: Vec<_> = [
+            self.memory.and_then(memory_shortfall),
+            self.disk.and_then(|needed| disk_shortfall(needed, dir)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        (!missing.is_empty()).then(|| missing.join(", "))
+    }
+}
+
+/// What's missing if less than `needed` bytes of memory are free. Memory that can't be measured
+/// counts as enough, so that the command isn't held back by trayme.
+pub fn memory_shortfall(needed: u64) -> Option<String> {
+    match platform::free_memory() {
+        Ok(free) => (free < needed).then(|| {
+            format!(
+                "only {} of memory free, {} needed",
+                format_bytes(free),
+                format_bytes(needed)
+            )
+        }),
+        Err(e) => {
+            warn!("Failed to get the free memory: {e:#}");
+            None
+        }
+    }
+}
+
+/// What's missing if less than `needed` bytes are free on the filesystem of `dir`, which counts
+/// as enough if it can't be measured.
+pub fn disk_shortfall(needed: u64, dir: &Path) -> Option<String> {
+    match platform::free_disk(dir) {
+        Ok(free) => (free < needed).then(|| {
+            format!(
+                "only {} free on the disk of {}, {} needed",
+                format_bytes(free),
+                dir.display(),
+                format_bytes(needed)
+            )
+        }),
+        Err(e) => {
+            warn!("Failed to get the free space of {}: {e:#}", dir.display());
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path, ptr};
+
+    use anyhow::Context;
+
+    /// The memory that can be used without swapping, according to the kernel.
+    #[cfg(target_os = "linux")]
+    pub fn free_memory() -> anyhow::Result<u64> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").context("Failed to read meminfo")?;
+        let kib = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("MemAvailable:"))
+            .and_then(|rest| {
+                rest.trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .context("meminfo has no MemAvailable")?;
+        Ok(kib * 1024)
+    }
+
+    /// The free, inactive, and speculative pages, which is what macOS hands out without
+    /// swapping.
+    #[cfg(target_os = "macos")]
+    pub fn free_memory() -> anyhow::Result<u64> {
+        let output = std::process::Command::new("vm_stat")
+            .output()
+            .context("Failed to run vm_stat")?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let page_size = output
+            .split("page size of ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|size| size.parse::<u64>().ok())
+            .context("vm_stat didn't say its page size")?;
+        let pages: u64 = output
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| {
+                ["Pages free", "Pages inactive", "Pages speculative"].contains(&name.trim())
+            })
+            .filter_map(|(_, count)| count.trim().trim_end_matches('.').parse::<u64>().ok())
+            .sum();
+        Ok(pages * page_size)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn free_memory() -> anyhow::Result<u64> {
+        anyhow::bail!("Free memory can't be measured on this platform")
+    }
+
+    /// The space on the filesystem of `dir` that users other than root can use.
+    pub fn free_disk(dir: &Path) -> anyhow::Result<u64> {
+        let path = CString::new(dir.as_os_str().as_bytes()).context("Path contains a NUL")?;
+        // SAFETY: statvfs is plain old data, which may be zeroed
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is NUL-terminated, and `stat` lives for the duration of the call
+        if unsafe { libc::statvfs(path.as_ptr(), ptr::addr_of_mut!(stat)) } != 0 {
+            return Err(io::Error::last_os_error()).context("statvfs failed");
+        }
+        // their types differ between platforms
+        #[allow(clippy::useless_conversion)]
+        Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{io, os::windows::ffi::OsStrExt, path::Path, ptr};
+
+    use anyhow::Context;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/sysinfoapi/ns-sysinfoapi-memorystatusex
+    #[repr(C)]
+    #[derive(Default)]
+    struct MemoryStatus {
+        length: u32,
+        memory_load: u32,
+        total_phys: u64,
+        avail_phys: u64,
+        total_page_file: u64,
+        avail_page_file: u64,
+        total_virtual: u64,
+        avail_virtual: u64,
+        avail_extended_virtual: u64,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GlobalMemoryStatusEx(status: *mut MemoryStatus) -> i32;
+        fn GetDiskFreeSpaceExW(
+            dir: *const u16,
+            free_to_caller: *mut u64,
+            total: *mut u64,
+            total_free: *mut u64,
+        ) -> i32;
+    }
+
+    /// The physical memory that's available right away.
+    pub fn free_memory() -> anyhow::Result<u64> {
+        let mut status = MemoryStatus {
+            length: u32::try_from(std::mem::size_of::<MemoryStatus>())?,
+            ..MemoryStatus::default()
+        };
+        // SAFETY: `status` lives for the duration of the call and has its length set
+        if unsafe { GlobalMemoryStatusEx(ptr::addr_of_mut!(status)) } == 0 {
+            return Err(io::Error::last_os_error()).context("GlobalMemoryStatusEx failed");
+        }
+        Ok(status.avail_phys)
+    }
+
+    /// The space on the volume of `dir` that the user can use, which quotas can make less than
+    /// what's free.
+    pub fn free_disk(dir: &Path) -> anyhow::Result<u64> {
+        let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut free = 0;
+        // SAFETY: `wide` is NUL-terminated, `free` lives for the duration of the call, and the
+        // other outputs are optional
+        let queried = unsafe {
+            GetDiskFreeSpaceExW(
+                wide.as_ptr(),
+                ptr::addr_of_mut!(free),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if queried == 0 {
+            return Err(io::Error::last_os_error()).context("GetDiskFreeSpaceExW failed");
+        }
+        Ok(free)
+    }
+}
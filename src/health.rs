@@ -0,0 +1,148 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// How many matching lines within how long a window make an instance unhealthy, written as
+/// `COUNT/WINDOW` (e.g. `10/60s`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Threshold {
+    pub count: usize,
+    pub window: Duration,
+}
+
+/// The default threshold: 10 matches within a minute.
+const DEFAULT_COUNT: usize = 10;
+const DEFAULT_WINDOW_SECS: u64 = 60;
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Self {
+            count: DEFAULT_COUNT,
+            window: Duration::from_secs(DEFAULT_WINDOW_SECS),
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.count,
+            humantime::format_duration(self.window)
+        )
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, window) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected COUNT/WINDOW (e.g. 10/60s), got '{s}'"))?;
+        let count = count
+            .parse()
+            .map_err(|e| format!("invalid count '{count}': {e}"))?;
+        let window = humantime::parse_duration(window)
+            .map_err(|e| format!("invalid window '{window}': {e}"))?;
+        if count == 0 || window.is_zero() {
+            return Err("count and window must be greater than zero".to_string());
+        }
+        Ok(Self { count, window })
+    }
+}
+
+impl TryFrom<String> for Threshold {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Threshold> for String {
+    fn from(threshold: Threshold) -> Self {
+        threshold.to_string()
+    }
+}
+
+/// A change in health reported by [`HealthCheck::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    /// The threshold was reached. Holds the number of matches in the window.
+    Unhealthy(usize),
+    /// No lines matched for a whole window.
+    Recovered,
+}
+
+/// Marks an instance unhealthy when too many lines of its output match a pattern within a
+/// sliding window, even if the process keeps running.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pattern: Regex,
+    threshold: Threshold,
+    hits: VecDeque<Instant>,
+    unhealthy: bool,
+}
+
+impl HealthCheck {
+    pub fn new(pattern: Regex, threshold: Threshold) -> Self {
+        Self {
+            pattern,
+            threshold,
+            hits: VecDeque::new(),
+            unhealthy: false,
+        }
+    }
+
+    /// Checks a line of output, logged at `now`, against the pattern.
+    pub fn observe(&mut self, line: &str, now: Instant) {
+        if self.pattern.is_match(line) {
+            self.hits.push_back(now);
+        }
+    }
+
+    /// Drops matches that fell out of the window by `now` and returns the change in health, if
+    /// any.
+    pub fn update(&mut self, now: Instant) -> Option<HealthChange> {
+        while self
+            .hits
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) > self.threshold.window)
+        {
+            self.hits.pop_front();
+        }
+        if !self.unhealthy && self.hits.len() >= self.threshold.count {
+            self.unhealthy = true;
+            return Some(HealthChange::Unhealthy(self.hits.len()));
+        }
+        if self.unhealthy && self.hits.is_empty() {
+            self.unhealthy = false;
+            return Some(HealthChange::Recovered);
+        }
+        None
+    }
+
+    /// Returns `true` unless the threshold was reached and the instance hasn't recovered yet.
+    pub fn is_healthy(&self) -> bool {
+        !self.unhealthy
+    }
+
+    /// Forgets all matches, e.g. after a restart.
+    pub fn reset(&mut self) {
+        self.hits.clear();
+        self.unhealthy = false;
+    }
+
+    pub fn threshold(&self) -> Threshold {
+        self.threshold
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    get_logs_dir, osargs, supervisor::CommandSpec, tempdir::TempCleanup, termination::Termination,
+    usage::ResourceUsage,
+};
+
+/// A snapshot of everything needed to reproduce a single run of a command: the exact command
+/// line, the environment and working directory the child received, and a hash of the binary
+/// that was executed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    pub started_at: DateTime<Local>,
+    pub ended_at: Option<DateTime<Local>>,
+    #[serde(with = "osargs")]
+    pub cmd: Vec<OsString>,
+    pub cwd: PathBuf,
+    /// The resolved path of the executed binary, if it could be found.
+    pub binary: Option<PathBuf>,
+    /// Hex-encoded SHA-256 of `binary` at the time of the run.
+    pub binary_hash: Option<String>,
+    /// The file the child's output currently goes to.
+    pub log_file: PathBuf,
+    /// Earlier log files of this run, oldest first, if the log was rotated while it ran. They
+    /// have a `.gz` suffix added if they were compressed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rotated_logs: Vec<PathBuf>,
+    /// How the run ended for people to read, `killed` if trayme had to kill it, or `detached`.
+    pub exit_status: Option<String>,
+    /// How the run ended, if it exited with a status.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub termination: Option<Termination>,
+    /// What the run used, recorded when it finishes.
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
+    /// The temporary directory the run got, see `--temp-dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+}
+
+impl RunRecord {
+    /// Captures a new record for the given command spec. The run ID is derived from the current
+    /// time and this process' PID, which is unique enough for one user's machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `spec` - The command spec that is about to be spawned.
+    /// * `log_file` - The file the child's output is written to.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the current working directory cannot be determined.
+    pub fn capture(spec: &CommandSpec, log_file: &Path) -> anyhow::Result<Self> {
+        let started_at = Local::now();
+        let base_id = format!(
+            "{}-{}",
+            started_at.format("%Y%m%d%H%M%S"),
+            std::process::id()
+        );
+        // restarts can start several runs within the same second
+        let history_dir = history_dir()?;
+        let mut id = base_id.clone();
+        for n in 2.. {
+            if !history_dir.join(format!("{id}.toml")).exists() {
+                break;
+            }
+            id = format!("{base_id}-{n}");
+        }
+        let cwd = match &spec.cwd {
+            Some(cwd) => cwd.clone(),
+            None => std::env::current_dir().context("Failed to get current directory")?,
+        };
+        let mut env = spec.env.clone().unwrap_or_else(|| {
+            std::env::vars_os()
+                .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+                .collect()
+        });
+        env.extend(spec.overrides());
+        let binary = resolve_program(&spec.cmd[0], env.get("PATH").map(String::as_str), &cwd);
+        let binary_hash = binary.as_deref().and_then(|path| {
+            hash_file(path)
+                .inspect_err(|e| warn!("Failed to hash binary {path:?}: {e}"))
+                .ok()
+        });
+
+        Ok(Self {
+            id,
+            started_at,
+            ended_at: None,
+            cmd: spec.cmd.clone(),
+            cwd,
+            binary,
+            binary_hash,
+            log_file: log_file.to_path_buf(),
+            rotated_logs: Vec::new(),
+            exit_status: None,
+            termination: None,
+            usage: None,
+            temp_dir: None,
+            env,
+        })
+    }
+
+    /// Converts this record back into a command spec that reproduces the run exactly.
+    pub fn to_spec(&self) -> CommandSpec {
+        CommandSpec {
+            cmd: self.cmd.clone(),
+            cwd: Some(self.cwd.clone()),
+            env: Some(self.env.clone()),
+            env_overrides: BTreeMap::new(),
+            env_provider: None,
+            tz: None,
+            locale: None,
+            ulimits: Vec::new(),
+            pre_check: None,
+            shell: false,
+            elevate: false,
+            // a new one, whose variables take precedence over the ones pointing to the old one
+            temp_dir: self.temp_dir.as_ref().map(|_| TempCleanup::default()),
+            interpreters: BTreeMap::new(),
+            caps: None,
+        }
+    }
+
+    /// Marks the run as finished with the given exit status (or `None` if it was killed) and
+    /// resource usage, and saves the record.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the record cannot be saved.
+    pub fn finish(
+        &mut self,
+        status: Option<ExitStatus>,
+        usage: Option<ResourceUsage>,
+    ) -> anyhow::Result<()> {
+        self.ended_at = Some(Local::now());
+        self.termination = status.map(Termination::of);
+        self.exit_status = Some(
+            self.termination
+                .map_or_else(|| "killed".to_string(), |t| t.to_string()),
+        );
+        self.usage = usage;
+        self.save()
+    }
+
+    /// Marks the run as left running when trayme exited, so its exit status is never known, and
+    /// saves the record.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the record cannot be saved.
+    pub fn detach(&mut self) -> anyhow::Result<()> {
+        self.ended_at = Some(Local::now());
+        self.exit_status = Some("detached".to_string());
+        self.save()
+    }
+
+    /// Writes this record to the history directory, overwriting any previous version.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the record cannot be serialized or written.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = history_dir()?.join(format!("{}.toml", self.id));
+        let contents = toml::to_string(self).context("Failed to serialize run record")?;
+        std::fs::write(&path, contents).context("Failed to write run record")?;
+        debug!("Saved run record to {path:?}");
+        Ok(())
+    }
+
+    /// Loads the record with the given run ID from the history directory.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if no such run exists or its record cannot be parsed.
+    pub fn load(id: &str) -> anyhow::Result<Self> {
+        let path = history_dir()?.join(format!("{id}.toml"));
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("No run with ID '{id}' found in history"))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse run record {}", path.display()))
+    }
+
+    /// Returns `true` if the binary on disk no longer matches the hash recorded for this run.
+    pub fn binary_changed(&self) -> bool {
+        let (Some(binary), Some(old_hash)) = (&self.binary, &self.binary_hash) else {
+            return false;
+        };
+        hash_file(binary).map_or(true, |new_hash| &new_hash != old_hash)
+    }
+}
+
+/// Loads every record in the history directory, sorted from most to least recent. Records that
+/// fail to parse are skipped with a warning.
+///
+/// # Errors
+///
+/// An error is returned if the history directory cannot be read.
+pub fn list_runs() -> anyhow::Result<Vec<RunRecord>> {
+    let mut runs = Vec::new();
+    for entry in std::fs::read_dir(history_dir()?).context("Failed to read history directory")? {
+        let path = entry?.path();
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        match RunRecord::load(id) {
+            Ok(record) => runs.push(record),
+            Err(e) => warn!("Skipping run record {path:?}: {e:#}"),
+        }
+    }
+    runs.sort_by_key(|run| std::cmp::Reverse(run.started_at));
+    Ok(runs)
+}
+
+fn history_dir() -> anyhow::Result<PathBuf> {
+    let dir = get_logs_dir()?.join("history");
+    std::fs::create_dir_all(&dir).context("Failed to create history directory")?;
+    Ok(dir)
+}
+
+/// Resolves `program` to the file that would be executed, searching `path_var` the same way the
+/// OS does if `program` is a bare name. Relative paths are resolved against `cwd`.
+pub fn resolve_program(program: &OsStr, path_var: Option<&str>, cwd: &Path) -> Option<PathBuf> {
+    let program = Path::new(program);
+    let candidates = |base: PathBuf| {
+        let mut candidates = vec![base.clone()];
+        if cfg!(windows) && base.extension().is_none() {
+            let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.BAT;.CMD".into());
+            candidates.extend(
+                pathext
+                    .split(';')
+                    .map(|ext| base.with_extension(ext.trim_start_matches('.'))),
+            );
+        }
+        candidates
+    };
+
+    if program.components().count() > 1 {
+        return candidates(cwd.join(program))
+            .into_iter()
+            .find(|p| p.is_file());
+    }
+    std::env::split_paths(path_var?)
+        .flat_map(|dir| candidates(dir.join(program)))
+        .find(|p| p.is_file())
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
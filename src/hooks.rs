@@ -0,0 +1,108 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use log::{debug, info, warn};
+
+use crate::{cli::InstanceArgs, notifyroute, precheck, supervisor::CommandSpec};
+
+/// How long a hook may run before it's killed.
+const HOOK_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// A point in the life of a run that a hook can run at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// A run started, see `--on-start`.
+    Start,
+    /// A run ended, see `--on-exit`.
+    Exit,
+    /// A run failed or timed out, see `--on-failure`.
+    Failure,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookEvent::Start => write!(f, "start"),
+            HookEvent::Exit => write!(f, "exit"),
+            HookEvent::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// What a hook is told about the run in its `TRAYME_*` variables.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub instance: String,
+    pub run_id: String,
+    pub pid: u32,
+    pub log_file: PathBuf,
+    /// The exit code trayme passes on for the run, once it exited with a status.
+    pub exit_code: Option<i32>,
+    /// How the run ended for people to read, once it did.
+    pub exit_status: Option<String>,
+}
+
+/// The shell commands an instance runs at points in the life of its runs, see `--on-start`,
+/// `--on-exit`, and `--on-failure`. Hooks run in the background in the command's working
+/// directory and environment, and what they do never affects the instance: a hook that fails is
+/// only logged.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    start: Option<String>,
+    exit: Option<String>,
+    failure: Option<String>,
+}
+
+impl Hooks {
+    /// The hooks of `instance`, or `None` if it has none.
+    pub fn new(instance: &InstanceArgs) -> Option<Self> {
+        let hooks = Self {
+            start: instance.on_start.clone(),
+            exit: instance.on_exit.clone(),
+            failure: instance.on_failure.clone(),
+        };
+        (hooks.start.is_some() || hooks.exit.is_some() || hooks.failure.is_some()).then_some(hooks)
+    }
+
+    /// Runs the hook for `event`, if there is one, on a thread of its own. trayme waits a little
+    /// for it before it exits, like it does for notifications.
+    pub fn run(&self, event: HookEvent, spec: &CommandSpec, context: &HookContext) {
+        let cmd = match event {
+            HookEvent::Start => &self.start,
+            HookEvent::Exit => &self.exit,
+            HookEvent::Failure => &self.failure,
+        };
+        let Some(cmd) = cmd.clone() else {
+            return;
+        };
+        info!("Running the {event} hook: {cmd}");
+        let mut command = precheck::spec_shell_command(&cmd, spec);
+        command
+            .env("TRAYME_EVENT", event.to_string())
+            .env("TRAYME_INSTANCE", &context.instance)
+            .env("TRAYME_RUN_ID", &context.run_id)
+            .env("TRAYME_PID", context.pid.to_string())
+            .env("TRAYME_LOG_FILE", &context.log_file)
+            .env(
+                "TRAYME_EXIT_CODE",
+                context
+                    .exit_code
+                    .map(|code| code.to_string())
+                    .unwrap_or_default(),
+            )
+            .env(
+                "TRAYME_EXIT_STATUS",
+                context.exit_status.as_deref().unwrap_or_default(),
+            );
+        notifyroute::spawn_pending(move || {
+            match precheck::run_captured(command, &cmd, HOOK_TIMEOUT) {
+                Ok(output) => {
+                    debug!("The {event} hook succeeded");
+                    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                        debug!("{event} hook: {line}");
+                    }
+                }
+                Err(e) => warn!("The {event} hook failed: {e:#}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Context;
+use tray_icon::{menu, Icon};
+
+/// The width and height of the generated icons.
+const SIZE: u32 = 32;
+
+/// The color of the tray icon while the instance is unhealthy.
+pub const UNHEALTHY_COLOR: [u8; 3] = [0xd3, 0x2f, 0x2f];
+
+/// The colors of the done and remaining parts of the progress icon.
+const PROGRESS_COLORS: [[u8; 3]; 2] = [[0x19, 0x76, 0xd2], [0x9e, 0x9e, 0x9e]];
+
+/// The colors identicons are drawn in. They're picked to be told apart easily on both light and
+/// dark panels, and none of them is the unhealthy red.
+const PALETTE: [[u8; 3]; 8] = [
+    [0x1e, 0x88, 0xe5],
+    [0x43, 0xa0, 0x47],
+    [0xfb, 0x8c, 0x00],
+    [0x8e, 0x24, 0xaa],
+    [0x00, 0xac, 0xc1],
+    [0xc0, 0xca, 0x33],
+    [0xd8, 0x1b, 0x60],
+    [0x6d, 0x4c, 0x41],
+];
+
+/// How many cells an identicon is wide and high.
+const GRID: u32 = 5;
+
+/// Builds an identicon for `seed`: a mirrored 5 by 5 pattern in one of the [`PALETTE`] colors,
+/// so that instances started without an icon can be told apart. The same seed always gives the
+/// same icon, on every platform and with every build of trayme.
+///
+/// # Errors
+///
+/// An error is returned if the icon cannot be built.
+pub fn identicon(seed: &str) -> anyhow::Result<Icon> {
+    let hash = fnv1a(seed.as_bytes());
+    // the low byte picks the color, the next 15 bits the pattern
+    let color = accent(seed);
+    // the left three columns, mirrored to the right, in column-major order
+    let mut cells = (hash >> 8) & 0x7fff;
+    if cells == 0 {
+        cells = 1 << 7;
+    }
+    let filled = |column: u32, row: u32| {
+        let column = column.min(GRID - 1 - column);
+        cells & (1 << (column * GRID + row)) != 0
+    };
+    let cell = SIZE / GRID;
+    let margin = (SIZE - cell * GRID) / 2;
+    let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let inside =
+                (margin..SIZE - margin).contains(&x) && (margin..SIZE - margin).contains(&y);
+            let on = inside && filled((x - margin) / cell, (y - margin) / cell);
+            rgba.extend_from_slice(&color);
+            rgba.push(if on { 255 } else { 0 });
+        }
+    }
+    Icon::from_rgba(rgba, SIZE, SIZE).context("Failed to build identicon")
+}
+
+/// The color of the identicon for `seed`, e.g. to mark the instance's menu with.
+pub fn accent(seed: &str) -> [u8; 3] {
+    PALETTE[usize::from(fnv1a(seed.as_bytes()).to_le_bytes()[0]) % PALETTE.len()]
+}
+
+/// Builds a round icon of a single color, used to show the instance's state at a glance.
+///
+/// # Errors
+///
+/// An error is returned if the icon cannot be built.
+pub fn status(color: [u8; 3]) -> anyhow::Result<Icon> {
+    Icon::from_rgba(dot(color), SIZE, SIZE).context("Failed to build status icon")
+}
+
+/// Builds a round menu icon of a single color, e.g. of the instance's [`accent`].
+///
+/// # Errors
+///
+/// An error is returned if the icon cannot be built.
+pub fn swatch(color: [u8; 3]) -> anyhow::Result<menu::Icon> {
+    menu::Icon::from_rgba(dot(color), SIZE, SIZE).context("Failed to build menu icon")
+}
+
+/// The pixels of a filled circle of `color`.
+fn dot([r, g, b]: [u8; 3]) -> Vec<u8> {
+    let center = f64::from(SIZE) / 2.0;
+    let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let dx = f64::from(x) + 0.5 - center;
+            let dy = f64::from(y) + 0.5 - center;
+            let alpha = if dx.hypot(dy) <= center - 1.0 { 255 } else { 0 };
+            rgba.extend_from_slice(&[r, g, b, alpha]);
+        }
+    }
+    rgba
+}
+
+/// Builds a round icon that fills up clockwise from the top as `percent` goes from 0 to 100.
+///
+/// # Errors
+///
+/// An error is returned if the icon cannot be built.
+pub fn progress(percent: u8) -> anyhow::Result<Icon> {
+    let [done, remaining] = PROGRESS_COLORS;
+    let center = f64::from(SIZE) / 2.0;
+    let filled = f64::from(percent.min(100)) / 100.0 * std::f64::consts::TAU;
+    let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let dx = f64::from(x) + 0.5 - center;
+            let dy = f64::from(y) + 0.5 - center;
+            let alpha = if dx.hypot(dy) <= center - 1.0 { 255 } else { 0 };
+            // the angle from 12 o'clock, clockwise
+            let angle = dx.atan2(-dy).rem_euclid(std::f64::consts::TAU);
+            let [r, g, b] = if angle < filled { done } else { remaining };
+            rgba.extend_from_slice(&[r, g, b, alpha]);
+        }
+    }
+    Icon::from_rgba(rgba, SIZE, SIZE).context("Failed to build progress icon")
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike the standard library's hasher, it's guaranteed to
+/// stay the same across Rust versions, so an instance keeps its icon when trayme is updated.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
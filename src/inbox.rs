@@ -0,0 +1,401 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::menu::{IsMenuItem, MenuItem, PredefinedMenuItem};
+
+use crate::{
+    build_tray, build_tray_menu, display, icon, logout,
+    notify::{show_notification, Notifier},
+    supervisor::CommandSpec,
+    traybackend::{NoTray, SystemTray, TrayBackend},
+    trigger::{QueueMessage, QueueStatus, RunQueue},
+    HEADLESS_POLL_INTERVAL,
+};
+
+/// How often the inbox is checked for new files.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The placeholder in the command template that is replaced with the new file's path.
+pub const FILE_PLACEHOLDER: &str = "{file}";
+
+/// The subfolder of the inbox that files whose run succeeded are moved to.
+const DONE_DIR: &str = "done";
+
+/// The subfolder of the inbox that files whose run failed are moved to.
+const FAILED_DIR: &str = "failed";
+
+/// Watches a folder for files to process, as a "hot folder": each new file is handed to a run
+/// once it's complete, then moved to `done/` or `failed/` depending on how the run went.
+#[derive(Debug)]
+pub struct InboxWatcher {
+    dir: PathBuf,
+    /// The files that were handed to a run and haven't been moved out yet.
+    taken: BTreeSet<PathBuf>,
+    /// New files and their size at the last check. A file is only complete once its size stops
+    /// changing, since it may still be being copied in.
+    settling: BTreeMap<PathBuf, u64>,
+    last_check: Option<Instant>,
+}
+
+impl InboxWatcher {
+    /// Starts watching `dir`, creating its `done/` and `failed/` subfolders. The files already in
+    /// it are processed too.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the subfolders cannot be created.
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        for sub in [DONE_DIR, FAILED_DIR] {
+            let path = dir.join(sub);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+        }
+        Ok(Self {
+            dir,
+            taken: BTreeSet::new(),
+            settling: BTreeMap::new(),
+            last_check: None,
+        })
+    }
+
+    /// Returns the files that are complete and weren't handed to a run yet, in name order.
+    /// Hidden files, such as the partial downloads of some browsers, are left alone.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the inbox cannot be read.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<PathBuf>> {
+        if self
+            .last_check
+            .is_some_and(|at| at.elapsed() < POLL_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_check = Some(Instant::now());
+        let mut present = BTreeMap::new();
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            // e.g. removed in the meantime
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !hidden && metadata.is_file() {
+                present.insert(entry.path(), metadata.len());
+            }
+        }
+        // files taken out of the inbox by hand are picked up again if they're put back
+        self.taken.retain(|path| present.contains_key(path));
+        let mut ready = Vec::new();
+        let mut settling = BTreeMap::new();
+        for (path, size) in present {
+            if self.taken.contains(&path) {
+                continue;
+            }
+            if self.settling.get(&path) == Some(&size) {
+                ready.push(path);
+            } else {
+                settling.insert(path, size);
+            }
+        }
+        self.settling = settling;
+        self.taken.extend(ready.iter().cloned());
+        Ok(ready)
+    }
+
+    /// Moves a file whose run finished to `done/` or `failed/`, numbering it if a file of the
+    /// same name is there already. Returns where it was moved to.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be moved, e.g. because the run moved it itself.
+    pub fn file_away(&mut self, file: &Path, success: bool) -> anyhow::Result<PathBuf> {
+        self.taken.remove(file);
+        let sub = if success { DONE_DIR } else { FAILED_DIR };
+        let name = file
+            .file_name()
+            .with_context(|| format!("{} has no file name", file.display()))?;
+        let to = unique_path(&self.dir.join(sub), name);
+        std::fs::rename(file, &to)
+            .with_context(|| format!("Failed to move {} to {}", file.display(), to.display()))?;
+        Ok(to)
+    }
+}
+
+/// `dir/name`, or `dir/name (2)` and so on if that's taken, keeping the extension last.
+fn unique_path(dir: &Path, name: &OsStr) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 2;
+    loop {
+        let numbered = dir.join(format!("{stem} ({n}){extension}"));
+        if !numbered.exists() {
+            return numbered;
+        }
+        n += 1;
+    }
+}
+
+/// The tray of `--watch-inbox`: an inbox watcher feeding a run queue.
+struct InboxTray {
+    watcher: InboxWatcher,
+    queue: RunQueue,
+    status: QueueStatus,
+    /// How many files were processed, by outcome.
+    processed: MenuItem,
+    done: usize,
+    failed: usize,
+    tooltip: String,
+}
+
+impl InboxTray {
+    fn new(watcher: InboxWatcher, queue: RunQueue) -> Self {
+        Self {
+            watcher,
+            queue,
+            status: QueueStatus::new(),
+            processed: MenuItem::new("0 done, 0 failed", false, None),
+            done: 0,
+            failed: 0,
+            tooltip: "trayme inbox: watching".to_string(),
+        }
+    }
+
+    fn tick(&mut self, tray: &mut dyn TrayBackend) -> anyhow::Result<ControlFlow> {
+        for file in self.watcher.poll()? {
+            if let Some(path) = file.to_str() {
+                self.queue.push(path.to_string());
+            } else {
+                warn!("Ignoring non-UTF-8 path {}", file.display());
+            }
+        }
+        self.queue.poll()?;
+        self.file_away_finished();
+        self.status.update(&self.queue)?;
+        let running = self.queue.running().count();
+        let queued = self.queue.pending().count();
+        let tooltip = match (running, queued) {
+            (0, 0) => "trayme inbox: watching".to_string(),
+            (running, 0) => format!("trayme inbox: {running} running"),
+            (running, queued) => format!("trayme inbox: {running} running, {queued} queued"),
+        };
+        if tooltip != self.tooltip {
+            tray.set_tooltip(&tooltip)?;
+            self.tooltip = tooltip;
+        }
+
+        if let Some(id) = tray.menu_event() {
+            let flow = self.queue.handle_menu_event(&id)?;
+            // runs killed from the menu count as failed, but those stopped by quitting are left
+            // in the inbox for next time
+            if flow != ControlFlow::Exit {
+                self.file_away_finished();
+            }
+            return Ok(flow);
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+
+    /// Moves the files of the runs that finished out of the inbox.
+    fn file_away_finished(&mut self) {
+        let finished = self.queue.take_finished();
+        if finished.is_empty() {
+            return;
+        }
+        for (file, success) in finished {
+            match self.watcher.file_away(Path::new(&file), success) {
+                Ok(to) => info!("Moved {file} to {}", to.display()),
+                Err(e) => {
+                    error!("{e:#}");
+                    show_notification("Failed to move processed file", &format!("{e:#}"));
+                }
+            }
+            if success {
+                self.done += 1;
+            } else {
+                self.failed += 1;
+            }
+        }
+        self.processed
+            .set_text(format!("{} done, {} failed", self.done, self.failed));
+    }
+}
+
+/// Runs `cmd` for every file that appears in `dir`, moving each to `done/` or `failed/` once its
+/// run finishes, until the user quits from the tray or trayme is interrupted.
+///
+/// # Arguments
+///
+/// * `dir` - The inbox to watch.
+/// * `spec` - The command to run, whose arguments are the template. `{file}` is replaced with
+///   the path of the new file.
+/// * `notifier` - Used for the notifications of each run.
+/// * `max_concurrent` - How many runs may be in progress at once.
+/// * `headless` - Runs without a tray, see `--headless`.
+///
+/// # Errors
+///
+/// An error is returned if the inbox cannot be prepared or the tray icon cannot be built.
+pub fn run_inbox(
+    dir: PathBuf,
+    spec: CommandSpec,
+    mut notifier: Notifier,
+    max_concurrent: NonZeroUsize,
+    headless: bool,
+) -> anyhow::Result<()> {
+    let watcher = InboxWatcher::new(dir)?;
+    if headless {
+        // there's usually no notification server outside of a desktop session
+        notifier.mute();
+    }
+    let mut queue = RunQueue::new(spec.cmd.clone(), FILE_PLACEHOLDER, notifier, max_concurrent);
+    queue.set_spec(spec);
+    queue.set_keep_finished();
+    let mut inbox_tray = InboxTray::new(watcher, queue);
+    if headless {
+        return run_headless(inbox_tray);
+    }
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(QueueMessage::VARIANTS)?;
+    menu.prepend_items(&[
+        &inbox_tray.status.running as &dyn IsMenuItem,
+        &inbox_tray.status.queued,
+        &inbox_tray.processed,
+        &PredefinedMenuItem::separator(),
+    ])?;
+    let tooltip = &inbox_tray.tooltip;
+    let mut tray = Some(SystemTray::new(build_tray(
+        tooltip,
+        menu,
+        icon::identicon(tooltip)?,
+    )?));
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        let Some(icon) = tray.as_mut() else {
+            return;
+        };
+        match inbox_tray.tick(icon) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = inbox_tray.queue.stop();
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
+
+/// Watches the inbox of `inbox_tray` without a tray until trayme is interrupted or the session
+/// ends. The files of the runs that are stopped then are left in the inbox for next time.
+///
+/// # Errors
+///
+/// An error is returned if the inbox cannot be read or a run cannot be started or stopped.
+fn run_headless(mut inbox_tray: InboxTray) -> anyhow::Result<()> {
+    if let Err(e) = logout::watch() {
+        warn!("The runs will outlive trayme if it's terminated: {e:#}");
+    }
+    while !logout::interrupted() && !logout::session_ending() {
+        if let Err(e) = inbox_tray.tick(&mut NoTray) {
+            let _ = inbox_tray.queue.stop();
+            return Err(e);
+        }
+        thread::sleep(HEADLESS_POLL_INTERVAL);
+    }
+    info!("Interrupted");
+    let stopped = inbox_tray.queue.stop();
+    logout::handled();
+    stopped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{notify::FakeNotifier, spawner::FakeSpawner, traybackend::FakeTray};
+
+    #[test]
+    fn files_away_processed_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-inbox-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        let spawner = FakeSpawner::exiting_with([0, 1]);
+        let mut notifier = Notifier::default();
+        notifier.set_desktop(Arc::new(FakeNotifier::default()));
+        let mut queue = RunQueue::new(
+            vec!["convert".into()],
+            FILE_PLACEHOLDER,
+            notifier,
+            NonZeroUsize::MIN,
+        );
+        queue.set_spawner(Arc::new(spawner.clone()));
+        queue.set_keep_finished();
+        let mut inbox_tray = InboxTray::new(InboxWatcher::new(dir.clone()).unwrap(), queue);
+        let mut tray = FakeTray::default();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while inbox_tray.done + inbox_tray.failed < 2 {
+            assert!(Instant::now() < deadline, "the files were never processed");
+            assert!(inbox_tray.tick(&mut tray).unwrap() == ControlFlow::Poll);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(dir.join(DONE_DIR).join("a.txt").exists());
+        assert!(dir.join(FAILED_DIR).join("b.txt").exists());
+        let args: Vec<_> = spawner.spawned().into_iter().map(|run| run.args).collect();
+        assert_eq!(
+            args,
+            [
+                [dir.join("a.txt").into_os_string()],
+                [dir.join("b.txt").into_os_string()]
+            ]
+        );
+        let tooltips = tray.tooltips();
+        assert_eq!(
+            tooltips.first().map(String::as_str),
+            Some("trayme inbox: 1 running, 1 queued")
+        );
+        assert_eq!(
+            tooltips.last().map(String::as_str),
+            Some("trayme inbox: watching")
+        );
+
+        tray.click(&QueueMessage::Quit.to_string());
+        assert!(inbox_tray.tick(&mut tray).unwrap() == ControlFlow::Exit);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::EventRecord,
+    osargs,
+    output::LevelCounts,
+    parse,
+    selflog::Verbosity,
+    termination::Termination,
+    token::{self, Scope},
+};
+
+/// How long a client waits for the instance to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long writing an event to a subscriber may take before it's dropped.
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A command sent to a running instance over its control socket. On the wire, commands are
+/// single lines of text, optionally starting with `token <SECRET> ` (see [`crate::token`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Status,
+    Kill,
+    /// Kills the instance even if that was disabled with `--no-kill-menu`, which only `admin`
+    /// tokens may do.
+    ForceKill,
+    /// Writes a line to the process' stdin.
+    Send(String),
+    /// Keeps the connection open and streams the instance's events as JSON lines (see
+    /// [`EventRecord`]) instead of answering once.
+    Subscribe,
+    /// Turns maintenance mode on for the given duration, or the instance's
+    /// `--maintenance-duration`.
+    StartMaintenance(Option<Duration>),
+    /// Turns maintenance mode off before it runs out.
+    EndMaintenance,
+    /// Changes how verbose trayme's own log is, see [`crate::selflog::set_verbosity`].
+    LogLevel(Verbosity),
+    /// Replaces trayme with the trayme on disk, which takes the process over, see
+    /// [`crate::supervisor::Supervisor::hand_over`].
+    Handover,
+    /// Sends the output to a new log file, see [`crate::supervisor::Supervisor::rotate_log`].
+    RotateLog,
+}
+
+impl ControlCommand {
+    /// The scope a token needs to send this command.
+    pub fn scope(&self) -> Scope {
+        match self {
+            ControlCommand::Status | ControlCommand::Subscribe => Scope::Read,
+            ControlCommand::Kill
+            | ControlCommand::Send(_)
+            | ControlCommand::StartMaintenance(_)
+            | ControlCommand::EndMaintenance
+            | ControlCommand::LogLevel(_)
+            | ControlCommand::Handover
+            | ControlCommand::RotateLog => Scope::Control,
+            ControlCommand::ForceKill => Scope::Admin,
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::Status => write!(f, "status"),
+            ControlCommand::Subscribe => write!(f, "subscribe"),
+            ControlCommand::Kill => write!(f, "kill"),
+            ControlCommand::ForceKill => write!(f, "kill --force"),
+            ControlCommand::Send(line) => write!(f, "send {line}"),
+            ControlCommand::StartMaintenance(None) => write!(f, "maintenance on"),
+            ControlCommand::StartMaintenance(Some(duration)) => {
+                write!(
+                    f,
+                    "maintenance on {}",
+                    humantime::format_duration(*duration)
+                )
+            }
+            ControlCommand::EndMaintenance => write!(f, "maintenance off"),
+            ControlCommand::LogLevel(verbosity) => match verbosity.to_possible_value() {
+                Some(value) => write!(f, "log-level {}", value.get_name()),
+                None => write!(f, "log-level"),
+            },
+            ControlCommand::Handover => write!(f, "handover"),
+            ControlCommand::RotateLog => write!(f, "rotate-log"),
+        }
+    }
+}
+
+impl FromStr for ControlCommand {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse::control_command(s).ok_or(strum::ParseError::VariantNotFound)
+    }
+}
+
+/// The state of the supervised process, as reported over IPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessState {
+    Running,
+    Exited,
+    Killed,
+    /// Left running when trayme exited, see `--on-logout detach`.
+    Detached,
+    /// Exited and waiting to be started again, see `--restart-policy`.
+    Restarting,
+}
+
+/// A snapshot of a running instance, returned by [`ControlCommand::Status`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceStatus {
+    pub name: String,
+    #[serde(with = "osargs")]
+    pub cmd: Vec<OsString>,
+    pub state: ProcessState,
+    pub pid: u32,
+    pub run_id: String,
+    pub started_at: DateTime<Local>,
+    pub log_file: std::path::PathBuf,
+    pub exit_status: Option<String>,
+    #[serde(default)]
+    pub termination: Option<Termination>,
+    #[serde(default)]
+    pub levels: LevelCounts,
+    #[serde(default = "default_healthy")]
+    pub healthy: bool,
+    /// The progress reported by the current run, from 0 to 100, if `--progress-regex` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f64>,
+    /// The estimated seconds until the current run reaches 100%.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+    /// When maintenance mode runs out, if it's on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_until: Option<DateTime<Local>>,
+    /// The first line the current run wrote that isn't blank.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_output: Option<String>,
+    /// The URLs the current run printed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
+    /// Whether the process is paused from the tray.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// Whether the fallback runs in place of the command, see `--fallback`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+}
+
+fn default_healthy() -> bool {
+    true
+}
+
+/// The answer to a [`ControlCommand`]. Responses are serialized as TOML and the connection is
+/// closed afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)] // one response per connection
+pub enum ControlResponse {
+    Ok,
+    Status(InstanceStatus),
+    Error { message: String },
+}
+
+/// A request received by the [`ControlServer`]. The receiver must answer it through
+/// [`ControlRequest::respond`], otherwise the client times out.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: mpsc::Sender<Reply>,
+}
+
+/// A response, and who to tell once it was written to the client, see
+/// [`ControlRequest::respond_written`].
+type Reply = (ControlResponse, Option<mpsc::Sender<()>>);
+
+impl ControlRequest {
+    /// Sends the response back to the client. Errors are logged, since the client hanging up
+    /// early isn't something the instance can do anything about.
+    pub fn respond(self, response: ControlResponse) {
+        if self.reply.send((response, None)).is_err() {
+            warn!("Control client disconnected before receiving a response");
+        }
+    }
+
+    /// Like [`ControlRequest::respond`], but returns only once the response was written, for
+    /// when trayme is about to go away, see [`crate::supervisor::Supervisor::hand_over`].
+    pub fn respond_written(self, response: ControlResponse) {
+        let (written, done) = mpsc::channel();
+        if self.reply.send((response, Some(written))).is_err() {
+            warn!("Control client disconnected before receiving a response");
+            return;
+        }
+        // the sender is dropped without a word if the client hung up
+        let _ = done.recv_timeout(REQUEST_TIMEOUT);
+    }
+}
+
+/// The clients subscribed to an instance's events with [`ControlCommand::Subscribe`]. Clones
+/// share the same list, so the supervisor can publish to the clients the server accepted.
+#[derive(Debug, Clone, Default)]
+pub struct Subscribers(Arc<Mutex<Vec<TcpStream>>>);
+
+impl Subscribers {
+    /// Sends `record` to every subscriber as a line of JSON, dropping those that hung up or
+    /// stopped reading.
+    pub fn publish(&self, record: &EventRecord) {
+        let mut subscribers = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if subscribers.is_empty() {
+            return;
+        }
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize event: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+        subscribers.retain_mut(|stream| stream.write_all(line.as_bytes()).is_ok());
+    }
+
+    fn add(&self, stream: TcpStream) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(stream);
+    }
+}
+
+/// Listens for control connections on a local TCP socket. Each connection is handled on its own
+/// thread and forwarded to the owner of the server through [`ControlServer::try_recv`], so that
+/// all state changes still happen on the main thread.
+#[derive(Debug)]
+pub struct ControlServer {
+    addr: SocketAddr,
+    requests: mpsc::Receiver<ControlRequest>,
+    subscribers: Subscribers,
+}
+
+impl ControlServer {
+    /// Binds the control socket and starts accepting connections in the background.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address to listen on. Use port 0 to let the OS pick a free port.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the socket cannot be bound.
+    pub fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).context("Failed to bind control socket")?;
+        let addr = listener.local_addr()?;
+        let (tx, requests) = mpsc::channel();
+        let subscribers = Subscribers::default();
+        let accepted = subscribers.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = tx.clone();
+                        let subscribers = accepted.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_connection(&stream, &tx, &subscribers) {
+                                warn!("Control connection failed: {e:#}");
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed to accept control connection: {e}"),
+                }
+            }
+        });
+        debug!("Control socket listening on {addr}");
+        Ok(Self {
+            addr,
+            requests,
+            subscribers,
+        })
+    }
+
+    /// The address the control socket is bound to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The clients subscribed to the instance's events, for the supervisor to publish to.
+    pub fn subscribers(&self) -> Subscribers {
+        self.subscribers.clone()
+    }
+
+    /// Returns the next pending request, if any, without blocking.
+    pub fn try_recv(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+fn handle_connection(
+    stream: &TcpStream,
+    tx: &mpsc::Sender<ControlRequest>,
+    subscribers: &Subscribers,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let (secret, line) = parse::control_line(&line);
+    let scope = token::authorize(secret)?;
+    let (response, written) = match ControlCommand::from_str(line) {
+        Ok(command) if scope.is_none_or(|scope| scope < command.scope()) => (
+            ControlResponse::Error {
+                message: match (secret, scope) {
+                    (None, _) => "A token is required, see `trayme token create`".to_string(),
+                    (Some(_), None) => "Unknown token".to_string(),
+                    (Some(_), Some(_)) => format!("The token doesn't allow '{command}'"),
+                },
+            },
+            None,
+        ),
+        Ok(ControlCommand::Subscribe) => {
+            // a subscriber that stops reading must not hold up the instance
+            stream.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT))?;
+            subscribers.add(stream.try_clone()?);
+            return Ok(());
+        }
+        Ok(command) => {
+            let (reply, response) = mpsc::channel();
+            tx.send(ControlRequest { command, reply })
+                .context("Instance is shutting down")?;
+            response
+                .recv_timeout(REQUEST_TIMEOUT)
+                .context("Instance did not answer in time")?
+        }
+        Err(_) => (
+            ControlResponse::Error {
+                message: format!("Unknown command '{line}'"),
+            },
+            None,
+        ),
+    };
+    let body = toml::to_string(&response).context("Failed to serialize response")?;
+    let mut stream = stream;
+    stream.write_all(body.as_bytes())?;
+    if let Some(written) = written {
+        let _ = written.send(());
+    }
+    Ok(())
+}
+
+/// Subscribes to the events of the instance listening on `addr` and returns the stream, which
+/// has one JSON [`EventRecord`] per line. If the instance refused the subscription, the stream
+/// has its TOML error response instead.
+///
+/// # Errors
+///
+/// An error is returned if the instance cannot be reached.
+pub fn subscribe(addr: SocketAddr) -> anyhow::Result<BufReader<TcpStream>> {
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)
+        .with_context(|| format!("Failed to connect to instance at {addr}"))?;
+    write_command(&mut stream, &ControlCommand::Subscribe)?;
+    Ok(BufReader::new(stream))
+}
+
+/// Sends a command to the instance listening on `addr` and waits for its response.
+///
+/// # Errors
+///
+/// An error is returned if the instance cannot be reached or its response cannot be parsed.
+pub fn request(addr: SocketAddr, command: &ControlCommand) -> anyhow::Result<ControlResponse> {
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)
+        .with_context(|| format!("Failed to connect to instance at {addr}"))?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    write_command(&mut stream, command)?;
+    let mut body = String::new();
+    stream.read_to_string(&mut body)?;
+    toml::from_str(&body).context("Failed to parse response from instance")
+}
+
+/// Writes `command` to the control socket, with the client's token.
+fn write_command(stream: &mut TcpStream, command: &ControlCommand) -> anyhow::Result<()> {
+    match token::client_secret() {
+        Ok(secret) => writeln!(stream, "token {secret} {command}")?,
+        Err(e) => {
+            warn!("Connecting without a token: {e:#}");
+            writeln!(stream, "{command}")?;
+        }
+    }
+    Ok(())
+}
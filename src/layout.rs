@@ -0,0 +1,353 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use regex::Regex;
+
+use crate::{get_logs_dir, history, history::RunRecord, registry};
+
+/// The layout of the data directory this version of trayme uses:
+///
+/// * `logs/<program>/` - The logs of each program's runs.
+/// * `history/` - The run records, see [`RunRecord`].
+/// * `instances/` - The registry of running instances.
+/// * `log.log` - trayme's own log, along with a few state files.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// The file in the data directory that records its layout version. Versions of trayme from
+/// before the layout was versioned didn't write one, which is version 0.
+const VERSION_FILE: &str = "layout-version";
+
+/// Plans the steps that bring the data directory from one version to the next, given the run
+/// records in its history. Plans must not depend on earlier steps having been applied, so that a
+/// migration that was interrupted can simply be run again.
+type Migration = fn(&Path, &[RunRecord]) -> anyhow::Result<Vec<Step>>;
+
+/// The migration at index `n` goes from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [per_program_log_dirs];
+
+/// Matches the name of a run's log as version 0 named them: `<program>_<timestamp>.log`, with a
+/// number added for logs started within the same second and `.gz` if it was compressed.
+static LOG_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(.+)_\d{4}-\d{2}-\d{2}_\d{2}-\d{2}-\d{2}(_\d+)?\.log(\.gz)?$").unwrap()
+});
+
+/// The directory the logs of `program` go in, as named by
+/// [`crate::supervisor::program_name`].
+///
+/// # Errors
+///
+/// An error is returned if the directory cannot be created.
+pub fn program_logs_dir(program: &str) -> anyhow::Result<PathBuf> {
+    let dir = get_logs_dir()?.join("logs").join(program);
+    std::fs::create_dir_all(&dir).context("Failed to create logs directory")?;
+    Ok(dir)
+}
+
+/// One change made to the data directory by a migration.
+#[derive(Debug)]
+enum Step {
+    /// Moves a file, creating the directory it goes in.
+    Move { from: PathBuf, to: PathBuf },
+    /// Saves a run record whose paths were updated.
+    UpdateRecord(Box<RunRecord>),
+    /// Records that the directory is at this version.
+    SetVersion(u32),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Move { from, to } => write!(f, "move {} to {}", from.display(), to.display()),
+            Step::UpdateRecord(record) => write!(f, "update the log paths of run {}", record.id),
+            Step::SetVersion(version) => write!(f, "set the layout version to {version}"),
+        }
+    }
+}
+
+impl Step {
+    fn apply(&self, dir: &Path) -> anyhow::Result<()> {
+        match self {
+            Step::Move { from, to } => {
+                // rename replaces files on Unix
+                if to.exists() {
+                    bail!("{} already exists", to.display());
+                }
+                if let Some(parent) = to.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Failed to create {}", parent.display()))?;
+                }
+                std::fs::rename(from, to)
+                    .with_context(|| format!("Failed to move {}", from.display()))
+            }
+            Step::UpdateRecord(record) => record.save(),
+            Step::SetVersion(version) => {
+                std::fs::write(dir.join(VERSION_FILE), format!("{version}\n"))
+                    .context("Failed to write the layout version")
+            }
+        }
+    }
+}
+
+/// The layout version the data directory is at.
+///
+/// # Errors
+///
+/// An error is returned if the version file exists but cannot be read.
+fn version(dir: &Path) -> anyhow::Result<u32> {
+    let path = dir.join(VERSION_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => contents
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid layout version in {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Plans the steps that bring the data directory from `version` to [`CURRENT_VERSION`].
+fn plan(dir: &Path, version: u32, runs: &[RunRecord]) -> anyhow::Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for migration in &MIGRATIONS[version as usize..] {
+        steps.extend(migration(dir, runs)?);
+    }
+    steps.push(Step::SetVersion(CURRENT_VERSION));
+    Ok(steps)
+}
+
+/// Where version 1 keeps the log at `path`, if it's a run's log in the data directory itself.
+fn per_program_log_path(dir: &Path, path: &Path) -> Option<PathBuf> {
+    if path.parent() != Some(dir) {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    let program = LOG_NAME.captures(name)?.get(1)?.as_str();
+    Some(dir.join("logs").join(program).join(name))
+}
+
+/// Version 1 moves the logs of runs from the data directory itself into a directory per
+/// program, so that the directory "Show Logs" opens isn't crowded with every program's logs.
+fn per_program_log_dirs(dir: &Path, runs: &[RunRecord]) -> anyhow::Result<Vec<Step>> {
+    let new_path = |path: &Path| per_program_log_path(dir, path);
+    let mut files = Vec::new();
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        files.push(entry?.path());
+    }
+    files.sort();
+    let mut steps: Vec<_> = files
+        .into_iter()
+        .filter(|from| from.is_file())
+        .filter_map(|from| {
+            Some(Step::Move {
+                to: new_path(&from)?,
+                from,
+            })
+        })
+        .collect();
+    // the history is the index of the logs, so it has to follow them
+    for record in runs {
+        let mut record = record.clone();
+        let mut changed = false;
+        for path in std::iter::once(&mut record.log_file).chain(&mut record.rotated_logs) {
+            if let Some(moved) = new_path(path) {
+                *path = moved;
+                changed = true;
+            }
+        }
+        if changed {
+            steps.push(Step::UpdateRecord(Box::new(record)));
+        }
+    }
+    Ok(steps)
+}
+
+/// The version `dir` has to be migrated from, or `None` if it's already up to date.
+///
+/// # Errors
+///
+/// An error is returned if the version cannot be read, or if it's newer than this version of
+/// trayme knows about.
+fn outdated_version(dir: &Path) -> anyhow::Result<Option<u32>> {
+    let version = version(dir)?;
+    if version > CURRENT_VERSION {
+        bail!(
+            "{} was last used by a newer version of trayme (layout version {version})",
+            dir.display()
+        );
+    }
+    Ok((version < CURRENT_VERSION).then_some(version))
+}
+
+/// Checks that the data directory can be migrated: it has to be at an older version, and no
+/// instance may be running, since they would keep writing to the old locations. Returns the
+/// directory and its version, or `None` if it's already up to date.
+fn migratable() -> anyhow::Result<Option<(PathBuf, u32)>> {
+    let dir = get_logs_dir()?;
+    let Some(version) = outdated_version(&dir)? else {
+        return Ok(None);
+    };
+    let running: Vec<_> = registry::list()?.into_iter().map(|r| r.name).collect();
+    if !running.is_empty() {
+        bail!("Stop the running instances first: {}", running.join(", "));
+    }
+    Ok(Some((dir, version)))
+}
+
+/// Brings the data directory up to the current layout when trayme starts. Problems are logged
+/// rather than returned, since trayme still works with the data it couldn't move, and the
+/// migration is tried again the next time.
+pub fn upgrade() {
+    let result = migratable().and_then(|migratable| {
+        let Some((dir, version)) = migratable else {
+            return Ok(());
+        };
+        info!("Migrating {} from layout version {version}", dir.display());
+        for step in plan(&dir, version, &history::list_runs()?)? {
+            step.apply(&dir)
+                .with_context(|| format!("Failed to {step}"))?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Not migrating the data directory: {e:#}");
+    }
+}
+
+/// Migrates the data directory to the current layout, printing every step, for `trayme migrate`.
+///
+/// # Arguments
+///
+/// * `dry_run` - Only prints the steps, without changing anything.
+///
+/// # Errors
+///
+/// An error is returned if the directory cannot be migrated, e.g. because instances are
+/// running, or if a step fails. The steps before it stay applied, and running the migration
+/// again picks up where it left off.
+pub fn migrate(dry_run: bool) -> anyhow::Result<()> {
+    let Some((dir, version)) = migratable()? else {
+        println!("The data directory is up to date (layout version {CURRENT_VERSION})");
+        return Ok(());
+    };
+    let would = if dry_run { " would" } else { "" };
+    println!(
+        "Migrating {} from layout version {version} to {CURRENT_VERSION}{would}:",
+        dir.display()
+    );
+    for step in plan(&dir, version, &history::list_runs()?)? {
+        println!("  {step}");
+        if !dry_run {
+            step.apply(&dir)
+                .with_context(|| format!("Failed to {step}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-layout-{name}-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_the_logs_of_runs() {
+        let dir = Path::new("/data");
+        for (name, program) in [
+            ("web_2024-01-02_03-04-05.log", "web"),
+            ("web_2024-01-02_03-04-05_2.log", "web"),
+            ("my_app_2024-01-02_03-04-05.log.gz", "my_app"),
+        ] {
+            assert_eq!(
+                per_program_log_path(dir, &dir.join(name)),
+                Some(dir.join("logs").join(program).join(name))
+            );
+        }
+        for path in [
+            dir.join("log.log"),
+            dir.join("web_2024-01-02.log"),
+            dir.join("web_2024-01-02_03-04-05.txt"),
+            dir.join("logs/web/web_2024-01-02_03-04-05.log"),
+        ] {
+            assert_eq!(per_program_log_path(dir, &path), None, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn picks_up_an_interrupted_migration() {
+        let dir = test_dir("resume");
+        let names = ["a_2024-01-02_03-04-05.log", "b_2024-01-02_03-04-05.log"];
+        for name in names {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        let steps = plan(&dir, 0, &[]).unwrap();
+        assert_eq!(steps.len(), 3);
+        steps[0].apply(&dir).unwrap();
+
+        // only what's left is planned again
+        let steps = plan(&dir, 0, &[]).unwrap();
+        assert!(matches!(
+            &steps[..],
+            [Step::Move { .. }, Step::SetVersion(1)]
+        ));
+        for step in &steps {
+            step.apply(&dir).unwrap();
+        }
+        for (name, program) in names.into_iter().zip(["a", "b"]) {
+            let moved = dir.join("logs").join(program).join(name);
+            assert_eq!(std::fs::read_to_string(moved).unwrap(), name);
+            assert!(!dir.join(name).exists());
+        }
+        assert_eq!(outdated_version(&dir).unwrap(), None);
+        assert!(matches!(
+            &plan(&dir, 0, &[]).unwrap()[..],
+            [Step::SetVersion(1)]
+        ));
+    }
+
+    #[test]
+    fn does_not_replace_files() {
+        let dir = test_dir("conflict");
+        let from = dir.join("web_2024-01-02_03-04-05.log");
+        let to = dir.join("logs/web/web_2024-01-02_03-04-05.log");
+        std::fs::write(&from, "old").unwrap();
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        std::fs::write(&to, "new").unwrap();
+        let step = Step::Move {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        let error = step.apply(&dir).unwrap_err();
+        assert!(error.to_string().contains("already exists"), "{error:#}");
+        assert_eq!(std::fs::read_to_string(from).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(to).unwrap(), "new");
+    }
+
+    #[test]
+    fn refuses_newer_layouts() {
+        let dir = test_dir("version");
+        assert_eq!(outdated_version(&dir).unwrap(), Some(0));
+        std::fs::write(dir.join(VERSION_FILE), format!("{CURRENT_VERSION}\n")).unwrap();
+        assert_eq!(outdated_version(&dir).unwrap(), None);
+        std::fs::write(dir.join(VERSION_FILE), format!("{}\n", CURRENT_VERSION + 1)).unwrap();
+        let error = outdated_version(&dir).unwrap_err();
+        assert!(error.to_string().contains("newer version"), "{error:#}");
+    }
+}
@@ -0,0 +1,1860 @@
+//! trayme runs a command in the tray and supervises it. The `trayme` binary is [`main`]; other
+//! programs can supervise commands through [`ffi`], its C interface.
+#![warn(clippy::all, clippy::pedantic)]
+
+mod attach;
+mod calendar;
+mod caps;
+mod capture;
+mod chain;
+mod child;
+mod cli;
+mod clipboard;
+mod clock;
+mod cmdline;
+mod config;
+mod confirm;
+mod console;
+mod crash;
+mod display;
+mod doctor;
+mod dotenv;
+#[cfg(any(windows, target_os = "macos"))]
+mod dropzone;
+mod elevate;
+mod envdiff;
+mod envedit;
+mod envprovider;
+mod events;
+mod exitcode;
+pub mod ffi;
+mod fleet;
+#[cfg(unix)]
+mod handover;
+mod headroom;
+mod health;
+mod history;
+mod hooks;
+mod icon;
+mod inbox;
+mod ipc;
+mod layout;
+mod limits;
+mod logout;
+mod logusage;
+mod notify;
+mod notifyroute;
+mod osargs;
+mod output;
+mod parse;
+mod pidwatch;
+mod ping;
+mod precheck;
+mod priority;
+mod progress;
+mod qr;
+mod readiness;
+mod registry;
+mod reload;
+mod remote;
+mod restart;
+mod schedule;
+mod selflog;
+#[cfg(windows)]
+mod service;
+mod setup;
+mod shebang;
+mod sleep;
+mod spawner;
+mod spawntrace;
+mod state;
+mod statusline;
+mod stop;
+mod supervisor;
+mod suspend;
+mod tempdir;
+mod termination;
+mod throttle;
+mod token;
+mod traybackend;
+mod trayhost;
+mod tree;
+mod trigger;
+mod uistate;
+mod urls;
+mod usage;
+mod waitfor;
+mod wake;
+mod window;
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    num::NonZeroUsize,
+    path::PathBuf,
+    process::{self, ExitCode},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use calendar::BusyCalendar;
+use caps::ResourceCaps;
+use chain::Chain;
+use chrono::{DateTime, Local};
+use clap::Parser;
+use cli::{CliArgs, CliSubcommand, InstanceArgs, RunArgs};
+use confirm::{Protection, TextPrompt};
+use envedit::{EnvEditor, EnvFile};
+use exitcode::{ErrorKind, WithKind};
+use headroom::Headroom;
+use health::HealthCheck;
+use history::RunRecord;
+use hooks::Hooks;
+use ipc::ControlServer;
+use log::{debug, error, info, warn};
+use logusage::LogUsage;
+use notify::{show_notification, Notifier, NotifyEvent};
+use output::LevelCounts;
+use priority::PriorityMenu;
+use registry::Registration;
+use restart::{Fallback, PlannedRestarts, RestartBackoff, RestartPolicy};
+use sleep::WakeAction;
+use strum::VariantArray;
+use supervisor::{program_name, CommandSpec, Supervisor};
+use tao::{event::Event, event_loop::ControlFlow};
+use tray_icon::{
+    menu::{
+        IconMenuItem, Menu, MenuEvent, MenuEventReceiver, MenuItem, MenuItemBuilder,
+        PredefinedMenuItem, Submenu,
+    },
+    Icon, MouseButton, MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent,
+    TrayIconEventReceiver,
+};
+use trayhost::TrayHostWatcher;
+use uistate::{Observation, StateMachine, UiState};
+use urls::UrlMenu;
+use waitfor::Waiter;
+
+/// How often a headless instance checks on its process and control socket.
+const HEADLESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many characters of a line of output are shown in a menu item.
+const MAX_MENU_TEXT: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VariantArray)]
+enum TrayMessage {
+    Restart,
+    RestartWith,
+    Pause,
+    Resume,
+    Kill,
+    Detach,
+    ShowLogs,
+    RotateLog,
+    PurgeLogs,
+    OpenTempDir,
+    Console,
+    SendInput,
+    Environment,
+    EnvDiff,
+    Maintenance,
+    Rename,
+    ShowWindow,
+    HideWindow,
+    Relaunch,
+    Quit,
+}
+
+impl std::fmt::Display for TrayMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrayMessage::Restart => write!(f, "Restart"),
+            TrayMessage::RestartWith => write!(f, "Restart With Arguments…"),
+            TrayMessage::Pause => write!(f, "Pause"),
+            TrayMessage::Resume => write!(f, "Resume"),
+            TrayMessage::Kill => write!(f, "Kill"),
+            TrayMessage::Detach => write!(f, "Detach"),
+            TrayMessage::ShowLogs => write!(f, "Show Logs"),
+            TrayMessage::RotateLog => write!(f, "Rotate Log Now"),
+            TrayMessage::PurgeLogs => write!(f, "Delete Old Logs"),
+            TrayMessage::OpenTempDir => write!(f, "Open Temp Dir"),
+            TrayMessage::Console => write!(f, "Console…"),
+            TrayMessage::SendInput => write!(f, "Send Input…"),
+            TrayMessage::Environment => write!(f, "Environment…"),
+            TrayMessage::EnvDiff => write!(f, "Environment Diff…"),
+            TrayMessage::Maintenance => write!(f, "Maintenance Mode"),
+            TrayMessage::Rename => write!(f, "Rename…"),
+            TrayMessage::ShowWindow => write!(f, "Show Window"),
+            TrayMessage::HideWindow => write!(f, "Hide Window"),
+            TrayMessage::Relaunch => write!(f, "Relaunch"),
+            TrayMessage::Quit => write!(f, "Quit"),
+        }
+    }
+}
+
+impl TrayMessage {
+    /// Whether the item kills the process, so that it's left out with `--no-kill-menu`.
+    fn is_destructive(self) -> bool {
+        matches!(
+            self,
+            TrayMessage::Kill | TrayMessage::Restart | TrayMessage::RestartWith
+        )
+    }
+
+    /// Whether the item shows or hides the process' windows, so that it's only shown with
+    /// `--start-hidden` or `--click-to-toggle`.
+    fn is_window_control(self) -> bool {
+        matches!(self, TrayMessage::ShowWindow | TrayMessage::HideWindow)
+    }
+
+    /// Whether the item is for after the process exited, so that it's only shown with
+    /// `--stay-open`.
+    fn is_after_exit(self) -> bool {
+        matches!(self, TrayMessage::Relaunch | TrayMessage::Quit)
+    }
+}
+
+impl FromStr for TrayMessage {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Restart" => Ok(TrayMessage::Restart),
+            "Restart With Arguments…" => Ok(TrayMessage::RestartWith),
+            "Pause" => Ok(TrayMessage::Pause),
+            "Resume" => Ok(TrayMessage::Resume),
+            "Kill" => Ok(TrayMessage::Kill),
+            "Detach" => Ok(TrayMessage::Detach),
+            "Show Logs" => Ok(TrayMessage::ShowLogs),
+            "Rotate Log Now" => Ok(TrayMessage::RotateLog),
+            "Delete Old Logs" => Ok(TrayMessage::PurgeLogs),
+            "Open Temp Dir" => Ok(TrayMessage::OpenTempDir),
+            "Console…" => Ok(TrayMessage::Console),
+            "Send Input…" => Ok(TrayMessage::SendInput),
+            "Environment…" => Ok(TrayMessage::Environment),
+            "Environment Diff…" => Ok(TrayMessage::EnvDiff),
+            "Maintenance Mode" => Ok(TrayMessage::Maintenance),
+            "Rename…" => Ok(TrayMessage::Rename),
+            "Show Window" => Ok(TrayMessage::ShowWindow),
+            "Hide Window" => Ok(TrayMessage::HideWindow),
+            "Relaunch" => Ok(TrayMessage::Relaunch),
+            "Quit" => Ok(TrayMessage::Quit),
+            _ => Err(strum::ParseError::VariantNotFound),
+        }
+    }
+}
+
+fn build_tray_menu<M: std::fmt::Display>(messages: &[M]) -> anyhow::Result<Menu> {
+    let menu = Menu::new();
+    for msg in messages {
+        let item = MenuItemBuilder::new()
+            .text(msg.to_string())
+            .id(msg.into())
+            .enabled(true)
+            .build();
+        menu.append(&item)?;
+    }
+    Ok(menu)
+}
+
+/// The [`TrayMessage`]s that get an item in the menu, given `instance`'s options.
+fn menu_messages(instance: &InstanceArgs) -> Vec<TrayMessage> {
+    let window_control = instance.start_hidden || instance.click_to_toggle;
+    TrayMessage::VARIANTS
+        .iter()
+        .copied()
+        .filter(|msg| !(instance.no_kill_menu && msg.is_destructive()))
+        .filter(|msg| window_control || !msg.is_window_control())
+        .filter(|msg| instance.stay_open || !msg.is_after_exit())
+        .filter(|msg| instance.temp_dir.is_some() || *msg != TrayMessage::OpenTempDir)
+        .collect()
+}
+
+/// Builds the disabled item at the top of the menu that shows `name`, next to a dot in the color
+/// of the identicon for `seed`.
+///
+/// # Errors
+///
+/// An error is returned if the dot cannot be built.
+fn menu_header(name: &str, seed: &str) -> anyhow::Result<IconMenuItem> {
+    let dot = icon::swatch(icon::accent(seed))?;
+    Ok(IconMenuItem::new(name, false, Some(dot), None))
+}
+
+/// The items of the tray's [`TrayMessage`]s, which are only enabled in the states they do
+/// anything in, see [`UiState::enables`].
+struct ActionMenu {
+    items: Vec<(TrayMessage, MenuItem)>,
+    shown: UiState,
+}
+
+impl ActionMenu {
+    /// Appends an item for each of `messages` to `menu`, enabled for a process that was just
+    /// started.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if an item cannot be appended.
+    fn new(menu: &Menu, messages: &[TrayMessage]) -> anyhow::Result<Self> {
+        let shown = StateMachine::new().state();
+        let mut items = Vec::with_capacity(messages.len());
+        for &msg in messages {
+            let item = MenuItemBuilder::new()
+                .text(msg.to_string())
+                .id((&msg).into())
+                .enabled(shown.enables(msg))
+                .build();
+            menu.append(&item)?;
+            items.push((msg, item));
+        }
+        Ok(Self { items, shown })
+    }
+
+    /// Enables the items that do something in `state`, and disables the others.
+    fn update(&mut self, state: UiState) {
+        if state == self.shown {
+            return;
+        }
+        for (msg, item) in &self.items {
+            item.set_enabled(state.enables(*msg));
+        }
+        self.shown = state;
+    }
+}
+
+/// The "Status" submenu of the tray, showing live counters for the current run. It also keeps
+/// the tray icon, tooltip, status glyph, and the enabled items of the menu in sync with the
+/// instance's state and reported progress, the "Open…" submenu with the URLs the run printed,
+/// and the "Priority" submenu.
+struct StatusMenu {
+    /// The item at the top of the menu with the instance's name, next to a dot in the color of
+    /// its identicon, so that the menus of several instances can be told apart.
+    header: IconMenuItem,
+    submenu: Submenu,
+    actions: ActionMenu,
+    urls: UrlMenu,
+    priority: PriorityMenu,
+    errors: MenuItem,
+    warnings: MenuItem,
+    health: MenuItem,
+    progress: Option<MenuItem>,
+    /// How long the run has left, with `--timeout`, and the seconds it showed last.
+    time_left: Option<(MenuItem, Option<u64>)>,
+    /// The first line of output, kept for later, see `--first-output-notify`.
+    first_output: Option<MenuItem>,
+    first_output_text: Option<String>,
+    logs: MenuItem,
+    log_usage: LogUsage,
+    counts: LevelCounts,
+    /// Decides what the icon, tooltip, and glyph show.
+    machine: StateMachine,
+    /// Whether the health item says the output is healthy.
+    healthy: bool,
+    /// When maintenance mode runs out, as shown in place of the health.
+    maintenance: Option<DateTime<Local>>,
+    /// The icon shown while there's nothing else to show.
+    default_icon: Icon,
+    tooltip: String,
+    /// Whether the status glyph is shown next to the icon, see `--status-glyphs`.
+    glyph_title: bool,
+    progress_text: Option<String>,
+    /// Whether the fallback runs in place of the command, which the tooltip says too.
+    fallback: bool,
+    /// Shown at the top of the submenu while the fallback runs.
+    fallback_item: MenuItem,
+    /// The step of the chain that runs, as the tooltip says, see `--then`.
+    step: Option<String>,
+}
+
+impl StatusMenu {
+    /// Creates the submenu.
+    ///
+    /// # Arguments
+    ///
+    /// * `tooltip` - The tray's tooltip, which the status glyph and progress are added to.
+    /// * `header` - The disabled item at the top of the menu that shows the instance's name.
+    /// * `instance` - The instance's options, which decide whether progress (see
+    ///   `--progress-regex`) and the first line of output are shown, and whether the status glyph
+    ///   is shown next to the icon too.
+    /// * `default_icon` - The icon shown while the instance is healthy and reports no progress.
+    /// * `program` - The program whose logs' disk usage is shown.
+    /// * `actions` - The items of the menu, enabled according to the state.
+    fn new(
+        tooltip: &str,
+        header: IconMenuItem,
+        instance: &InstanceArgs,
+        default_icon: Icon,
+        program: String,
+        actions: ActionMenu,
+    ) -> anyhow::Result<Self> {
+        let errors = MenuItem::new("0 errors this run", false, None);
+        let warnings = MenuItem::new("0 warnings this run", false, None);
+        let health = MenuItem::new("Healthy", false, None);
+        let submenu = Submenu::with_items("Status", true, &[&errors, &warnings, &health])?;
+        let progress = if instance.progress_regex.is_some() {
+            let item = MenuItem::new("No progress reported", false, None);
+            submenu.append(&item)?;
+            Some(item)
+        } else {
+            None
+        };
+        let time_left = if instance.timeout.is_some() {
+            let item = MenuItem::new("Time left: -", false, None);
+            submenu.append(&item)?;
+            Some((item, None))
+        } else {
+            None
+        };
+        let first_output = if instance.first_output_notify {
+            let item = MenuItem::new("No output yet", false, None);
+            submenu.append(&item)?;
+            Some(item)
+        } else {
+            None
+        };
+        let logs = MenuItem::new("Logs: measuring…", false, None);
+        submenu.append(&logs)?;
+        Ok(Self {
+            header,
+            submenu,
+            actions,
+            urls: UrlMenu::new(),
+            priority: PriorityMenu::new(instance.priority.unwrap_or_default())?,
+            errors,
+            warnings,
+            health,
+            progress,
+            time_left,
+            first_output,
+            first_output_text: None,
+            logs,
+            log_usage: LogUsage::new(program),
+            counts: LevelCounts::default(),
+            machine: StateMachine::new(),
+            healthy: true,
+            maintenance: None,
+            default_icon,
+            tooltip: tooltip.to_string(),
+            glyph_title: instance.status_glyphs,
+            progress_text: None,
+            fallback: false,
+            fallback_item: MenuItem::new("Running the fallback command", false, None),
+            step: None,
+        })
+    }
+
+    fn update(&mut self, supervisor: &Supervisor, tray: &TrayIcon) -> anyhow::Result<()> {
+        self.priority.update(supervisor.priority());
+        let counts = supervisor.levels();
+        if counts != self.counts {
+            self.errors
+                .set_text(format!("{} errors this run", counts.error));
+            self.warnings
+                .set_text(format!("{} warnings this run", counts.warn));
+            self.counts = counts;
+        }
+        if let Some(item) = &self.first_output {
+            let line = supervisor.first_output();
+            if line != self.first_output_text.as_deref() {
+                item.set_text(line.map_or_else(|| "No output yet".to_string(), menu_text));
+                self.first_output_text = line.map(str::to_string);
+            }
+        }
+        if let Some((item, shown)) = &mut self.time_left {
+            let secs = supervisor.time_left().map(|left| left.as_secs());
+            if secs != *shown {
+                item.set_text(match secs {
+                    Some(secs) => format!(
+                        "Time left: {}",
+                        humantime::format_duration(Duration::from_secs(secs))
+                    ),
+                    None => "Time left: -".to_string(),
+                });
+                *shown = secs;
+            }
+        }
+        self.urls.update(supervisor.urls())?;
+        if let Some(bytes) = self.log_usage.poll() {
+            self.logs
+                .set_text(format!("Logs: {}", usage::format_bytes(bytes)));
+        }
+
+        let healthy = supervisor.is_healthy();
+        let maintenance = supervisor.maintenance_until();
+        if healthy != self.healthy || maintenance != self.maintenance {
+            self.health.set_text(match maintenance {
+                Some(until) => format!("Maintenance until {}", until.format("%H:%M")),
+                None if healthy => "Healthy".to_string(),
+                None => "Unhealthy".to_string(),
+            });
+            self.healthy = healthy;
+            self.maintenance = maintenance;
+        }
+        let (percent, text_changed) = self.update_progress(supervisor);
+        let changes = self.machine.observe(Observation {
+            percent,
+            ..supervisor.observation()
+        });
+        if changes.state {
+            self.actions.update(self.machine.state());
+            if self.glyph_title {
+                tray.set_title(Some(self.machine.state().glyph()));
+            }
+        }
+        let flags_changed = supervisor.is_fallback() != self.fallback;
+        if flags_changed {
+            if supervisor.is_fallback() {
+                self.submenu.prepend(&self.fallback_item)?;
+            } else {
+                self.submenu.remove(&self.fallback_item)?;
+            }
+            self.fallback = supervisor.is_fallback();
+        }
+        let step = supervisor
+            .step()
+            .map(|(step, total, program)| format!("step {step}/{total}: {program}"));
+        let step_changed = step != self.step;
+        self.step = step;
+
+        if changes.state || text_changed || flags_changed || step_changed {
+            tray.set_tooltip(Some(self.tooltip_text()))
+                .context("Failed to update tooltip")?;
+        }
+        if changes.icon {
+            tray.set_icon(Some(self.icon()?))
+                .context("Failed to update tray icon")?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the text the status glyph and progress are added to in the tooltip.
+    fn set_tooltip(&mut self, tooltip: &str, tray: &TrayIcon) -> anyhow::Result<()> {
+        tooltip.clone_into(&mut self.tooltip);
+        tray.set_tooltip(Some(self.tooltip_text()))
+            .context("Failed to update tooltip")
+    }
+
+    /// The tooltip with the status glyph, progress, and whether the process is paused or
+    /// restarting, the fallback runs, or which step of the chain does.
+    fn tooltip_text(&self) -> String {
+        let state = self.machine.state();
+        let glyph = state.glyph();
+        let mut text = match &self.progress_text {
+            Some(text) => format!("{glyph} {} ({text})", self.tooltip),
+            None => format!("{glyph} {}", self.tooltip),
+        };
+        let flags: Vec<_> = state
+            .flag()
+            .into_iter()
+            .chain(self.fallback.then_some("fallback"))
+            .chain(self.step.as_deref())
+            .collect();
+        if !flags.is_empty() {
+            text.push_str(" (");
+            text.push_str(&flags.join(", "));
+            text.push(')');
+        }
+        text
+    }
+
+    /// Shows the run's progress and ETA in the status menu. Returns the percentage for the icon,
+    /// and whether the text changed, in which case the tooltip needs updating.
+    fn update_progress(&mut self, supervisor: &Supervisor) -> (Option<u8>, bool) {
+        let Some(item) = &self.progress else {
+            return (None, false);
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // 0 to 100
+        let percent = supervisor.progress().map(|percent| percent.round() as u8);
+        let text = percent.map(|percent| {
+            // rounded, so the text doesn't change on every iteration
+            match supervisor.eta().map(round_eta) {
+                Some(eta) => format!("{percent}%, ETA {}", humantime::format_duration(eta)),
+                None => format!("{percent}%"),
+            }
+        });
+        let text_changed = text != self.progress_text;
+        if text_changed {
+            item.set_text(match &text {
+                Some(text) => format!("Progress: {text}"),
+                None => "No progress reported".to_string(),
+            });
+            self.progress_text = text;
+        }
+        (percent, text_changed)
+    }
+
+    /// Builds the tray icon with `menu`, showing the current state.
+    ///
+    /// # Arguments
+    ///
+    /// * `menu` - The tray's menu, which the submenus were added to.
+    /// * `click_to_toggle` - Whether left clicks toggle the process' windows instead of showing
+    ///   the menu, see `--click-to-toggle`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the icon cannot be built.
+    fn build_tray(&self, menu: Menu, click_to_toggle: bool) -> anyhow::Result<TrayIcon> {
+        let tray = build_tray(self.tooltip_text(), menu, self.icon()?)?;
+        if self.glyph_title {
+            tray.set_title(Some(self.machine.state().glyph()));
+        }
+        if click_to_toggle {
+            tray.set_show_menu_on_left_click(false);
+        }
+        Ok(tray)
+    }
+
+    /// The icon for the current state, see [`StateMachine::icon`].
+    fn icon(&self) -> anyhow::Result<Icon> {
+        self.machine.icon().build(&self.default_icon)
+    }
+}
+
+/// A line of output as the text of a menu item, shortened to [`MAX_MENU_TEXT`] characters, since
+/// menus don't wrap.
+fn menu_text(line: &str) -> String {
+    match line.char_indices().nth(MAX_MENU_TEXT) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// Rounds an ETA to whole seconds below a minute and to whole minutes above.
+fn round_eta(eta: Duration) -> Duration {
+    let secs = eta.as_secs();
+    if secs < 60 {
+        Duration::from_secs(secs)
+    } else {
+        Duration::from_secs((secs + 30) / 60 * 60)
+    }
+}
+
+fn build_tray(tooltip: impl AsRef<str>, menu: Menu, icon: Icon) -> anyhow::Result<TrayIcon> {
+    TrayIconBuilder::new()
+        .with_menu(Box::new(menu))
+        .with_tooltip(tooltip)
+        .with_icon(icon)
+        .build()
+        .context("Failed to build tray icon")
+        .with_kind(ErrorKind::TrayUnavailable)
+}
+
+/// Where the tray's events come from.
+struct TrayEvents {
+    menu: &'static MenuEventReceiver,
+    /// Clicks on the icon, if they toggle the process' windows.
+    clicks: Option<&'static TrayIconEventReceiver>,
+}
+
+/// The dialogs the tray opens, which run in the background and are checked for answers on every
+/// iteration of the event loop.
+struct Dialogs {
+    env_editor: EnvEditor,
+    /// Confirms Kill and Restart, with `--protected`.
+    protection: Option<Protection<TrayMessage>>,
+    /// Asks for a new name, see [`TrayMessage::Rename`].
+    rename: TextPrompt,
+    /// Asks for a line to send to the process' stdin, see [`TrayMessage::SendInput`].
+    input: TextPrompt,
+    /// Asks for a new command line, see [`TrayMessage::RestartWith`].
+    command: TextPrompt,
+    /// The command line that was typed in, while `protection` confirms restarting with it.
+    confirming_command: Option<String>,
+}
+
+impl Dialogs {
+    /// Creates the dialogs of the instance `supervisor` runs, none of which is open yet.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the environment editor cannot be set up.
+    fn new(supervisor: &Supervisor, instance: &InstanceArgs) -> anyhow::Result<Self> {
+        Ok(Self {
+            env_editor: EnvEditor::new(&supervisor.status().name, instance.profile.clone())?,
+            protection: instance
+                .protected
+                .then(|| Protection::new(instance.confirm.unwrap_or_default())),
+            rename: TextPrompt::default(),
+            input: TextPrompt::default(),
+            command: TextPrompt::default(),
+            confirming_command: None,
+        })
+    }
+}
+
+/// Handles tray events in the event loop. Returns a [`tao::event_loop::ControlFlow`]
+/// to be used by the next iteration of the event loop.
+fn run_event_loop(
+    supervisor: &mut Supervisor,
+    control: &ControlServer,
+    tray: &TrayIcon,
+    status_menu: &mut StatusMenu,
+    dialogs: &mut Dialogs,
+    events: &TrayEvents,
+) -> anyhow::Result<ControlFlow> {
+    supervisor.poll()?;
+    status_menu.update(supervisor, tray)?;
+    match dialogs.env_editor.poll() {
+        Ok(Some(edited)) => apply_env_edit(supervisor, &dialogs.env_editor, edited),
+        Ok(None) => {}
+        Err(e) => {
+            error!("{e:#}");
+            show_notification("Invalid environment", &format!("{e:#}"));
+        }
+    }
+    if let Some(request) = control.try_recv() {
+        supervisor.handle_request(request);
+    }
+    if supervisor.is_ended() {
+        return Ok(exit_flow(supervisor));
+    }
+    if terminated(supervisor)? {
+        return Ok(ControlFlow::Exit);
+    }
+    if let Some(answer) = dialogs.rename.answer() {
+        rename(supervisor, status_menu, tray, answer.trim())?;
+    }
+    if let Some(line) = dialogs.input.answer() {
+        // cancelled, which can't be told apart from an empty line
+        if !line.is_empty() {
+            if let Err(e) = supervisor.send_line(&line) {
+                error!("{e:#}");
+                show_notification("Failed to send input", &format!("{e:#}"));
+            }
+        }
+    }
+    if let Some(answer) = dialogs.command.answer() {
+        let line = answer.trim();
+        // cancelled, or nothing to change
+        if !line.is_empty() && line != supervisor.command_line() {
+            if let Some(protection) = &mut dialogs.protection {
+                dialogs.confirming_command = Some(line.to_string());
+                protection.ask(
+                    &supervisor.status().name,
+                    "restart",
+                    TrayMessage::RestartWith,
+                );
+            } else {
+                restart_with(supervisor, status_menu, tray, line)?;
+            }
+        }
+    }
+    match dialogs.protection.as_mut().and_then(Protection::confirmed) {
+        Some(TrayMessage::Restart) => restart(supervisor),
+        Some(TrayMessage::RestartWith) => {
+            if let Some(line) = dialogs.confirming_command.take() {
+                restart_with(supervisor, status_menu, tray, &line)?;
+            }
+        }
+        Some(_) => {
+            supervisor.kill()?;
+            return Ok(ControlFlow::Exit);
+        }
+        None => {}
+    }
+
+    handle_click(supervisor, events);
+
+    if let Ok(event) = events.menu.try_recv() {
+        debug!("{event:?}");
+
+        if open_url(status_menu, &event.id().0) {
+            return Ok(ControlFlow::Poll);
+        }
+        if let Some(priority) = status_menu.priority.handle(&event.id().0) {
+            if let Err(e) = supervisor.set_priority(priority) {
+                error!("{e:#}");
+                show_notification("Failed to change the priority", &format!("{e:#}"));
+            }
+            return Ok(ControlFlow::Poll);
+        }
+        // a stray event must not take the tray down with it
+        let Ok(msg) = TrayMessage::from_str(&event.id().0) else {
+            warn!("Ignoring unknown menu item '{}'", event.id().0);
+            return Ok(ControlFlow::Poll);
+        };
+
+        let state = status_menu.machine.state();
+        if !state.enables(msg) {
+            info!("Ignoring {msg} while the process is {state}");
+            return Ok(ControlFlow::Poll);
+        }
+        return handle_message(msg, supervisor, status_menu, dialogs);
+    }
+
+    Ok(ControlFlow::Poll)
+}
+
+/// Stops the process like the Kill item does if trayme was interrupted (or leaves it running
+/// with `--detach-on-exit`), or deals with it as the
+/// `--on-logout` policy says if trayme is being terminated, e.g. because the session is ending.
+/// Returns `true` if trayme should exit.
+///
+/// # Errors
+///
+/// An error is returned if the process cannot be stopped.
+fn terminated(supervisor: &mut Supervisor) -> anyhow::Result<bool> {
+    if logout::interrupted() {
+        info!("Interrupted");
+        supervisor.exit()?;
+    } else if logout::session_ending() {
+        supervisor.end_session()?;
+    } else {
+        return Ok(false);
+    }
+    logout::handled();
+    Ok(true)
+}
+
+/// Leaves the process running for trayme to exit, like the Detach item does.
+///
+/// # Errors
+///
+/// An error is returned if the process cannot be let go of.
+fn detach(supervisor: &mut Supervisor) -> anyhow::Result<()> {
+    if supervisor.is_finished() {
+        return Ok(());
+    }
+    let pid = supervisor.status().pid;
+    supervisor.detach()?;
+    show_notification(
+        "Detached",
+        &format!(
+            "PID {pid} keeps running without trayme, its output still goes to {}",
+            supervisor.status().log_file.display()
+        ),
+    );
+    Ok(())
+}
+
+/// Handles a click on one of the tray's [`TrayMessage`] items.
+fn handle_message(
+    msg: TrayMessage,
+    supervisor: &mut Supervisor,
+    status_menu: &mut StatusMenu,
+    dialogs: &mut Dialogs,
+) -> anyhow::Result<ControlFlow> {
+    match msg {
+        TrayMessage::Restart => {
+            if let Some(protection) = &mut dialogs.protection {
+                protection.ask(&supervisor.status().name, "restart", msg);
+            } else {
+                restart(supervisor);
+            }
+        }
+        TrayMessage::RestartWith => {
+            let prompt = format!(
+                "Restart '{}' with this command line:",
+                supervisor.status().name
+            );
+            dialogs.command.ask(&prompt, &supervisor.command_line());
+        }
+        TrayMessage::Kill => {
+            if let Some(protection) = &mut dialogs.protection {
+                protection.ask(&supervisor.status().name, "kill", msg);
+            } else {
+                supervisor.kill()?;
+                return Ok(ControlFlow::Exit);
+            }
+        }
+        TrayMessage::Detach => {
+            detach(supervisor)?;
+            return Ok(ControlFlow::Exit);
+        }
+        TrayMessage::ShowLogs => show_logs(supervisor)?,
+        TrayMessage::RotateLog => match supervisor.rotate_log() {
+            Ok(path) => show_notification(
+                "Log rotated",
+                &format!("Output now goes to {}", path.display()),
+            ),
+            Err(e) => {
+                error!("{e:#}");
+                show_notification("Failed to rotate log", &format!("{e:#}"));
+            }
+        },
+        TrayMessage::PurgeLogs => purge_logs(supervisor, status_menu),
+        TrayMessage::OpenTempDir => open_temp_dir(supervisor),
+        TrayMessage::Console => {
+            if let Err(e) = console::open_console(&supervisor.status().name) {
+                error!("{e:#}");
+                show_notification("Failed to open console", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::SendInput => {
+            let prompt = format!("Line to send to '{}':", supervisor.status().name);
+            dialogs.input.ask(&prompt, "");
+        }
+        TrayMessage::Environment => {
+            if let Err(e) = dialogs.env_editor.open(supervisor.env_overrides()) {
+                error!("{e:#}");
+                show_notification("Failed to open environment", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::Pause | TrayMessage::Resume => {
+            let result = if msg == TrayMessage::Pause {
+                supervisor.pause()
+            } else {
+                supervisor.resume()
+            };
+            if let Err(e) = result {
+                error!("{e:#}");
+                show_notification("Failed to pause or resume", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::ShowWindow | TrayMessage::HideWindow => {
+            let result = if msg == TrayMessage::ShowWindow {
+                supervisor.show_window()
+            } else {
+                supervisor.hide_window()
+            };
+            if let Err(e) = result {
+                error!("{e:#}");
+                show_notification("Failed to change window", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::EnvDiff => {
+            if let Err(e) = envdiff::open_report(&supervisor.status().name, supervisor.env()) {
+                error!("{e:#}");
+                show_notification("Failed to show environment diff", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::Maintenance => {
+            if let Err(e) = supervisor.toggle_maintenance() {
+                error!("{e:#}");
+                show_notification("Failed to start maintenance", &format!("{e:#}"));
+            }
+        }
+        TrayMessage::Rename => {
+            let prompt = format!("New name for '{}':", supervisor.status().name);
+            dialogs.rename.ask(&prompt, &supervisor.status().name);
+        }
+        TrayMessage::Relaunch => relaunch(supervisor),
+        TrayMessage::Quit => return Ok(exit_flow(supervisor)),
+    }
+    Ok(ControlFlow::Poll)
+}
+
+/// Opens the directory of the instance's log file.
+///
+/// # Errors
+///
+/// An error is returned if the directory cannot be opened.
+fn show_logs(supervisor: &Supervisor) -> anyhow::Result<()> {
+    let logs_dir = match supervisor.status().log_file.parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => get_logs_dir()?,
+    };
+    open::that(logs_dir).context("Failed to open logs dir")
+}
+
+/// Opens the temporary directory of the current or last run, or notifies that there's none to
+/// open.
+fn open_temp_dir(supervisor: &Supervisor) {
+    let result = match supervisor.temp_dir() {
+        Some(dir) if dir.is_dir() => open::that(dir).context("Failed to open temp dir"),
+        Some(dir) => Err(anyhow::anyhow!("{} was deleted", dir.display())),
+        None => Err(anyhow::anyhow!("The run has no temporary directory")),
+    };
+    if let Err(e) = result {
+        error!("{e:#}");
+        show_notification("Failed to open temp dir", &format!("{e:#}"));
+    }
+}
+
+/// Renames the instance to what was typed into the "Rename…" dialog, and shows the new name in
+/// the tooltip and the menu's header. Nothing happens if the dialog was cancelled.
+///
+/// # Errors
+///
+/// An error is returned if the tooltip cannot be updated.
+fn rename(
+    supervisor: &mut Supervisor,
+    status_menu: &mut StatusMenu,
+    tray: &TrayIcon,
+    name: &str,
+) -> anyhow::Result<()> {
+    if name.is_empty() || name == supervisor.status().name {
+        return Ok(());
+    }
+    let renamed = cli::parse_instance_name(name)
+        .map_err(anyhow::Error::msg)
+        .and_then(|name| supervisor.rename(name));
+    match renamed {
+        Ok(()) => {
+            status_menu.set_tooltip(name, tray)?;
+            status_menu.header.set_text(name);
+            show_notification("Instance renamed", &format!("Now called '{name}'"));
+        }
+        Err(e) => {
+            error!("{e:#}");
+            show_notification("Failed to rename", &format!("{e:#}"));
+        }
+    }
+    Ok(())
+}
+
+/// Restarts the process from the tray. The tray stays up, unless the process couldn't be started
+/// again, in which case the instance is finished and the next iteration exits.
+fn restart(supervisor: &mut Supervisor) {
+    if let Err(e) = supervisor.restart() {
+        error!("{e:#}");
+        show_notification("Failed to restart", &format!("{e:#}"));
+    }
+}
+
+/// Starts the command again from the tray once the process exited, with `--stay-open`.
+fn relaunch(supervisor: &mut Supervisor) {
+    if let Err(e) = supervisor.relaunch() {
+        error!("{e:#}");
+        show_notification("Failed to relaunch", &format!("{e:#}"));
+    }
+}
+
+/// Exits the event loop with the exit code of the instance, see [`Supervisor::exit_code`].
+fn exit_flow(supervisor: &Supervisor) -> ControlFlow {
+    ControlFlow::ExitWithCode(supervisor.exit_code().unwrap_or_default())
+}
+
+/// Restarts the process with the command line typed into the "Restart With Arguments…" dialog.
+/// The tooltip shows the new command, unless the instance was renamed. As with Restart, the tray
+/// stays up unless the new command couldn't be started.
+///
+/// # Errors
+///
+/// An error is returned if the tooltip cannot be updated.
+fn restart_with(
+    supervisor: &mut Supervisor,
+    status_menu: &mut StatusMenu,
+    tray: &TrayIcon,
+    line: &str,
+) -> anyhow::Result<()> {
+    let shown = status_menu.tooltip == osargs::display(&supervisor.status().cmd);
+    if let Err(e) = supervisor.restart_with(line) {
+        error!("{e:#}");
+        show_notification("Failed to restart", &format!("{e:#}"));
+    } else if shown {
+        status_menu.set_tooltip(&osargs::display(&supervisor.status().cmd), tray)?;
+    }
+    Ok(())
+}
+
+/// Opens the URL of an item of the "Open…" submenu, or shows its QR code for an item of the
+/// "Show QR…" submenu. Returns whether the item was one of them.
+fn open_url(status_menu: &StatusMenu, id: &str) -> bool {
+    status_menu.urls.handle(id).unwrap_or_else(|e| {
+        error!("{e:#}");
+        show_notification("Failed to open URL", &format!("{e:#}"));
+        true
+    })
+}
+
+/// Toggles the process' windows if the icon was left-clicked, with `--click-to-toggle`.
+fn handle_click(supervisor: &mut Supervisor, events: &TrayEvents) {
+    let clicked = events.clicks.and_then(|clicks| clicks.try_recv().ok());
+    if let Some(TrayIconEvent::Click {
+        button: MouseButton::Left,
+        button_state: MouseButtonState::Up,
+        ..
+    }) = clicked
+    {
+        if let Err(e) = supervisor.toggle_window() {
+            error!("{e:#}");
+            show_notification("Failed to toggle window", &format!("{e:#}"));
+        }
+    }
+}
+
+/// Deletes the logs of the instance's previous runs and rotations, keeping the one the process
+/// writes to, and shows how much space that freed.
+fn purge_logs(supervisor: &Supervisor, status_menu: &mut StatusMenu) {
+    match status_menu.log_usage.purge(&supervisor.status().log_file) {
+        Ok((deleted, freed)) => show_notification(
+            "Old logs deleted",
+            &format!(
+                "Deleted {deleted} logs, freeing {}",
+                usage::format_bytes(freed)
+            ),
+        ),
+        Err(e) => {
+            error!("{e:#}");
+            show_notification("Failed to delete old logs", &format!("{e:#}"));
+        }
+    }
+}
+
+/// Applies an edit made with the "Environment…" dialog: the new variables are used from the next
+/// restart on, and are saved back to the profile if requested.
+fn apply_env_edit(supervisor: &mut Supervisor, env_editor: &EnvEditor, edited: EnvFile) {
+    let saved = match (edited.save_to_profile, env_editor.profile()) {
+        (false, _) => Ok(false),
+        (true, Some(profile)) => config::save_profile_env(profile, &edited.env).map(|()| true),
+        (true, None) => Err(anyhow::anyhow!(
+            "This instance wasn't started from a profile"
+        )),
+    };
+    supervisor.set_env_overrides(edited.env);
+    match saved {
+        Ok(true) => show_notification(
+            "Environment updated",
+            "Saved to the profile. Takes effect on the next restart.",
+        ),
+        Ok(false) => show_notification("Environment updated", "Takes effect on the next restart."),
+        Err(e) => {
+            error!("{e:#}");
+            show_notification(
+                "Environment updated, not saved",
+                &format!("Takes effect on the next restart. {e:#}"),
+            );
+        }
+    }
+}
+
+pub(crate) fn get_logs_dir() -> anyhow::Result<PathBuf> {
+    // so that tests which start processes don't add to the user's logs and history
+    #[cfg(test)]
+    let logs_dir = std::env::temp_dir().join(format!(
+        "{}-test-{}",
+        env!("CARGO_PKG_NAME"),
+        std::process::id()
+    ));
+    #[cfg(not(test))]
+    let logs_dir = dirs::data_dir()
+        .context("Failed to get data directory")?
+        .join(env!("CARGO_PKG_NAME"));
+    std::fs::create_dir_all(&logs_dir).context("Failed to create logs directory")?;
+    Ok(logs_dir)
+}
+
+/// Prints the run history to stdout, most recent first.
+fn print_history() -> anyhow::Result<()> {
+    for run in history::list_runs()? {
+        let started = run.started_at.format("%Y-%m-%d %H:%M:%S");
+        let status = run.exit_status.as_deref().unwrap_or("running");
+        match run.usage {
+            Some(usage) => println!(
+                "{}  {started}  [{status}]  {}  ({usage})",
+                run.id,
+                osargs::display(&run.cmd)
+            ),
+            None => println!(
+                "{}  {started}  [{status}]  {}",
+                run.id,
+                osargs::display(&run.cmd)
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Sets the options of `instance` that decide how its command is spawned on `spec`.
+fn configure_spec(spec: &mut CommandSpec, instance: &InstanceArgs) {
+    spec.env_provider.clone_from(&instance.env_provider);
+    spec.tz.clone_from(&instance.tz);
+    spec.locale.clone_from(&instance.locale);
+    spec.ulimits.clone_from(&instance.ulimits);
+    spec.pre_check.clone_from(&instance.pre_check);
+    spec.elevate = instance.elevate;
+    spec.caps = ResourceCaps::new(instance);
+    // a rerun gets a new temporary directory if the run had one
+    spec.temp_dir = instance.temp_dir.or(spec.temp_dir);
+    if cfg!(windows) {
+        spec.interpreters =
+            shebang::configured(instance.profile.as_ref().map(|p| p.config.as_path()));
+    }
+}
+
+/// Sets up when the process of `supervisor` is restarted, and what with, per `instance`.
+fn configure_restarts(supervisor: &mut Supervisor, instance: &InstanceArgs) {
+    // the fallback is only started by restarting
+    let policy = instance
+        .restart_policy
+        .or(instance.fallback.as_ref().map(|_| RestartPolicy::OnFailure));
+    if let Some(policy) = policy {
+        supervisor.set_restart_policy(RestartBackoff::new(
+            policy,
+            instance.restart_backoff.unwrap_or(restart::DEFAULT_BACKOFF),
+            instance.max_restarts,
+        ));
+    }
+    if let Some(fallback) = &instance.fallback {
+        supervisor.set_fallback(Fallback::new(
+            osargs::from_strings(precheck::shell_argv(fallback)),
+            instance
+                .fallback_after
+                .unwrap_or(restart::DEFAULT_FALLBACK_AFTER),
+        ));
+    }
+    if let Some(planned) =
+        PlannedRestarts::new(instance.restart_every, instance.restart_cron.clone())
+    {
+        supervisor.set_planned_restarts(planned);
+    }
+}
+
+/// Stops the process along with trayme when trayme is interrupted or the session ends, see
+/// [`logout::watch`]. Embedded instances leave the signals to the app, see [`ffi`].
+fn watch_logout() {
+    if let Err(e) = logout::watch() {
+        warn!("The process will outlive trayme if it's terminated: {e:#}");
+    }
+}
+
+/// Spawns the command, or takes its process over if this trayme was started by `trayme
+/// self-restart`, see [`Supervisor::adopt`].
+///
+/// # Errors
+///
+/// An error is returned if the process cannot be started or taken over.
+fn start_or_adopt(
+    name: String,
+    spec: CommandSpec,
+    notifier: Notifier,
+) -> anyhow::Result<Supervisor> {
+    #[cfg(unix)]
+    if let Some(handover) = handover::take() {
+        return Supervisor::adopt(name, spec, notifier, handover);
+    }
+    Supervisor::start(name, spec, notifier)
+}
+
+/// What the command waits for before it starts (see [`Waiter`]), unless its process was handed
+/// over to this trayme, which it was already started for.
+fn waiter(instance: &InstanceArgs) -> Option<Waiter> {
+    #[cfg(unix)]
+    if handover::pending() {
+        return None;
+    }
+    Waiter::new(instance)
+}
+
+/// Binds the control socket, spawns the command, and registers the instance so that it can be
+/// controlled from other trayme processes.
+fn start_instance(
+    mut spec: CommandSpec,
+    notifier: Notifier,
+    instance: &InstanceArgs,
+) -> anyhow::Result<(Supervisor, ControlServer)> {
+    let name = instance.name.clone().unwrap_or_else(|| spec.program_name());
+    configure_spec(&mut spec, instance);
+    let on_wake = WakeAction::new(instance.on_wake, instance.wake_check.as_deref())?;
+    let control = ControlServer::bind(instance.listen)?;
+    let registration = registry::register(&Registration {
+        name: name.clone(),
+        pid: std::process::id(),
+        addr: control.addr(),
+        cmd: spec.cmd.clone(),
+        tags: instance.tags.clone(),
+    })?;
+    let chain = (!instance.then.is_empty()).then(|| {
+        Chain::new(
+            spec.cmd.clone(),
+            instance.then.clone(),
+            instance.continue_on_failure,
+        )
+    });
+    let mut supervisor = start_or_adopt(name, spec, notifier)?;
+    supervisor.set_registration(registration);
+    if let Some(chain) = chain {
+        supervisor.set_chain(chain);
+    }
+    supervisor.set_subscribers(control.subscribers());
+    supervisor.set_stop_strategy(instance.stop_strategy.unwrap_or_default());
+    if let Some(timeout) = instance.kill_timeout {
+        supervisor.set_kill_timeout(timeout);
+    }
+    supervisor.set_verbose_exit(instance.verbose_exit);
+    supervisor.set_first_output_notify(instance.first_output_notify);
+    supervisor.set_compress_rotated_logs(instance.compress_rotated_logs);
+    supervisor.set_kill_disabled(instance.no_kill_menu);
+    supervisor.set_handover_allowed(true);
+    if let Some(duration) = instance.maintenance_duration {
+        supervisor.set_maintenance_duration(duration);
+    }
+    configure_restarts(&mut supervisor, instance);
+    supervisor.watch_sleep(on_wake);
+    if let Some(timeout) = instance.timeout {
+        supervisor.set_timeout(timeout);
+    }
+    if let Some(url) = instance.ping_url.clone() {
+        supervisor.set_ping_url(url);
+    }
+    if let Some(headroom) = Headroom::new(instance) {
+        supervisor.set_headroom(headroom);
+    }
+    if let Some(hooks) = Hooks::new(instance) {
+        supervisor.set_hooks(hooks);
+    }
+    if let Some(source) = instance.constraints.busy_calendar.clone() {
+        supervisor.set_busy_calendar(BusyCalendar::watch(source));
+    }
+    if instance.start_hidden || instance.click_to_toggle {
+        supervisor.set_window_control(instance.start_hidden);
+    }
+    if let Some(pattern) = instance.unhealthy_if.clone() {
+        let health = HealthCheck::new(pattern, instance.threshold.unwrap_or_default());
+        supervisor.set_health_check(health, instance.restart_on_unhealthy);
+    }
+    if let Some(pattern) = instance.progress_regex.clone() {
+        supervisor.set_progress_pattern(pattern);
+    }
+    if let Some(percent) = instance.cpu_throttle {
+        supervisor.set_cpu_throttle(percent);
+    }
+    if let Some(priority) = instance.priority {
+        if let Err(e) = supervisor.set_priority(priority) {
+            warn!("{e:#}, running at the normal priority");
+        }
+    }
+    if let Some(policy) = instance.on_logout {
+        supervisor.set_on_logout(policy);
+    }
+    if instance.detach_on_exit {
+        supervisor.set_detach_on_exit();
+    }
+    if instance.stay_open {
+        supervisor.set_stay_open();
+    }
+    if let Some(profile) = &instance.profile {
+        match reload::ProfileWatcher::new(profile.clone()) {
+            Ok(watcher) => supervisor.set_profile_watcher(watcher),
+            Err(e) => warn!("Changes to the profile won't be applied while it runs: {e:#}"),
+        }
+    }
+    Ok((supervisor, control))
+}
+
+/// Runs the given command in the tray until it exits or is killed.
+fn run_in_tray(
+    spec: CommandSpec,
+    notifier: Notifier,
+    instance: &InstanceArgs,
+) -> anyhow::Result<()> {
+    let full_cmd_string = osargs::display(&spec.cmd);
+
+    if let Some(timeout) = instance.wait_for_tray {
+        if !readiness::wait_for_tray(timeout) {
+            warn!(
+                "No tray showed up within {}, showing the icon anyway",
+                humantime::format_duration(timeout)
+            );
+        }
+    }
+    let mut event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = Menu::new();
+    let actions = ActionMenu::new(&menu, &menu_messages(instance))?;
+    // without a name, two instances of the same program still get different icons
+    let seed = instance.name.as_deref().unwrap_or(&full_cmd_string);
+    let icon = icon::identicon(seed)?;
+    let name = instance.name.clone().unwrap_or_else(|| spec.program_name());
+    let header = menu_header(&name, seed)?;
+    let mut status_menu = StatusMenu::new(
+        &full_cmd_string,
+        header,
+        instance,
+        icon,
+        spec.program_name(),
+        actions,
+    )?;
+    let urls = &status_menu.urls;
+    menu.prepend_items(&[
+        &status_menu.header,
+        &PredefinedMenuItem::separator(),
+        &status_menu.submenu,
+        &urls.submenu,
+        &urls.qr_submenu,
+        &status_menu.priority.submenu,
+    ])?;
+    let click_to_toggle = instance.click_to_toggle;
+    let mut tray = Some(status_menu.build_tray(menu.clone(), click_to_toggle)?);
+    let mut tray_host = TrayHostWatcher::spawn();
+    let events = TrayEvents {
+        menu: MenuEvent::receiver(),
+        clicks: instance.click_to_toggle.then(TrayIconEvent::receiver),
+    };
+
+    if let Some(mut waiter) = waiter(instance) {
+        let tooltip = status_menu.tooltip_text();
+        // Start Now goes below the header and its separator
+        let icon = tray.as_ref().context("The tray icon is gone")?;
+        if !waiter.wait_in_tray(
+            &mut event_loop,
+            &spec,
+            &notifier,
+            icon,
+            &tooltip,
+            (&menu, 2),
+        )? {
+            return Ok(());
+        }
+        icon.set_tooltip(Some(tooltip))
+            .context("Failed to update tooltip")?;
+    }
+    let (mut supervisor, control) = start_instance(spec, notifier, instance)?;
+    watch_logout();
+    let mut dialogs = Dialogs::new(&supervisor, instance)?;
+    readiness::notify_ready();
+
+    event_loop.run(move |event, _window, control_flow| {
+        if let Event::LoopDestroyed = event {
+            // `run` exits the process right after this, without returning to `main`
+            notifyroute::wait_for_pending();
+            return;
+        }
+        // tao doesn't exit immediately anymore, so this
+        // guard is here to prevent spamming notifications
+        // and logs.
+        if matches!(*control_flow, ControlFlow::ExitWithCode(_)) {
+            return;
+        }
+        if tray_host.restarted() {
+            // only one icon is ever kept, so the old one is removed as soon as the new one is up
+            match status_menu.build_tray(menu.clone(), click_to_toggle) {
+                Ok(rebuilt) => tray = Some(rebuilt),
+                Err(e) => {
+                    warn!("{e:#}, trying again");
+                    tray_host.retry();
+                }
+            }
+        }
+        let Some(icon) = tray.as_ref() else {
+            return;
+        };
+        match run_event_loop(
+            &mut supervisor,
+            &control,
+            icon,
+            &mut status_menu,
+            &mut dialogs,
+            &events,
+        ) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
+
+/// Runs the given command without a tray icon or notifications until it exits or is killed over
+/// IPC. This is meant for running trayme as a system service, with `trayme tray <NAME>` providing
+/// the tray icon from a desktop session.
+///
+/// # Arguments
+///
+/// * `stop_requested` - Checked on every iteration. Once it returns `true`, the process is killed.
+///
+/// Returns the exit code to pass on, see [`Supervisor::exit_code`].
+fn run_headless(
+    spec: CommandSpec,
+    mut notifier: Notifier,
+    instance: &InstanceArgs,
+    stop_requested: impl Fn() -> bool,
+) -> anyhow::Result<Option<i32>> {
+    // there's usually no notification server outside of a desktop session
+    notifier.mute();
+    if let Some(mut waiter) = waiter(instance) {
+        waiter.wait(&spec, &notifier)?;
+    }
+    let (mut supervisor, control) = start_instance(spec, notifier, instance)?;
+    watch_logout();
+    readiness::notify_ready();
+    while !supervisor.is_finished() {
+        if let Some(request) = control.try_recv() {
+            supervisor.handle_request(request);
+        }
+        if stop_requested() {
+            supervisor.kill()?;
+            break;
+        }
+        if terminated(&mut supervisor)? {
+            break;
+        }
+        supervisor.poll()?;
+        thread::sleep(HEADLESS_POLL_INTERVAL);
+    }
+    Ok(supervisor.exit_code())
+}
+
+#[cfg(windows)]
+fn run_service_action(action: cli::ServiceAction) -> anyhow::Result<()> {
+    use cli::ServiceAction;
+
+    match action {
+        ServiceAction::Install {
+            profile,
+            config,
+            listen,
+            auto_start,
+        } => service::install(&profile, config.config.as_deref(), listen, auto_start),
+        ServiceAction::Start { profile } => service::start(&profile),
+        ServiceAction::Stop { profile } => service::stop(&profile),
+        ServiceAction::Uninstall { profile } => service::uninstall(&profile),
+        ServiceAction::Run {
+            profile,
+            config,
+            listen,
+        } => service::run(profile, config, listen),
+    }
+}
+
+/// Runs `token create`, `token ls`, or `token revoke`.
+fn run_token_action(action: cli::TokenAction) -> anyhow::Result<()> {
+    use cli::TokenAction;
+
+    match action {
+        TokenAction::Create { name, scope } => {
+            let secret = token::create(&name, scope)?;
+            println!("{secret}");
+            eprintln!("This is the only time the secret is shown.");
+        }
+        TokenAction::Ls => {
+            for token in token::list()? {
+                println!(
+                    "{}\t{}\t{}",
+                    token.name,
+                    token.scope,
+                    token.created_at.format("%Y-%m-%d %H:%M")
+                );
+            }
+        }
+        TokenAction::Revoke { name } => token::revoke(&name)?,
+    }
+    Ok(())
+}
+
+/// Runs `export-setup` or `import-setup`.
+fn run_setup_command(command: CliSubcommand) -> anyhow::Result<()> {
+    match command {
+        CliSubcommand::ExportSetup { file, config } => {
+            setup::export(&file, config.config.as_deref())
+        }
+        CliSubcommand::ImportSetup {
+            file,
+            config,
+            overwrite,
+        } => setup::import(&file, config.config.as_deref(), overwrite),
+        _ => unreachable!("not a setup command"),
+    }
+}
+
+/// Runs `trayme clip`.
+fn run_clip(
+    pattern: regex::Regex,
+    queue: &cli::QueueArgs,
+    cmd: Vec<OsString>,
+) -> anyhow::Result<()> {
+    clipboard::run_clipboard_trigger(pattern, cmd, Notifier::default(), queue.max_concurrent)
+}
+
+/// Runs the command of an instance with `--watch-inbox` for every file in the inbox.
+///
+/// # Errors
+///
+/// An error is returned if the instance's options don't work with an inbox, or if
+/// [`inbox::run_inbox`] fails.
+fn run_watched_inbox(
+    dir: PathBuf,
+    spec: CommandSpec,
+    notifier: Notifier,
+    instance: &InstanceArgs,
+) -> anyhow::Result<()> {
+    if !instance.then.is_empty() {
+        bail!("--watch-inbox runs one command for every file, so it can't be used with --then");
+    }
+    let max_concurrent = instance.max_concurrent.unwrap_or(NonZeroUsize::MIN);
+    inbox::run_inbox(dir, spec, notifier, max_concurrent, instance.headless)
+}
+
+/// Prints the systemd unit for `trayme systemd-unit`.
+#[cfg(target_os = "linux")]
+fn print_systemd_unit(profile: &str, config: Option<&std::path::Path>) -> anyhow::Result<()> {
+    // the unit doesn't start in the current directory
+    let config = config
+        .map(|path| {
+            path.canonicalize()
+                .with_context(|| format!("Failed to resolve {}", path.display()))
+        })
+        .transpose()?;
+    config::load_config(config.as_deref())?.profile(profile)?;
+    let exe = std::env::current_exe().context("Failed to get trayme's path")?;
+    print!(
+        "{}",
+        readiness::systemd_unit(&exe, profile, config.as_deref())
+    );
+    Ok(())
+}
+
+/// Runs `trayme schedule`.
+fn run_schedule(
+    schedule: &cli::ScheduleArgs,
+    queue: &cli::QueueArgs,
+    cmd: Vec<OsString>,
+) -> anyhow::Result<()> {
+    let name = schedule
+        .name
+        .clone()
+        .unwrap_or_else(|| program_name(&cmd[0].to_string_lossy()));
+    let mut scheduler = schedule::Scheduler::new(
+        &name,
+        schedule.schedule(),
+        schedule.missed,
+        schedule.constraints.constraints(),
+    )?;
+    let mut notifier = Notifier::default();
+    if let Some(window) = schedule.expect_success_within {
+        scheduler.set_expect_success_within(window);
+        // only failures and the overdue alert are worth hearing about
+        notifier.silence(NotifyEvent::Start);
+        notifier.silence(NotifyEvent::Exit);
+    }
+    let wake = schedule.wake.then(|| wake::WakeTimer::new(&name));
+    schedule::run_scheduled(
+        scheduler,
+        schedule.overlap,
+        wake,
+        cmd,
+        notifier,
+        queue.max_concurrent,
+        schedule.ping_url.clone(),
+    )
+}
+
+/// Runs trayme with the command line it was started with, as the `trayme` binary does.
+#[must_use]
+pub fn main() -> ExitCode {
+    let args = CliArgs::parse();
+    let error_format = args.error_format;
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => exitcode::report(&e, error_format),
+    }
+}
+
+/// Gets trayme itself ready to carry out `args`: its own log, the layout of its files, and the
+/// display backend.
+///
+/// # Errors
+///
+/// An error is returned if logging cannot be set up.
+fn set_up(args: &CliArgs) -> anyhow::Result<()> {
+    // before logging starts, which it has to be on for
+    if args.trace_spawn {
+        spawntrace::enable();
+    }
+    selflog::init()?;
+    debug!("{args:#?}");
+    if args
+        .subcommand
+        .as_ref()
+        .is_none_or(CliSubcommand::upgrades_layout)
+    {
+        layout::upgrade();
+    }
+    display::select(args.display_backend);
+    Ok(())
+}
+
+fn run(args: CliArgs) -> anyhow::Result<()> {
+    set_up(&args)?;
+    let (spec, notifier, instance) = match args.subcommand {
+        Some(CliSubcommand::History) => return print_history(),
+        Some(CliSubcommand::Doctor) => return doctor::run(),
+        Some(CliSubcommand::Migrate { dry_run }) => return layout::migrate(dry_run),
+        Some(CliSubcommand::Tray {
+            instance: Some(instance),
+        }) => return remote::run_frontend(instance),
+        Some(CliSubcommand::Tray { instance: None }) => return remote::run_aggregator(),
+        Some(CliSubcommand::Ls { tags }) => return fleet::print_instances(&tags),
+        Some(CliSubcommand::Events {
+            instances,
+            follow,
+            json,
+        }) => return events::print(&instances, follow, json),
+        Some(CliSubcommand::Statusline { format, follow }) => {
+            return statusline::print(format, follow)
+        }
+        Some(CliSubcommand::Down { names, tags, force }) => {
+            return fleet::stop_instances(&names, &tags, force)
+        }
+        Some(CliSubcommand::SelfRestart { names, tags }) => {
+            return fleet::hand_over_instances(&names, &tags)
+        }
+        Some(CliSubcommand::Maintenance {
+            names,
+            tags,
+            duration,
+            off,
+        }) => return fleet::set_maintenance(&names, &tags, duration, off),
+        Some(CliSubcommand::Console { instance }) => return console::run_console(&instance),
+        Some(CliSubcommand::Send { instance, lines }) => {
+            return console::send_lines(&instance, &lines)
+        }
+        Some(CliSubcommand::SelfLogs {
+            follow,
+            lines,
+            level,
+            names,
+        }) => return selflog::run(&names, level, lines, follow),
+        Some(CliSubcommand::Attach { pid, name }) => return attach::run_attached(pid, name),
+        Some(CliSubcommand::Clip {
+            pattern,
+            queue,
+            cmd,
+        }) => return run_clip(pattern, &queue, cmd),
+        #[cfg(any(windows, target_os = "macos"))]
+        Some(CliSubcommand::Drop { queue, cmd }) => {
+            return dropzone::run_drop_window(cmd, Notifier::default(), queue.max_concurrent)
+        }
+        Some(CliSubcommand::Schedule {
+            schedule,
+            queue,
+            cmd,
+        }) => return run_schedule(&schedule, &queue, cmd),
+        Some(CliSubcommand::Rerun { run_id, instance }) => {
+            (rerun_spec(&run_id)?, Notifier::default(), instance)
+        }
+        Some(CliSubcommand::Up {
+            profiles,
+            config,
+            mut instance,
+        }) => {
+            let loaded = config::load_config(config.config.as_deref())?;
+            let selected = loaded.select(&profiles, &instance.tags)?;
+            if !instance.tags.is_empty() || selected.len() > 1 {
+                let names: Vec<_> = selected.iter().map(|(name, _)| *name).collect();
+                return fleet::spawn_profiles(&names, config.config.as_deref(), &instance);
+            }
+            let (name, profile) = selected[0];
+            profile
+                .apply_to(name, &mut instance)
+                .with_kind(ErrorKind::Config)?;
+            instance.profile = Some(config::ProfileRef {
+                name: name.to_string(),
+                config: config.config.map_or_else(config::config_path, Ok)?,
+            });
+            (profile.to_spec(), profile.notifier(), instance)
+        }
+        Some(command @ (CliSubcommand::ExportSetup { .. } | CliSubcommand::ImportSetup { .. })) => {
+            return run_setup_command(command)
+        }
+        #[cfg(windows)]
+        Some(CliSubcommand::Service { action }) => return run_service_action(action),
+        #[cfg(target_os = "linux")]
+        Some(CliSubcommand::SystemdUnit { profile, config }) => {
+            return print_systemd_unit(&profile, config.config.as_deref())
+        }
+        Some(CliSubcommand::Token { action }) => return run_token_action(action),
+        #[cfg(unix)]
+        Some(CliSubcommand::RelayOutput { log }) => return capture::relay(&log),
+        None => run_args_spec(args.run)?,
+    };
+    run_to_end(spec, notifier, &instance)
+}
+
+/// Runs the instance, or its inbox with `--watch-inbox`, and exits with the command's exit code
+/// if it failed.
+///
+/// # Errors
+///
+/// An error is returned if the instance cannot be run.
+fn run_to_end(
+    spec: CommandSpec,
+    notifier: Notifier,
+    instance: &InstanceArgs,
+) -> anyhow::Result<()> {
+    if let Some(dir) = instance.watch_inbox.clone() {
+        return run_watched_inbox(dir, spec, notifier, instance);
+    }
+    let result = run_instance(spec, notifier, instance);
+    notifyroute::wait_for_pending();
+    match result {
+        // the instance is over, so nothing is left to clean up
+        Ok(Some(code)) if code != 0 => process::exit(code),
+        result => result.map(|_| ()),
+    }
+}
+
+/// The command, notifier, and instance options of running a command without a subcommand.
+///
+/// # Errors
+///
+/// An error is returned if a `--then` isn't followed by a command.
+fn run_args_spec(run: RunArgs) -> anyhow::Result<(CommandSpec, Notifier, InstanceArgs)> {
+    let mut env_overrides: BTreeMap<_, _> = run.env_files.into_iter().flatten().collect();
+    env_overrides.extend(run.env);
+    let (cmd, then) = chain::split(run.cmd).context("Every --then needs a command after it")?;
+    let mut instance = run.instance;
+    instance.then = then;
+    let spec = CommandSpec {
+        cmd,
+        cwd: run.cwd,
+        env: run.clear_env.then(dotenv::clean),
+        env_overrides,
+        env_provider: None,
+        tz: None,
+        locale: None,
+        ulimits: Vec::new(),
+        pre_check: None,
+        shell: run.shell,
+        elevate: false,
+        temp_dir: None,
+        interpreters: BTreeMap::new(),
+        caps: None,
+    };
+    let notifier = Notifier::new(run.notify_urgency, run.notify_sound);
+    Ok((spec, notifier, instance))
+}
+
+/// The command of a past run, warning if its binary changed since.
+///
+/// # Errors
+///
+/// An error is returned if the run cannot be loaded.
+fn rerun_spec(run_id: &str) -> anyhow::Result<CommandSpec> {
+    let record = RunRecord::load(run_id)?;
+    if record.binary_changed() {
+        warn!("Binary {:?} changed since run {run_id}", record.binary);
+        show_notification(
+            "Binary changed",
+            &format!(
+                "{} differs from run {run_id}",
+                record.cmd[0].to_string_lossy()
+            ),
+        );
+    }
+    Ok(record.to_spec())
+}
+
+/// Runs the command headless or in the tray, unless its constraints don't allow it to start
+/// right now. Returns the exit code to pass on, see [`Supervisor::exit_code`]; the tray exits
+/// with it by itself.
+///
+/// # Errors
+///
+/// An error is returned if the instance fails to run.
+fn run_instance(
+    spec: CommandSpec,
+    notifier: Notifier,
+    instance: &InstanceArgs,
+) -> anyhow::Result<Option<i32>> {
+    let now = chrono::Local::now();
+    if let Some(blocked) = instance.constraints.constraints().blocked_at(now) {
+        let program = spec.program_name();
+        info!("Not starting {program}: {blocked}");
+        println!("Not starting {program}: {blocked}");
+        return Ok(None);
+    }
+    if instance.headless {
+        run_headless(spec, notifier, instance, || false)
+    } else {
+        run_in_tray(spec, notifier, instance).map(|()| None)
+    }
+}
@@ -0,0 +1,280 @@
+use std::{fmt, process::Command, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A resource whose limit can be set for the child with `--ulimit`, named as in `ulimit` and
+/// `prlimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Open file descriptors.
+    Nofile,
+    /// Processes (and threads) of the user.
+    Nproc,
+    /// The size of core dumps, in bytes.
+    Core,
+    /// The size of the main thread's stack, in bytes.
+    Stack,
+    /// Memory locked into RAM, in bytes.
+    Memlock,
+    /// The size of the address space, in bytes.
+    As,
+    /// CPU time, in seconds.
+    Cpu,
+    /// The size of files written, in bytes.
+    Fsize,
+}
+
+impl Resource {
+    const ALL: [Resource; 8] = [
+        Resource::Nofile,
+        Resource::Nproc,
+        Resource::Core,
+        Resource::Stack,
+        Resource::Memlock,
+        Resource::As,
+        Resource::Cpu,
+        Resource::Fsize,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Nofile => "nofile",
+            Resource::Nproc => "nproc",
+            Resource::Core => "core",
+            Resource::Stack => "stack",
+            Resource::Memlock => "memlock",
+            Resource::As => "as",
+            Resource::Cpu => "cpu",
+            Resource::Fsize => "fsize",
+        }
+    }
+}
+
+/// One side of a resource limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Value(u64),
+    Unlimited,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Value(value) => write!(f, "{value}"),
+            Limit::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(Limit::Unlimited);
+        }
+        s.parse()
+            .map(Limit::Value)
+            .map_err(|e| format!("invalid limit '{s}': {e}"))
+    }
+}
+
+/// A limit on a resource of the child, written as `RESOURCE=SOFT[:HARD]` (e.g. `nofile=8192`),
+/// where either limit may be `unlimited`. Without a hard limit, only the soft one is changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ResourceLimit {
+    pub resource: Resource,
+    pub soft: Limit,
+    pub hard: Option<Limit>,
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.resource.name(), self.soft)?;
+        if let Some(hard) = self.hard {
+            write!(f, ":{hard}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ResourceLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, limits) = s.split_once('=').ok_or_else(|| {
+            format!("expected RESOURCE=SOFT[:HARD] (e.g. nofile=8192), got '{s}'")
+        })?;
+        let resource = Resource::ALL
+            .into_iter()
+            .find(|resource| resource.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Resource::ALL.iter().map(|r| r.name()).collect();
+                format!(
+                    "unknown resource '{name}', expected one of {}",
+                    names.join(", ")
+                )
+            })?;
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (soft.parse()?, Some(hard.parse()?)),
+            None => (limits.parse()?, None),
+        };
+        if let (Limit::Value(soft), Some(Limit::Value(hard))) = (soft, hard) {
+            if soft > hard {
+                return Err(format!(
+                    "the soft limit {soft} is above the hard limit {hard}"
+                ));
+            }
+        }
+        if matches!((soft, hard), (Limit::Unlimited, Some(Limit::Value(_)))) {
+            return Err("the soft limit can't be unlimited below a hard limit".to_string());
+        }
+        Ok(Self {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+impl TryFrom<String> for ResourceLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ResourceLimit> for String {
+    fn from(limit: ResourceLimit) -> Self {
+        limit.to_string()
+    }
+}
+
+/// Sets `limits` on the process `command` spawns, right before it runs. A soft limit above the
+/// current hard limit is lowered to it with a warning, unless trayme runs as root, because only
+/// root may raise hard limits.
+///
+/// # Errors
+///
+/// An error is returned if the current limits cannot be read.
+#[cfg(unix)]
+pub fn apply(command: &mut Command, limits: &[ResourceLimit]) -> anyhow::Result<()> {
+    use std::{io, os::unix::process::CommandExt};
+
+    use anyhow::Context;
+    use log::{debug, warn};
+
+    if limits.is_empty() {
+        return Ok(());
+    }
+    // SAFETY: geteuid has no preconditions
+    let is_root = unsafe { libc::geteuid() } == 0;
+    let mut resolved = Vec::with_capacity(limits.len());
+    for limit in limits {
+        let mut current = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: current is a valid rlimit to write to
+        if unsafe { platform::getrlimit(limit.resource, &mut current) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to read the {} limit", limit.resource.name()));
+        }
+        let hard = limit.hard.map_or(current.rlim_max, to_rlim);
+        let mut wanted = libc::rlimit {
+            rlim_cur: to_rlim(limit.soft),
+            rlim_max: hard,
+        };
+        if !is_root && wanted.rlim_max > current.rlim_max {
+            warn!(
+                "Only root can raise the hard {} limit, keeping it at {}",
+                limit.resource.name(),
+                current.rlim_max
+            );
+            wanted.rlim_max = current.rlim_max;
+        }
+        if wanted.rlim_cur > wanted.rlim_max {
+            warn!(
+                "Lowering the {} limit to its hard limit of {}",
+                limit.resource.name(),
+                wanted.rlim_max
+            );
+            wanted.rlim_cur = wanted.rlim_max;
+        }
+        debug!(
+            "Setting {} limit to {}:{}",
+            limit.resource.name(),
+            wanted.rlim_cur,
+            wanted.rlim_max
+        );
+        resolved.push((limit.resource, wanted));
+    }
+    // SAFETY: the closure only calls setrlimit, which is async-signal-safe, and doesn't allocate
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in &resolved {
+                if platform::setrlimit(*resource, limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Windows has no equivalent of these limits for a single process: it has no limit on open
+/// handles short of the system's, and memory and CPU time can only be limited with a job object,
+/// so the limits are ignored with a warning.
+///
+/// # Errors
+///
+/// This never returns an error, it only has the signature of the Unix version.
+#[cfg(windows)]
+#[allow(clippy::unnecessary_wraps)]
+pub fn apply(_command: &mut Command, limits: &[ResourceLimit]) -> anyhow::Result<()> {
+    if !limits.is_empty() {
+        log::warn!("--ulimit is not supported on Windows, ignoring it");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn to_rlim(limit: Limit) -> libc::rlim_t {
+    match limit {
+        Limit::Value(value) => libc::rlim_t::try_from(value).unwrap_or(libc::RLIM_INFINITY),
+        Limit::Unlimited => libc::RLIM_INFINITY,
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::Resource;
+
+    /// The type of the resource constants differs between libcs, so they're only ever passed
+    /// straight on.
+    macro_rules! resource_id {
+        ($resource:expr) => {
+            match $resource {
+                Resource::Nofile => libc::RLIMIT_NOFILE,
+                Resource::Nproc => libc::RLIMIT_NPROC,
+                Resource::Core => libc::RLIMIT_CORE,
+                Resource::Stack => libc::RLIMIT_STACK,
+                Resource::Memlock => libc::RLIMIT_MEMLOCK,
+                Resource::As => libc::RLIMIT_AS,
+                Resource::Cpu => libc::RLIMIT_CPU,
+                Resource::Fsize => libc::RLIMIT_FSIZE,
+            }
+        };
+    }
+
+    pub unsafe fn getrlimit(resource: Resource, limit: &mut libc::rlimit) -> libc::c_int {
+        libc::getrlimit(resource_id!(resource), limit)
+    }
+
+    pub unsafe fn setrlimit(resource: Resource, limit: &libc::rlimit) -> libc::c_int {
+        libc::setrlimit(resource_id!(resource), limit)
+    }
+}
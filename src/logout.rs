@@ -0,0 +1,246 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Set once the desktop session is ending.
+static SESSION_ENDING: AtomicBool = AtomicBool::new(false);
+
+/// Set once trayme is interrupted, e.g. with Ctrl+C in its terminal.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// What happens to the command when the desktop session trayme runs in ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnLogout {
+    /// Stops the command with its stop strategy before trayme exits.
+    #[default]
+    Kill,
+    /// Leaves the command running in its own process group, with its output still going to its
+    /// log. On Windows every process of the session ends with it anyway, so this only keeps
+    /// trayme from stopping it first.
+    Detach,
+}
+
+/// Starts watching for the end of the desktop session: `SIGTERM` and `SIGHUP` on Unix, which
+/// session managers and launchd send on logout (and `kill` by default), and the end-session
+/// messages on Windows. Once one arrives, [`session_ending`] returns `true` instead of trayme
+/// being terminated right away. Interrupts (`SIGINT` and Ctrl+C on Windows) are caught too, see
+/// [`interrupted`]. Either way the command would outlive trayme otherwise, since it runs in a
+/// process group of its own.
+///
+/// # Errors
+///
+/// An error is returned if the handlers cannot be installed.
+pub fn watch() -> anyhow::Result<()> {
+    platform::watch()
+}
+
+/// Whether the desktop session is ending, see [`watch`].
+pub fn session_ending() -> bool {
+    SESSION_ENDING.load(Ordering::SeqCst)
+}
+
+/// Whether trayme was interrupted, see [`watch`]. Unlike the end of the session, this always
+/// means the command is stopped.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Marks the end of the session (or the interrupt) as dealt with, so that trayme can be terminated.
+pub fn handled() {
+    platform::handled();
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{io, ptr, sync::atomic::Ordering};
+
+    use anyhow::Context;
+
+    use super::{INTERRUPTED, SESSION_ENDING};
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        if signal == libc::SIGINT {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else {
+            SESSION_ENDING.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub fn watch() -> anyhow::Result<()> {
+        for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGINT] {
+            // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(ptr::addr_of_mut!(action.sa_mask));
+                if libc::sigaction(signal, ptr::addr_of!(action), ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error())
+                        .context("Failed to install the logout handler");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Nothing waits for the session end to be handled: trayme exits by itself afterwards.
+    pub fn handled() {}
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{
+        io, ptr,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Context;
+
+    use super::{INTERRUPTED, SESSION_ENDING};
+
+    type Handle = *mut std::ffi::c_void;
+
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_CLOSE_EVENT: u32 = 2;
+    const CTRL_LOGOFF_EVENT: u32 = 5;
+    const CTRL_SHUTDOWN_EVENT: u32 = 6;
+    const WM_QUERYENDSESSION: u32 = 0x0011;
+    /// How long the console handler holds off termination while the command is stopped. Windows
+    /// terminates console processes after 5 seconds at logoff.
+    const HANDLER_TIMEOUT: Duration = Duration::from_millis(4500);
+
+    static HANDLED: AtomicBool = AtomicBool::new(false);
+
+    #[repr(C)]
+    struct WndClass {
+        style: u32,
+        wnd_proc: extern "system" fn(Handle, u32, usize, isize) -> isize,
+        cls_extra: i32,
+        wnd_extra: i32,
+        instance: Handle,
+        icon: Handle,
+        cursor: Handle,
+        background: Handle,
+        menu_name: *const u16,
+        class_name: *const u16,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: extern "system" fn(u32) -> i32, add: i32) -> i32;
+        fn GetModuleHandleW(name: *const u16) -> Handle;
+    }
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn RegisterClassW(class: *const WndClass) -> u16;
+        #[allow(clippy::too_many_arguments)]
+        fn CreateWindowExW(
+            ex_style: u32,
+            class_name: *const u16,
+            window_name: *const u16,
+            style: u32,
+            x: i32,
+            y: i32,
+            width: i32,
+            height: i32,
+            parent: Handle,
+            menu: Handle,
+            instance: Handle,
+            param: *const std::ffi::c_void,
+        ) -> Handle;
+        fn DefWindowProcW(window: Handle, msg: u32, wparam: usize, lparam: isize) -> isize;
+    }
+
+    /// Console sessions (`--headless` or a debug build) are told through the control handler,
+    /// which runs on its own thread and may wait for the command to be stopped. `CTRL_BREAK` is
+    /// left alone, since trayme sends it to stop commands (see `StopStrategy::CtrlBreak`).
+    extern "system" fn on_console_event(event: u32) -> i32 {
+        match event {
+            CTRL_C_EVENT => INTERRUPTED.store(true, Ordering::SeqCst),
+            CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+                SESSION_ENDING.store(true, Ordering::SeqCst);
+            }
+            _ => return 0,
+        }
+        let deadline = Instant::now() + HANDLER_TIMEOUT;
+        while !HANDLED.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        1
+    }
+
+    /// GUI processes are asked whether the session may end through a top-level window. The
+    /// event loop runs again before the session actually ends, which is when the command is
+    /// stopped.
+    extern "system" fn window_proc(
+        window: Handle,
+        msg: u32,
+        wparam: usize,
+        lparam: isize,
+    ) -> isize {
+        if msg == WM_QUERYENDSESSION {
+            SESSION_ENDING.store(true, Ordering::SeqCst);
+            return 1;
+        }
+        // SAFETY: forwards the message as it was received
+        unsafe { DefWindowProcW(window, msg, wparam, lparam) }
+    }
+
+    /// Installs the console handler and creates a hidden window on the current thread, which is
+    /// the thread the event loop runs on.
+    pub fn watch() -> anyhow::Result<()> {
+        let class_name: Vec<u16> = "trayme-logout\0".encode_utf16().collect();
+        // SAFETY: the class and window name outlive the calls, and the window is never destroyed
+        unsafe {
+            if SetConsoleCtrlHandler(on_console_event, 1) == 0 {
+                return Err(io::Error::last_os_error())
+                    .context("Failed to install the logout handler");
+            }
+            let instance = GetModuleHandleW(ptr::null());
+            let class = WndClass {
+                style: 0,
+                wnd_proc: window_proc,
+                cls_extra: 0,
+                wnd_extra: 0,
+                instance,
+                icon: ptr::null_mut(),
+                cursor: ptr::null_mut(),
+                background: ptr::null_mut(),
+                menu_name: ptr::null(),
+                class_name: class_name.as_ptr(),
+            };
+            if RegisterClassW(&class) == 0 {
+                return Err(io::Error::last_os_error())
+                    .context("Failed to register the logout window class");
+            }
+            let window = CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                class_name.as_ptr(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                instance,
+                ptr::null(),
+            );
+            if window.is_null() {
+                return Err(io::Error::last_os_error())
+                    .context("Failed to create the logout window");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handled() {
+        HANDLED.store(true, Ordering::SeqCst);
+    }
+}
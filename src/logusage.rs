@@ -0,0 +1,108 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+
+use crate::layout;
+
+/// How long the measured size is shown before the logs are measured again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps track of how much space the logs of one program take up, across all of its runs. The
+/// logs are only measured every [`REFRESH_INTERVAL`], so this is cheap to poll.
+#[derive(Debug)]
+pub struct LogUsage {
+    program: String,
+    bytes: Option<u64>,
+    last_check: Option<Instant>,
+}
+
+impl LogUsage {
+    /// Tracks the logs of `program`, as named by [`crate::supervisor::program_name`].
+    pub fn new(program: String) -> Self {
+        Self {
+            program,
+            bytes: None,
+            last_check: None,
+        }
+    }
+
+    /// Measures the logs again if they're due to be, returning their size if it changed.
+    pub fn poll(&mut self) -> Option<u64> {
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < REFRESH_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+        let bytes = match program_logs(&self.program) {
+            Ok(logs) => logs.iter().map(|(_, size)| size).sum(),
+            Err(e) => {
+                debug!("Failed to measure logs: {e:#}");
+                return None;
+            }
+        };
+        if self.bytes == Some(bytes) {
+            return None;
+        }
+        self.bytes = Some(bytes);
+        Some(bytes)
+    }
+
+    /// Makes the next [`LogUsage::poll`] measure the logs, e.g. after some were deleted.
+    pub fn invalidate(&mut self) {
+        self.last_check = None;
+    }
+
+    /// Deletes every log of the program except `current`, the one the running process writes to.
+    /// Returns how many files were deleted and how many bytes that freed.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the logs directory cannot be read. Logs that can't be deleted are
+    /// skipped with a warning.
+    pub fn purge(&mut self, current: &Path) -> anyhow::Result<(usize, u64)> {
+        let mut deleted = 0;
+        let mut freed = 0;
+        for (path, size) in program_logs(&self.program)? {
+            if path == current {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    deleted += 1;
+                    freed += size;
+                }
+                Err(e) => warn!("Failed to delete {}: {e}", path.display()),
+            }
+        }
+        info!("Deleted {deleted} old logs of {}", self.program);
+        self.invalidate();
+        Ok((deleted, freed))
+    }
+}
+
+/// The logs of `program` with their sizes, including rotated and compressed ones. Logs are
+/// named `<program>_<timestamp>.log`, and only files named like that are counted.
+fn program_logs(program: &str) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let dir = layout::program_logs_dir(program)?;
+    let prefix = format!("{program}_");
+    let entries =
+        std::fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    Ok(entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let timestamp = name.strip_prefix(&prefix)?;
+            #[allow(clippy::case_sensitive_file_extension_comparisons)] // trayme names them
+            let is_log = timestamp.starts_with(|c: char| c.is_ascii_digit())
+                && (name.ends_with(".log") || name.ends_with(".log.gz"));
+            let metadata = entry.metadata().ok().filter(|_| is_log)?;
+            metadata.is_file().then(|| (entry.path(), metadata.len()))
+        })
+        .collect())
+}
@@ -0,0 +1,380 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+#[cfg(test)]
+use std::sync::{Mutex, PoisonError};
+
+use chrono::Local;
+use clap::ValueEnum;
+use log::{debug, warn};
+use notify_rust::{Notification, Timeout, Urgency};
+use serde::{Deserialize, Serialize};
+
+use crate::notifyroute::{self, Backend, LogSource, Message, NotifyRoute, Route};
+
+/// The sound played for critical notifications when no sound was configured for the event. These
+/// are the closest thing each platform has to a standard "something went wrong" sound.
+#[cfg(all(unix, not(target_os = "macos")))]
+const DEFAULT_ALERT_SOUND: &str = "dialog-error";
+#[cfg(target_os = "macos")]
+const DEFAULT_ALERT_SOUND: &str = "Basso";
+#[cfg(windows)]
+const DEFAULT_ALERT_SOUND: &str = "Reminder";
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyUrgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl From<NotifyUrgency> for Urgency {
+    fn from(urgency: NotifyUrgency) -> Self {
+        match urgency {
+            NotifyUrgency::Low => Urgency::Low,
+            NotifyUrgency::Normal => Urgency::Normal,
+            NotifyUrgency::Critical => Urgency::Critical,
+        }
+    }
+}
+
+/// The kinds of events trayme notifies the user about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyEvent {
+    /// The process was started.
+    Start,
+    /// The process exited successfully or was killed.
+    Exit,
+    /// The process exited with a non-zero status.
+    Failure,
+    /// The process' output went over the `--unhealthy-if` threshold.
+    Unhealthy,
+    /// The process' output went back under the `--unhealthy-if` threshold.
+    Recovered,
+    /// The instance's profile changed in the config file and was reloaded.
+    Reloaded,
+    /// The log file couldn't be written and the output went to a temporary file, or back.
+    LogFallback,
+    /// The `--pre-check` command failed, or a dependency of `--wait-for-*` didn't become
+    /// available, so the process wasn't started.
+    PreCheck,
+    /// Maintenance mode was turned on or off.
+    Maintenance,
+    /// The process wrote its first line that isn't blank, see `--first-output-notify`.
+    FirstOutput,
+    /// The process was paused for a busy event of the calendar, or resumed after it, see
+    /// `--busy-calendar`.
+    Calendar,
+    /// The process was restarted on the schedule of `--restart-every` or `--restart-cron`.
+    PlannedRestart,
+    /// The process ran for longer than `--timeout` and was stopped.
+    Timeout,
+    /// No scheduled run succeeded within `--expect-success-within`.
+    Overdue,
+    /// The process went over `--max-memory`.
+    MemoryLimit,
+    /// The process was restarted after the machine woke from sleep, see `--on-wake`.
+    Wake,
+}
+
+/// A notification for the desktop, as [`Notifier`] worked it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopNotification {
+    pub event: NotifyEvent,
+    pub urgency: NotifyUrgency,
+    pub title: String,
+    pub body: String,
+    /// The platform-specific name of the sound to play, if any.
+    pub sound: Option<String>,
+}
+
+/// Where desktop notifications are shown, so that what's notified about can be tested without a
+/// notification server.
+pub trait DesktopNotifier: fmt::Debug + Send + Sync {
+    fn show(&self, notification: &DesktopNotification);
+}
+
+/// The desktop's notification server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemNotifier;
+
+impl DesktopNotifier for SystemNotifier {
+    fn show(&self, desktop: &DesktopNotification) {
+        let mut notification = Notification::new();
+        notification.summary(&desktop.title).body(&desktop.body);
+        #[cfg(all(unix, not(target_os = "macos")))]
+        notification.urgency(desktop.urgency.into());
+        if desktop.urgency == NotifyUrgency::Critical {
+            notification.timeout(Timeout::Never);
+        }
+        if let Some(sound) = &desktop.sound {
+            notification.sound_name(sound);
+        }
+        show(&notification);
+    }
+}
+
+/// Keeps the notifications it's given instead of showing them. Clones share them.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct FakeNotifier(Arc<Mutex<Vec<DesktopNotification>>>);
+
+#[cfg(test)]
+impl FakeNotifier {
+    /// The notifications shown so far, oldest first.
+    pub fn shown(&self) -> Vec<DesktopNotification> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+impl DesktopNotifier for FakeNotifier {
+    fn show(&self, notification: &DesktopNotification) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(notification.clone());
+    }
+}
+
+/// Shows notifications for process events using the configured urgency and sounds, and sends
+/// them on to the configured routes.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    urgency: NotifyUrgency,
+    sounds: HashMap<NotifyEvent, String>,
+    muted: bool,
+    /// Events that aren't notified about at all, see [`Notifier::silence`].
+    silenced: Vec<NotifyEvent>,
+    /// Where notifications go. Only to the desktop if empty.
+    routes: Vec<Route>,
+    /// The instance notifications are about, passed on to remote backends.
+    instance: Option<String>,
+    /// Where the desktop notifications are shown.
+    desktop: Arc<dyn DesktopNotifier>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new(NotifyUrgency::default(), [])
+    }
+}
+
+impl Notifier {
+    /// Creates a new notifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `urgency` - The urgency of failure notifications. Routine notifications (start, exit) are
+    ///   never shown with more than normal urgency, so they don't drown out failures.
+    /// * `sounds` - Custom sounds to play per event. The sound names are platform-specific.
+    pub fn new(
+        urgency: NotifyUrgency,
+        sounds: impl IntoIterator<Item = (NotifyEvent, String)>,
+    ) -> Self {
+        Self {
+            urgency,
+            sounds: sounds.into_iter().collect(),
+            muted: false,
+            silenced: Vec::new(),
+            routes: Vec::new(),
+            instance: None,
+            desktop: Arc::new(SystemNotifier),
+        }
+    }
+
+    /// Shows the desktop notifications with `desktop` instead of the system's notification
+    /// server.
+    #[cfg(test)]
+    pub fn set_desktop(&mut self, desktop: Arc<dyn DesktopNotifier>) {
+        self.desktop = desktop;
+    }
+
+    /// Sends notifications through `routes` instead of only showing them on the desktop.
+    pub fn set_routes(&mut self, routes: Vec<NotifyRoute>) {
+        self.routes = routes.into_iter().map(Route::new).collect();
+    }
+
+    /// Names the instance notifications are about, for backends that aren't on its desktop.
+    pub fn set_instance(&mut self, name: &str) {
+        self.instance = Some(name.to_string());
+    }
+
+    /// Stops this notifier from showing any desktop notifications, e.g. without a display. They
+    /// are still logged and sent to the other backends of the routes.
+    pub fn mute(&mut self) {
+        self.muted = true;
+    }
+
+    /// Drops the notifications for `event` entirely, on the desktop and every route, e.g. the
+    /// routine ones of scheduled runs that only alert when something's wrong.
+    pub fn silence(&mut self, event: NotifyEvent) {
+        if !self.silenced.contains(&event) {
+            self.silenced.push(event);
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Shows a notification for the given event, and sends it to every route whose filters it
+    /// passes.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that happened, which decides the urgency and sound.
+    /// * `title` - The title of the notification.
+    /// * `body` - The body text of the notification.
+    pub fn notify(&self, event: NotifyEvent, title: &str, body: &str) {
+        self.notify_with_log(event, title, body, None);
+    }
+
+    /// Like [`Notifier::notify`], but routes with `attach_log_kb` attach the end of `log` to
+    /// failure notifications.
+    pub fn notify_with_log(
+        &self,
+        event: NotifyEvent,
+        title: &str,
+        body: &str,
+        log: Option<LogSource>,
+    ) {
+        if self.silenced.contains(&event) {
+            debug!("Not notifying about {event:?}: {title}");
+            return;
+        }
+        let urgency = match event {
+            NotifyEvent::Failure
+            | NotifyEvent::Unhealthy
+            | NotifyEvent::PreCheck
+            | NotifyEvent::Timeout
+            | NotifyEvent::Overdue
+            | NotifyEvent::MemoryLimit => self.urgency,
+            NotifyEvent::Start
+            | NotifyEvent::Exit
+            | NotifyEvent::Recovered
+            | NotifyEvent::Reloaded
+            | NotifyEvent::LogFallback
+            | NotifyEvent::Maintenance
+            | NotifyEvent::FirstOutput
+            | NotifyEvent::Calendar
+            | NotifyEvent::PlannedRestart
+            | NotifyEvent::Wake => self.urgency.min(NotifyUrgency::Normal),
+        };
+        if self.routes.is_empty() {
+            self.show_desktop(event, urgency, title, body);
+            return;
+        }
+        for route in &self.routes {
+            if !route.accepts(event, urgency) {
+                continue;
+            }
+            match &route.config.backend {
+                Backend::Desktop => self.show_desktop(event, urgency, title, body),
+                backend => notifyroute::send(
+                    backend,
+                    Message {
+                        at: Local::now(),
+                        instance: self.instance.clone(),
+                        event,
+                        urgency,
+                        title: title.to_string(),
+                        body: body.to_string(),
+                        log: log.and_then(|log| route.log_excerpt(event, log)),
+                    },
+                ),
+            }
+        }
+    }
+
+    fn show_desktop(&self, event: NotifyEvent, urgency: NotifyUrgency, title: &str, body: &str) {
+        if self.muted {
+            debug!("Muted {event:?} notification: title: '{title}' body: '{body}'");
+            return;
+        }
+        let sound = self
+            .sounds
+            .get(&event)
+            .map(String::as_str)
+            .or((urgency == NotifyUrgency::Critical).then_some(DEFAULT_ALERT_SOUND));
+        debug!("Showing {event:?} notification ({urgency:?}, sound: {sound:?})");
+        self.desktop.show(&DesktopNotification {
+            event,
+            urgency,
+            title: title.to_string(),
+            body: body.to_string(),
+            sound: sound.map(ToString::to_string),
+        });
+    }
+}
+
+/// Shows a notification with the given title and body.
+///
+/// # Arguments
+///
+/// * `title` - The title of the notification.
+/// * `body` - The body text of the notification.
+pub fn show_notification<S: AsRef<str>>(title: S, body: S) {
+    show(
+        Notification::new()
+            .summary(title.as_ref())
+            .body(body.as_ref()),
+    );
+}
+
+fn show(notification: &Notification) {
+    debug!(
+        "Showing notification: title: '{}' body: '{}'",
+        notification.summary, notification.body
+    );
+    // e.g. when no notification daemon is running, which is no reason to stop supervising
+    if let Err(e) = notification.show() {
+        warn!("Failed to show notification: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(urgency: NotifyUrgency) -> (Notifier, FakeNotifier) {
+        let desktop = FakeNotifier::default();
+        let mut notifier = Notifier::new(urgency, [(NotifyEvent::Start, "bell".to_string())]);
+        notifier.set_desktop(Arc::new(desktop.clone()));
+        (notifier, desktop)
+    }
+
+    #[test]
+    fn routine_events_are_never_urgent() {
+        let (notifier, desktop) = notifier(NotifyUrgency::Critical);
+        notifier.notify(NotifyEvent::Start, "Started", "web");
+        notifier.notify(NotifyEvent::Failure, "Failed", "web");
+        let shown = desktop.shown();
+        assert_eq!(shown.len(), 2);
+        assert_eq!(shown[0].urgency, NotifyUrgency::Normal);
+        assert_eq!(shown[0].sound.as_deref(), Some("bell"));
+        assert_eq!(shown[1].urgency, NotifyUrgency::Critical);
+        assert_eq!(shown[1].sound.as_deref(), Some(DEFAULT_ALERT_SOUND));
+        assert_eq!(shown[1].title, "Failed");
+    }
+
+    #[test]
+    fn silenced_and_muted_notifications_arent_shown() {
+        let (mut notifier, desktop) = notifier(NotifyUrgency::Normal);
+        notifier.silence(NotifyEvent::Exit);
+        notifier.notify(NotifyEvent::Exit, "Exited", "web");
+        notifier.notify(NotifyEvent::Failure, "Failed", "web");
+        assert_eq!(desktop.shown().len(), 1);
+        assert_eq!(desktop.shown()[0].sound, None);
+        notifier.mute();
+        notifier.notify(NotifyEvent::Failure, "Failed", "web");
+        assert_eq!(desktop.shown().len(), 1);
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    envdiff,
+    health::Threshold,
+    notify::{NotifyEvent, NotifyUrgency},
+};
+
+/// How long a webhook or push server has to accept a notification.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a route attaches the log, so that a crash loop doesn't post it over and over.
+/// Notifications in between are sent without it.
+const ATTACH_LOG_INTERVAL: Duration = Duration::from_mins(10);
+
+/// How many notifications and pings are still being sent, see [`wait_for_pending`].
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Where a [`NotifyRoute`] sends notifications.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Backend {
+    /// A desktop notification, as shown without any routes.
+    Desktop,
+    /// A JSON `POST` to `url` with the instance, event, urgency, title, and body, and the `log`
+    /// if it's attached.
+    Webhook { url: String },
+    /// A push notification through an ntfy server, where `url` is the topic's URL (e.g.
+    /// `https://ntfy.sh/my-topic`). The urgency becomes the message's priority, and an attached
+    /// log is added to the body.
+    Ntfy { url: String },
+    /// Runs `cmd` with the notification in the `TRAYME_EVENT`, `TRAYME_URGENCY`, `TRAYME_TITLE`,
+    /// `TRAYME_BODY`, and `TRAYME_INSTANCE` variables, for any other service. An attached log is
+    /// in `TRAYME_LOG`.
+    Command { cmd: Vec<String> },
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Desktop => write!(f, "desktop"),
+            Backend::Webhook { url } => write!(f, "webhook {url}"),
+            Backend::Ntfy { url } => write!(f, "ntfy {url}"),
+            Backend::Command { cmd } => write!(f, "command {}", cmd.join(" ")),
+        }
+    }
+}
+
+/// One of the places notifications are sent to, with the filters deciding which go there. A
+/// profile can have any number of them in its `notify` array:
+///
+/// ```toml
+/// [[profiles.web.notify]]
+/// backend = "desktop"
+///
+/// [[profiles.web.notify]]
+/// backend = "webhook"
+/// url = "https://hooks.example.com/trayme"
+/// events = ["failure", "unhealthy"]
+///
+/// [[profiles.web.notify]]
+/// backend = "ntfy"
+/// url = "https://ntfy.sh/my-topic"
+/// events = ["failure"]
+/// threshold = "3/10m"
+/// attach_log_kb = 8
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotifyRoute {
+    #[serde(flatten)]
+    pub backend: Backend,
+    /// Only these events are sent. Every event if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<NotifyEvent>,
+    /// Only events of at least this urgency are sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_urgency: Option<NotifyUrgency>,
+    /// Only sends a notification once this many of the other filters' events happened within
+    /// the window, e.g. `3/10m` with `events = ["failure"]` for crash loops.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<Threshold>,
+    /// Attaches the last this many kilobytes of the log to failure, timeout, and unhealthy
+    /// notifications, with the values of secret-looking environment variables masked. It's
+    /// attached at most once every 10 minutes. Desktop notifications never get it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attach_log_kb: Option<u64>,
+}
+
+/// The log of the run a notification is about, for routes that attach it.
+#[derive(Debug, Clone, Copy)]
+pub struct LogSource<'a> {
+    pub path: &'a Path,
+    /// The run's environment, whose secret values are masked in the attached log.
+    pub env: &'a BTreeMap<String, String>,
+}
+
+/// A [`NotifyRoute`] along with the recent events counted towards its threshold. Clones share
+/// the count, like a notifier's clones share the routes.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub config: NotifyRoute,
+    hits: Arc<Mutex<VecDeque<Instant>>>,
+    /// When the log was last attached, see [`ATTACH_LOG_INTERVAL`].
+    last_attached: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Route {
+    pub fn new(config: NotifyRoute) -> Self {
+        Self {
+            config,
+            hits: Arc::default(),
+            last_attached: Arc::default(),
+        }
+    }
+
+    /// The end of the log to attach to a notification for `event`, or `None` if this route
+    /// doesn't attach it to such events, or already did within [`ATTACH_LOG_INTERVAL`].
+    pub fn log_excerpt(&self, event: NotifyEvent, log: LogSource) -> Option<String> {
+        let kb = self.config.attach_log_kb?;
+        if !matches!(
+            event,
+            NotifyEvent::Failure | NotifyEvent::Timeout | NotifyEvent::Unhealthy
+        ) {
+            return None;
+        }
+        let mut last_attached = self
+            .last_attached
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last_attached.is_some_and(|at| at.elapsed() < ATTACH_LOG_INTERVAL) {
+            debug!(
+                "Not attaching the log to {}, it was recently",
+                self.config.backend
+            );
+            return None;
+        }
+        match read_tail(log.path, kb * 1024) {
+            Ok(tail) => {
+                *last_attached = Some(Instant::now());
+                Some(envdiff::redact(&tail, log.env))
+            }
+            Err(e) => {
+                warn!("Failed to read {} to attach it: {e}", log.path.display());
+                None
+            }
+        }
+    }
+
+    /// Whether an event should be sent through this route. Events that pass the filters count
+    /// towards the threshold, which is reset once it's reached.
+    pub fn accepts(&self, event: NotifyEvent, urgency: NotifyUrgency) -> bool {
+        let config = &self.config;
+        if !config.events.is_empty() && !config.events.contains(&event) {
+            return false;
+        }
+        if config.min_urgency.is_some_and(|min| urgency < min) {
+            return false;
+        }
+        let Some(threshold) = config.threshold else {
+            return true;
+        };
+        let mut hits = self.hits.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        while hits
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) > threshold.window)
+        {
+            hits.pop_front();
+        }
+        hits.push_back(now);
+        if hits.len() < threshold.count {
+            debug!(
+                "{} of {} events for {} within {}",
+                hits.len(),
+                threshold.count,
+                config.backend,
+                humantime::format_duration(threshold.window)
+            );
+            return false;
+        }
+        hits.clear();
+        true
+    }
+}
+
+/// A notification on its way to a remote backend.
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub at: DateTime<Local>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub event: NotifyEvent,
+    pub urgency: NotifyUrgency,
+    pub title: String,
+    pub body: String,
+    /// The end of the log, see [`NotifyRoute::attach_log_kb`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+}
+
+/// Sends `message` through `backend` on a thread of its own, so that a slow server doesn't hold
+/// up the instance. Failures are logged. Desktop notifications are shown by the notifier itself.
+pub fn send(backend: &Backend, message: Message) {
+    let backend = backend.clone();
+    spawn_pending(move || {
+        let result = match &backend {
+            Backend::Desktop => Ok(()),
+            Backend::Webhook { url } => send_webhook(url, &message),
+            Backend::Ntfy { url } => send_ntfy(url, &message),
+            Backend::Command { cmd } => run_command(cmd, &message),
+        };
+        match result {
+            Ok(()) => debug!("Sent {:?} notification to {backend}", message.event),
+            Err(e) => warn!(
+                "Failed to send {:?} notification to {backend}: {e:#}",
+                message.event
+            ),
+        }
+    });
+}
+
+/// Runs `task` on a thread of its own, which [`wait_for_pending`] waits for, e.g. to send
+/// something over the network without holding up the instance.
+pub fn spawn_pending(task: impl FnOnce() + Send + 'static) {
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    thread::spawn(move || {
+        task();
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Waits for the notifications and pings that are still being sent, up to [`SEND_TIMEOUT`].
+/// Called before trayme exits, since the exit notification is usually sent right before.
+pub fn wait_for_pending() {
+    let deadline = Instant::now() + SEND_TIMEOUT;
+    while PENDING.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn send_webhook(url: &str, message: &Message) -> anyhow::Result<()> {
+    let body = serde_json::to_string(message)?;
+    ureq::post(url)
+        .timeout(SEND_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&body)?;
+    Ok(())
+}
+
+/// Reads the last `len` bytes of the file at `path`, starting at the first full line.
+fn read_tail(path: &Path, len: u64) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    // from the byte before, so that a line starting right at the tail is kept
+    let start = file.metadata()?.len().saturating_sub(len.saturating_add(1));
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let tail = String::from_utf8_lossy(&tail);
+    Ok(match tail.split_once('\n') {
+        Some((_, rest)) if start > 0 => rest.to_string(),
+        _ => tail.into_owned(),
+    })
+}
+
+fn send_ntfy(url: &str, message: &Message) -> anyhow::Result<()> {
+    let title = match &message.instance {
+        Some(instance) => format!("{instance}: {}", message.title),
+        None => message.title.clone(),
+    };
+    // ntfy's priorities go from 1 (min) to 5 (max), with 3 as the default
+    let priority = match message.urgency {
+        NotifyUrgency::Low => "2",
+        NotifyUrgency::Normal => "3",
+        NotifyUrgency::Critical => "5",
+    };
+    ureq::post(url)
+        .timeout(SEND_TIMEOUT)
+        .set("Title", &title)
+        .set("Priority", priority)
+        .set("Tags", &event_name(message.event))
+        .send_string(&match &message.log {
+            Some(log) => format!("{}\n\n{log}", message.body),
+            None => message.body.clone(),
+        })?;
+    Ok(())
+}
+
+fn run_command(cmd: &[String], message: &Message) -> anyhow::Result<()> {
+    let (program, args) = cmd.split_first().context("The command is empty")?;
+    let urgency = message
+        .urgency
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("TRAYME_EVENT", event_name(message.event))
+        .env("TRAYME_URGENCY", urgency)
+        .env("TRAYME_TITLE", &message.title)
+        .env("TRAYME_BODY", &message.body)
+        .env(
+            "TRAYME_INSTANCE",
+            message.instance.as_deref().unwrap_or_default(),
+        )
+        .env("TRAYME_LOG", message.log.as_deref().unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#flags
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        bail!("{program} failed ({status})");
+    }
+    Ok(())
+}
+
+/// The name of `event` as it's written in the config file and on the command line.
+fn event_name(event: NotifyEvent) -> String {
+    event
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(events: Vec<NotifyEvent>, threshold: Option<Threshold>) -> Route {
+        Route::new(NotifyRoute {
+            backend: Backend::Desktop,
+            events,
+            min_urgency: Some(NotifyUrgency::Normal),
+            threshold,
+            attach_log_kb: None,
+        })
+    }
+
+    #[test]
+    fn filters_by_event_and_urgency() {
+        let route = route(vec![NotifyEvent::Failure, NotifyEvent::Exit], None);
+        assert!(route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+        assert!(route.accepts(NotifyEvent::Exit, NotifyUrgency::Normal));
+        assert!(!route.accepts(NotifyEvent::Exit, NotifyUrgency::Low));
+        assert!(!route.accepts(NotifyEvent::Start, NotifyUrgency::Critical));
+    }
+
+    #[test]
+    fn sends_once_the_threshold_is_reached() {
+        let threshold = Threshold {
+            count: 3,
+            window: Duration::from_hours(1),
+        };
+        let route = route(vec![NotifyEvent::Failure], Some(threshold));
+        let failures = |route: &Route| {
+            (0..3)
+                .map(|_| route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical))
+                .collect::<Vec<_>>()
+        };
+        // filtered out events don't count towards it
+        assert!(!route.accepts(NotifyEvent::Start, NotifyUrgency::Critical));
+        assert!(!route.accepts(NotifyEvent::Failure, NotifyUrgency::Low));
+        assert_eq!(failures(&route), [false, false, true]);
+        // it starts over once reached
+        assert_eq!(failures(&route), [false, false, true]);
+        // clones share the count
+        assert!(!route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+        assert!(!route
+            .clone()
+            .accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+        assert!(route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+    }
+
+    #[test]
+    fn events_fall_out_of_the_threshold_window() {
+        let threshold = Threshold {
+            count: 2,
+            window: Duration::from_millis(20),
+        };
+        let route = route(Vec::new(), Some(threshold));
+        assert!(!route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+        thread::sleep(Duration::from_millis(40));
+        assert!(!route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+        assert!(route.accepts(NotifyEvent::Failure, NotifyUrgency::Critical));
+    }
+
+    #[test]
+    fn tails_start_at_a_full_line() {
+        let path = std::env::temp_dir().join(format!(
+            "{}-tail-{}.log",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        // a tail that starts right at a line keeps it
+        assert_eq!(read_tail(&path, 6).unwrap(), "three\n");
+        // one that starts within a line drops the rest of it
+        assert_eq!(read_tail(&path, 8).unwrap(), "three\n");
+        assert_eq!(read_tail(&path, 10).unwrap(), "two\nthree\n");
+        assert_eq!(read_tail(&path, 100).unwrap(), "one\ntwo\nthree\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::ffi::{OsStr, OsString};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// One argument as it's stored in the config, history, registry, and IPC. Arguments that are
+/// valid Unicode are plain strings so that the files stay readable; the others keep their raw
+/// bytes (Unix) or UTF-16 code units (Windows), which is what they are to the OS.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Text(String),
+    Bytes { bytes: Vec<u8> },
+    Wide { wide: Vec<u16> },
+}
+
+impl Repr {
+    fn from_os(arg: &OsStr) -> Self {
+        if let Some(text) = arg.to_str() {
+            return Self::Text(text.to_string());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            Self::Bytes {
+                bytes: arg.as_bytes().to_vec(),
+            }
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            Self::Wide {
+                wide: arg.encode_wide().collect(),
+            }
+        }
+    }
+
+    fn into_os(self) -> Result<OsString, String> {
+        match self {
+            Self::Text(text) => Ok(text.into()),
+            #[cfg(unix)]
+            Self::Bytes { bytes } => {
+                use std::os::unix::ffi::OsStringExt;
+                Ok(OsString::from_vec(bytes))
+            }
+            #[cfg(windows)]
+            Self::Wide { wide } => {
+                use std::os::windows::ffi::OsStringExt;
+                Ok(OsString::from_wide(&wide))
+            }
+            // an argument from the other kind of OS only carries over if it's Unicode after all
+            #[cfg(windows)]
+            Self::Bytes { bytes } => String::from_utf8(bytes)
+                .map(OsString::from)
+                .map_err(|_| "an argument with non-UTF-8 bytes can't be used on Windows".into()),
+            #[cfg(unix)]
+            Self::Wide { wide } => String::from_utf16(&wide)
+                .map(OsString::from)
+                .map_err(|_| "an argument with unpaired surrogates can't be used on Unix".into()),
+        }
+    }
+}
+
+/// Serializes a command with [`Repr`], for `#[serde(with = "osargs")]`.
+///
+/// # Errors
+///
+/// An error is returned if the serializer fails.
+pub fn serialize<S: Serializer>(cmd: &[OsString], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(cmd.iter().map(|arg| Repr::from_os(arg)))
+}
+
+/// Deserializes a command written by [`serialize`], for `#[serde(with = "osargs")]`.
+///
+/// # Errors
+///
+/// An error is returned if an argument is neither a string nor raw bytes or code units this OS
+/// can use.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OsString>, D::Error> {
+    Vec::<Repr>::deserialize(deserializer)?
+        .into_iter()
+        .map(|arg| arg.into_os().map_err(de::Error::custom))
+        .collect()
+}
+
+/// The command as one line for showing to the user, with the arguments that aren't valid
+/// Unicode made readable the lossy way. Use [`crate::cmdline::join`] for a line that has to be
+/// split again.
+pub fn display(cmd: &[OsString]) -> String {
+    cmd.iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns the arguments of a command built from strings into a command.
+pub fn from_strings(cmd: Vec<String>) -> Vec<OsString> {
+    cmd.into_iter().map(OsString::from).collect()
+}
+
+/// Whether the argument contains `pattern`, looking at its raw bytes or code units so that an
+/// argument that isn't valid Unicode can still have a placeholder.
+pub fn contains(arg: &OsStr, pattern: &str) -> bool {
+    if let Some(text) = arg.to_str() {
+        return text.contains(pattern);
+    }
+    let (arg, pattern) = (units(arg), units(OsStr::new(pattern)));
+    pattern.is_empty() || arg.windows(pattern.len()).any(|window| window == pattern)
+}
+
+/// Replaces every occurrence of `pattern` in the argument with `value`, keeping the rest of it
+/// as it is even if it isn't valid Unicode.
+pub fn replace(arg: &OsStr, pattern: &str, value: &str) -> OsString {
+    if let Some(text) = arg.to_str() {
+        return text.replace(pattern, value).into();
+    }
+    let (arg, pattern, value) = (
+        units(arg),
+        units(OsStr::new(pattern)),
+        units(OsStr::new(value)),
+    );
+    if pattern.is_empty() {
+        return from_units(arg);
+    }
+    let mut replaced = Vec::with_capacity(arg.len());
+    let mut rest = &arg[..];
+    while !rest.is_empty() {
+        if rest.starts_with(&pattern) {
+            replaced.extend_from_slice(&value);
+            rest = &rest[pattern.len()..];
+        } else {
+            replaced.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    from_units(replaced)
+}
+
+#[cfg(unix)]
+fn units(arg: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    arg.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn from_units(units: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(units)
+}
+
+#[cfg(windows)]
+fn units(arg: &OsStr) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    arg.encode_wide().collect()
+}
+
+#[cfg(windows)]
+fn from_units(units: Vec<u16>) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    OsString::from_wide(&units)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn replaces_in_arguments_that_are_not_unicode() {
+        let arg = OsStr::from_bytes(b"\xff{clip}.mp4");
+        assert!(contains(arg, "{clip}"));
+        assert_eq!(
+            replace(arg, "{clip}", "url"),
+            OsStr::from_bytes(b"\xffurl.mp4")
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Partial lines longer than this are flushed as-is, so output without line breaks can't grow
+/// the buffer forever.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Follows the log file of a run and yields the child's output line by line, as it's written.
+/// Carriage returns count as line breaks too, so progress bars show up as separate lines.
+#[derive(Debug)]
+pub struct OutputTail {
+    file: File,
+    partial: Vec<u8>,
+}
+
+impl OutputTail {
+    /// Opens the log file at its start.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be opened.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Switches to following a new log file from its start, e.g. after the log was rotated. An
+    /// unterminated line carries over.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be opened. The old file is still followed then.
+    pub fn reopen(&mut self, path: &Path) -> io::Result<()> {
+        self.file = File::open(path)?;
+        Ok(())
+    }
+
+    /// Returns the complete lines written since the last call. Empty lines are skipped.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be read.
+    pub fn read_lines(&mut self) -> io::Result<Vec<String>> {
+        let mut buf = Vec::new();
+        self.file.read_to_end(&mut buf)?;
+        Ok(self.split_lines(&buf))
+    }
+
+    /// Returns whatever is left of an unterminated last line. Used once the child has exited.
+    pub fn flush(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.partial);
+        (!rest.is_empty()).then(|| String::from_utf8_lossy(&rest).into_owned())
+    }
+
+    fn split_lines(&mut self, buf: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in buf {
+            if byte == b'\n' || byte == b'\r' {
+                if let Some(line) = self.flush() {
+                    lines.push(line);
+                }
+            } else {
+                self.partial.push(byte);
+                if self.partial.len() >= MAX_LINE_LEN {
+                    lines.extend(self.flush());
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// A log level detected in the child's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Parses a level name as used by common logging libraries, ignoring case.
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" | "err" | "fatal" | "crit" | "critical" | "panic" | "alert" | "emerg"
+            | "emergency" | "severe" => Some(LogLevel::Error),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "info" | "notice" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" | "verbose" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    /// Maps an RFC 5424 severity (0-7) to a level.
+    fn from_syslog_severity(severity: u8) -> Self {
+        match severity {
+            0..=3 => LogLevel::Error,
+            4 => LogLevel::Warn,
+            5 | 6 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    /// Maps a numeric level as used by pino and bunyan (10 = trace ... 60 = fatal) to a level.
+    fn from_number(level: u64) -> Option<Self> {
+        match level {
+            50.. => Some(LogLevel::Error),
+            40..=49 => Some(LogLevel::Warn),
+            30..=39 => Some(LogLevel::Info),
+            20..=29 => Some(LogLevel::Debug),
+            10..=19 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    /// The ANSI escape sequence used to color lines of this level in the console.
+    pub fn ansi_color(self) -> &'static str {
+        match self {
+            LogLevel::Error => "\x1b[31m",
+            LogLevel::Warn => "\x1b[33m",
+            LogLevel::Info => "\x1b[0m",
+            LogLevel::Debug | LogLevel::Trace => "\x1b[2m",
+        }
+    }
+}
+
+/// How many lines of each level the current run has written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelCounts {
+    pub error: u64,
+    pub warn: u64,
+    pub info: u64,
+    pub debug: u64,
+    pub trace: u64,
+}
+
+impl LevelCounts {
+    /// Counts one line of the given level.
+    pub fn add(&mut self, level: LogLevel) {
+        let count = match level {
+            LogLevel::Error => &mut self.error,
+            LogLevel::Warn => &mut self.warn,
+            LogLevel::Info => &mut self.info,
+            LogLevel::Debug => &mut self.debug,
+            LogLevel::Trace => &mut self.trace,
+        };
+        *count += 1;
+    }
+}
+
+impl fmt::Display for LevelCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} errors, {} warnings", self.error, self.warn)
+    }
+}
+
+/// Detects the log level of a line of output. Recognized formats, in order:
+///
+/// * JSON objects with a `level`, `lvl`, or `severity` field (names or pino-style numbers)
+/// * RFC 5424 syslog lines (`<PRI>1 ...`)
+/// * `level=<name>` logfmt fields
+/// * Upper-case level tokens such as `ERROR` or `[WARN]` near the start of the line
+pub fn detect_level(line: &str) -> Option<LogLevel> {
+    let line = line.trim_start();
+    if line.starts_with('{') {
+        return detect_json_level(line);
+    }
+    if let Some(level) = detect_syslog_level(line) {
+        return Some(level);
+    }
+    if let Some(value) = find_field(line, "level=") {
+        return LogLevel::from_name(value);
+    }
+    // only the first few words are checked, since messages often mention errors in passing
+    line.split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|word| !word.is_empty())
+        .take(6)
+        .filter(|word| word.chars().all(|c| c.is_ascii_uppercase()))
+        .find_map(LogLevel::from_name)
+}
+
+fn detect_json_level(line: &str) -> Option<LogLevel> {
+    for key in ["\"level\"", "\"lvl\"", "\"severity\""] {
+        let Some(start) = line.find(key) else {
+            continue;
+        };
+        let value = line[start + key.len()..]
+            .trim_start()
+            .strip_prefix(':')?
+            .trim_start();
+        if let Some(quoted) = value.strip_prefix('"') {
+            return LogLevel::from_name(quoted.split('"').next()?);
+        }
+        let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+        return LogLevel::from_number(digits.parse().ok()?);
+    }
+    None
+}
+
+fn detect_syslog_level(line: &str) -> Option<LogLevel> {
+    let (pri, rest) = line.strip_prefix('<')?.split_once('>')?;
+    // RFC 5424 lines continue with the version number 1, RFC 3164 ones don't
+    if !rest.starts_with("1 ") && !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let pri: u8 = pri.parse().ok()?;
+    Some(LogLevel::from_syslog_severity(pri % 8))
+}
+
+/// Returns the unquoted value of a `key=value` field.
+fn find_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(key)? + key.len();
+    let value = &line[start..];
+    let value = value.strip_prefix('"').unwrap_or(value);
+    value.split(|c: char| c.is_whitespace() || c == '"').next()
+}
+
+/// Removes the ANSI escape sequences (colors, cursor movement) from a line of output, so that it
+/// can be shown as text.
+pub fn strip_ansi(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI sequences (`ESC [ ... letter`) end with a character from @ to ~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC sequences (`ESC ] ... BEL`), such as hyperlinks, end with BEL or `ESC \`
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    plain
+}
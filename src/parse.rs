@@ -0,0 +1,352 @@
+use clap::ValueEnum;
+
+use crate::{ipc::ControlCommand, selflog::Verbosity};
+
+/// Splits a line received on the control socket into the token's secret, if it starts with
+/// `token <SECRET> `, and the command. Only the line ending is stripped, since whitespace matters
+/// to whatever reads a sent line.
+pub fn control_line(line: &str) -> (Option<&str>, &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    match line
+        .strip_prefix("token ")
+        .and_then(|rest| rest.split_once(' '))
+    {
+        Some((secret, command)) => (Some(secret), command),
+        None => (None, line),
+    }
+}
+
+/// Parses a command as [`ControlCommand`]'s `Display` writes it.
+pub fn control_command(s: &str) -> Option<ControlCommand> {
+    match s {
+        "status" => Some(ControlCommand::Status),
+        "subscribe" => Some(ControlCommand::Subscribe),
+        "kill" => Some(ControlCommand::Kill),
+        "kill --force" => Some(ControlCommand::ForceKill),
+        "maintenance on" => Some(ControlCommand::StartMaintenance(None)),
+        "maintenance off" => Some(ControlCommand::EndMaintenance),
+        "handover" => Some(ControlCommand::Handover),
+        "rotate-log" => Some(ControlCommand::RotateLog),
+        _ => {
+            if let Some(line) = s.strip_prefix("send ") {
+                return Some(ControlCommand::Send(line.to_string()));
+            }
+            if let Some(level) = s.strip_prefix("log-level ") {
+                return Verbosity::from_str(level, false)
+                    .ok()
+                    .map(ControlCommand::LogLevel);
+            }
+            let duration = humantime::parse_duration(s.strip_prefix("maintenance on ")?).ok()?;
+            Some(ControlCommand::StartMaintenance(Some(duration)))
+        }
+    }
+}
+
+/// Splits the ID of a menu item that acts on one of several instances, `<ACTION>/<INSTANCE>`,
+/// into the action and the instance. Instance names can't contain slashes (see
+/// `cli::parse_instance_name`), so the instance is everything after the last one.
+pub fn instance_menu_id(id: &str) -> Option<(&str, &str)> {
+    id.rsplit_once('/')
+        .filter(|(action, name)| !action.is_empty() && !name.is_empty())
+}
+
+/// The number in the ID of a menu item made of `prefix` and a number, such as the items that
+/// cancel a queued run.
+pub fn numbered_menu_id(id: &str, prefix: &str) -> Option<u64> {
+    id.strip_prefix(prefix)?.parse().ok()
+}
+
+/// Splits the `#!` line at the start of a script into the name of its interpreter and the
+/// arguments it's given, e.g. `python3` and `-u` for `#!/usr/bin/env python3 -u`. `env` is
+/// looked through, along with its options and variable assignments, and the interpreter's
+/// directory is dropped, since it's a Unix path that rarely exists on Windows.
+pub fn shebang(line: &str) -> Option<(&str, Vec<&str>)> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = base_name(words.next()?);
+    if interpreter == "env" {
+        // `-S` splits the rest of the line, which split_whitespace already did
+        interpreter = base_name(
+            words
+                .by_ref()
+                .find(|word| !word.starts_with('-') && !word.contains('='))?,
+        );
+    }
+    (!interpreter.is_empty()).then(|| (interpreter, words.collect()))
+}
+
+/// Parses an amount of memory, as bytes with an optional binary unit of `K`, `M`, `G`, or `T`
+/// (`KiB`, `KB`, and so on too, all case-insensitive), e.g. `512M` or `1.5G`.
+pub fn byte_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let unit = unit.trim_start().to_ascii_lowercase();
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let shift = match unit.strip_suffix('i').unwrap_or(unit) {
+        "" if !unit.ends_with('i') => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return None,
+    };
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(1 << shift);
+    }
+    let fraction: f64 = number.parse().ok()?;
+    #[allow(clippy::cast_precision_loss)] // sizes are nowhere near 2^52 bytes
+    let bytes = (fraction * (1u64 << shift) as f64).round();
+    // a little under u64::MAX, which f64 can't hold exactly
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // checked just before
+    (0.0..1.8e19).contains(&bytes).then_some(bytes as u64)
+}
+
+/// The last component of a Unix or Windows path.
+fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use super::*;
+    use crate::{config::Config, TrayMessage};
+
+    /// A small deterministic generator, so that failures can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            usize::try_from(self.next() % n as u64).unwrap()
+        }
+
+        /// A string of up to `max` characters, mostly from `alphabet` and sometimes any
+        /// character at all.
+        fn string(&mut self, alphabet: &[char], max: usize) -> String {
+            (0..self.below(max + 1))
+                .map(|_| {
+                    if self.below(8) == 0 {
+                        let code = u32::try_from(self.next() % 0x11_0000).unwrap();
+                        char::from_u32(code).unwrap_or('\u{fffd}')
+                    } else {
+                        alphabet[self.below(alphabet.len())]
+                    }
+                })
+                .collect()
+        }
+    }
+
+    const RUNS: usize = 5_000;
+
+    const WIRE_ALPHABET: &[char] = &[
+        't', 'o', 'k', 'e', 'n', 's', 'a', 'u', 'd', 'i', 'l', 'm', 'h', '1', '0', '-', ' ', ' ',
+        '\r', '\n', '/', '…',
+    ];
+
+    const CONFIG: &str = r#"
+[profiles.base]
+cmd = ["sleep", "10"]
+notify_urgency = "low"
+
+[profiles.web]
+extends = "base"
+cmd = ["python", "-m", "http.server"]
+cwd = "/tmp"
+
+[profiles.web.env]
+PORT = "8000"
+"#;
+
+    #[test]
+    fn control_line_splits_token() {
+        assert_eq!(control_line("status\n"), (None, "status"));
+        assert_eq!(control_line("status\r\n"), (None, "status"));
+        assert_eq!(
+            control_line("token abc send  hi \n"),
+            (Some("abc"), "send  hi ")
+        );
+        assert_eq!(control_line("token abc"), (None, "token abc"));
+        assert_eq!(control_line(""), (None, ""));
+    }
+
+    #[test]
+    fn control_commands_round_trip() {
+        let mut rng = Rng(0x5eed);
+        let mut commands = vec![
+            ControlCommand::Status,
+            ControlCommand::Subscribe,
+            ControlCommand::Kill,
+            ControlCommand::ForceKill,
+            ControlCommand::StartMaintenance(None),
+            ControlCommand::StartMaintenance(Some(Duration::from_mins(90))),
+            ControlCommand::StartMaintenance(Some(Duration::from_millis(1_500))),
+            ControlCommand::EndMaintenance,
+            ControlCommand::Handover,
+            ControlCommand::RotateLog,
+        ];
+        commands.extend(
+            Verbosity::value_variants()
+                .iter()
+                .copied()
+                .map(ControlCommand::LogLevel),
+        );
+        for _ in 0..RUNS {
+            let line = rng.string(WIRE_ALPHABET, 40).replace(['\r', '\n'], " ");
+            commands.push(ControlCommand::Send(line));
+        }
+        for command in commands {
+            let line = format!("token secret {command}\n");
+            let (secret, parsed) = control_line(&line);
+            assert_eq!(secret, Some("secret"));
+            assert_eq!(control_command(parsed), Some(command));
+        }
+    }
+
+    #[test]
+    fn control_lines_never_panic() {
+        let mut rng = Rng(0x00c0_ffee);
+        for _ in 0..RUNS {
+            let line = rng.string(WIRE_ALPHABET, 60);
+            let (_, command) = control_line(&line);
+            if let Some(command) = control_command(command) {
+                // whatever parses is a command that can be sent back the same way
+                assert_eq!(control_command(&command.to_string()), Some(command));
+            }
+        }
+    }
+
+    #[test]
+    fn menu_ids() {
+        assert_eq!(instance_menu_id("Kill/web"), Some(("Kill", "web")));
+        assert_eq!(instance_menu_id("Kill/"), None);
+        assert_eq!(instance_menu_id("/web"), None);
+        assert_eq!(instance_menu_id("Kill"), None);
+        assert_eq!(numbered_menu_id("Cancel/3", "Cancel/"), Some(3));
+        assert_eq!(numbered_menu_id("Cancel/", "Cancel/"), None);
+        assert_eq!(numbered_menu_id("Cancel/-1", "Cancel/"), None);
+        assert_eq!(numbered_menu_id("Run Now", "Cancel/"), None);
+        assert_eq!(TrayMessage::from_str("Kill").ok(), Some(TrayMessage::Kill));
+        assert!(TrayMessage::from_str("1001").is_err());
+    }
+
+    #[test]
+    fn menu_ids_never_panic() {
+        let mut rng = Rng(0x0bad_c0de);
+        let alphabet: Vec<char> = "CancelKil Shw/0123456789…".chars().collect();
+        for _ in 0..RUNS {
+            let id = rng.string(&alphabet, 30);
+            if let Some((action, name)) = instance_menu_id(&id) {
+                assert!(!name.contains('/'));
+                assert_eq!(format!("{action}/{name}"), id);
+            }
+            if numbered_menu_id(&id, "Cancel/").is_some() {
+                assert!(id.starts_with("Cancel/"));
+            }
+            let _ = TrayMessage::from_str(&id);
+        }
+    }
+
+    #[test]
+    fn shebangs() {
+        assert_eq!(shebang("#!/bin/sh\n"), Some(("sh", vec![])));
+        assert_eq!(
+            shebang("#!/usr/bin/env python3 -u\r\n"),
+            Some(("python3", vec!["-u"]))
+        );
+        assert_eq!(
+            shebang("#!/usr/bin/env -S NODE_ENV=dev node --inspect"),
+            Some(("node", vec!["--inspect"]))
+        );
+        assert_eq!(shebang("#! /usr/bin/perl -w"), Some(("perl", vec!["-w"])));
+        assert_eq!(shebang("#!/usr/bin/env"), None);
+        assert_eq!(shebang("#!/usr/bin/"), None);
+        assert_eq!(shebang("echo hi"), None);
+    }
+
+    #[test]
+    fn shebangs_never_panic() {
+        let mut rng = Rng(0x5eba);
+        let alphabet: Vec<char> = "#!/usr/bin/env -S X=1 python\\ \t\r\n".chars().collect();
+        for _ in 0..RUNS {
+            let line = format!("#!{}", rng.string(&alphabet, 40));
+            if let Some((interpreter, args)) = shebang(&line) {
+                assert!(!interpreter.is_empty() && !interpreter.contains(['/', '\\']));
+                assert!(args.iter().all(|arg| !arg.is_empty()));
+            }
+        }
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(byte_size("512"), Some(512));
+        assert_eq!(byte_size("512M"), Some(512 << 20));
+        assert_eq!(byte_size("2 GiB"), Some(2 << 30));
+        assert_eq!(byte_size("1.5g"), Some(3 << 29));
+        assert_eq!(byte_size("64kb"), Some(64 << 10));
+        assert_eq!(byte_size("1T"), Some(1 << 40));
+        assert_eq!(byte_size("M"), None);
+        assert_eq!(byte_size("5i"), None);
+        assert_eq!(byte_size("5X"), None);
+        assert_eq!(byte_size("-1G"), None);
+        assert_eq!(byte_size("99999999999T"), None);
+        for size in ["1", "100", "2048", "512M", "3G", "7T", "1536K"] {
+            let parsed: crate::caps::ByteSize = size.parse().unwrap();
+            assert_eq!(
+                parsed.to_string().parse::<crate::caps::ByteSize>(),
+                Ok(parsed)
+            );
+        }
+    }
+
+    #[test]
+    fn byte_sizes_never_panic() {
+        let mut rng = Rng(0xb17e);
+        let alphabet: Vec<char> = "0123456789.kKmMgGtTiIbB -e".chars().collect();
+        for _ in 0..RUNS {
+            let _ = byte_size(&rng.string(&alphabet, 12));
+        }
+    }
+
+    #[test]
+    fn config_parses() {
+        let config = Config::parse(CONFIG, None).unwrap();
+        let web = config.profile("web").unwrap();
+        assert_eq!(web.cmd, ["python", "-m", "http.server"]);
+        assert!(Config::parse("[profiles.a]\nextends = \"a\"\n", None).is_err());
+        assert!(Config::parse("[profiles.a]\ncmd = 1\n", None).is_err());
+        assert!(Config::parse("", None).is_ok());
+    }
+
+    #[test]
+    fn configs_never_panic() {
+        let mut rng = Rng(0xfeed);
+        let bytes: Vec<char> = CONFIG.chars().collect();
+        for _ in 0..RUNS / 10 {
+            // a few characters of the valid config deleted, duplicated, or replaced
+            let mut mutated = bytes.clone();
+            for _ in 0..=rng.below(4) {
+                let at = rng.below(mutated.len());
+                match rng.below(3) {
+                    0 => {
+                        mutated.remove(at);
+                    }
+                    1 => mutated.insert(at, mutated[at]),
+                    _ => mutated[at] = bytes[rng.below(bytes.len())],
+                }
+            }
+            let mutated: String = mutated.into_iter().collect();
+            let _ = Config::parse(&mutated, None);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt;
+
+/// A process trayme didn't start, watched by its PID. Unlike a [`std::process::Child`], it can't
+/// be waited for, so it's polled instead. On Linux it's held by a pidfd, which keeps referring to
+/// it even if the PID is reused after it exits.
+#[derive(Debug)]
+pub struct WatchedProcess {
+    pid: u32,
+    handle: platform::Handle,
+}
+
+/// How a watched process ended, as far as trayme can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchedExit {
+    /// It exited with this code. Only Windows tells it to processes other than the parent.
+    #[cfg(windows)]
+    Code(u32),
+    /// It's gone, but how it ended isn't known.
+    Unknown,
+}
+
+impl fmt::Display for WatchedExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            WatchedExit::Code(code) => write!(f, "exited with code {code}"),
+            WatchedExit::Unknown => write!(f, "exited"),
+        }
+    }
+}
+
+impl WatchedProcess {
+    /// Starts watching the process with this PID.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if there is no such process, or it cannot be watched, e.g. because
+    /// it belongs to another user on Windows.
+    pub fn open(pid: u32) -> anyhow::Result<Self> {
+        Ok(Self {
+            pid,
+            handle: platform::Handle::open(pid)?,
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns how the process ended if it did, or `None` while it's running.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process' state cannot be queried.
+    pub fn try_wait(&self) -> anyhow::Result<Option<WatchedExit>> {
+        self.handle.try_wait(self.pid)
+    }
+
+    /// Asks the process to stop: `SIGTERM` on Unix. Windows has no such request for processes of
+    /// another console, so it's killed there.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be signaled, e.g. because it belongs to another
+    /// user.
+    pub fn terminate(&self) -> anyhow::Result<()> {
+        self.handle.terminate(self.pid)
+    }
+
+    /// Kills the process.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be killed, e.g. because it belongs to another
+    /// user.
+    pub fn kill(&self) -> anyhow::Result<()> {
+        self.handle.kill(self.pid)
+    }
+
+    /// The process' command line, or its program on Windows, if it can be found out.
+    pub fn describe(&self) -> Option<String> {
+        platform::describe(&self.handle, self.pid)
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{io, process::Command};
+
+    use anyhow::{bail, Context};
+
+    use super::WatchedExit;
+
+    #[derive(Debug)]
+    pub struct Handle {
+        /// `None` where pidfds aren't available, in which case the PID is signaled directly.
+        #[cfg(target_os = "linux")]
+        pidfd: Option<std::os::fd::OwnedFd>,
+    }
+
+    impl Handle {
+        pub fn open(pid: u32) -> anyhow::Result<Self> {
+            let pid = libc::pid_t::try_from(pid).context("Invalid PID")?;
+            // SAFETY: signal 0 only checks that the process exists
+            if unsafe { libc::kill(pid, 0) } != 0 {
+                let err = io::Error::last_os_error();
+                // it exists, but belongs to another user, so it can be watched but not killed
+                if err.raw_os_error() != Some(libc::EPERM) {
+                    bail!("No process with PID {pid}: {err}");
+                }
+            }
+            Ok(Self {
+                #[cfg(target_os = "linux")]
+                pidfd: pidfd_open(pid),
+            })
+        }
+
+        pub fn try_wait(&self, pid: u32) -> anyhow::Result<Option<WatchedExit>> {
+            #[cfg(target_os = "linux")]
+            if let Some(pidfd) = &self.pidfd {
+                use std::os::fd::AsRawFd;
+                let mut poll = libc::pollfd {
+                    fd: pidfd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: `poll` is one valid pollfd
+                let ready = unsafe { libc::poll(&raw mut poll, 1, 0) };
+                if ready < 0 {
+                    return Err(io::Error::last_os_error()).context("Failed to poll the pidfd");
+                }
+                // a pidfd becomes readable once the process exits
+                return Ok((ready > 0).then_some(WatchedExit::Unknown));
+            }
+            // SAFETY: signal 0 only checks that the process exists
+            if unsafe { libc::kill(pid.cast_signed(), 0) } == 0 {
+                return Ok(None);
+            }
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ESRCH) => Ok(Some(WatchedExit::Unknown)),
+                // still there, but another user's
+                _ => Ok(None),
+            }
+        }
+
+        pub fn terminate(&self, pid: u32) -> anyhow::Result<()> {
+            self.signal(pid, libc::SIGTERM)
+                .context("Failed to send SIGTERM")
+        }
+
+        pub fn kill(&self, pid: u32) -> anyhow::Result<()> {
+            self.signal(pid, libc::SIGKILL)
+                .context("Failed to send SIGKILL")
+        }
+
+        fn signal(&self, pid: u32, signal: libc::c_int) -> io::Result<()> {
+            #[cfg(target_os = "linux")]
+            if let Some(pidfd) = &self.pidfd {
+                use std::os::fd::AsRawFd;
+                // SAFETY: the pidfd is open, and no siginfo is passed
+                let sent = unsafe {
+                    libc::syscall(
+                        libc::SYS_pidfd_send_signal,
+                        pidfd.as_raw_fd(),
+                        signal,
+                        std::ptr::null::<libc::siginfo_t>(),
+                        0,
+                    )
+                };
+                return if sent == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                };
+            }
+            // SAFETY: kill has no memory safety preconditions
+            if unsafe { libc::kill(pid.cast_signed(), signal) } == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    }
+
+    pub fn describe(_handle: &Handle, pid: u32) -> Option<String> {
+        let output = Command::new("ps")
+            .args(["-o", "args=", "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        let args = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !args.is_empty()).then_some(args)
+    }
+
+    /// Opens a pidfd for the process, or returns `None` on kernels older than 5.3.
+    #[cfg(target_os = "linux")]
+    fn pidfd_open(pid: libc::pid_t) -> Option<std::os::fd::OwnedFd> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+        // SAFETY: pidfd_open has no memory safety preconditions
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        let fd = libc::c_int::try_from(fd).ok().filter(|&fd| fd >= 0)?;
+        // SAFETY: the fd was just opened and isn't owned by anything else
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{ffi::OsString, io, os::windows::ffi::OsStringExt, path::PathBuf};
+
+    use anyhow::bail;
+
+    use super::WatchedExit;
+
+    type RawHandle = *mut std::ffi::c_void;
+
+    const SYNCHRONIZE: u32 = 0x0010_0000;
+    const PROCESS_TERMINATE: u32 = 0x0001;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const WAIT_OBJECT_0: u32 = 0;
+    const WAIT_TIMEOUT: u32 = 0x102;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> RawHandle;
+        fn WaitForSingleObject(handle: RawHandle, millis: u32) -> u32;
+        fn GetExitCodeProcess(process: RawHandle, code: *mut u32) -> i32;
+        fn TerminateProcess(process: RawHandle, code: u32) -> i32;
+        fn QueryFullProcessImageNameW(
+            process: RawHandle,
+            flags: u32,
+            name: *mut u16,
+            size: *mut u32,
+        ) -> i32;
+        fn CloseHandle(handle: RawHandle) -> i32;
+    }
+
+    #[derive(Debug)]
+    pub struct Handle(RawHandle);
+
+    impl Handle {
+        pub fn open(pid: u32) -> anyhow::Result<Self> {
+            let access = SYNCHRONIZE | PROCESS_QUERY_LIMITED_INFORMATION;
+            // SAFETY: OpenProcess has no memory safety preconditions
+            let mut handle = unsafe { OpenProcess(access | PROCESS_TERMINATE, 0, pid) };
+            if handle.is_null() {
+                // e.g. an elevated process, which can still be watched
+                // SAFETY: as above
+                handle = unsafe { OpenProcess(access, 0, pid) };
+            }
+            if handle.is_null() {
+                bail!(
+                    "Failed to open the process with PID {pid}: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            Ok(Self(handle))
+        }
+
+        pub fn try_wait(&self, _pid: u32) -> anyhow::Result<Option<WatchedExit>> {
+            // SAFETY: the handle is open until this is dropped
+            match unsafe { WaitForSingleObject(self.0, 0) } {
+                WAIT_TIMEOUT => Ok(None),
+                WAIT_OBJECT_0 => {
+                    let mut code = 0;
+                    // SAFETY: as above, and `code` is a valid u32
+                    if unsafe { GetExitCodeProcess(self.0, &raw mut code) } == 0 {
+                        return Ok(Some(WatchedExit::Unknown));
+                    }
+                    Ok(Some(WatchedExit::Code(code)))
+                }
+                _ => bail!(
+                    "Failed to query the process: {}",
+                    io::Error::last_os_error()
+                ),
+            }
+        }
+
+        pub fn terminate(&self, pid: u32) -> anyhow::Result<()> {
+            self.kill(pid)
+        }
+
+        pub fn kill(&self, _pid: u32) -> anyhow::Result<()> {
+            // SAFETY: the handle is open until this is dropped
+            if unsafe { TerminateProcess(self.0, 1) } == 0 {
+                bail!("Failed to kill the process: {}", io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    pub fn describe(handle: &Handle, _pid: u32) -> Option<String> {
+        let mut name = vec![0_u16; 1024];
+        let mut size = u32::try_from(name.len()).ok()?;
+        // SAFETY: `name` holds `size` u16s
+        let ok =
+            unsafe { QueryFullProcessImageNameW(handle.0, 0, name.as_mut_ptr(), &raw mut size) };
+        if ok == 0 {
+            return None;
+        }
+        name.truncate(size as usize);
+        Some(
+            PathBuf::from(OsString::from_wide(&name))
+                .display()
+                .to_string(),
+        )
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle is open and only closed here
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
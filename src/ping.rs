@@ -0,0 +1,87 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::notifyroute;
+
+/// How long the monitoring service has to accept a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much of the body is sent with a ping. healthchecks.io keeps up to 100 kB of it.
+const MAX_BODY_LEN: usize = 10_000;
+
+/// The URL of a check of a dead man's switch service, pinged around each run the way
+/// healthchecks.io expects: `<url>/start` when a run starts, `<url>` when it succeeds, and
+/// `<url>/fail` when it fails. The service alerts once the pings stop coming, which it also does
+/// when the machine or trayme itself is down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PingUrl(String);
+
+impl PingUrl {
+    /// Tells the service that a run started, so that it can time it and notice runs that hang.
+    pub fn start(&self) {
+        self.ping("/start", String::new());
+    }
+
+    /// Tells the service that a run succeeded, with `body` for its log.
+    pub fn success(&self, body: &str) {
+        self.ping("", body.to_string());
+    }
+
+    /// Tells the service that a run failed, with `body` for its log.
+    pub fn fail(&self, body: &str) {
+        self.ping("/fail", body.to_string());
+    }
+
+    /// Sends the ping in the background. Failures are logged, the service alerts about missing
+    /// pings by itself.
+    fn ping(&self, suffix: &str, mut body: String) {
+        let url = format!("{}{suffix}", self.0);
+        if body.len() > MAX_BODY_LEN {
+            let mut end = MAX_BODY_LEN;
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            body.truncate(end);
+        }
+        notifyroute::spawn_pending(move || {
+            match ureq::post(&url).timeout(PING_TIMEOUT).send_string(&body) {
+                Ok(_) => debug!("Pinged {url}"),
+                Err(e) => warn!("Failed to ping {url}: {e}"),
+            }
+        });
+    }
+}
+
+impl FromStr for PingUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !(s.starts_with("http://") || s.starts_with("https://")) {
+            return Err(format!("'{s}' is not an http:// or https:// URL"));
+        }
+        Ok(Self(s.trim_end_matches('/').to_string()))
+    }
+}
+
+impl fmt::Display for PingUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl TryFrom<String> for PingUrl {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PingUrl> for String {
+    fn from(url: PingUrl) -> Self {
+        url.0
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    ffi::OsStr,
+    io::Read,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use log::{debug, info};
+
+use crate::supervisor::CommandSpec;
+
+/// How long the pre-check may run before it's killed and counted as failed.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the pre-check is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many characters of the end of the pre-check's output are shown in the notification.
+const MAX_OUTPUT: usize = 300;
+
+/// Builds the command that runs `cmd` with the shell, in the working directory and environment
+/// the command of `spec` is spawned in.
+pub fn spec_shell_command(cmd: impl AsRef<OsStr>, spec: &CommandSpec) -> Command {
+    let mut command = shell_command(cmd);
+    if let Some(cwd) = &spec.cwd {
+        command.current_dir(cwd);
+    }
+    if let Some(env) = &spec.env {
+        command.env_clear().envs(env);
+    }
+    command.envs(spec.overrides());
+    command
+}
+
+/// Runs `check` with the shell (`sh -c` on Unix, `cmd /C` on Windows), in the working directory
+/// and environment the command of `spec` is spawned in.
+///
+/// # Errors
+///
+/// An error is returned if the check cannot be run, exits with a non-zero status, or takes longer
+/// than [`TIMEOUT`]. The message ends with the check's output.
+pub fn run(check: &str, spec: &CommandSpec) -> anyhow::Result<()> {
+    info!("Running pre-check: {check}");
+    run_captured(spec_shell_command(check, spec), check, TIMEOUT)?;
+    debug!("Pre-check passed");
+    Ok(())
+}
+
+/// Runs `command`, the shell running `cmd`, with its output captured, and returns it.
+///
+/// # Errors
+///
+/// An error is returned if the command cannot be run, exits with a non-zero status, or takes
+/// longer than `timeout`. The message ends with the end of its output.
+pub fn run_captured(mut command: Command, cmd: &str, timeout: Duration) -> anyhow::Result<String> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to run `{cmd}`"))?;
+    let readers = [
+        child.stdout.take().map(read_all),
+        child.stderr.take().map(read_all),
+    ];
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("Failed to wait for `{cmd}`"))?
+        {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let output: String = readers
+        .into_iter()
+        .flatten()
+        .filter_map(|reader| reader.join().ok())
+        .collect();
+    if status.is_some_and(|status| status.success()) {
+        return Ok(output);
+    }
+    let output = match tail(output.trim_end()) {
+        "" => String::new(),
+        tail => format!("\n{tail}"),
+    };
+    match status {
+        Some(status) => bail!("`{cmd}` failed ({status}){output}"),
+        None => bail!(
+            "`{cmd}` didn't finish within {}{output}",
+            humantime::format_duration(timeout)
+        ),
+    }
+}
+
+/// The command line that runs `cmd` with the same shell as the pre-check, for the other options
+/// that take a shell command, such as `--fallback`.
+pub fn shell_argv(cmd: &str) -> Vec<String> {
+    if cfg!(windows) {
+        vec!["cmd".to_string(), "/C".to_string(), cmd.to_string()]
+    } else {
+        vec!["sh".to_string(), "-c".to_string(), cmd.to_string()]
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(check: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(check);
+    command
+}
+
+#[cfg(windows)]
+fn shell_command(check: impl AsRef<OsStr>) -> Command {
+    use std::os::windows::process::CommandExt;
+    // https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#flags
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    let mut command = Command::new("cmd");
+    // passed as is, since cmd doesn't parse its command line like other programs do
+    command
+        .arg("/C")
+        .raw_arg(check)
+        .creation_flags(CREATE_NO_WINDOW);
+    command
+}
+
+/// Reads `pipe` to the end on a thread of its own, so that a check with a lot of output on both
+/// stdout and stderr doesn't block on either.
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+/// The last [`MAX_OUTPUT`] characters of `output`, starting at a line if possible.
+fn tail(output: &str) -> &str {
+    let Some((start, _)) = output.char_indices().rev().nth(MAX_OUTPUT) else {
+        return output;
+    };
+    let rest = &output[start..];
+    match rest.find('\n') {
+        Some(newline) if newline + 1 < rest.len() => &rest[newline + 1..],
+        _ => rest,
+    }
+}
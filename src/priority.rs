@@ -0,0 +1,181 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use strum::VariantArray;
+use tray_icon::menu::{CheckMenuItem, Submenu};
+
+use crate::child::ChildProcess;
+
+/// The prefix of the items of the "Priority" submenu. It's followed by the priority's name.
+const PRIORITY_PREFIX: &str = "Priority/";
+
+/// How much CPU time the process gets when others want it too, see `--priority`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, VariantArray, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    /// The same as every other program.
+    #[default]
+    Normal,
+    /// Behind foreground work: nice 10 on Unix, `BELOW_NORMAL_PRIORITY_CLASS` on Windows.
+    BelowNormal,
+    /// Only when nothing else wants the CPU: nice 19 on Unix, `IDLE_PRIORITY_CLASS` on Windows.
+    Idle,
+}
+
+impl Priority {
+    /// Gives the process this priority, along with the processes it already started on Unix.
+    /// Processes it starts from now on inherit it.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the priority cannot be changed, e.g. because raising it back up
+    /// takes privileges on Unix.
+    pub fn apply(self, child: &ChildProcess) -> anyhow::Result<()> {
+        platform::apply(child, self)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Priority::Normal => "normal",
+            Priority::BelowNormal => "below-normal",
+            Priority::Idle => "idle",
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Normal => write!(f, "Normal"),
+            Priority::BelowNormal => write!(f, "Below Normal"),
+            Priority::Idle => write!(f, "Idle"),
+        }
+    }
+}
+
+/// The "Priority" submenu of the tray, with the current priority checked.
+pub struct PriorityMenu {
+    pub submenu: Submenu,
+    items: Vec<(Priority, CheckMenuItem)>,
+    shown: Priority,
+}
+
+impl PriorityMenu {
+    /// Creates the submenu.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the submenu cannot be built.
+    pub fn new(current: Priority) -> anyhow::Result<Self> {
+        let submenu = Submenu::new("Priority", true);
+        let mut items = Vec::new();
+        for &priority in Priority::VARIANTS {
+            let id = format!("{PRIORITY_PREFIX}{}", priority.name());
+            let item =
+                CheckMenuItem::with_id(id, priority.to_string(), true, priority == current, None);
+            submenu.append(&item)?;
+            items.push((priority, item));
+        }
+        Ok(Self {
+            submenu,
+            items,
+            shown: current,
+        })
+    }
+
+    /// Checks the item of `current` if the priority changed since the last call.
+    pub fn update(&mut self, current: Priority) {
+        if current == self.shown {
+            return;
+        }
+        for (priority, item) in &self.items {
+            item.set_checked(*priority == current);
+        }
+        self.shown = current;
+    }
+
+    /// The priority of the clicked item, or `None` if the item isn't in the submenu. The item's
+    /// check mark is put back until the priority was actually changed, since clicking toggles it.
+    pub fn handle(&mut self, id: &str) -> Option<Priority> {
+        let name = id.strip_prefix(PRIORITY_PREFIX)?;
+        let (priority, _) = self.items.iter().find(|(p, _)| p.name() == name)?;
+        let priority = *priority;
+        for (p, item) in &self.items {
+            item.set_checked(*p == self.shown);
+        }
+        Some(priority)
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+
+    use anyhow::bail;
+
+    use super::Priority;
+    use crate::child::ChildProcess;
+
+    pub fn apply(child: &ChildProcess, priority: Priority) -> anyhow::Result<()> {
+        let nice = match priority {
+            Priority::Normal => 0,
+            Priority::BelowNormal => 10,
+            Priority::Idle => 19,
+        };
+        // the child leads a process group of its own, see spawn_process
+        // SAFETY: setpriority has no memory safety preconditions
+        if unsafe { libc::setpriority(libc::PRIO_PGRP, child.id(), nice) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => bail!(
+                "Failed to set the priority to {priority}: {err} (raising it back up takes root, \
+                 or a high enough RLIMIT_NICE)"
+            ),
+            _ => bail!("Failed to set the priority to {priority}: {err}"),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{io, os::windows::io::AsRawHandle};
+
+    use anyhow::bail;
+
+    use super::Priority;
+    use crate::child::ChildProcess;
+
+    type Handle = *mut std::ffi::c_void;
+
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetPriorityClass(process: Handle, priority_class: u32) -> i32;
+    }
+
+    pub fn apply(child: &ChildProcess, priority: Priority) -> anyhow::Result<()> {
+        let class = match priority {
+            Priority::Normal => NORMAL_PRIORITY_CLASS,
+            Priority::BelowNormal => BELOW_NORMAL_PRIORITY_CLASS,
+            Priority::Idle => IDLE_PRIORITY_CLASS,
+        };
+        // the processes the child starts from now on inherit below normal and idle, but the
+        // ones it already started keep theirs
+        // SAFETY: the handle belongs to `child`, which outlives the call
+        if unsafe { SetPriorityClass(child.as_raw_handle().cast(), class) } == 0 {
+            bail!(
+                "Failed to set the priority to {priority}: {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
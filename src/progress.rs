@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+/// Follows the progress a process reports in its output, e.g. `42%` from a backup or encode job,
+/// and estimates when it will be done.
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    pattern: Regex,
+    percent: Option<f64>,
+    /// When and at what progress the rate measurement started.
+    since: Option<(Instant, f64)>,
+}
+
+impl ProgressTracker {
+    /// Creates a tracker for lines matching `pattern`. The first capture group (or the whole
+    /// match if there is none) must be the percentage, e.g. `(\d+)%`.
+    pub fn new(pattern: Regex) -> Self {
+        Self {
+            pattern,
+            percent: None,
+            since: None,
+        }
+    }
+
+    /// Checks a line of output for a progress report. Lines that don't match, or whose match
+    /// isn't a number from 0 to 100, are ignored.
+    pub fn observe(&mut self, line: &str) {
+        let Some(captures) = self.pattern.captures(line) else {
+            return;
+        };
+        let found = captures.get(1).or_else(|| captures.get(0));
+        let percent = found.and_then(|found| {
+            found
+                .as_str()
+                .trim_end_matches('%')
+                .trim()
+                .parse::<f64>()
+                .ok()
+        });
+        let Some(percent) = percent.filter(|percent| (0.0..=100.0).contains(percent)) else {
+            return;
+        };
+        // going backwards usually means the next step of a multi-step job started
+        if !self.percent.is_some_and(|last| percent >= last) {
+            self.since = Some((Instant::now(), percent));
+        }
+        self.percent = Some(percent);
+    }
+
+    /// The last reported progress, from 0 to 100, or `None` if nothing was reported yet.
+    pub fn percent(&self) -> Option<f64> {
+        self.percent
+    }
+
+    /// Estimates how long it will take to reach 100%, assuming the progress keeps going at the
+    /// average rate since it started. Returns `None` until there was some progress.
+    pub fn eta(&self) -> Option<Duration> {
+        let (since, start) = self.since?;
+        let percent = self.percent?;
+        if percent <= start || percent >= 100.0 {
+            return None;
+        }
+        let per_percent = since.elapsed().as_secs_f64() / (percent - start);
+        Some(Duration::from_secs_f64(per_percent * (100.0 - percent)))
+    }
+
+    /// Forgets the reported progress, e.g. when the process is restarted.
+    pub fn reset(&mut self) {
+        self.percent = None;
+        self.since = None;
+    }
+}
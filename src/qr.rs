@@ -0,0 +1,55 @@
+use anyhow::Context;
+use qrcode::{render::svg, QrCode};
+
+use crate::get_logs_dir;
+
+/// The width and height of the code, in pixels. Big enough to scan from a laptop screen.
+const SIZE: u32 = 320;
+
+/// Shows a QR code of `url` for opening it on a phone. The code is written to a small page
+/// with the URL below it, which is opened with the default browser.
+///
+/// # Arguments
+///
+/// * `url` - The URL to encode.
+/// * `index` - Which of the instance's URLs it is, so that codes shown side by side don't
+///   overwrite each other.
+///
+/// # Errors
+///
+/// An error is returned if the URL is too long for a QR code, or if the page cannot be written
+/// or opened.
+pub fn show(url: &str, index: usize) -> anyhow::Result<()> {
+    let code = QrCode::new(url.as_bytes()).context("Failed to make a QR code")?;
+    let image = code
+        .render::<svg::Color<'_>>()
+        .min_dimensions(SIZE, SIZE)
+        .quiet_zone(true)
+        .build();
+    let url = escape(url);
+    let page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{url}</title></head>\n\
+         <body style=\"font-family: sans-serif; text-align: center; margin-top: 2em\">\n\
+         {image}\n<p>{url}</p>\n</body></html>\n"
+    );
+    let dir = get_logs_dir()?.join("qr");
+    std::fs::create_dir_all(&dir).context("Failed to create QR code directory")?;
+    let path = dir.join(format!("{}-{index}.html", std::process::id()));
+    std::fs::write(&path, page).with_context(|| format!("Failed to write {}", path.display()))?;
+    open::that(&path).context("Failed to open QR code")
+}
+
+/// Escapes text for HTML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
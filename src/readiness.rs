@@ -0,0 +1,108 @@
+use std::{path::Path, time::Duration};
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use std::time::Instant;
+
+use log::{debug, warn};
+
+/// How often the bus is checked for a tray host while waiting for one.
+#[cfg(all(unix, not(target_os = "macos")))]
+const TRAY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Waits until the desktop can show tray icons, for autostart entries that start before the
+/// panel does. Returns `false` if it still can't after `timeout`. Only Linux desktops start
+/// their tray late, so this returns right away elsewhere.
+pub fn wait_for_tray(timeout: Duration) -> bool {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        let start = Instant::now();
+        loop {
+            match crate::display::bus_name_owned(crate::display::TRAY_BUS_NAME) {
+                Ok(Some(true)) => {
+                    debug!("Tray host is up after {:?}", start.elapsed());
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Not waiting for the tray, it can't be checked: {e}");
+                    return true;
+                }
+            }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(TRAY_POLL_INTERVAL);
+        }
+    }
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    {
+        let _ = timeout;
+        true
+    }
+}
+
+/// Tells the service manager that the instance is up, if it was started by systemd as a
+/// `Type=notify` service. Does nothing otherwise.
+pub fn notify_ready() {
+    #[cfg(target_os = "linux")]
+    if let Err(e) = sd_notify("READY=1") {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Sends a state change to systemd over the socket in `NOTIFY_SOCKET`, like `sd_notify(3)`.
+#[cfg(target_os = "linux")]
+fn sd_notify(state: &str) -> std::io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+        None => std::os::unix::net::SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    debug!("Notified systemd: {state}");
+    Ok(())
+}
+
+/// Returns a systemd user unit that starts `profile` with the graphical session and reports
+/// readiness once its tray icon and process are both up.
+///
+/// # Arguments
+///
+/// * `exe` - The trayme binary to start.
+/// * `profile` - The profile to start.
+/// * `config` - The config file to take the profile from, if not the default one.
+pub fn systemd_unit(exe: &Path, profile: &str, config: Option<&Path>) -> String {
+    let quote = |arg: &str| {
+        if arg.contains(char::is_whitespace) {
+            format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+        } else {
+            arg.to_string()
+        }
+    };
+    let mut exec = format!("{} up {}", quote(&exe.to_string_lossy()), quote(profile));
+    if let Some(config) = config {
+        exec.push_str(" --config ");
+        exec.push_str(&quote(&config.to_string_lossy()));
+    }
+    exec.push_str(" --wait-for-tray");
+    format!(
+        "[Unit]\n\
+         Description=trayme ({profile})\n\
+         PartOf=graphical-session.target\n\
+         After=graphical-session.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={exec}\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         WantedBy=graphical-session.target\n"
+    )
+}
@@ -0,0 +1,187 @@
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf};
+
+use anyhow::{bail, Context};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exitcode::{ErrorKind, WithKind},
+    get_logs_dir,
+    ipc::{self, ControlCommand},
+    osargs,
+};
+
+/// An entry in the instance registry. Every running instance writes one so that other trayme
+/// processes (e.g. a tray front-end) can find its control socket by name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    pub name: String,
+    pub pid: u32,
+    pub addr: SocketAddr,
+    #[serde(with = "osargs")]
+    pub cmd: Vec<OsString>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Registration {
+    /// Returns `true` if the instance answers on its control socket. Entries of crashed instances
+    /// stay behind in the registry, so this is the only reliable way to tell.
+    pub fn is_alive(&self) -> bool {
+        ipc::request(self.addr, &ControlCommand::Status).is_ok()
+    }
+
+    /// Returns `true` if the instance has any of `tags`. Every instance matches an empty list.
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        tags.is_empty() || self.tags.iter().any(|tag| tags.contains(tag))
+    }
+}
+
+/// Removes the registry entry it was created for when dropped or when [`RegistryGuard::remove`]
+/// is called, whichever comes first.
+#[derive(Debug)]
+pub struct RegistryGuard {
+    path: Option<PathBuf>,
+}
+
+impl RegistryGuard {
+    /// Moves the registry entry to `name`, for when the instance is renamed.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the entry was already removed or cannot be read, if another live
+    /// instance is registered under `name`, or if the new entry cannot be written.
+    pub fn rename(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut registration = self.read()?;
+        registration.name = name.to_string();
+        let mut renamed = register(&registration)?;
+        self.remove();
+        self.path = renamed.path.take();
+        Ok(())
+    }
+
+    /// Records the command the instance runs now, for when it's restarted with another one.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the entry was already removed or cannot be read or written.
+    pub fn set_cmd(&mut self, cmd: &[OsString]) -> anyhow::Result<()> {
+        let mut registration = self.read()?;
+        registration.cmd = cmd.to_vec();
+        // not through `register`, which would find this very instance running under the name
+        let contents =
+            toml::to_string(&registration).context("Failed to serialize registration")?;
+        std::fs::write(self.path()?, contents).context("Failed to write registry entry")
+    }
+
+    fn path(&self) -> anyhow::Result<&PathBuf> {
+        self.path
+            .as_ref()
+            .context("The instance is no longer registered")
+    }
+
+    fn read(&self) -> anyhow::Result<Registration> {
+        let path = self.path()?;
+        let contents = std::fs::read_to_string(path).context("Failed to read registry entry")?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Invalid registry entry {}", path.display()))
+    }
+
+    /// Removes the registry entry. This is idempotent.
+    pub fn remove(&mut self) {
+        if let Some(path) = self.path.take() {
+            debug!("Unregistering instance {path:?}");
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove registry entry {path:?}: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for RegistryGuard {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Registers a running instance under its name.
+///
+/// # Errors
+///
+/// An error is returned if another live instance is already registered under the same name, or if
+/// the entry cannot be written. Stale entries left behind by crashed instances are overwritten.
+pub fn register(registration: &Registration) -> anyhow::Result<RegistryGuard> {
+    let path = entry_path(&registration.name)?;
+    if let Ok(existing) = lookup(&registration.name) {
+        if existing.is_alive() {
+            bail!(
+                "An instance named '{}' is already running (PID {})",
+                existing.name,
+                existing.pid
+            );
+        }
+        debug!("Overwriting stale registry entry for '{}'", existing.name);
+    }
+    let contents = toml::to_string(registration).context("Failed to serialize registration")?;
+    std::fs::write(&path, contents).context("Failed to write registry entry")?;
+    Ok(RegistryGuard { path: Some(path) })
+}
+
+/// Looks up the instance registered under `name`.
+///
+/// # Errors
+///
+/// An error is returned if no instance with that name is registered.
+pub fn lookup(name: &str) -> anyhow::Result<Registration> {
+    let path = entry_path(name)?;
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("No instance named '{name}' is running"))
+        .with_kind(ErrorKind::NotFound)?;
+    toml::from_str(&contents).with_context(|| format!("Invalid registry entry {}", path.display()))
+}
+
+/// Lists the live instances in the registry, sorted by name.
+///
+/// # Errors
+///
+/// An error is returned if the registry cannot be read.
+pub fn list() -> anyhow::Result<Vec<Registration>> {
+    let mut instances = Vec::new();
+    for entry in std::fs::read_dir(registry_dir()?).context("Failed to read instance registry")? {
+        let path = entry?.path();
+        let parsed = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(toml::from_str::<Registration>(&contents)?));
+        match parsed {
+            Ok(registration) if registration.is_alive() => instances.push(registration),
+            Ok(registration) => debug!("Skipping stale registry entry for '{}'", registration.name),
+            Err(e) => warn!("Skipping invalid registry entry {}: {e:#}", path.display()),
+        }
+    }
+    instances.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(instances)
+}
+
+/// Resolves an instance name or control socket address, as given on the command line, to the
+/// address of the instance's control socket.
+///
+/// # Errors
+///
+/// An error is returned if `target` isn't an address and no instance with that name is
+/// registered.
+pub fn resolve(target: &str) -> anyhow::Result<SocketAddr> {
+    match target.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => Ok(lookup(target)?.addr),
+    }
+}
+
+fn registry_dir() -> anyhow::Result<PathBuf> {
+    let dir = get_logs_dir()?.join("instances");
+    std::fs::create_dir_all(&dir).context("Failed to create instance registry")?;
+    Ok(dir)
+}
+
+fn entry_path(name: &str) -> anyhow::Result<PathBuf> {
+    Ok(registry_dir()?.join(format!("{name}.toml")))
+}
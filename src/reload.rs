@@ -0,0 +1,182 @@
+use std::{
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::config::{Config, Profile, ProfileRef};
+
+/// How often the config file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings that are applied to the running instance as soon as they change.
+const LIVE: &[&str] = &[
+    "notify_urgency",
+    "notify_sounds",
+    "notify",
+    "stop_strategy",
+    "kill_timeout",
+    "unhealthy_if",
+    "threshold",
+    "restart_on_unhealthy",
+    "restart_policy",
+    "restart_backoff",
+    "max_restarts",
+    "restart_every",
+    "restart_cron",
+    "on_wake",
+    "wake_check",
+    "progress_regex",
+    "cpu_throttle",
+    "priority",
+    "maintenance_duration",
+];
+
+/// Settings that are used from the next time the process is restarted.
+const NEXT_RUN: &[&str] = &[
+    "cmd",
+    "cwd",
+    "env",
+    "env_provider",
+    "tz",
+    "locale",
+    "ulimits",
+    "pre_check",
+];
+
+/// Watches the config file an instance's profile came from, so that changes to it can be applied
+/// without restarting the instance.
+#[derive(Debug)]
+pub struct ProfileWatcher {
+    profile: ProfileRef,
+    current: toml::Table,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+/// How the profile changed since it was last loaded.
+#[derive(Debug, Clone)]
+pub struct ProfileChange {
+    /// The profile as it is now.
+    pub profile: Profile,
+    /// The changed settings that were applied right away.
+    pub applied: Vec<String>,
+    /// The changed settings that take effect when the process is restarted.
+    pub next_run: Vec<String>,
+    /// The changed settings that only take effect when trayme is restarted, e.g. because
+    /// they're part of the tray menu.
+    pub needs_restart: Vec<String>,
+}
+
+impl ProfileWatcher {
+    /// Starts watching the config file of `profile`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the profile cannot be loaded.
+    pub fn new(profile: ProfileRef) -> anyhow::Result<Self> {
+        let modified = modified(&profile);
+        let current = load(&profile)?.1;
+        Ok(Self {
+            profile,
+            current,
+            modified,
+            last_check: Instant::now(),
+        })
+    }
+
+    /// Checks whether the profile changed since the last call. Call this regularly: the file is
+    /// only looked at every [`CHECK_INTERVAL`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the config file changed but the profile cannot be loaded from it,
+    /// e.g. because it's in the middle of being edited. The next change is picked up anyway.
+    pub fn poll(&mut self) -> anyhow::Result<Option<ProfileChange>> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return Ok(None);
+        }
+        self.last_check = Instant::now();
+        let modified = modified(&self.profile);
+        if modified == self.modified {
+            return Ok(None);
+        }
+        self.modified = modified;
+        let (profile, settings) = load(&self.profile)?;
+        let mut changed: Vec<_> = settings
+            .iter()
+            .filter(|(key, value)| self.current.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .chain(
+                self.current
+                    .keys()
+                    .filter(|key| !settings.contains_key(*key))
+                    .cloned(),
+            )
+            .collect();
+        self.current = settings;
+        // what it inherits shows up in the other settings
+        changed.retain(|key| key != "extends");
+        if changed.is_empty() {
+            return Ok(None);
+        }
+        changed.sort();
+        let (applied, rest): (Vec<_>, Vec<_>) = changed
+            .into_iter()
+            .partition(|key| LIVE.contains(&key.as_str()));
+        let (next_run, needs_restart) = rest
+            .into_iter()
+            .partition(|key| NEXT_RUN.contains(&key.as_str()));
+        Ok(Some(ProfileChange {
+            profile,
+            applied,
+            next_run,
+            needs_restart,
+        }))
+    }
+}
+
+impl ProfileChange {
+    /// Whether the setting `key` changed and is applied right away.
+    pub fn applies(&self, key: &str) -> bool {
+        self.applied.iter().any(|applied| applied == key)
+    }
+}
+
+/// Lists the changed settings by when they take effect.
+impl fmt::Display for ProfileChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups = [
+            ("Applied", &self.applied),
+            ("After the next restart", &self.next_run),
+            ("Needs trayme restarted", &self.needs_restart),
+        ];
+        let mut first = true;
+        for (label, keys) in groups {
+            if keys.is_empty() {
+                continue;
+            }
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{label}: {}", keys.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn modified(profile: &ProfileRef) -> Option<SystemTime> {
+    std::fs::metadata(&profile.config)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Loads the profile, along with its settings as they'd be written, to compare them one by one.
+fn load(profile: &ProfileRef) -> anyhow::Result<(Profile, toml::Table)> {
+    let config = Config::load(&profile.config)?;
+    let profile = config.profile(&profile.name)?.clone();
+    let settings = toml::Table::try_from(&profile).context("Failed to serialize profile")?;
+    Ok((profile, settings))
+}
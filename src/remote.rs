@@ -0,0 +1,441 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::{
+    menu::{Menu, MenuEvent, MenuEventReceiver, MenuItem, PredefinedMenuItem, Submenu},
+    TrayIcon,
+};
+
+use crate::{
+    build_tray, build_tray_menu, console, display, icon,
+    ipc::{self, ControlCommand, ControlResponse, InstanceStatus, ProcessState},
+    notify::show_notification,
+    parse,
+    registry::{self, Registration},
+    state::TrayState,
+};
+
+/// How often the front-end asks the instance for its status.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Menu items of the front-end tray. These mirror the regular tray, except that closing the tray
+/// leaves the instance running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VariantArray)]
+enum FrontendMessage {
+    Kill,
+    ShowLogs,
+    Console,
+    Maintenance,
+    CloseTray,
+}
+
+impl fmt::Display for FrontendMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrontendMessage::Kill => write!(f, "Kill"),
+            FrontendMessage::ShowLogs => write!(f, "Show Logs"),
+            FrontendMessage::Console => write!(f, "Console…"),
+            FrontendMessage::Maintenance => write!(f, "Maintenance Mode"),
+            FrontendMessage::CloseTray => write!(f, "Close Tray"),
+        }
+    }
+}
+
+impl FromStr for FrontendMessage {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Kill" => Ok(FrontendMessage::Kill),
+            "Show Logs" => Ok(FrontendMessage::ShowLogs),
+            "Console…" => Ok(FrontendMessage::Console),
+            "Maintenance Mode" => Ok(FrontendMessage::Maintenance),
+            "Close Tray" => Ok(FrontendMessage::CloseTray),
+            _ => Err(strum::ParseError::VariantNotFound),
+        }
+    }
+}
+
+/// A tray icon for an instance running in another process. It polls the instance's status over
+/// IPC and forwards menu actions to it.
+struct Frontend {
+    /// An instance name or control socket address, as given on the command line.
+    target: String,
+    last_poll: Option<Instant>,
+    status: Option<InstanceStatus>,
+}
+
+impl Frontend {
+    fn request(&self, command: &ControlCommand) -> anyhow::Result<ControlResponse> {
+        // instances get a new port every time they start, so names are looked up every time
+        ipc::request(registry::resolve(&self.target)?, command)
+    }
+
+    /// Turns the instance's maintenance mode off if the cached status has it on, and on
+    /// otherwise. The instance notifies about the change itself.
+    fn toggle_maintenance(&self) {
+        let command = if self
+            .status
+            .as_ref()
+            .is_some_and(|status| status.maintenance_until.is_some())
+        {
+            ControlCommand::EndMaintenance
+        } else {
+            ControlCommand::StartMaintenance(None)
+        };
+        match self.request(&command) {
+            Ok(ControlResponse::Error { message }) => {
+                show_notification("Failed to change maintenance mode", &message);
+            }
+            Ok(_) => {}
+            Err(e) => show_notification("Failed to change maintenance mode", &format!("{e:#}")),
+        }
+    }
+
+    /// Refreshes the cached status and the tooltip, notifying about state changes.
+    fn refresh(&mut self, tray: &TrayIcon) -> anyhow::Result<()> {
+        let status = match self.request(&ControlCommand::Status) {
+            Ok(ControlResponse::Status(status)) => Some(status),
+            Ok(response) => {
+                warn!("Unexpected status response: {response:?}");
+                None
+            }
+            Err(e) => {
+                debug!("Instance not reachable: {e:#}");
+                None
+            }
+        };
+
+        let was_running = self
+            .status
+            .as_ref()
+            .is_some_and(|s| s.state == ProcessState::Running);
+        let is_running = status
+            .as_ref()
+            .is_some_and(|s| s.state == ProcessState::Running);
+        if was_running && !is_running {
+            info!("Instance '{}' stopped", self.target);
+            show_notification(
+                "Process exited",
+                &format!("'{}' is no longer running", self.target),
+            );
+        }
+
+        let tooltip = match &status {
+            Some(s) => {
+                let state = TrayState::of(s);
+                let detail = match state {
+                    TrayState::Running | TrayState::Unhealthy => {
+                        format!("{state} (PID {}), {}", s.pid, s.levels)
+                    }
+                    TrayState::Failed | TrayState::Stopped => {
+                        s.exit_status.as_deref().unwrap_or("stopped").to_string()
+                    }
+                };
+                format!("{} {}: {detail}", state.glyph(), s.name)
+            }
+            None => format!(
+                "{} {}: not running",
+                TrayState::Stopped.glyph(),
+                self.target
+            ),
+        };
+        tray.set_tooltip(Some(tooltip))
+            .context("Failed to update tooltip")?;
+        self.status = status;
+        Ok(())
+    }
+
+    /// Handles one iteration of the event loop. Returns the [`ControlFlow`] for the next one.
+    fn tick(
+        &mut self,
+        tray: &TrayIcon,
+        menu_channel: &MenuEventReceiver,
+    ) -> anyhow::Result<ControlFlow> {
+        let poll_due = match self.last_poll {
+            Some(last_poll) => last_poll.elapsed() >= STATUS_POLL_INTERVAL,
+            None => true,
+        };
+        if poll_due {
+            self.last_poll = Some(Instant::now());
+            self.refresh(tray)?;
+        }
+
+        if let Ok(event) = menu_channel.try_recv() {
+            debug!("{event:?}");
+
+            let Ok(msg) = FrontendMessage::from_str(&event.id().0) else {
+                warn!("Ignoring unknown menu item '{}'", event.id().0);
+                return Ok(ControlFlow::Poll);
+            };
+            match msg {
+                FrontendMessage::Kill => match self.request(&ControlCommand::Kill) {
+                    Ok(ControlResponse::Error { message }) => {
+                        show_notification("Failed to kill process", &message);
+                    }
+                    Ok(_) => self.refresh(tray)?,
+                    Err(e) => show_notification("Failed to kill process", &format!("{e:#}")),
+                },
+                FrontendMessage::ShowLogs => {
+                    let logs_dir = match self.status.as_ref().and_then(|s| s.log_file.parent()) {
+                        Some(dir) => dir.to_path_buf(),
+                        None => crate::get_logs_dir()?,
+                    };
+                    open::that(logs_dir).context("Failed to open logs dir")?;
+                }
+                FrontendMessage::Console => {
+                    if let Err(e) = console::open_console(&self.target) {
+                        error!("{e:#}");
+                        show_notification("Failed to open console", &format!("{e:#}"));
+                    }
+                }
+                FrontendMessage::Maintenance => {
+                    self.toggle_maintenance();
+                    self.refresh(tray)?;
+                }
+                FrontendMessage::CloseTray => return Ok(ControlFlow::Exit),
+            }
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+}
+
+/// Shows a tray icon for an instance running in another process, such as a headless service,
+/// until the user closes it. The instance keeps running when the tray is closed.
+///
+/// # Arguments
+///
+/// * `target` - The instance name, or the address of its control socket.
+///
+/// # Errors
+///
+/// An error is returned if the tray icon cannot be built.
+pub fn run_frontend(target: String) -> anyhow::Result<()> {
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(FrontendMessage::VARIANTS)?;
+    let mut tray = Some(build_tray(&target, menu, icon::identicon(&target)?)?);
+    let menu_channel = MenuEvent::receiver();
+    let mut frontend = Frontend {
+        target,
+        last_poll: None,
+        status: None,
+    };
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        let Some(icon) = tray.as_ref() else {
+            return;
+        };
+        match frontend.tick(icon, menu_channel) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
+
+/// The menu item that closes the aggregator tray.
+const CLOSE_TRAY_ID: &str = "Close Tray";
+
+/// A single tray icon for every running instance. Instances get a submenu each, grouped by tag,
+/// and the menu is rebuilt whenever instances come and go.
+struct Aggregator {
+    last_poll: Option<Instant>,
+    instances: Vec<Registration>,
+}
+
+impl Aggregator {
+    fn refresh(&mut self, tray: &TrayIcon) -> anyhow::Result<()> {
+        let instances = registry::list()?;
+        let changed = instances.len() != self.instances.len()
+            || instances
+                .iter()
+                .zip(&self.instances)
+                .any(|(a, b)| a.name != b.name || a.pid != b.pid || a.tags != b.tags);
+        if changed || self.last_poll.is_none() {
+            debug!(
+                "Rebuilding aggregator menu for {} instances",
+                instances.len()
+            );
+            tray.set_menu(Some(Box::new(build_aggregator_menu(&instances)?)));
+            tray.set_tooltip(Some(format!("trayme: {} running", instances.len())))
+                .context("Failed to update tooltip")?;
+        }
+        self.instances = instances;
+        Ok(())
+    }
+
+    fn tick(
+        &mut self,
+        tray: &TrayIcon,
+        menu_channel: &MenuEventReceiver,
+    ) -> anyhow::Result<ControlFlow> {
+        let poll_due = match self.last_poll {
+            Some(last_poll) => last_poll.elapsed() >= STATUS_POLL_INTERVAL,
+            None => true,
+        };
+        if poll_due {
+            self.refresh(tray)?;
+            self.last_poll = Some(Instant::now());
+        }
+
+        if let Ok(event) = menu_channel.try_recv() {
+            debug!("{event:?}");
+
+            if event.id().0 == CLOSE_TRAY_ID {
+                return Ok(ControlFlow::Exit);
+            }
+            let parsed = parse::instance_menu_id(&event.id().0)
+                .and_then(|(msg, name)| Some((FrontendMessage::from_str(msg).ok()?, name)));
+            let Some((msg, name)) = parsed else {
+                warn!("Ignoring unknown menu item '{}'", event.id().0);
+                return Ok(ControlFlow::Poll);
+            };
+            let mut frontend = Frontend {
+                target: name.to_string(),
+                last_poll: None,
+                status: None,
+            };
+            match msg {
+                FrontendMessage::Kill => match frontend.request(&ControlCommand::Kill) {
+                    Ok(ControlResponse::Error { message }) => {
+                        show_notification("Failed to kill process", &message);
+                    }
+                    Ok(_) => self.refresh(tray)?,
+                    Err(e) => show_notification("Failed to kill process", &format!("{e:#}")),
+                },
+                FrontendMessage::ShowLogs => {
+                    if let Ok(ControlResponse::Status(status)) =
+                        frontend.request(&ControlCommand::Status)
+                    {
+                        frontend.status = Some(status);
+                    }
+                    let logs_dir = match frontend.status.as_ref().and_then(|s| s.log_file.parent())
+                    {
+                        Some(dir) => dir.to_path_buf(),
+                        None => crate::get_logs_dir()?,
+                    };
+                    open::that(logs_dir).context("Failed to open logs dir")?;
+                }
+                FrontendMessage::Console => {
+                    if let Err(e) = console::open_console(name) {
+                        error!("{e:#}");
+                        show_notification("Failed to open console", &format!("{e:#}"));
+                    }
+                }
+                FrontendMessage::Maintenance => {
+                    if let Ok(ControlResponse::Status(status)) =
+                        frontend.request(&ControlCommand::Status)
+                    {
+                        frontend.status = Some(status);
+                    }
+                    frontend.toggle_maintenance();
+                }
+                FrontendMessage::CloseTray => return Ok(ControlFlow::Exit),
+            }
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+}
+
+/// Builds the aggregator menu. Instances with several tags show up in every group, and untagged
+/// instances are listed after the groups.
+fn build_aggregator_menu(instances: &[Registration]) -> anyhow::Result<Menu> {
+    let menu = Menu::new();
+    let mut groups: BTreeMap<&str, Vec<&Registration>> = BTreeMap::new();
+    let mut untagged = Vec::new();
+    for instance in instances {
+        if instance.tags.is_empty() {
+            untagged.push(instance);
+        }
+        for tag in &instance.tags {
+            groups.entry(tag).or_default().push(instance);
+        }
+    }
+
+    for (tag, members) in groups {
+        let submenu = Submenu::new(format!("{tag} ({})", members.len()), true);
+        for instance in members {
+            submenu.append(&build_instance_submenu(instance)?)?;
+        }
+        menu.append(&submenu)?;
+    }
+    for instance in untagged {
+        menu.append(&build_instance_submenu(instance)?)?;
+    }
+    if instances.is_empty() {
+        menu.append(&MenuItem::new("No instances running", false, None))?;
+    }
+    menu.append(&PredefinedMenuItem::separator())?;
+    menu.append(&MenuItem::with_id(CLOSE_TRAY_ID, CLOSE_TRAY_ID, true, None))?;
+    Ok(menu)
+}
+
+fn build_instance_submenu(instance: &Registration) -> anyhow::Result<Submenu> {
+    let submenu = Submenu::new(&instance.name, true);
+    for msg in FrontendMessage::VARIANTS {
+        if *msg == FrontendMessage::CloseTray {
+            continue;
+        }
+        let id = format!("{msg}/{}", instance.name);
+        submenu.append(&MenuItem::with_id(id, msg.to_string(), true, None))?;
+    }
+    Ok(submenu)
+}
+
+/// Shows a single tray icon for all running instances until the user closes it.
+///
+/// # Errors
+///
+/// An error is returned if the tray icon cannot be built.
+pub fn run_aggregator() -> anyhow::Result<()> {
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let mut tray = Some(build_tray(
+        "trayme",
+        Menu::new(),
+        icon::identicon("trayme")?,
+    )?);
+    let menu_channel = MenuEvent::receiver();
+    let mut aggregator = Aggregator {
+        last_poll: None,
+        instances: Vec::new(),
+    };
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        let Some(icon) = tray.as_ref() else {
+            return;
+        };
+        match aggregator.tick(icon, menu_channel) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
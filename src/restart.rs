@@ -0,0 +1,437 @@
+use std::{ffi::OsString, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    schedule::{CronExpr, Schedule},
+};
+
+/// How long the first restart waits unless `--restart-backoff` says otherwise.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest the backoff grows to.
+const MAX_BACKOFF: Duration = Duration::from_mins(5);
+
+/// How many failures in a row switch to the fallback command unless `--fallback-after` says
+/// otherwise.
+pub const DEFAULT_FALLBACK_AFTER: u32 = 3;
+
+/// How long a run has to last for the backoff and the restart count to start over, so that a
+/// process that crashes once a day isn't given up on after a week.
+pub const STABLE_RUN: Duration = Duration::from_mins(1);
+
+/// When the process is started again after it exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Never, the instance ends with the process.
+    #[default]
+    Never,
+    /// When it exits with a non-zero status, crashes, or is killed by a signal other than the
+    /// ones that ask it to stop (`SIGHUP`, `SIGINT`, `SIGTERM`, and `SIGPIPE`).
+    OnFailure,
+    /// Whenever it exits, unless it was stopped through trayme.
+    Always,
+}
+
+/// What happens after the process exits, see [`RestartBackoff::next`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Restart it after this long.
+    RestartIn(Duration),
+    /// The policy would restart it, but it was restarted as often in a row as allowed.
+    GiveUp,
+    /// The policy doesn't restart it.
+    Stop,
+}
+
+/// Decides whether and when the process is restarted after it exits. The delay doubles with
+/// every restart in a row, up to [`MAX_BACKOFF`].
+#[derive(Debug, Clone)]
+pub struct RestartBackoff {
+    policy: RestartPolicy,
+    initial: Duration,
+    max_restarts: Option<u32>,
+    /// The restarts in a row so far.
+    attempts: u32,
+}
+
+impl RestartBackoff {
+    /// Creates the backoff.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - When the process is restarted.
+    /// * `initial` - How long the first restart in a row waits.
+    /// * `max_restarts` - How many restarts in a row are made before giving up. Unlimited if
+    ///   `None`.
+    pub fn new(policy: RestartPolicy, initial: Duration, max_restarts: Option<u32>) -> Self {
+        Self {
+            policy,
+            initial,
+            max_restarts,
+            attempts: 0,
+        }
+    }
+
+    /// Whether and when to restart a process that exited. Counts the restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `success` - Whether the process ended cleanly, see [`crate::termination::Termination::is_clean`].
+    /// * `uptime` - How long the run lasted.
+    /// * `limited` - Whether `max_restarts` applies. It doesn't in maintenance mode, where
+    ///   failures are expected.
+    pub fn next(&mut self, success: bool, uptime: Duration, limited: bool) -> Decision {
+        let applies = match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !success,
+            RestartPolicy::Always => true,
+        };
+        if !applies {
+            return Decision::Stop;
+        }
+        self.next_forced(uptime, limited)
+    }
+
+    /// Like [`RestartBackoff::next`] for a restart that trayme decided on whatever the policy,
+    /// e.g. over `--max-memory` with `--on-limit restart`, so that a process that keeps running
+    /// into its limit isn't restarted in a tight loop. Counts the restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `uptime` - How long the run lasted.
+    /// * `limited` - Whether `max_restarts` applies, as with [`RestartBackoff::next`].
+    pub fn next_forced(&mut self, uptime: Duration, limited: bool) -> Decision {
+        if uptime >= STABLE_RUN {
+            self.attempts = 0;
+        }
+        if limited && self.max_restarts.is_some_and(|max| self.attempts >= max) {
+            return Decision::GiveUp;
+        }
+        let delay = self
+            .initial
+            .saturating_mul(2_u32.saturating_pow(self.attempts))
+            .min(MAX_BACKOFF);
+        self.attempts = self.attempts.saturating_add(1);
+        Decision::RestartIn(delay)
+    }
+
+    /// The restarts in a row so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// A description of the restart count for notifications, e.g. `restart 2 of 5`.
+    pub fn describe(&self) -> String {
+        match self.max_restarts {
+            Some(max) => format!("restart {} of {max}", self.attempts),
+            None => format!("restart {}", self.attempts),
+        }
+    }
+
+    /// Starts counting restarts over, e.g. after the process was restarted by hand.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Runs another command in place of the primary one once that failed too often in a row, e.g. a
+/// stable build when the nightly one keeps crashing. See `--fallback`.
+#[derive(Debug, Clone)]
+pub struct Fallback {
+    cmd: Vec<OsString>,
+    after: u32,
+    /// The failures of the primary command in a row so far.
+    failures: u32,
+    /// The primary command while the fallback runs in its place.
+    primary: Option<Vec<OsString>>,
+}
+
+impl Fallback {
+    /// Creates the fallback.
+    ///
+    /// # Arguments
+    ///
+    /// * `cmd` - The command to run instead.
+    /// * `after` - How many failures of the primary command in a row switch to it.
+    pub fn new(cmd: Vec<OsString>, after: u32) -> Self {
+        Self {
+            cmd,
+            after,
+            failures: 0,
+            primary: None,
+        }
+    }
+
+    /// Counts a run of the primary command that exited. Returns `true` once it failed `after`
+    /// times in a row, a run that lasts a minute starting the count over as with the backoff.
+    /// Runs of the fallback aren't counted.
+    ///
+    /// # Arguments
+    ///
+    /// * `success` - Whether the process exited successfully.
+    /// * `uptime` - How long the run lasted.
+    pub fn observe(&mut self, success: bool, uptime: Duration) -> bool {
+        if self.is_active() {
+            return false;
+        }
+        if success || uptime >= STABLE_RUN {
+            self.failures = 0;
+        }
+        if success {
+            return false;
+        }
+        self.failures = self.failures.saturating_add(1);
+        self.failures >= self.after
+    }
+
+    /// Puts the fallback command in place of `cmd`, the primary one.
+    pub fn activate(&mut self, cmd: &mut Vec<OsString>) {
+        if self.primary.is_none() {
+            self.primary = Some(std::mem::replace(cmd, self.cmd.clone()));
+        }
+        self.failures = 0;
+    }
+
+    /// Puts the primary command back in place of `cmd`, if the fallback is running.
+    pub fn deactivate(&mut self, cmd: &mut Vec<OsString>) {
+        if let Some(primary) = self.primary.take() {
+            *cmd = primary;
+        }
+        self.failures = 0;
+    }
+
+    /// Returns `true` while the fallback runs in place of the primary command.
+    pub fn is_active(&self) -> bool {
+        self.primary.is_some()
+    }
+}
+
+/// Restarts the process on a schedule, with `--restart-every` and `--restart-cron`, so that one
+/// that leaks is cycled before it gets bad. Intervals are counted from the start of each run,
+/// while cron times are on the clock. With both, whichever comes first applies.
+#[derive(Debug, Clone)]
+pub struct PlannedRestarts {
+    schedules: Vec<Schedule>,
+    /// When the current run started, which intervals are counted from.
+    anchor: DateTime<Local>,
+    next: Option<DateTime<Local>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PlannedRestarts {
+    /// Plans restarts `every` so long and at the times of `cron`. Returns `None` without either.
+    pub fn new(every: Option<Duration>, cron: Option<CronExpr>) -> Option<Self> {
+        Self::with_clock(every, cron, Arc::new(SystemClock))
+    }
+
+    /// Like [`PlannedRestarts::new`], with the time coming from `clock`.
+    pub fn with_clock(
+        every: Option<Duration>,
+        cron: Option<CronExpr>,
+        clock: Arc<dyn Clock>,
+    ) -> Option<Self> {
+        let schedules: Vec<_> = every
+            .map(Schedule::Every)
+            .into_iter()
+            .chain(cron.map(Schedule::Cron))
+            .collect();
+        if schedules.is_empty() {
+            return None;
+        }
+        let mut planned = Self {
+            schedules,
+            anchor: clock.now(),
+            next: None,
+            clock,
+        };
+        planned.reset();
+        Some(planned)
+    }
+
+    /// Plans the next restart from now, for when a run starts.
+    pub fn reset(&mut self) {
+        self.anchor = self.clock.now();
+        self.next = self.next_after(self.anchor);
+    }
+
+    /// When the process is restarted next.
+    pub fn next(&self) -> Option<DateTime<Local>> {
+        self.next
+    }
+
+    /// Returns `true` once the planned restart is due, and plans the one after it. Restarts
+    /// that came due while the machine was asleep only count once.
+    pub fn is_due(&mut self) -> bool {
+        let now = self.clock.now();
+        if self.next.is_none_or(|next| now < next) {
+            return false;
+        }
+        self.next = self.next_after(now);
+        true
+    }
+
+    fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.schedules
+            .iter()
+            .filter_map(|schedule| schedule.next_after(self.anchor, after))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::clock::FakeClock;
+
+    const SHORT_RUN: Duration = Duration::from_secs(1);
+
+    fn delays(backoff: &mut RestartBackoff, count: usize) -> Vec<Decision> {
+        (0..count)
+            .map(|_| backoff.next(false, SHORT_RUN, true))
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = RestartBackoff::new(RestartPolicy::Always, DEFAULT_BACKOFF, None);
+        let secs: Vec<_> = delays(&mut backoff, 10)
+            .into_iter()
+            .map(|decision| match decision {
+                Decision::RestartIn(delay) => delay.as_secs(),
+                other => panic!("expected a restart, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+        assert_eq!(backoff.attempts(), 10);
+        assert_eq!(backoff.describe(), "restart 10");
+    }
+
+    #[test]
+    fn policies() {
+        let decide = |policy, success| {
+            RestartBackoff::new(policy, DEFAULT_BACKOFF, None).next(success, SHORT_RUN, true)
+        };
+        let restart = Decision::RestartIn(DEFAULT_BACKOFF);
+        assert_eq!(decide(RestartPolicy::Never, false), Decision::Stop);
+        assert_eq!(decide(RestartPolicy::Never, true), Decision::Stop);
+        assert_eq!(decide(RestartPolicy::OnFailure, false), restart);
+        assert_eq!(decide(RestartPolicy::OnFailure, true), Decision::Stop);
+        assert_eq!(decide(RestartPolicy::Always, false), restart);
+        assert_eq!(decide(RestartPolicy::Always, true), restart);
+    }
+
+    #[test]
+    fn gives_up_after_max_restarts() {
+        let mut backoff = RestartBackoff::new(RestartPolicy::OnFailure, DEFAULT_BACKOFF, Some(2));
+        let decisions = delays(&mut backoff, 3);
+        assert!(matches!(
+            decisions[..2],
+            [Decision::RestartIn(_), Decision::RestartIn(_)]
+        ));
+        assert_eq!(decisions[2], Decision::GiveUp);
+        assert_eq!(backoff.describe(), "restart 2 of 2");
+        // maintenance mode doesn't give up
+        assert!(matches!(
+            backoff.next(false, SHORT_RUN, false),
+            Decision::RestartIn(_)
+        ));
+        backoff.reset();
+        assert_eq!(
+            backoff.next(false, SHORT_RUN, true),
+            Decision::RestartIn(DEFAULT_BACKOFF)
+        );
+    }
+
+    #[test]
+    fn stable_runs_start_over() {
+        let mut backoff = RestartBackoff::new(RestartPolicy::OnFailure, DEFAULT_BACKOFF, Some(2));
+        delays(&mut backoff, 2);
+        assert_eq!(
+            backoff.next(false, STABLE_RUN, true),
+            Decision::RestartIn(DEFAULT_BACKOFF)
+        );
+        assert_eq!(backoff.attempts(), 1);
+    }
+
+    #[test]
+    fn forced_restarts_back_off_whatever_the_policy() {
+        let mut backoff = RestartBackoff::new(RestartPolicy::Never, DEFAULT_BACKOFF, Some(2));
+        assert_eq!(backoff.next(false, SHORT_RUN, true), Decision::Stop);
+        let forced: Vec<_> = (0..3)
+            .map(|_| backoff.next_forced(SHORT_RUN, true))
+            .collect();
+        assert_eq!(
+            forced,
+            [
+                Decision::RestartIn(DEFAULT_BACKOFF),
+                Decision::RestartIn(DEFAULT_BACKOFF * 2),
+                Decision::GiveUp
+            ]
+        );
+    }
+
+    #[test]
+    fn fallback_after_failures_in_a_row() {
+        let primary = vec![OsString::from("nightly")];
+        let mut cmd = primary.clone();
+        let mut fallback = Fallback::new(vec!["stable".into()], 3);
+        assert!(!fallback.observe(false, SHORT_RUN));
+        assert!(!fallback.observe(true, SHORT_RUN));
+        assert!(!fallback.observe(false, SHORT_RUN));
+        assert!(!fallback.observe(false, STABLE_RUN));
+        assert!(!fallback.observe(false, SHORT_RUN));
+        assert!(fallback.observe(false, SHORT_RUN));
+        fallback.activate(&mut cmd);
+        assert!(fallback.is_active());
+        assert_eq!(cmd, [OsString::from("stable")]);
+        // runs of the fallback aren't counted
+        assert!(!fallback.observe(false, SHORT_RUN));
+        fallback.deactivate(&mut cmd);
+        assert!(!fallback.is_active());
+        assert_eq!(cmd, primary);
+    }
+
+    #[test]
+    fn planned_restarts_follow_the_clock() {
+        let start = Local.with_ymd_and_hms(2024, 3, 4, 9, 30, 0).unwrap();
+        let clock = FakeClock::at(start);
+        let cron = "0 12 * * *".parse().unwrap();
+        let mut planned = PlannedRestarts::with_clock(
+            Some(Duration::from_hours(1)),
+            Some(cron),
+            Arc::new(clock.clone()),
+        )
+        .unwrap();
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(planned.next(), Some(start + hour));
+        assert!(!planned.is_due());
+
+        clock.advance(hour);
+        assert!(planned.is_due());
+        assert_eq!(planned.next(), Some(start + hour * 2));
+
+        // the cron time comes before the next interval does
+        clock.advance(chrono::Duration::minutes(40));
+        planned.reset();
+        let noon = Local.with_ymd_and_hms(2024, 3, 4, 12, 0, 0).unwrap();
+        assert_eq!(planned.next(), Some(noon));
+
+        // restarts due during a sleep only count once
+        clock.sleep(hour * 5);
+        assert!(planned.is_due());
+        assert!(!planned.is_due());
+        assert!(planned.next() > Some(clock.now()));
+    }
+
+    #[test]
+    fn nothing_planned() {
+        assert!(PlannedRestarts::new(None, None).is_none());
+    }
+}
@@ -0,0 +1,1161 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeZone, Weekday};
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::menu::{IsMenuItem, MenuItem, PredefinedMenuItem};
+
+use crate::{
+    build_tray, build_tray_menu,
+    calendar::{BusyCalendar, CalendarSource},
+    clock::{Clock, SystemClock},
+    display, get_logs_dir, icon,
+    notify::{show_notification, Notifier, NotifyEvent},
+    osargs,
+    ping::PingUrl,
+    traybackend::{SystemTray, TrayBackend},
+    trigger::{QueueMessage, QueueStatus, RunQueue},
+    wake::WakeTimer,
+};
+
+/// The placeholder in the command template that is replaced with the time the run was due.
+pub const TIME_PLACEHOLDER: &str = "{time}";
+
+/// How the due time is formatted, both for `{time}` and in the menu.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How often the schedule is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const MINUTE_SECS: u64 = 60;
+
+/// Runs that are due for longer than this when they're noticed count as missed, e.g. because the
+/// machine was asleep or trayme wasn't running.
+const MISSED_AFTER: Duration = Duration::from_secs(MINUTE_SECS);
+
+/// How often the schedule state is saved while nothing is due, so that the time trayme stopped
+/// is known on the next start.
+const SAVE_INTERVAL: Duration = Duration::from_secs(MINUTE_SECS);
+
+/// At most this many missed runs are caught up on at once.
+const MAX_MISSED: usize = 100;
+
+/// How far ahead a cron expression is searched for its next run. Expressions that never match
+/// (e.g. February 30th) give up after this.
+const MAX_SEARCH_DAYS: i64 = 366 * 5;
+
+/// How many disallowed runs are looked past when searching for the next allowed one.
+const MAX_BLOCKED_SEARCH: usize = 100_000;
+
+/// The menu item that starts a run right away.
+const RUN_NOW_ID: &str = "Run Now";
+
+/// What to do about runs that were due while the machine was asleep or off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissedPolicy {
+    /// Doesn't catch up on missed runs.
+    Skip,
+    /// Runs once if any runs were missed.
+    #[default]
+    Once,
+    /// Runs once for every missed run, up to 100. These runs are queued one after the other,
+    /// whatever the overlap policy.
+    All,
+}
+
+/// What to do when a run is due while the previous one is still going.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlapPolicy {
+    /// Drops the new run.
+    #[default]
+    Skip,
+    /// Queues the new run until a slot is free (see `--max-concurrent`).
+    Queue,
+    /// Stops the previous run and starts the new one.
+    Kill,
+}
+
+/// A five-field cron expression: minute, hour, day of month, month, and day of week. Fields
+/// accept `*`, numbers, ranges (`1-5`), lists (`1,15`), and steps (`*/15`). Months and days of
+/// the week can also be given by their English three-letter names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or the day of week are `*`. If neither is, a day matching
+    /// either of them matches, as in standard cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Parses one cron field into a bit set of the values it matches.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let lower = s.to_ascii_lowercase();
+        if let Some(index) = names.iter().position(|name| *name == lower) {
+            // names count from the field's minimum, e.g. jan is 1 and sun is 0
+            return Ok(u32::try_from(index).unwrap_or_default() + min);
+        }
+        s.parse().map_err(|_| format!("invalid value '{s}'"))
+    };
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("invalid step '{step}'"))?;
+                if step == 0 {
+                    return Err("step must be greater than zero".to_string());
+                }
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/10` means from 5 to the maximum in steps of 10
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{item}' is out of range {min}-{max}"));
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields (minute hour day month weekday), got {}",
+                fields.len()
+            ));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7, &WEEKDAY_NAMES)?;
+        // both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            source: s.to_string(),
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: parse_field(day, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, &MONTH_NAMES)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+impl fmt::Display for CronExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl CronExpr {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            (true, false) => weekday,
+            _ => day,
+        }
+    }
+
+    /// Returns the first time after `after` that matches. Times skipped by daylight saving time
+    /// changes don't match.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let after = after.naive_local();
+        let start = after.date();
+        for offset in 0..MAX_SEARCH_DAYS {
+            let date = start + chrono::Duration::days(offset);
+            if !self.matches_day(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                    let Some(time) = date.and_hms_opt(hour, minute, 0) else {
+                        continue;
+                    };
+                    if time <= after {
+                        continue;
+                    }
+                    if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                        return Some(time);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// When a scheduled command runs.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// At a fixed interval, counted from when the command was first scheduled.
+    Every(Duration),
+    Cron(CronExpr),
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(interval) => {
+                write!(f, "every {}", humantime::format_duration(*interval))
+            }
+            Schedule::Cron(expr) => write!(f, "cron '{expr}'"),
+        }
+    }
+}
+
+impl Schedule {
+    /// Returns the first run after `after`.
+    ///
+    /// # Arguments
+    ///
+    /// * `anchor` - When the command was first scheduled, which intervals are counted from.
+    /// * `after` - The time to look after.
+    pub fn next_after(
+        &self,
+        anchor: DateTime<Local>,
+        after: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Every(interval) => {
+                let interval = chrono::Duration::from_std(*interval).ok()?;
+                let elapsed = after - anchor;
+                if elapsed < chrono::Duration::zero() {
+                    return Some(anchor);
+                }
+                let periods = elapsed.num_milliseconds() / interval.num_milliseconds().max(1);
+                Some(anchor + interval * i32::try_from(periods + 1).ok()?)
+            }
+            Schedule::Cron(expr) => expr.next_after(after),
+        }
+    }
+}
+
+/// A time of day range, e.g. `08:00-18:00`. Ranges whose end is before their start go past
+/// midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// Whether `time` is within the window. The start is included, the end isn't.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{s}'"))?;
+        let time = |s: &str| {
+            NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|e| format!("'{s}': {e}"))
+        };
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Limits on when a schedule runs or a profile starts, e.g. to keep a work VPN from starting on
+/// weekends. Stored in a profile as:
+///
+/// ```toml
+/// [profiles.vpn.constraints]
+/// only_weekdays = true
+/// not_on = ["2024-12-24", "2024-12-25"]
+/// windows = ["08:00-18:00"]
+/// busy_calendar = "https://calendar.example.com/me/work.ics"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Constraints {
+    /// Only allows Monday to Friday.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub only_weekdays: bool,
+    /// Dates that are never allowed, e.g. holidays.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_on: Vec<NaiveDate>,
+    /// The times of day that are allowed. Any time is if there are none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<TimeWindow>,
+    /// The calendar whose busy blocks scheduled runs are deferred past and instances are paused
+    /// during, see [`BusyCalendar`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub busy_calendar: Option<CalendarSource>,
+}
+
+impl Constraints {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn allows(&self, at: DateTime<Local>) -> bool {
+        if self.only_weekdays && matches!(at.weekday(), Weekday::Sat | Weekday::Sun) {
+            return false;
+        }
+        !self.not_on.contains(&at.date_naive())
+            && (self.windows.is_empty() || self.windows.iter().any(|w| w.contains(at.time())))
+    }
+
+    /// Returns which constraint `at` breaks, or `None` if it's allowed.
+    pub fn blocked_at(&self, at: DateTime<Local>) -> Option<Blocked> {
+        if self.only_weekdays && matches!(at.weekday(), Weekday::Sat | Weekday::Sun) {
+            return Some(Blocked::Weekend);
+        }
+        let date = at.date_naive();
+        if self.not_on.contains(&date) {
+            return Some(Blocked::Date(date));
+        }
+        if !self.windows.is_empty() && !self.windows.iter().any(|w| w.contains(at.time())) {
+            return Some(Blocked::OutsideWindows(self.windows.clone()));
+        }
+        None
+    }
+}
+
+/// Why a time isn't allowed by [`Constraints`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocked {
+    Weekend,
+    Date(NaiveDate),
+    OutsideWindows(Vec<TimeWindow>),
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocked::Weekend => write!(f, "only allowed on weekdays"),
+            Blocked::Date(date) => write!(f, "not allowed on {date}"),
+            Blocked::OutsideWindows(windows) => {
+                let windows: Vec<_> = windows.iter().map(ToString::to_string).collect();
+                write!(f, "only allowed during {}", windows.join(", "))
+            }
+        }
+    }
+}
+
+/// A run the scheduler found to be due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DueRun {
+    /// When the run was due.
+    pub at: DateTime<Local>,
+    /// Whether the run was missed and is being caught up on.
+    pub missed: bool,
+}
+
+/// What the scheduler remembers across restarts of trayme.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduleState {
+    /// When the command was first scheduled.
+    anchor: DateTime<Local>,
+    /// When the schedule was last checked. Runs due after this that aren't noticed in time are
+    /// missed.
+    last_check: DateTime<Local>,
+    /// When a run last succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_success: Option<DateTime<Local>>,
+    /// How many runs finished, and how many of them succeeded.
+    #[serde(default)]
+    runs: u64,
+    #[serde(default)]
+    succeeded: u64,
+    /// How many of the last runs succeeded in a row.
+    #[serde(default)]
+    streak: u64,
+    /// Whether the user was alerted that no run succeeded within `--expect-success-within`,
+    /// which happens once until the next success.
+    #[serde(default)]
+    overdue: bool,
+}
+
+/// Keeps track of when a scheduled command is due, including runs that were missed while the
+/// machine was asleep or trayme wasn't running.
+#[derive(Debug)]
+pub struct Scheduler {
+    schedule: Schedule,
+    missed: MissedPolicy,
+    constraints: Constraints,
+    state: ScheduleState,
+    /// How long is too long since the last successful run, see `--expect-success-within`.
+    expect_success_within: Option<Duration>,
+    path: PathBuf,
+    last_poll: Instant,
+    last_save: Instant,
+    /// What to tell the user about the runs that were missed, see
+    /// [`Scheduler::take_missed_notice`].
+    missed_notice: Option<String>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    /// Creates the scheduler for `name`, picking up where the last one with that name left off.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Identifies the schedule's saved state.
+    /// * `schedule` - When to run.
+    /// * `missed` - What to do about missed runs.
+    /// * `constraints` - When runs are allowed. Runs due at other times are dropped.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the logs directory cannot be determined.
+    pub fn new(
+        name: &str,
+        schedule: Schedule,
+        missed: MissedPolicy,
+        constraints: Constraints,
+    ) -> anyhow::Result<Self> {
+        let path = get_logs_dir()?
+            .join("schedule")
+            .join(format!("{name}.toml"));
+        Ok(Self::load(
+            path,
+            schedule,
+            missed,
+            constraints,
+            Arc::new(SystemClock),
+        ))
+    }
+
+    /// Creates the scheduler whose state is saved at `path`, with the time coming from `clock`.
+    fn load(
+        path: PathBuf,
+        schedule: Schedule,
+        missed: MissedPolicy,
+        constraints: Constraints,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        let state = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| {
+                toml::from_str(&contents)
+                    .inspect_err(|e| warn!("Ignoring invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or(ScheduleState {
+                anchor: now,
+                last_check: now,
+                last_success: None,
+                runs: 0,
+                succeeded: 0,
+                streak: 0,
+                overdue: false,
+            });
+        Self {
+            schedule,
+            missed,
+            constraints,
+            state,
+            expect_success_within: None,
+            path,
+            last_poll: clock.instant(),
+            last_save: clock.instant(),
+            missed_notice: None,
+            clock,
+        }
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Alerts once no run has succeeded for `window`, see [`Scheduler::check_overdue`].
+    pub fn set_expect_success_within(&mut self, window: Duration) {
+        self.expect_success_within = Some(window);
+    }
+
+    /// Records how a run ended, for the success streak and `--expect-success-within`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the schedule state cannot be saved.
+    pub fn record_run(&mut self, succeeded: bool) -> anyhow::Result<()> {
+        let state = &mut self.state;
+        state.runs += 1;
+        if succeeded {
+            state.succeeded += 1;
+            state.streak += 1;
+            state.last_success = Some(self.clock.now());
+            state.overdue = false;
+        } else {
+            state.streak = 0;
+        }
+        self.save()
+    }
+
+    /// Returns the alert to show if no run has succeeded within `--expect-success-within`,
+    /// counted from the last success or, before the first one, from when the command was first
+    /// scheduled. It's only returned once until the next success, across restarts too.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the schedule state cannot be saved.
+    pub fn check_overdue(&mut self) -> anyhow::Result<Option<String>> {
+        let Some(window) = self.expect_success_within else {
+            return Ok(None);
+        };
+        if self.state.overdue {
+            return Ok(None);
+        }
+        let since = self.state.last_success.unwrap_or(self.state.anchor);
+        if self.clock.now() - since < chrono::Duration::from_std(window)? {
+            return Ok(None);
+        }
+        let window = humantime::format_duration(window);
+        let body = match self.state.last_success {
+            Some(at) => format!(
+                "No run succeeded in {window}, the last success was at {}",
+                at.format(TIME_FORMAT)
+            ),
+            None => format!("No run succeeded in the {window} since the command was scheduled"),
+        };
+        warn!("{body}");
+        self.state.overdue = true;
+        self.save()?;
+        Ok(Some(body))
+    }
+
+    /// The success streak and rate for the menu, e.g. "Succeeded 12 of 14 runs, 5 in a row".
+    pub fn success_summary(&self) -> String {
+        let state = &self.state;
+        let mut summary = if state.runs == 0 {
+            "No runs finished yet".to_string()
+        } else {
+            format!(
+                "Succeeded {} of {} runs, {} in a row",
+                state.succeeded, state.runs, state.streak
+            )
+        };
+        if state.overdue {
+            summary.push_str(" (overdue)");
+        }
+        summary
+    }
+
+    /// The next time the command is due and allowed by the constraints.
+    pub fn next_run(&self) -> Option<DateTime<Local>> {
+        let mut after = self.state.last_check;
+        for _ in 0..MAX_BLOCKED_SEARCH {
+            let due = self.schedule.next_after(self.state.anchor, after)?;
+            if self.constraints.allows(due) {
+                return Some(due);
+            }
+            after = due;
+        }
+        None
+    }
+
+    /// Returns the runs to start now, oldest first. Runs that were missed are
+    /// included according to the [`MissedPolicy`], and told about with
+    /// [`Scheduler::take_missed_notice`]. Only checks once per [`CHECK_INTERVAL`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the schedule state cannot be saved.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<DueRun>> {
+        let polled = self.clock.instant();
+        if polled.duration_since(self.last_poll) < CHECK_INTERVAL {
+            return Ok(Vec::new());
+        }
+        self.last_poll = polled;
+        let now = self.clock.now();
+        let missed_after = chrono::Duration::from_std(MISSED_AFTER)?;
+
+        let mut on_time = Vec::new();
+        let mut missed = Vec::new();
+        let mut more_missed = false;
+        let mut after = self.state.last_check;
+        while let Some(due) = self.schedule.next_after(self.state.anchor, after) {
+            if due > now {
+                break;
+            }
+            if !self.constraints.allows(due) {
+                after = due;
+                continue;
+            }
+            if now - due <= missed_after {
+                on_time.push(due);
+            } else if missed.len() < MAX_MISSED {
+                missed.push(due);
+            } else {
+                more_missed = true;
+                // skip ahead to the runs that aren't missed
+                after = now - missed_after;
+                continue;
+            }
+            after = due;
+        }
+
+        if !missed.is_empty() {
+            let count = if more_missed {
+                format!("More than {MAX_MISSED}")
+            } else {
+                missed.len().to_string()
+            };
+            info!("{count} runs were missed, policy: {:?}", self.missed);
+            let body = match self.missed {
+                MissedPolicy::Skip => format!("{count} missed runs were skipped"),
+                MissedPolicy::Once => format!("Running once for {count} missed runs"),
+                MissedPolicy::All => format!("Catching up on {} missed runs", missed.len()),
+            };
+            self.missed_notice = Some(body);
+        }
+        let catch_up = match self.missed {
+            MissedPolicy::Skip => Vec::new(),
+            MissedPolicy::Once => missed.last().copied().into_iter().collect(),
+            MissedPolicy::All => missed,
+        };
+        let due: Vec<_> = catch_up
+            .into_iter()
+            .map(|at| DueRun { at, missed: true })
+            .chain(on_time.into_iter().map(|at| DueRun { at, missed: false }))
+            .collect();
+
+        self.state.last_check = now;
+        if !due.is_empty() || polled.duration_since(self.last_save) >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(due)
+    }
+
+    /// What to tell the user about the runs [`Scheduler::poll`] found were missed, once.
+    pub fn take_missed_notice(&mut self) -> Option<String> {
+        self.missed_notice.take()
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create schedule directory")?;
+        }
+        let contents = toml::to_string(&self.state).context("Failed to serialize schedule")?;
+        std::fs::write(&self.path, contents)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.last_save = self.clock.instant();
+        debug!("Saved schedule state to {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// The tray of `trayme schedule`: a scheduler feeding a run queue.
+struct ScheduleTray {
+    scheduler: Scheduler,
+    calendar: Option<BusyCalendar>,
+    /// The run that was due during a busy block of the calendar, started once it's over.
+    deferred: Option<DueRun>,
+    /// Wakes the machine for the next run, with `--wake`.
+    wake: Option<WakeTimer>,
+    queue: RunQueue,
+    overlap: OverlapPolicy,
+    status: QueueStatus,
+    /// Used for the alert of `--expect-success-within`.
+    notifier: Notifier,
+    next_item: MenuItem,
+    next_shown: Option<DateTime<Local>>,
+    success_item: MenuItem,
+    success_shown: String,
+}
+
+impl ScheduleTray {
+    /// Starts a run that was due at `due`, following the [`OverlapPolicy`]. Runs caught up on
+    /// with [`MissedPolicy::All`] are always queued.
+    fn trigger(&mut self, due: DueRun) -> anyhow::Result<()> {
+        let busy = self.queue.running().next().is_some() || self.queue.pending().next().is_some();
+        let value = due.at.format(TIME_FORMAT).to_string();
+        let overlap = if due.missed && self.scheduler.missed == MissedPolicy::All {
+            OverlapPolicy::Queue
+        } else {
+            self.overlap
+        };
+        match overlap {
+            OverlapPolicy::Skip if busy => {
+                info!("Skipping run due at {value}, the previous one is still going");
+                show_notification("Scheduled run skipped", "The previous run is still going");
+                return Ok(());
+            }
+            OverlapPolicy::Kill if busy => {
+                info!("Stopping the previous run for the one due at {value}");
+                self.queue.stop()?;
+            }
+            _ => {}
+        }
+        self.queue.push(value);
+        Ok(())
+    }
+
+    fn tick(&mut self, tray: &mut dyn TrayBackend) -> anyhow::Result<ControlFlow> {
+        let busy = self.calendar.as_mut().and_then(BusyCalendar::busy);
+        let due = self.scheduler.poll()?;
+        if let Some(body) = self.scheduler.take_missed_notice() {
+            show_notification("Missed scheduled runs", &body);
+        }
+        for due in due {
+            let Some(busy) = &busy else {
+                self.trigger(due)?;
+                continue;
+            };
+            let until = busy.until.format("%H:%M");
+            info!(
+                "Deferring the run due at {} until {until}",
+                due.at.format(TIME_FORMAT)
+            );
+            // runs due during the same block only start once
+            if self.deferred.replace(due).is_none() {
+                show_notification(
+                    "Scheduled run deferred",
+                    &format!("Starting it once {} is over at {until}", busy.summary),
+                );
+            }
+        }
+        if busy.is_none() {
+            if let Some(due) = self.deferred.take() {
+                self.trigger(due)?;
+            }
+        }
+        self.queue.poll()?;
+        self.status.update(&self.queue)?;
+        self.track_success()?;
+
+        let next = self.scheduler.next_run();
+        if next != self.next_shown {
+            let text = match next {
+                Some(next) => format!("Next run: {}", next.format(TIME_FORMAT)),
+                None => "No more runs scheduled".to_string(),
+            };
+            self.next_item.set_text(&text);
+            tray.set_tooltip(&format!(
+                "trayme schedule ({}): {text}",
+                self.scheduler.schedule()
+            ))?;
+            self.next_shown = next;
+            if let Some(wake) = self.wake.as_mut() {
+                wake.arm(next);
+            }
+        }
+
+        if let Some(id) = tray.menu_event() {
+            if id == RUN_NOW_ID {
+                self.trigger(DueRun {
+                    at: self.scheduler.clock.now(),
+                    missed: false,
+                })?;
+                return Ok(ControlFlow::Poll);
+            }
+            let flow = self.queue.handle_menu_event(&id)?;
+            if flow == ControlFlow::Exit {
+                self.disarm();
+            }
+            return Ok(flow);
+        }
+
+        Ok(ControlFlow::Poll)
+    }
+
+    /// Records the runs that finished, alerts if none succeeded for too long, and shows the
+    /// streak in the menu.
+    fn track_success(&mut self) -> anyhow::Result<()> {
+        for (value, succeeded) in self.queue.take_finished() {
+            debug!("Run due at {value} finished, succeeded: {succeeded}");
+            self.scheduler.record_run(succeeded)?;
+        }
+        if let Some(body) = self.scheduler.check_overdue()? {
+            self.notifier
+                .notify(NotifyEvent::Overdue, "No successful scheduled run", &body);
+        }
+        let summary = self.scheduler.success_summary();
+        if summary != self.success_shown {
+            self.success_item.set_text(&summary);
+            self.success_shown = summary;
+        }
+        Ok(())
+    }
+
+    /// Removes the wake for the next run, which nobody is there for once the schedule is quit.
+    fn disarm(&mut self) {
+        if let Some(wake) = self.wake.as_mut() {
+            wake.disarm();
+        }
+    }
+}
+
+/// Runs `cmd` on a schedule until the user quits from the tray.
+///
+/// # Arguments
+///
+/// * `name` - Identifies the schedule, so that runs missed while trayme wasn't running are known
+///   on the next start.
+/// * `scheduler` - When to run and what to do about missed runs.
+/// * `overlap` - What to do when a run is due while the previous one is still going.
+/// * `wake` - Wakes the machine from sleep for each run.
+/// * `cmd` - The command template. `{time}` is replaced with the time the run was due.
+/// * `notifier` - Used for the notifications of each run.
+/// * `max_concurrent` - How many runs may be in progress at once with [`OverlapPolicy::Queue`].
+/// * `ping_url` - Pinged around each run.
+///
+/// # Errors
+///
+/// An error is returned if the tray icon cannot be built.
+pub fn run_scheduled(
+    scheduler: Scheduler,
+    overlap: OverlapPolicy,
+    wake: Option<WakeTimer>,
+    cmd: Vec<OsString>,
+    notifier: Notifier,
+    max_concurrent: NonZeroUsize,
+    ping_url: Option<PingUrl>,
+) -> anyhow::Result<()> {
+    let event_loop = display::build_event_loop()?;
+
+    // tray must be built AFTER event loop to prevent initializing low-level
+    // libraries out of order (mostly a macOS issue)
+    let menu = build_tray_menu(QueueMessage::VARIANTS)?;
+    let status = QueueStatus::new();
+    let next_item = MenuItem::new("Next run: -", false, None);
+    let success_shown = scheduler.success_summary();
+    let success_item = MenuItem::new(&success_shown, false, None);
+    let run_now = MenuItem::with_id(RUN_NOW_ID, RUN_NOW_ID, true, None);
+    menu.prepend_items(&[
+        &next_item as &dyn IsMenuItem,
+        &success_item,
+        &status.running,
+        &status.queued,
+        &run_now,
+        &PredefinedMenuItem::separator(),
+    ])?;
+    let tooltip = format!("trayme schedule: {}", osargs::display(&cmd));
+    let mut tray = Some(SystemTray::new(build_tray(
+        &tooltip,
+        menu,
+        icon::identicon(&tooltip)?,
+    )?));
+    let mut queue = RunQueue::new(cmd, TIME_PLACEHOLDER, notifier.clone(), max_concurrent);
+    queue.set_append_value(false);
+    queue.set_keep_finished();
+    if let Some(url) = ping_url {
+        queue.set_ping_url(url);
+    }
+    let calendar = scheduler
+        .constraints
+        .busy_calendar
+        .clone()
+        .map(BusyCalendar::watch);
+    let mut schedule_tray = ScheduleTray {
+        scheduler,
+        calendar,
+        deferred: None,
+        wake,
+        queue,
+        overlap,
+        status,
+        notifier,
+        next_item,
+        next_shown: None,
+        success_item,
+        success_shown,
+    };
+
+    event_loop.run(move |_event, _window, control_flow| {
+        if *control_flow == ControlFlow::Exit {
+            return;
+        }
+        let Some(icon) = tray.as_mut() else {
+            return;
+        };
+        match schedule_tray.tick(icon) {
+            Ok(cf) => *control_flow = cf,
+            Err(err) => {
+                error!("Error: {err:#}");
+                let _ = schedule_tray.queue.stop();
+                schedule_tray.disarm();
+                let _ = tray.take();
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn cron(expr: &str) -> CronExpr {
+        expr.parse().unwrap()
+    }
+
+    /// A scheduler running every ten minutes from 2024-03-04 09:00 on `clock`, whose state is
+    /// saved in a temporary file.
+    fn scheduler(name: &str, missed: MissedPolicy, clock: &FakeClock) -> Scheduler {
+        let path = std::env::temp_dir().join(format!(
+            "{}-schedule-{name}-{}.toml",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Scheduler::load(
+            path,
+            Schedule::Every(Duration::from_secs(10 * MINUTE_SECS)),
+            missed,
+            Constraints::default(),
+            Arc::new(clock.clone()),
+        )
+    }
+
+    #[test]
+    fn cron_next_after() {
+        // 2024-03-04 is a Monday
+        let monday = at(2024, 3, 4, 9, 30);
+        let cases = [
+            ("*/15 * * * *", at(2024, 3, 4, 9, 45)),
+            ("0 9 * * *", at(2024, 3, 5, 9, 0)),
+            ("30 9 * * *", at(2024, 3, 5, 9, 30)),
+            ("0 8-17/3 * * *", at(2024, 3, 4, 11, 0)),
+            ("0 0 1 * *", at(2024, 4, 1, 0, 0)),
+            ("0 12 * * sat,sun", at(2024, 3, 9, 12, 0)),
+            ("0 12 * * 7", at(2024, 3, 10, 12, 0)),
+            ("0 0 29 feb *", at(2028, 2, 29, 0, 0)),
+            // with both a day and a weekday, either matches
+            ("0 0 15 * fri", at(2024, 3, 8, 0, 0)),
+        ];
+        for (expr, next) in cases {
+            assert_eq!(cron(expr).next_after(monday), Some(next), "{expr}");
+        }
+        assert_eq!(cron("0 0 30 feb *").next_after(monday), None);
+    }
+
+    #[test]
+    fn invalid_cron() {
+        for expr in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(expr.parse::<CronExpr>().is_err(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn intervals_count_from_the_anchor() {
+        let anchor = at(2024, 3, 4, 9, 0);
+        let every = Schedule::Every(Duration::from_secs(25 * MINUTE_SECS));
+        assert_eq!(
+            every.next_after(anchor, anchor),
+            Some(at(2024, 3, 4, 9, 25))
+        );
+        assert_eq!(
+            every.next_after(anchor, at(2024, 3, 4, 10, 14)),
+            Some(at(2024, 3, 4, 10, 15))
+        );
+        assert_eq!(every.next_after(anchor, at(2024, 3, 4, 8, 0)), Some(anchor));
+    }
+
+    #[test]
+    fn catches_up_on_runs_missed_during_a_sleep() {
+        let cases = [
+            (MissedPolicy::Skip, vec![], "5 missed runs were skipped"),
+            (
+                MissedPolicy::Once,
+                vec![at(2024, 3, 4, 10, 0)],
+                "Running once for 5 missed runs",
+            ),
+            (
+                MissedPolicy::All,
+                (2..=6)
+                    .map(|i| at(2024, 3, 4, 9, 0) + chrono::Duration::minutes(i * 10))
+                    .collect(),
+                "Catching up on 5 missed runs",
+            ),
+        ];
+        for (missed, caught_up, notice) in cases {
+            let clock = FakeClock::at(at(2024, 3, 4, 9, 0));
+            let mut scheduler = scheduler(&format!("{missed:?}"), missed, &clock);
+            assert!(scheduler.poll().unwrap().is_empty());
+            clock.advance(chrono::Duration::minutes(10));
+            let due = scheduler.poll().unwrap();
+            assert_eq!(
+                due,
+                [DueRun {
+                    at: at(2024, 3, 4, 9, 10),
+                    missed: false
+                }]
+            );
+            assert_eq!(scheduler.take_missed_notice(), None);
+
+            clock.sleep(chrono::Duration::hours(1));
+            clock.advance(chrono::Duration::seconds(1));
+            let mut expected: Vec<_> = caught_up
+                .into_iter()
+                .map(|at| DueRun { at, missed: true })
+                .collect();
+            expected.push(DueRun {
+                at: at(2024, 3, 4, 10, 10),
+                missed: false,
+            });
+            assert_eq!(scheduler.poll().unwrap(), expected, "{missed:?}");
+            assert_eq!(scheduler.take_missed_notice().as_deref(), Some(notice));
+            assert_eq!(scheduler.next_run(), Some(at(2024, 3, 4, 10, 20)));
+            let _ = std::fs::remove_file(&scheduler.path);
+        }
+    }
+
+    #[test]
+    fn alerts_once_when_overdue() {
+        let clock = FakeClock::at(at(2024, 3, 4, 9, 0));
+        let mut scheduler = scheduler("overdue", MissedPolicy::Skip, &clock);
+        scheduler.set_expect_success_within(Duration::from_hours(1));
+        clock.advance(chrono::Duration::minutes(59));
+        assert_eq!(scheduler.check_overdue().unwrap(), None);
+        clock.advance(chrono::Duration::minutes(1));
+        let alert = scheduler.check_overdue().unwrap().unwrap();
+        assert!(alert.contains("since the command was scheduled"), "{alert}");
+        assert_eq!(scheduler.check_overdue().unwrap(), None);
+        assert!(scheduler.success_summary().ends_with("(overdue)"));
+
+        scheduler.record_run(false).unwrap();
+        scheduler.record_run(true).unwrap();
+        assert_eq!(
+            scheduler.success_summary(),
+            "Succeeded 1 of 2 runs, 1 in a row"
+        );
+        clock.advance(chrono::Duration::hours(1));
+        let alert = scheduler.check_overdue().unwrap().unwrap();
+        assert!(
+            alert.contains("the last success was at 2024-03-04 10:00:00"),
+            "{alert}"
+        );
+        let _ = std::fs::remove_file(&scheduler.path);
+    }
+
+    #[test]
+    fn time_windows() {
+        let day: TimeWindow = "08:00-18:00".parse().unwrap();
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert!(day.contains(time(8, 0)));
+        assert!(!day.contains(time(18, 0)));
+        assert!(!day.contains(time(23, 0)));
+        assert!(night.contains(time(23, 0)));
+        assert!(night.contains(time(5, 59)));
+        assert!(!night.contains(time(12, 0)));
+        assert_eq!(night.to_string(), "22:00-06:00");
+        assert!("08:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn constraints() {
+        let holiday = NaiveDate::from_ymd_opt(2024, 12, 25).unwrap();
+        let window: TimeWindow = "08:00-18:00".parse().unwrap();
+        let constraints = Constraints {
+            only_weekdays: true,
+            not_on: vec![holiday],
+            windows: vec![window],
+            busy_calendar: None,
+        };
+        let allowed = at(2024, 12, 24, 9, 0);
+        assert!(constraints.allows(allowed));
+        assert_eq!(constraints.blocked_at(allowed), None);
+        let cases = [
+            (at(2024, 12, 21, 9, 0), Blocked::Weekend),
+            (at(2024, 12, 25, 9, 0), Blocked::Date(holiday)),
+            (
+                at(2024, 12, 24, 7, 0),
+                Blocked::OutsideWindows(vec![window]),
+            ),
+        ];
+        for (time, blocked) in cases {
+            assert!(!constraints.allows(time), "{time}");
+            assert_eq!(constraints.blocked_at(time), Some(blocked), "{time}");
+        }
+        assert!(Constraints::default().is_empty());
+        assert!(Constraints::default().allows(at(2024, 12, 21, 3, 0)));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use env_logger::{Logger, Target};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::{fleet, get_logs_dir, spawntrace};
+
+/// How often `trayme self-logs --follow` checks for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How much of the log is read at a time while looking for the last lines.
+const CHUNK_LEN: u64 = 8 * 1024;
+
+/// The level trayme's own modules log at on top of `RUST_LOG`, see [`set_verbosity`]. `0` while
+/// it isn't raised, otherwise a [`LevelFilter`] as `usize`.
+static RAISED: AtomicUsize = AtomicUsize::new(0);
+
+/// The level `RUST_LOG` lets through at most, as `usize`.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// How verbose trayme's own log is while it runs, set with `trayme self-logs --level`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Verbosity {
+    Warn,
+    Info,
+    Debug,
+    Trace,
+    /// Back to what `RUST_LOG` says.
+    Reset,
+}
+
+impl Verbosity {
+    fn level(self) -> LevelFilter {
+        match self {
+            Verbosity::Warn => LevelFilter::Warn,
+            Verbosity::Info => LevelFilter::Info,
+            Verbosity::Debug => LevelFilter::Debug,
+            Verbosity::Trace => LevelFilter::Trace,
+            Verbosity::Reset => LevelFilter::Off,
+        }
+    }
+}
+
+/// Logs what `RUST_LOG` lets through, and what trayme's own modules log at or above the raised
+/// level, if it was raised.
+struct RuntimeLogger {
+    /// Filtered by `RUST_LOG`.
+    base: Logger,
+    /// Lets everything trayme's own modules log through, the level is checked on top of it.
+    raised: Logger,
+}
+
+impl RuntimeLogger {
+    fn raised_enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() as usize <= RAISED.load(Ordering::Relaxed) && self.raised.enabled(metadata)
+    }
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.base.enabled(metadata) || self.raised_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.base.matches(record) {
+            self.base.log(record);
+        } else if self.raised_enabled(record.metadata()) {
+            self.raised.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.base.flush();
+        self.raised.flush();
+    }
+}
+
+/// The file release builds log to, which every instance appends to.
+///
+/// # Errors
+///
+/// An error is returned if the logs directory cannot be created.
+pub fn log_file() -> anyhow::Result<PathBuf> {
+    Ok(get_logs_dir()?.join("log.log"))
+}
+
+/// Where the log goes: stdout in debug builds, [`log_file`] otherwise.
+fn target() -> anyhow::Result<Target> {
+    if cfg!(debug_assertions) {
+        return Ok(Target::Stdout);
+    }
+    let writer = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file()?)
+        .context("Failed to open log file")?;
+    Ok(Target::Pipe(Box::new(writer)))
+}
+
+/// Sets up trayme's own log, filtered by `RUST_LOG` until [`set_verbosity`] raises it.
+///
+/// # Errors
+///
+/// An error is returned if the log file cannot be opened or a logger was already set up.
+pub fn init() -> anyhow::Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if spawntrace::enabled() {
+        builder.filter_module(spawntrace::LOG_TARGET, LevelFilter::Info);
+    }
+    let base = builder.target(target()?).build();
+    let raised = env_logger::Builder::new()
+        .filter_module(env!("CARGO_CRATE_NAME"), LevelFilter::Trace)
+        .target(target()?)
+        .build();
+    BASE.store(base.filter() as usize, Ordering::Relaxed);
+    log::set_max_level(base.filter());
+    log::set_boxed_logger(Box::new(RuntimeLogger { base, raised }))
+        .context("Failed to set up logging")
+}
+
+/// Makes trayme's own modules log at `verbosity` on top of what `RUST_LOG` lets through, until
+/// this process exits or it's reset.
+pub fn set_verbosity(verbosity: Verbosity) {
+    let level = verbosity.level();
+    RAISED.store(level as usize, Ordering::Relaxed);
+    let base = BASE.load(Ordering::Relaxed);
+    log::set_max_level(
+        LevelFilter::iter()
+            .nth(base.max(level as usize))
+            .unwrap_or(LevelFilter::Trace),
+    );
+    log::info!("Log level set to {verbosity:?}");
+}
+
+/// Runs `trayme self-logs`: prints the last `lines` lines of [`log_file`] to stdout, then keeps
+/// printing what's appended to it until interrupted if `follow` is set.
+///
+/// # Arguments
+///
+/// * `names` - The instances whose log level is changed first.
+/// * `level` - What their log level is changed to, if it is.
+/// * `lines` - How many of the last lines are printed.
+/// * `follow` - Whether to keep printing new lines.
+///
+/// # Errors
+///
+/// An error is returned if the level couldn't be changed, if this is a debug build, which logs
+/// to stdout instead, or if the log cannot be read.
+pub fn run(
+    names: &[String],
+    level: Option<Verbosity>,
+    lines: usize,
+    follow: bool,
+) -> anyhow::Result<()> {
+    if let Some(level) = level {
+        fleet::set_log_level(names, level)?;
+    }
+    if cfg!(debug_assertions) {
+        bail!("Debug builds log to stdout rather than a file");
+    }
+    let path = log_file()?;
+    let mut file =
+        File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let start = last_lines_start(&mut file, lines).context("Failed to read log")?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut stdout = io::stdout();
+    io::copy(&mut file, &mut stdout)?;
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        stdout.flush()?;
+        thread::sleep(FOLLOW_INTERVAL);
+        // truncated by hand, start over
+        if file.metadata()?.len() < file.stream_position()? {
+            file.seek(SeekFrom::Start(0))?;
+        }
+        io::copy(&mut file, &mut stdout)?;
+    }
+}
+
+/// The offset of the start of the last `lines` lines of `file`.
+fn last_lines_start(file: &mut File, lines: usize) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    if lines == 0 {
+        return Ok(len);
+    }
+    // the line ending of the last line doesn't start another one
+    let mut end = len.saturating_sub(1);
+    let mut newlines = 0;
+    let mut buf = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK_LEN);
+        buf.resize(usize::try_from(end - start).unwrap_or_default(), 0);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        for i in (0..buf.len()).rev().filter(|&i| buf[i] == b'\n') {
+            newlines += 1;
+            if newlines == lines {
+                return Ok(start + i as u64 + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
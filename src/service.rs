@@ -0,0 +1,346 @@
+use std::{
+    ffi::{OsStr, OsString},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{error, info};
+use windows_service::{
+    define_windows_service,
+    service::{
+        Service, ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+        ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{
+    cli::{ConstraintArgs, InstanceArgs},
+    config::{self, load_config, ProfileRef},
+    run_headless,
+};
+
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// What the service process was started with. The service control manager calls
+/// [`service_main`] without our command line, so it's stashed here before dispatching.
+#[derive(Debug)]
+struct ServiceRun {
+    profile: String,
+    config: PathBuf,
+    listen: SocketAddr,
+}
+
+static SERVICE_RUN: OnceLock<ServiceRun> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Returns the name of the service that runs `profile`.
+pub fn service_name(profile: &str) -> String {
+    format!("{}-{profile}", env!("CARGO_PKG_NAME"))
+}
+
+/// Registers a service that runs `profile` with `trayme service run`.
+///
+/// # Arguments
+///
+/// * `profile` - The profile to run.
+/// * `config` - The config file to read the profile from. The service runs as the system account,
+///   so the path is resolved now rather than when the service starts.
+/// * `listen` - The address of the service's control socket.
+/// * `auto_start` - Whether the service starts at boot.
+///
+/// # Errors
+///
+/// An error is returned if the profile doesn't exist or the service cannot be created.
+pub fn install(
+    profile: &str,
+    config: Option<&Path>,
+    listen: SocketAddr,
+    auto_start: bool,
+) -> anyhow::Result<()> {
+    let config = match config {
+        Some(path) => path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", path.display()))?,
+        None => config::config_path()?,
+    };
+    // fail now instead of when the service starts
+    load_config(Some(&config))?.profile(profile)?;
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context("Failed to connect to the service manager")?;
+    let name = service_name(profile);
+    let info = ServiceInfo {
+        name: OsString::from(&name),
+        display_name: OsString::from(format!("trayme ({profile})")),
+        service_type: SERVICE_TYPE,
+        start_type: if auto_start {
+            ServiceStartType::AutoStart
+        } else {
+            ServiceStartType::OnDemand
+        },
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().context("Failed to get trayme's path")?,
+        launch_arguments: vec![
+            "service".into(),
+            "run".into(),
+            profile.into(),
+            "--config".into(),
+            config.into_os_string(),
+            "--listen".into(),
+            listen.to_string().into(),
+        ],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .with_context(|| format!("Failed to create service '{name}'"))?;
+    service
+        .set_description(format!("Runs the trayme profile '{profile}' headless"))
+        .context("Failed to set service description")?;
+    info!("Installed service '{name}'");
+    Ok(())
+}
+
+/// Starts the service installed for `profile`.
+///
+/// # Errors
+///
+/// An error is returned if the service doesn't exist or cannot be started.
+pub fn start(profile: &str) -> anyhow::Result<()> {
+    let service = open_service(profile, ServiceAccess::START)?;
+    service
+        .start::<&OsStr>(&[])
+        .context("Failed to start service")
+}
+
+/// Stops the service installed for `profile`, if it's running.
+///
+/// # Errors
+///
+/// An error is returned if the service doesn't exist or cannot be stopped.
+pub fn stop(profile: &str) -> anyhow::Result<()> {
+    let service = open_service(profile, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP)?;
+    stop_service(&service)
+}
+
+/// Stops and removes the service installed for `profile`.
+///
+/// # Errors
+///
+/// An error is returned if the service doesn't exist or cannot be removed.
+pub fn uninstall(profile: &str) -> anyhow::Result<()> {
+    let service = open_service(
+        profile,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    stop_service(&service)?;
+    service.delete().context("Failed to delete service")?;
+    info!("Uninstalled service '{}'", service_name(profile));
+    Ok(())
+}
+
+/// Hands the process over to the service control manager, which then calls [`service_main`].
+/// This blocks until the service stops.
+///
+/// # Errors
+///
+/// An error is returned if the process wasn't started by the service control manager.
+pub fn run(profile: String, config: PathBuf, listen: SocketAddr) -> anyhow::Result<()> {
+    let name = service_name(&profile);
+    SERVICE_RUN
+        .set(ServiceRun {
+            profile,
+            config,
+            listen,
+        })
+        .expect("service is only dispatched once");
+    service_dispatcher::start(name, ffi_service_main).context(
+        "Failed to start service dispatcher (services must be started with `trayme service start`)",
+    )
+}
+
+/// Returns the control socket address and whether the service installed for `profile` starts at
+/// boot, or `None` if there is no such service.
+pub fn query(profile: &str) -> Option<(SocketAddr, bool)> {
+    let service = open_service(profile, ServiceAccess::QUERY_CONFIG).ok()?;
+    let config = service
+        .query_config()
+        .inspect_err(|e| error!("Failed to query service for '{profile}': {e}"))
+        .ok()?;
+    // the command line written by `install`
+    let command_line = config.executable_path.to_string_lossy().into_owned();
+    let listen = command_line
+        .split_whitespace()
+        .skip_while(|arg| *arg != "--listen")
+        .nth(1)?
+        .trim_matches('"')
+        .parse()
+        .ok()?;
+    Some((listen, config.start_type == ServiceStartType::AutoStart))
+}
+
+fn open_service(profile: &str, access: ServiceAccess) -> anyhow::Result<Service> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("Failed to connect to the service manager")?;
+    let name = service_name(profile);
+    manager
+        .open_service(&name, access)
+        .with_context(|| format!("Failed to open service '{name}'"))
+}
+
+fn stop_service(service: &Service) -> anyhow::Result<()> {
+    let status = service
+        .query_status()
+        .context("Failed to query service status")?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().context("Failed to stop service")?;
+    }
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Service failed: {e:#}");
+    }
+}
+
+fn run_service() -> anyhow::Result<()> {
+    let run = SERVICE_RUN.get().context("Service was not dispatched")?;
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            let _ = stop_tx.send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = service_control_handler::register(service_name(&run.profile), handler)
+        .context("Failed to register service control handler")?;
+
+    let result = load_config(Some(&run.config)).and_then(|config| {
+        let profile = config.profile(&run.profile)?;
+        set_state(
+            &status_handle,
+            ServiceState::Running,
+            ServiceExitCode::Win32(0),
+        )?;
+        let mut instance = InstanceArgs {
+            name: None,
+            headless: true,
+            wait_for_tray: None,
+            on_logout: None,
+            detach_on_exit: false,
+            stay_open: false,
+            listen: run.listen,
+            stop_strategy: None,
+            kill_timeout: None,
+            tags: Vec::new(),
+            unhealthy_if: None,
+            threshold: None,
+            restart_on_unhealthy: false,
+            restart_policy: None,
+            restart_backoff: None,
+            max_restarts: None,
+            timeout: None,
+            ping_url: None,
+            restart_every: None,
+            restart_cron: None,
+            fallback: None,
+            fallback_after: None,
+            on_wake: None,
+            wake_check: None,
+            maintenance_duration: None,
+            verbose_exit: false,
+            progress_regex: None,
+            env_provider: None,
+            tz: None,
+            locale: None,
+            ulimits: Vec::new(),
+            pre_check: None,
+            require_free_mem: None,
+            require_free_disk: None,
+            elevate: false,
+            temp_dir: None,
+            on_start: None,
+            on_exit: None,
+            on_failure: None,
+            wait_for_port: Vec::new(),
+            wait_for_file: Vec::new(),
+            wait_for_cmd: Vec::new(),
+            wait_timeout: None,
+            delay: None,
+            cpu_throttle: None,
+            max_memory: None,
+            cpu_limit: None,
+            on_limit: None,
+            priority: None,
+            status_glyphs: false,
+            first_output_notify: false,
+            compress_rotated_logs: false,
+            no_kill_menu: false,
+            start_hidden: false,
+            click_to_toggle: false,
+            protected: false,
+            confirm: None,
+            constraints: ConstraintArgs::default(),
+            continue_on_failure: false,
+            watch_inbox: None,
+            max_concurrent: None,
+            then: Vec::new(),
+            profile: Some(ProfileRef {
+                name: run.profile.clone(),
+                config: run.config.clone(),
+            }),
+        };
+        profile.apply_to(&run.profile, &mut instance)?;
+        run_headless(profile.to_spec(), profile.notifier(), &instance, || {
+            stop_rx.try_recv().is_ok()
+        })
+    });
+    // a non-zero exit code lets the service manager's recovery options restart the service
+    let exit_code = match &result {
+        Ok(Some(code)) if *code != 0 => {
+            ServiceExitCode::ServiceSpecific(u32::from_ne_bytes(code.to_ne_bytes()))
+        }
+        Ok(_) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_state(&status_handle, ServiceState::Stopped, exit_code)?;
+    result.map(|_| ())
+}
+
+fn set_state(
+    status_handle: &ServiceStatusHandle,
+    state: ServiceState,
+    exit_code: ServiceExitCode,
+) -> anyhow::Result<()> {
+    let controls_accepted = if state == ServiceState::Running {
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+    } else {
+        ServiceControlAccept::empty()
+    };
+    status_handle
+        .set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+        .context("Failed to report service status")
+}
@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::Path};
+
+use anyhow::{bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, Config},
+    envdiff::is_secret,
+};
+
+/// The version of the setup file format written by [`export`].
+const SETUP_VERSION: u32 = 1;
+
+/// A Windows service that runs a profile, as set up with `trayme service install`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceSetup {
+    pub profile: String,
+    pub listen: SocketAddr,
+    #[serde(default)]
+    pub auto_start: bool,
+}
+
+/// Everything needed to recreate a trayme setup on another machine: the profiles and the
+/// services that start them. Secret variables are left out, but their names are kept so that
+/// whoever imports the setup knows what to fill in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Setup {
+    pub version: u32,
+    #[serde(default)]
+    pub config: Config,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceSetup>,
+    /// The secret variables left out of each profile's `env`, by profile name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing_secrets: BTreeMap<String, Vec<String>>,
+}
+
+/// Writes the profiles in the config file, and the services installed for them, to `file`.
+///
+/// # Arguments
+///
+/// * `file` - Where to write the setup.
+/// * `config` - The config file to export. If `None`, the default one is used.
+///
+/// # Errors
+///
+/// An error is returned if the config file cannot be loaded or the setup cannot be written.
+pub fn export(file: &Path, config: Option<&Path>) -> anyhow::Result<()> {
+    let mut config = config::load_config(config)?;
+    let mut missing_secrets = BTreeMap::new();
+    for (name, profile) in &mut config.profiles {
+        let secrets: Vec<_> = profile
+            .env
+            .keys()
+            .filter(|key| is_secret(key))
+            .cloned()
+            .collect();
+        if secrets.is_empty() {
+            continue;
+        }
+        for secret in &secrets {
+            profile.env.remove(secret);
+        }
+        println!("Left out secrets of '{name}': {}", secrets.join(", "));
+        missing_secrets.insert(name.clone(), secrets);
+    }
+    let services = installed_services(&config);
+    let setup = Setup {
+        version: SETUP_VERSION,
+        config,
+        services,
+        missing_secrets,
+    };
+    let contents = toml::to_string_pretty(&setup).context("Failed to serialize setup")?;
+    std::fs::write(file, contents)
+        .with_context(|| format!("Failed to write {}", file.display()))?;
+    println!(
+        "Exported {} profiles and {} services to {}",
+        setup.config.profiles.len(),
+        setup.services.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+/// Adds the profiles from a setup written by [`export`] to the config file, and installs its
+/// services on Windows.
+///
+/// # Arguments
+///
+/// * `file` - The setup to import.
+/// * `config` - The config file to add the profiles to. If `None`, the default one is used.
+/// * `overwrite` - Whether to replace profiles that already exist. Otherwise they're skipped.
+///
+/// # Errors
+///
+/// An error is returned if the setup cannot be read, the config file cannot be loaded or saved,
+/// or a service cannot be installed.
+pub fn import(file: &Path, config: Option<&Path>, overwrite: bool) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let setup: Setup = toml::from_str(&contents)
+        .with_context(|| format!("Invalid setup file {}", file.display()))?;
+    if setup.version > SETUP_VERSION {
+        bail!(
+            "{} was exported by a newer version of trayme (setup version {})",
+            file.display(),
+            setup.version
+        );
+    }
+
+    let config_path = match config {
+        Some(path) => path.to_path_buf(),
+        None => config::config_path()?,
+    };
+    let mut current = Config::load(&config_path)?;
+    let mut imported = Vec::new();
+    for (name, profile) in setup.config.profiles {
+        if current.profiles.contains_key(&name) && !overwrite {
+            println!("Skipped '{name}', which already exists (use --overwrite to replace it)");
+            continue;
+        }
+        current.profiles.insert(name.clone(), profile);
+        imported.push(name);
+    }
+    if !imported.is_empty() {
+        current.save(&config_path)?;
+    }
+    info!(
+        "Imported profiles {imported:?} into {}",
+        config_path.display()
+    );
+    println!(
+        "Imported {} profiles into {}",
+        imported.len(),
+        config_path.display()
+    );
+
+    for service in setup
+        .services
+        .iter()
+        .filter(|service| imported.contains(&service.profile))
+    {
+        #[cfg(windows)]
+        {
+            crate::service::install(
+                &service.profile,
+                Some(&config_path),
+                service.listen,
+                service.auto_start,
+            )?;
+            println!("Installed the service for '{}'", service.profile);
+        }
+        #[cfg(not(windows))]
+        println!(
+            "Skipped the service for '{}', services are only supported on Windows",
+            service.profile
+        );
+    }
+    for (name, secrets) in &setup.missing_secrets {
+        if imported.contains(name) {
+            println!(
+                "Set these variables of '{name}' yourself: {}",
+                secrets.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn installed_services(config: &Config) -> Vec<ServiceSetup> {
+    config
+        .profiles
+        .keys()
+        .filter_map(|profile| {
+            let (listen, auto_start) = crate::service::query(profile)?;
+            Some(ServiceSetup {
+                profile: profile.clone(),
+                listen,
+                auto_start,
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn installed_services(_config: &Config) -> Vec<ServiceSetup> {
+    Vec::new()
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+
+use crate::{config, history, parse, supervisor::CommandSpec};
+
+/// How much of a script is read looking for its `#!` line.
+const MAX_LINE_LEN: u64 = 512;
+
+/// The extensions of files Windows runs by itself, which are never looked into.
+const NATIVE_EXTENSIONS: &[&str] = &["exe", "com", "bat", "cmd"];
+
+/// The `[interpreters]` table of the config file at `path` (or the default one), which says what
+/// the interpreters named in `#!` lines are run with on Windows, e.g. `python3 = ["py", "-3"]`.
+/// Problems with the file are logged, and leave the table empty.
+pub fn configured(path: Option<&Path>) -> BTreeMap<String, Vec<String>> {
+    match config::load_config(path) {
+        Ok(config) => config.interpreters,
+        Err(e) => {
+            warn!("Not using the interpreters of the config file: {e:#}");
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Returns the spec that runs the script of `spec` with the interpreter its `#!` line names, for
+/// Windows, which ignores those lines. The interpreter is run with what the `interpreters` of
+/// `spec` say for its name, or by its name from `PATH` otherwise, with the arguments of the line,
+/// then the script and its own arguments.
+///
+/// Returns `None` if the command isn't a script with a `#!` line.
+pub fn apply(spec: &CommandSpec) -> Option<CommandSpec> {
+    let script = locate(spec)?;
+    let line = match first_line(&script) {
+        Ok(line) => line,
+        Err(e) => {
+            debug!("Failed to read {}: {e}", script.display());
+            return None;
+        }
+    };
+    let (interpreter, args) = parse::shebang(&line)?;
+    let mut cmd: Vec<OsString> = match spec.interpreters.get(interpreter) {
+        Some(mapped) if !mapped.is_empty() => mapped.iter().map(OsString::from).collect(),
+        _ => vec![interpreter.into()],
+    };
+    info!(
+        "Running {} with {} from its #! line",
+        script.display(),
+        cmd[0].to_string_lossy()
+    );
+    cmd.extend(args.into_iter().map(OsString::from));
+    cmd.push(script.into_os_string());
+    cmd.extend(spec.cmd[1..].iter().cloned());
+    Some(CommandSpec {
+        cmd,
+        ..spec.clone()
+    })
+}
+
+/// The file the program of `spec` is, if it's one Windows can't run by itself.
+fn locate(spec: &CommandSpec) -> Option<PathBuf> {
+    let cwd = match &spec.cwd {
+        Some(cwd) => cwd.clone(),
+        None => std::env::current_dir().ok()?,
+    };
+    let path_var = spec
+        .overrides()
+        .get("PATH")
+        .or_else(|| spec.env.as_ref().and_then(|env| env.get("PATH")))
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok());
+    let program = history::resolve_program(&spec.cmd[0], path_var.as_deref(), &cwd)?;
+    let native = program.extension().is_some_and(|ext| {
+        NATIVE_EXTENSIONS
+            .iter()
+            .any(|native| ext.eq_ignore_ascii_case(native))
+    });
+    (!native).then_some(program)
+}
+
+/// The first line of the file at `path`, as far as it's within [`MAX_LINE_LEN`].
+fn first_line(path: &Path) -> io::Result<String> {
+    let mut start = Vec::new();
+    File::open(path)?
+        .take(MAX_LINE_LEN)
+        .read_to_end(&mut start)?;
+    let line = start
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    // editors on Windows like to start files with a byte order mark
+    Ok(line.trim_start_matches('\u{feff}').to_string())
+}
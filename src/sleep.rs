@@ -0,0 +1,192 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+
+/// How much more time has to pass on the clock than trayme saw between two checks for the
+/// machine to count as having slept. Shorter sleeps aren't noticed.
+const MIN_SLEEP: Duration = Duration::from_mins(1);
+
+/// What happens to the process after the machine wakes from sleep, since network connections
+/// such as SSH tunnels often don't survive it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnWake {
+    /// Leaves it alone. The wake is only logged.
+    #[default]
+    Nothing,
+    /// Restarts it.
+    Restart,
+    /// Runs `--wake-check`, and restarts it if the check fails.
+    Healthcheck,
+}
+
+/// What `--on-wake` does, with the command of its health check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WakeAction {
+    Restart,
+    Check(String),
+}
+
+impl WakeAction {
+    /// The action for `on_wake`, which defaults to `healthcheck` with a `check` and to `nothing`
+    /// without one. Returns `None` for `nothing`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned for `healthcheck` without a `check` to run.
+    pub fn new(on_wake: Option<OnWake>, check: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let on_wake = on_wake.unwrap_or(if check.is_some() {
+            OnWake::Healthcheck
+        } else {
+            OnWake::Nothing
+        });
+        Ok(match on_wake {
+            OnWake::Nothing => None,
+            OnWake::Restart => Some(WakeAction::Restart),
+            OnWake::Healthcheck => Some(WakeAction::Check(
+                check
+                    .context("--on-wake healthcheck needs a --wake-check to run")?
+                    .to_string(),
+            )),
+        })
+    }
+}
+
+/// A sleep of the machine that ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sleep {
+    /// When trayme last saw the machine awake.
+    pub since: DateTime<Local>,
+    /// When it noticed the machine woke.
+    pub woke: DateTime<Local>,
+}
+
+impl fmt::Display for Sleep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let slept = (self.woke - self.since).to_std().unwrap_or_default();
+        write!(
+            f,
+            "The machine slept from {} to {} ({})",
+            self.since.format("%Y-%m-%d %H:%M:%S"),
+            self.woke.format("%Y-%m-%d %H:%M:%S"),
+            humantime::format_duration(Duration::from_secs(slept.as_secs()))
+        )
+    }
+}
+
+/// Notices when the machine slept, from the time that passed on the clock between two checks
+/// but not for trayme, whose monotonic clock stands still during sleep. There are no events for
+/// it in the tray's event loop on the desktop. Windows keeps the monotonic clock going, so there
+/// a gap of [`MIN_SLEEP`] between checks counts as a sleep, which the event loop polling all the
+/// time makes reliable enough.
+#[derive(Debug)]
+pub struct SleepWatch {
+    last_check: Instant,
+    last_seen: DateTime<Local>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SleepWatch {
+    /// Starts watching `clock` for sleeps.
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            last_check: clock.instant(),
+            last_seen: clock.now(),
+            clock,
+        }
+    }
+
+    /// Returns the sleep that ended since the last check, if the machine slept.
+    pub fn check(&mut self) -> Option<Sleep> {
+        let checked = self.clock.instant();
+        let seen = checked.duration_since(self.last_check);
+        let since = std::mem::replace(&mut self.last_seen, self.clock.now());
+        self.last_check = checked;
+        woke(since, self.last_seen, seen)
+    }
+}
+
+/// The sleep between `since` and `now` on the clock, if trayme only saw `seen` of that time
+/// pass. Clocks that were set back don't count.
+fn woke(since: DateTime<Local>, now: DateTime<Local>, seen: Duration) -> Option<Sleep> {
+    let passed = (now - since).to_std().ok()?;
+    let seen = if cfg!(windows) { Duration::ZERO } else { seen };
+    (passed.saturating_sub(seen) >= MIN_SLEEP).then_some(Sleep { since, woke: now })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn notices_sleeps() {
+        let since = Local.with_ymd_and_hms(2024, 3, 4, 18, 0, 0).unwrap();
+        let now = since + chrono::Duration::hours(14);
+        let sleep = woke(since, now, Duration::from_millis(100)).unwrap();
+        assert_eq!(sleep.since, since);
+        assert_eq!(sleep.woke, now);
+        assert_eq!(
+            sleep.to_string(),
+            "The machine slept from 2024-03-04 18:00:00 to 2024-03-05 08:00:00 (14h)"
+        );
+        assert_eq!(
+            woke(since, since + chrono::Duration::seconds(5), Duration::ZERO),
+            None
+        );
+        assert_eq!(woke(now, since, Duration::ZERO), None);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn stalls_arent_sleeps() {
+        let since = Local.with_ymd_and_hms(2024, 3, 4, 18, 0, 0).unwrap();
+        let stall = Duration::from_secs(90);
+        let now = since + chrono::Duration::from_std(stall).unwrap();
+        assert_eq!(woke(since, now, stall), None);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn watches_the_clock() {
+        let since = Local.with_ymd_and_hms(2024, 3, 4, 18, 0, 0).unwrap();
+        let clock = FakeClock::at(since);
+        let mut watch = SleepWatch::new(Arc::new(clock.clone()));
+        clock.advance(chrono::Duration::hours(2));
+        assert_eq!(watch.check(), None);
+        clock.sleep(chrono::Duration::hours(12));
+        let sleep = watch.check().unwrap();
+        assert_eq!(sleep.since, since + chrono::Duration::hours(2));
+        assert_eq!(sleep.woke, clock.now());
+        assert_eq!(watch.check(), None);
+    }
+
+    #[test]
+    fn wake_actions() {
+        assert_eq!(WakeAction::new(None, None).unwrap(), None);
+        assert_eq!(
+            WakeAction::new(None, Some("nc -z localhost 8080")).unwrap(),
+            Some(WakeAction::Check("nc -z localhost 8080".into()))
+        );
+        assert_eq!(
+            WakeAction::new(Some(OnWake::Restart), Some("true")).unwrap(),
+            Some(WakeAction::Restart)
+        );
+        assert_eq!(
+            WakeAction::new(Some(OnWake::Nothing), Some("true")).unwrap(),
+            None
+        );
+        assert!(WakeAction::new(Some(OnWake::Healthcheck), None).is_err());
+    }
+}
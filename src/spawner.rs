@@ -0,0 +1,102 @@
+use std::{
+    fmt, io,
+    process::{Child, Command},
+};
+
+#[cfg(test)]
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Starts the processes of commands, so that what's done with them can be tested with stand-ins
+/// that exit the way a test needs them to.
+pub trait ProcessSpawner: fmt::Debug + Send + Sync {
+    /// Spawns `command`, which is set up with everything the process gets, including its pipes.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be spawned.
+    fn spawn(&self, command: &mut Command) -> io::Result<Child>;
+}
+
+/// Spawns the commands themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemSpawner;
+
+impl ProcessSpawner for SystemSpawner {
+    fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        command.spawn()
+    }
+}
+
+/// A command [`FakeSpawner`] was asked to spawn.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spawned {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub cwd: Option<PathBuf>,
+    /// The variables set, or removed if `None`, on top of trayme's environment.
+    pub env: Vec<(OsString, Option<OsString>)>,
+}
+
+/// Spawns a shell that exits with the next of the codes it was given (0 once they run out) in
+/// place of each command, and keeps the commands. Clones share them.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct FakeSpawner(Arc<Mutex<(VecDeque<i32>, Vec<Spawned>)>>);
+
+#[cfg(test)]
+impl FakeSpawner {
+    /// A spawner whose stand-ins exit with `codes`, in order.
+    pub fn exiting_with(codes: impl IntoIterator<Item = i32>) -> Self {
+        Self(Arc::new(Mutex::new((
+            codes.into_iter().collect(),
+            Vec::new(),
+        ))))
+    }
+
+    /// The commands spawned so far, oldest first.
+    pub fn spawned(&self) -> Vec<Spawned> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1
+            .clone()
+    }
+}
+
+#[cfg(test)]
+impl ProcessSpawner for FakeSpawner {
+    fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        let mut fake = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let code = fake.0.pop_front().unwrap_or(0);
+        fake.1.push(Spawned {
+            program: command.get_program().to_os_string(),
+            args: command.get_args().map(ToOwned::to_owned).collect(),
+            cwd: command.get_current_dir().map(ToOwned::to_owned),
+            env: command
+                .get_envs()
+                .map(|(key, value)| (key.to_os_string(), value.map(ToOwned::to_owned)))
+                .collect(),
+        });
+        let mut stand_in = if cfg!(windows) {
+            let mut stand_in = Command::new("cmd");
+            stand_in.arg("/C").arg(format!("exit {code}"));
+            stand_in
+        } else {
+            let mut stand_in = Command::new("sh");
+            stand_in.arg("-c").arg(format!("exit {code}"));
+            stand_in
+        };
+        stand_in
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::anyhow;
+use log::info;
+
+use crate::{envdiff::EnvDiff, history::RunRecord, supervisor::CommandSpec};
+
+/// Set with `--trace-spawn`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The module the trace is logged from, which `--trace-spawn` turns logging on for whatever the
+/// level of the rest.
+pub const LOG_TARGET: &str = module_path!();
+
+/// How much of the program is read to find its `#!` line.
+const SHEBANG_MAX: usize = 256;
+
+/// Turns on tracing of every spawn, see [`trace`].
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--trace-spawn` is on.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Logs everything that goes into spawning a run, if `--trace-spawn` is on: each argument
+/// exactly as it's passed, the program it resolves to and its interpreter, the working
+/// directory, how the environment differs from trayme's, and how the child's standard streams,
+/// process group, and limits are set up.
+///
+/// # Arguments
+///
+/// * `spec` - The spec being spawned, after `--shell` and the environment provider applied.
+/// * `record` - The run's record, which has its resolved program and whole environment.
+pub fn trace(spec: &CommandSpec, record: &RunRecord) {
+    if !enabled() {
+        return;
+    }
+    info!("[trace-spawn] run {}", record.id);
+    for (i, arg) in spec.cmd.iter().enumerate() {
+        let bytes = if arg.is_ascii() {
+            String::new()
+        } else {
+            raw_bytes(arg)
+        };
+        info!("[trace-spawn] argv[{i}] = {arg:?}{bytes}");
+    }
+    if let Some(binary) = &record.binary {
+        info!("[trace-spawn] program: {}", binary.display());
+        if let Some(shebang) = shebang(binary) {
+            info!("[trace-spawn] interpreter: {shebang:?}");
+        }
+    } else {
+        info!(
+            "[trace-spawn] program: {:?} not found on PATH or relative to the working directory",
+            spec.cmd[0]
+        );
+    }
+    let cwd_state = if record.cwd.is_dir() {
+        ""
+    } else {
+        " (not a directory)"
+    };
+    info!("[trace-spawn] cwd: {}{cwd_state}", record.cwd.display());
+    let base = if spec.env.is_some() {
+        "replaced"
+    } else {
+        "inherited"
+    };
+    info!("[trace-spawn] environment: {base}, differing from trayme's by:");
+    for line in EnvDiff::from_current(&record.env).to_string().lines() {
+        info!("[trace-spawn]   {line}");
+    }
+    info!(
+        "[trace-spawn] stdin: pipe (for the console), stdout and stderr: pipes to {}",
+        record.log_file.display()
+    );
+    #[cfg(unix)]
+    info!("[trace-spawn] flags: process_group(0)");
+    #[cfg(windows)]
+    info!("[trace-spawn] flags: CREATE_NO_WINDOW (0x08000000), in a job object");
+    for limit in &spec.ulimits {
+        info!("[trace-spawn] limit: {limit}");
+    }
+}
+
+/// The bytes of an argument that isn't ASCII, which tell apart look-alike characters and show
+/// what an argument that isn't valid Unicode really is.
+#[cfg(unix)]
+fn raw_bytes(arg: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let encoding = if arg.to_str().is_some() {
+        "UTF-8"
+    } else {
+        "not UTF-8"
+    };
+    format!(" ({encoding} bytes: {:02x?})", arg.as_bytes())
+}
+
+/// The UTF-16 code units of an argument that isn't ASCII, which tell apart look-alike
+/// characters and show unpaired surrogates.
+#[cfg(windows)]
+fn raw_bytes(arg: &OsStr) -> String {
+    use std::os::windows::ffi::OsStrExt;
+    format!(" (UTF-16: {:04x?})", arg.encode_wide().collect::<Vec<_>>())
+}
+
+/// The `#!` line of `program`, if it's a script.
+fn shebang(program: &Path) -> Option<String> {
+    let mut head = Vec::new();
+    File::open(program)
+        .ok()?
+        .take(SHEBANG_MAX as u64)
+        .read_to_end(&mut head)
+        .ok()?;
+    if !head.starts_with(b"#!") {
+        return None;
+    }
+    let end = head.iter().position(|&b| b == b'\n').unwrap_or(head.len());
+    Some(String::from_utf8_lossy(&head[..end]).into_owned())
+}
+
+/// Adds the OS error code of a failed spawn and what it usually means to `err`, if
+/// `--trace-spawn` is on.
+pub fn explain(err: io::Error, record: &RunRecord) -> anyhow::Error {
+    if !enabled() {
+        return err.into();
+    }
+    let Some(code) = err.raw_os_error() else {
+        return err.into();
+    };
+    let interpreter = record.binary.as_deref().and_then(shebang);
+    let hint = hint(code, interpreter.as_deref());
+    info!("[trace-spawn] spawn failed with OS error {code}: {hint}");
+    anyhow::Error::new(err).context(anyhow!("OS error {code}: {hint}"))
+}
+
+#[cfg(unix)]
+fn hint(code: i32, interpreter: Option<&str>) -> String {
+    match (code, interpreter) {
+        (libc::ENOENT, Some(shebang)) if shebang.ends_with('\r') => {
+            "ENOENT, the #! line ends in a carriage return (the script has Windows line endings)"
+                .to_string()
+        }
+        (libc::ENOENT, Some(shebang)) => {
+            format!("ENOENT, the interpreter of the script ({shebang}) doesn't exist")
+        }
+        (libc::ENOENT, None) => "ENOENT, the program doesn't exist".to_string(),
+        (libc::EACCES, _) => "EACCES, the program isn't executable, or a directory on the way \
+                              to it can't be searched"
+            .to_string(),
+        (libc::ENOEXEC, _) => "ENOEXEC, the program isn't in a format this system can run, or \
+                               is a script without a #! line"
+            .to_string(),
+        (libc::E2BIG, _) => "E2BIG, the arguments and environment are too long".to_string(),
+        (libc::ETXTBSY, _) => "ETXTBSY, the program is open for writing".to_string(),
+        (libc::ENOTDIR, _) => "ENOTDIR, a part of the path isn't a directory".to_string(),
+        (libc::ELOOP, _) => "ELOOP, too many symbolic links".to_string(),
+        _ => io::Error::from_raw_os_error(code).to_string(),
+    }
+}
+
+/// Windows' spawn errors are `GetLastError` codes.
+#[cfg(windows)]
+fn hint(code: i32, _interpreter: Option<&str>) -> String {
+    // https://learn.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-
+    let meaning = match code {
+        2 => "ERROR_FILE_NOT_FOUND, the program doesn't exist",
+        3 => "ERROR_PATH_NOT_FOUND, a directory on the way to the program doesn't exist",
+        5 => "ERROR_ACCESS_DENIED, the program can't be executed by this user",
+        193 => "ERROR_BAD_EXE_FORMAT, the program isn't an executable for this system",
+        206 => "ERROR_FILENAME_EXCED_RANGE, the command line or a path is too long",
+        740 => "ERROR_ELEVATION_REQUIRED, the program has to run as administrator",
+        _ => return io::Error::from_raw_os_error(code).to_string(),
+    };
+    meaning.to_string()
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+
+use crate::ipc::{InstanceStatus, ProcessState};
+
+/// The state of an instance as the tray shows it, in its icon, tooltip, and status glyph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayState {
+    Running,
+    /// Running, but over its `--unhealthy-if` threshold.
+    Unhealthy,
+    /// Exited with a non-zero status.
+    Failed,
+    /// Exited successfully or was killed.
+    Stopped,
+}
+
+impl TrayState {
+    /// The state of an instance reported over IPC.
+    pub fn of(status: &InstanceStatus) -> Self {
+        match status.state {
+            ProcessState::Running if status.healthy => TrayState::Running,
+            ProcessState::Running => TrayState::Unhealthy,
+            ProcessState::Killed | ProcessState::Detached => TrayState::Stopped,
+            ProcessState::Exited | ProcessState::Restarting if exited_successfully(status) => {
+                TrayState::Stopped
+            }
+            ProcessState::Exited | ProcessState::Restarting => TrayState::Failed,
+        }
+    }
+
+    /// A glyph for the state, shown next to the name where swapping the icon is unreliable or
+    /// the icon is monochrome.
+    pub fn glyph(self) -> &'static str {
+        match self {
+            TrayState::Running => "●",
+            TrayState::Unhealthy => "▲",
+            TrayState::Failed => "✖",
+            TrayState::Stopped => "■",
+        }
+    }
+}
+
+impl fmt::Display for TrayState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrayState::Running => write!(f, "running"),
+            TrayState::Unhealthy => write!(f, "unhealthy"),
+            TrayState::Failed => write!(f, "failed"),
+            TrayState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Whether the instance's last run ended cleanly, see [`crate::termination::Termination::is_clean`].
+fn exited_successfully(status: &InstanceStatus) -> bool {
+    match status.termination {
+        Some(termination) => termination.is_clean(),
+        // an older trayme only reports ExitStatus' Display, which is "exit status: 0" on Unix and
+        // "exit code: 0" on Windows
+        None => status
+            .exit_status
+            .as_deref()
+            .is_some_and(|status| status.ends_with(": 0")),
+    }
+}
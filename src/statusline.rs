@@ -0,0 +1,161 @@
+use std::io::Write;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    events,
+    icon::UNHEALTHY_COLOR,
+    ipc::{self, ControlCommand, ControlResponse},
+    registry,
+    state::TrayState,
+};
+
+/// The states in the order they're counted in, worst first.
+const STATES: [TrayState; 4] = [
+    TrayState::Failed,
+    TrayState::Unhealthy,
+    TrayState::Running,
+    TrayState::Stopped,
+];
+
+/// The status bar a status line is formatted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BarFormat {
+    /// A JSON object with `text`, `tooltip`, and `class`, for a waybar `custom` module with
+    /// `"return-type": "json"`.
+    Waybar,
+    /// A line of text with polybar color tags, for a `custom/script` module.
+    Polybar,
+    /// The title followed by a dropdown listing the instances, for an xbar or `SwiftBar` plugin.
+    Xbar,
+}
+
+/// What a waybar `custom` module with `"return-type": "json"` reads. `class` is the worst state
+/// of any instance, or `none`, for styling the module.
+#[derive(Serialize)]
+struct WaybarOutput {
+    text: String,
+    tooltip: String,
+    class: String,
+}
+
+/// Prints a summary of the running instances for a status bar: how many are in each state, and
+/// in formats that support it, the state of each one.
+///
+/// # Arguments
+///
+/// * `format` - The status bar to format the summary for.
+/// * `follow` - Whether to keep running and print a new summary whenever it changes, for bars
+///   that read the output of a long-running script (waybar, polybar with `tail = true`, and
+///   streaming xbar plugins).
+///
+/// # Errors
+///
+/// An error is returned if the instance registry cannot be read or stdout cannot be written.
+pub fn print(format: BarFormat, follow: bool) -> anyhow::Result<()> {
+    let mut last = None;
+    let mut print_changed = || -> anyhow::Result<()> {
+        let line = render(format, &instance_states()?);
+        if last.as_ref() == Some(&line) {
+            return Ok(());
+        }
+        let mut stdout = std::io::stdout().lock();
+        // streaming xbar plugins separate each output from the previous one
+        if format == BarFormat::Xbar && last.is_some() {
+            writeln!(stdout, "~~~")?;
+        }
+        writeln!(stdout, "{line}")?;
+        stdout.flush().context("Failed to write the status line")?;
+        last = Some(line);
+        Ok(())
+    };
+    print_changed()?;
+    if !follow {
+        return Ok(());
+    }
+    // the instances' states change with their events, and when they start or exit
+    events::follow_instances(&[], |_| print_changed())
+}
+
+/// The name and state of every instance that answers on its control socket.
+fn instance_states() -> anyhow::Result<Vec<(String, TrayState)>> {
+    let mut found = Vec::new();
+    for instance in registry::list()? {
+        match ipc::request(instance.addr, &ControlCommand::Status) {
+            Ok(ControlResponse::Status(status)) => {
+                found.push((instance.name, TrayState::of(&status)));
+            }
+            Ok(_) => debug!("Unexpected status response from '{}'", instance.name),
+            Err(e) => debug!("Skipping '{}': {e:#}", instance.name),
+        }
+    }
+    found.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(found)
+}
+
+/// Formats the summary of `instances` for the status bar.
+fn render(format: BarFormat, instances: &[(String, TrayState)]) -> String {
+    let counts: Vec<_> = STATES
+        .iter()
+        .map(|&state| {
+            let count = instances
+                .iter()
+                .filter(|(_, other)| *other == state)
+                .count();
+            (state, count)
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    let worst = counts.first().map(|&(state, _)| state);
+    let text = counts
+        .iter()
+        .map(|(state, count)| format!("{} {count}", state.glyph()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let lines = instances
+        .iter()
+        .map(|(name, state)| format!("{} {name}: {state}", state.glyph()));
+    let [r, g, b] = UNHEALTHY_COLOR;
+    let alert = format!("#{r:02x}{g:02x}{b:02x}");
+
+    match format {
+        BarFormat::Waybar => {
+            let output = WaybarOutput {
+                text,
+                tooltip: lines.collect::<Vec<_>>().join("\n"),
+                class: worst.map_or_else(|| "none".to_string(), |state| state.to_string()),
+            };
+            // three strings always serialize
+            serde_json::to_string(&output).unwrap_or_default()
+        }
+        BarFormat::Polybar => counts
+            .iter()
+            .map(|&(state, count)| match state {
+                TrayState::Failed | TrayState::Unhealthy => {
+                    format!("%{{F{alert}}}{} {count}%{{F-}}", state.glyph())
+                }
+                TrayState::Running | TrayState::Stopped => format!("{} {count}", state.glyph()),
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        BarFormat::Xbar => {
+            let title = if text.is_empty() { "trayme" } else { &text };
+            let color = match worst {
+                Some(TrayState::Failed | TrayState::Unhealthy) => format!(" | color={alert}"),
+                _ => String::new(),
+            };
+            let mut out = format!("{title}{color}\n---");
+            if instances.is_empty() {
+                out.push_str("\nNo running instances");
+            }
+            for line in lines {
+                out.push('\n');
+                out.push_str(&line);
+            }
+            out
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    path::Path,
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    child::ChildProcess,
+    supervisor::program_name,
+    tree::ProcessTree,
+    usage::{self, ResourceUsage},
+};
+
+/// How long the child gets to exit after each stop request before the next one is tried, unless
+/// `--kill-timeout` says otherwise.
+pub const STOP_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How trayme asks the child to stop. Every strategy other than [`StopStrategy::Kill`] gives the
+/// child a chance to clean up, and the process is killed if it doesn't exit in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StopStrategy {
+    /// Picks the best strategies for the program and tries them in order.
+    #[default]
+    Auto,
+    /// Sends SIGTERM to the process and the processes it started (Unix only).
+    Terminate,
+    /// Sends `CTRL_BREAK` to the child's console (Windows console apps only).
+    CtrlBreak,
+    /// Posts `WM_CLOSE` to the child's windows (Windows GUI apps only).
+    CloseWindow,
+    /// Runs `docker stop` for the container started by a `docker run --name <NAME>` command.
+    Docker,
+    /// Kills the process and the processes it started immediately.
+    Kill,
+}
+
+/// Returns the strategies to try, in order, to stop the given command. The list always ends
+/// with [`StopStrategy::Kill`].
+///
+/// # Arguments
+///
+/// * `strategy` - The configured strategy. Anything but [`StopStrategy::Auto`] is tried on its own
+///   before killing.
+/// * `cmd` - The command that was spawned.
+/// * `binary` - The resolved path of the spawned binary, used to tell GUI apps from console apps.
+/// * `elevated` - Whether the command runs with administrator rights. On Windows, it's then only
+///   killed: its console and windows are the elevated process', which trayme can't reach, and
+///   asking the wrapper that waits for it to stop would leave it running.
+pub fn plan(
+    strategy: StopStrategy,
+    cmd: &[OsString],
+    binary: Option<&Path>,
+    elevated: bool,
+) -> Vec<StopStrategy> {
+    let mut steps = match strategy {
+        #[cfg(windows)]
+        _ if elevated => Vec::new(),
+        StopStrategy::Auto => {
+            let mut steps = Vec::new();
+            if docker_container(cmd).is_some() {
+                steps.push(StopStrategy::Docker);
+            }
+            #[cfg(unix)]
+            {
+                let _ = (binary, elevated);
+                steps.push(StopStrategy::Terminate);
+            }
+            #[cfg(windows)]
+            match binary.map(win::is_gui_binary) {
+                Some(Ok(true)) => steps.push(StopStrategy::CloseWindow),
+                // most things wrapped by trayme are console apps, so that's the better guess
+                _ => steps.push(StopStrategy::CtrlBreak),
+            }
+            steps
+        }
+        strategy => vec![strategy],
+    };
+    if steps.last() != Some(&StopStrategy::Kill) {
+        steps.push(StopStrategy::Kill);
+    }
+    steps
+}
+
+/// Asks the child to stop using the given strategy. This doesn't wait for it to exit.
+///
+/// # Arguments
+///
+/// * `strategy` - How to ask the child to stop.
+/// * `child` - The child to stop.
+/// * `tree` - The child and the processes it started, which are stopped along with it.
+/// * `cmd` - The command that was spawned.
+/// * `timeout` - How long the child gets to exit, for strategies that stop it themselves, such
+///   as `docker stop`.
+///
+/// # Errors
+///
+/// An error is returned if the strategy isn't supported for this child or platform, or if the
+/// request cannot be sent.
+pub fn request_stop(
+    strategy: StopStrategy,
+    child: &mut ChildProcess,
+    tree: &ProcessTree,
+    cmd: &[OsString],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    debug!("Stopping PID {} with {strategy:?}", child.id());
+    match strategy {
+        StopStrategy::Auto => bail!("Auto must be resolved with plan() first"),
+        StopStrategy::Kill => tree.kill(child),
+        StopStrategy::Docker => {
+            let Some(container) = docker_container(cmd) else {
+                bail!("Not a `docker run --name <NAME>` command");
+            };
+            // docker only takes whole seconds, and 0 would kill the container right away
+            let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+            let status = Command::new(&cmd[0])
+                .args(["stop", "--time", &secs.to_string()])
+                .arg(container)
+                .status()
+                .context("Failed to run docker stop")?;
+            if !status.success() {
+                bail!("docker stop failed: {status}");
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        StopStrategy::Terminate => tree.terminate().context("Failed to send SIGTERM"),
+        #[cfg(windows)]
+        StopStrategy::CtrlBreak => {
+            win::send_ctrl_break(child.id()).context("Failed to send CTRL_BREAK")
+        }
+        #[cfg(windows)]
+        StopStrategy::CloseWindow => {
+            win::close_windows(child.id()).context("Failed to close windows")
+        }
+        _ => bail!("{strategy:?} is not supported on this platform"),
+    }
+}
+
+/// Waits up to `timeout` for the child to exit. Returns `None` if it's still running, or its exit
+/// status and resource usage otherwise.
+///
+/// # Errors
+///
+/// An error is returned if the child's status cannot be queried.
+pub fn wait_timeout(
+    child: &mut ChildProcess,
+    timeout: Duration,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    let start = Instant::now();
+    loop {
+        if let Some(exit) = usage::try_wait(child)? {
+            return Ok(Some(exit));
+        }
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Returns the container name of a `docker run --name <NAME>` (or podman) command.
+fn docker_container(cmd: &[OsString]) -> Option<&OsStr> {
+    let program = program_name(&cmd.first()?.to_string_lossy());
+    let program = program.strip_suffix(".exe").unwrap_or(&program);
+    if !matches!(program, "docker" | "podman") || !cmd.iter().any(|arg| arg == "run") {
+        return None;
+    }
+    let mut args = cmd.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--name" {
+            return args.next().map(OsString::as_os_str);
+        }
+        if let Some(name) = arg.to_str().and_then(|arg| arg.strip_prefix("--name=")) {
+            return Some(OsStr::new(name));
+        }
+    }
+    None
+}
+
+#[cfg(windows)]
+mod win {
+    use std::{
+        ffi::c_void,
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+        path::Path,
+    };
+
+    type Bool = i32;
+    type Hwnd = *mut c_void;
+
+    const CTRL_BREAK_EVENT: u32 = 1;
+    const WM_CLOSE: u32 = 0x0010;
+    const IMAGE_SUBSYSTEM_WINDOWS_GUI: u16 = 2;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(pid: u32) -> Bool;
+        fn FreeConsole() -> Bool;
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> Bool>,
+            add: Bool,
+        ) -> Bool;
+        fn GenerateConsoleCtrlEvent(event: u32, process_group: u32) -> Bool;
+    }
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn EnumWindows(
+            callback: unsafe extern "system" fn(Hwnd, isize) -> Bool,
+            lparam: isize,
+        ) -> Bool;
+        fn GetWindowThreadProcessId(hwnd: Hwnd, pid: *mut u32) -> u32;
+        fn IsWindowVisible(hwnd: Hwnd) -> Bool;
+        fn PostMessageW(hwnd: Hwnd, msg: u32, wparam: usize, lparam: isize) -> Bool;
+    }
+
+    /// Returns `true` if the executable at `path` uses the Windows GUI subsystem.
+    pub fn is_gui_binary(path: &Path) -> io::Result<bool> {
+        let mut file = File::open(path)?;
+        let mut pe_offset = [0; 4];
+        file.seek(SeekFrom::Start(0x3C))?;
+        file.read_exact(&mut pe_offset)?;
+        // PE signature (4 bytes) + COFF header (20 bytes) + offset of Subsystem in the optional
+        // header (68 bytes, the same for PE32 and PE32+)
+        file.seek(SeekFrom::Start(
+            u64::from(u32::from_le_bytes(pe_offset)) + 4 + 20 + 68,
+        ))?;
+        let mut subsystem = [0; 2];
+        file.read_exact(&mut subsystem)?;
+        Ok(u16::from_le_bytes(subsystem) == IMAGE_SUBSYSTEM_WINDOWS_GUI)
+    }
+
+    /// Sends `CTRL_BREAK` to the console of process `pid`. Console events can only be sent to
+    /// processes sharing the caller's console, so trayme briefly attaches to the child's.
+    pub fn send_ctrl_break(pid: u32) -> io::Result<()> {
+        // SAFETY: these calls only change which console this process is attached to
+        unsafe {
+            FreeConsole();
+            if AttachConsole(pid) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // the event goes to every process on the console, trayme included
+            SetConsoleCtrlHandler(None, 1);
+            let sent = GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, 0);
+            let err = io::Error::last_os_error();
+            FreeConsole();
+            if sent == 0 {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    struct WindowSearch {
+        pid: u32,
+        closed: usize,
+    }
+
+    unsafe extern "system" fn close_if_owned(hwnd: Hwnd, lparam: isize) -> Bool {
+        let search = &mut *(lparam as *mut WindowSearch);
+        let mut window_pid = 0;
+        GetWindowThreadProcessId(hwnd, &mut window_pid);
+        if window_pid == search.pid && IsWindowVisible(hwnd) != 0 {
+            PostMessageW(hwnd, WM_CLOSE, 0, 0);
+            search.closed += 1;
+        }
+        // keep enumerating
+        1
+    }
+
+    /// Posts `WM_CLOSE` to every visible top-level window of process `pid`.
+    pub fn close_windows(pid: u32) -> io::Result<()> {
+        let mut search = WindowSearch { pid, closed: 0 };
+        // SAFETY: the callback only uses `search` during this call
+        unsafe {
+            EnumWindows(close_if_owned, std::ptr::addr_of_mut!(search) as isize);
+        }
+        if search.closed == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "process has no visible windows",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn plans_always_end_with_kill() {
+        let server = cmd(&["server", "--port", "8080"]);
+        assert_eq!(
+            plan(StopStrategy::Kill, &server, None, false),
+            [StopStrategy::Kill]
+        );
+        assert_eq!(
+            plan(StopStrategy::Docker, &server, None, false),
+            [StopStrategy::Docker, StopStrategy::Kill]
+        );
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                plan(StopStrategy::Auto, &server, None, false),
+                [StopStrategy::Terminate, StopStrategy::Kill]
+            );
+            // elevated commands are asked to stop through the elevation tool
+            assert_eq!(
+                plan(StopStrategy::Auto, &server, None, true),
+                [StopStrategy::Terminate, StopStrategy::Kill]
+            );
+        }
+        #[cfg(windows)]
+        assert_eq!(
+            plan(StopStrategy::CtrlBreak, &server, None, true),
+            [StopStrategy::Kill]
+        );
+    }
+
+    #[test]
+    fn auto_stops_docker_containers_with_docker() {
+        let docker = cmd(&["docker", "run", "--rm", "--name", "db", "postgres"]);
+        let steps = plan(StopStrategy::Auto, &docker, None, false);
+        assert_eq!(steps.first(), Some(&StopStrategy::Docker));
+        assert_eq!(steps.last(), Some(&StopStrategy::Kill));
+        // the container is only known with --name
+        let unnamed = cmd(&["docker", "run", "--rm", "postgres"]);
+        assert!(!plan(StopStrategy::Auto, &unnamed, None, false).contains(&StopStrategy::Docker));
+    }
+
+    #[test]
+    fn finds_the_container_of_docker_and_podman_runs() {
+        let container = |args: &[&str]| docker_container(&cmd(args)).map(OsStr::to_os_string);
+        assert_eq!(
+            container(&["docker", "run", "--name", "db", "postgres"]),
+            Some("db".into())
+        );
+        assert_eq!(
+            container(&["/usr/bin/podman", "run", "--name=web", "nginx"]),
+            Some("web".into())
+        );
+        assert_eq!(container(&["docker", "compose", "up"]), None);
+        assert_eq!(container(&["server", "run", "--name", "db"]), None);
+    }
+}
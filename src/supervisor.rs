@@ -0,0 +1,2663 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{self, ExitStatus, Stdio},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use regex::Regex;
+
+use crate::{
+    calendar::BusyCalendar,
+    caps::{self, CapWatch, LimitAction, ResourceCaps},
+    capture::{self, LogCapture, SinkEvent},
+    chain::Chain,
+    child::ChildProcess,
+    clock::{Clock, SystemClock},
+    cmdline,
+    crash::{self, Backtrace},
+    elevate::{self, Elevation},
+    envprovider::EnvProvider,
+    events::EventRecord,
+    exitcode::{ErrorKind, WithKind},
+    headroom::{self, Headroom},
+    health::{HealthChange, HealthCheck},
+    history::RunRecord,
+    hooks::{HookContext, HookEvent, Hooks},
+    ipc::{
+        ControlCommand, ControlRequest, ControlResponse, InstanceStatus, ProcessState, Subscribers,
+    },
+    layout,
+    limits::{self, ResourceLimit},
+    logout::OnLogout,
+    notify::{Notifier, NotifyEvent},
+    notifyroute::{self, LogSource},
+    osargs,
+    output::{detect_level, strip_ansi, LevelCounts, OutputTail},
+    ping::PingUrl,
+    precheck,
+    priority::Priority,
+    progress::ProgressTracker,
+    registry::RegistryGuard,
+    reload::{ProfileChange, ProfileWatcher},
+    restart::{self, Decision, Fallback, PlannedRestarts, RestartBackoff, RestartPolicy},
+    selflog, shebang,
+    sleep::{SleepWatch, WakeAction},
+    spawner::{ProcessSpawner, SystemSpawner},
+    spawntrace,
+    stop::{self, StopStrategy, STOP_GRACE_PERIOD},
+    suspend,
+    tempdir::{self, TempCleanup},
+    termination::Termination,
+    throttle::CpuThrottle,
+    tree::ProcessTree,
+    uistate::Observation,
+    urls::UrlTracker,
+    usage::{self, ResourceUsage},
+    window::WindowToggle,
+};
+
+#[cfg(unix)]
+use crate::handover::Handover;
+
+/// The longest name a command run with `--shell` gets, see [`shell_program_name`].
+const MAX_SHELL_NAME: usize = 40;
+
+/// How long maintenance mode lasts unless `--maintenance-duration` says otherwise.
+pub const DEFAULT_MAINTENANCE_DURATION: Duration = Duration::from_mins(30);
+
+/// Everything needed to spawn the child process.
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    /// The command (with args) to run, kept as the OS gave it so that arguments that aren't valid
+    /// Unicode survive.
+    pub cmd: Vec<OsString>,
+    /// The working directory of the child. If `None`, it inherits trayme's.
+    pub cwd: Option<PathBuf>,
+    /// The exact environment of the child. If `None`, it inherits trayme's.
+    pub env: Option<BTreeMap<String, String>>,
+    /// Variables set on top of `env` (or the inherited environment), e.g. from a profile.
+    pub env_overrides: BTreeMap<String, String>,
+    /// The environment the command runs in, e.g. a Python virtual environment.
+    pub env_provider: Option<EnvProvider>,
+    /// The time zone of the child, see `--tz`.
+    pub tz: Option<String>,
+    /// The locale of the child, see `--locale`.
+    pub locale: Option<String>,
+    /// The resource limits of the child, see `--ulimit`.
+    pub ulimits: Vec<ResourceLimit>,
+    /// A shell command that must succeed before each spawn, see `--pre-check`.
+    pub pre_check: Option<String>,
+    /// Whether `cmd` is joined into one string and run with the user's shell, see `--shell`.
+    pub shell: bool,
+    /// Whether the command runs with administrator rights, see `--elevate`.
+    pub elevate: bool,
+    /// Whether each run gets a temporary directory of its own and what happens to it afterwards,
+    /// see `--temp-dir`.
+    pub temp_dir: Option<TempCleanup>,
+    /// What the interpreters that `#!` lines name are run with on Windows, see
+    /// [`shebang::apply`].
+    pub interpreters: BTreeMap<String, Vec<String>>,
+    /// The memory and CPU caps of the command, see `--max-memory` and `--cpu-limit`.
+    pub caps: Option<ResourceCaps>,
+}
+
+impl CommandSpec {
+    /// The variables set on top of `env` (or the inherited environment): `env_overrides`, then
+    /// `TZ` for the time zone and `LANG` and `LC_ALL` for the locale, which take precedence.
+    pub fn overrides(&self) -> BTreeMap<String, String> {
+        let mut overrides = self.env_overrides.clone();
+        if let Some(tz) = &self.tz {
+            overrides.insert("TZ".to_string(), tz.clone());
+        }
+        if let Some(locale) = &self.locale {
+            // LC_ALL wins over every other LC_* variable the session might set
+            overrides.insert("LANG".to_string(), locale.clone());
+            overrides.insert("LC_ALL".to_string(), locale.clone());
+        }
+        overrides
+    }
+
+    /// The name the logs and, by default, the instance get: the program's file name, or the
+    /// command line made fit for a file name if it's run with the shell.
+    pub fn program_name(&self) -> String {
+        if self.shell {
+            shell_program_name(&osargs::display(&self.cmd))
+        } else {
+            program_name(&self.cmd[0].to_string_lossy())
+        }
+    }
+
+    /// This spec with `cmd` run as one string by `$SHELL -c` (`sh` if it isn't set) on Unix, or
+    /// `cmd /C` on Windows.
+    fn through_shell(&self) -> Self {
+        let mut line = OsString::new();
+        for (i, arg) in self.cmd.iter().enumerate() {
+            if i > 0 {
+                line.push(" ");
+            }
+            line.push(arg);
+        }
+        let cmd = if cfg!(windows) {
+            vec!["cmd".into(), "/C".into(), line]
+        } else {
+            let shell = std::env::var_os("SHELL")
+                .filter(|shell| !shell.is_empty())
+                .unwrap_or_else(|| "sh".into());
+            vec![shell, "-c".into(), line]
+        };
+        Self {
+            cmd,
+            shell: false,
+            ..self.clone()
+        }
+    }
+
+    /// Runs `cmd` as it is, with every option off, for tests.
+    #[cfg(test)]
+    pub fn of(cmd: &[&str]) -> Self {
+        Self {
+            cmd: cmd.iter().map(OsString::from).collect(),
+            cwd: None,
+            env: None,
+            env_overrides: BTreeMap::new(),
+            env_provider: None,
+            tz: None,
+            locale: None,
+            ulimits: Vec::new(),
+            pre_check: None,
+            shell: false,
+            elevate: false,
+            temp_dir: None,
+            interpreters: BTreeMap::new(),
+            caps: None,
+        }
+    }
+}
+
+/// The settings of a changed profile that [`Supervisor::apply_profile`] applies while the process
+/// runs, each `None` if it didn't change.
+#[derive(Default)]
+#[allow(clippy::option_option)] // `Some(None)` turns the setting off
+struct Reload {
+    /// The health check and whether failing it restarts the process.
+    health: Option<(Option<HealthCheck>, bool)>,
+    restarts: Option<Option<RestartBackoff>>,
+    planned_restarts: Option<Option<PlannedRestarts>>,
+    on_wake: Option<Option<WakeAction>>,
+    progress: Option<Option<ProgressTracker>>,
+    kill_timeout: Option<Duration>,
+    maintenance_duration: Option<Duration>,
+}
+
+/// What [`Supervisor::set_event_callback`] calls with every event.
+type EventCallback = Box<dyn Fn(&EventRecord)>;
+
+/// Owns the child process for the lifetime of an instance and carries out everything that can be
+/// done to it, whether the request came from the tray menu or over IPC.
+#[allow(clippy::struct_excessive_bools)] // independent settings set from the command line
+pub struct Supervisor {
+    name: String,
+    spec: CommandSpec,
+    notifier: Notifier,
+    child_proc: ChildProcess,
+    /// The child and the processes it started, see [`ProcessTree`].
+    tree: ProcessTree,
+    capture: LogCapture,
+    record: RunRecord,
+    state: ProcessState,
+    registration: Option<RegistryGuard>,
+    stop_strategy: StopStrategy,
+    kill_timeout: Duration,
+    output: Option<OutputTail>,
+    levels: LevelCounts,
+    health: Option<HealthCheck>,
+    restart_on_unhealthy: bool,
+    last_unhealthy_restart: Option<Instant>,
+    /// Restarts the process when it exits, see [`Supervisor::set_restart_policy`].
+    restarts: Option<RestartBackoff>,
+    /// Runs another command once this one failed too often, see [`Supervisor::set_fallback`].
+    fallback: Option<Fallback>,
+    chain: Option<Chain>,
+    /// Restarts the process on a schedule, see [`Supervisor::set_planned_restarts`].
+    planned_restarts: Option<PlannedRestarts>,
+    /// Notices when the machine woke from sleep, see [`Supervisor::watch_sleep`].
+    sleep_watch: Option<SleepWatch>,
+    on_wake: Option<WakeAction>,
+    /// The result of the `--wake-check` that runs.
+    wake_check: Option<mpsc::Receiver<anyhow::Result<()>>>,
+    /// How long a run may last, see [`Supervisor::set_timeout`].
+    timeout: Option<Duration>,
+    /// When the process is restarted, while it's [`ProcessState::Restarting`].
+    next_restart: Option<Instant>,
+    verbose_exit: bool,
+    compress_rotated_logs: bool,
+    kill_disabled: bool,
+    /// Whether [`Supervisor::hand_over`] may replace trayme.
+    handover_allowed: bool,
+    progress: Option<ProgressTracker>,
+    /// The first line the current run wrote that isn't blank, see [`Supervisor::first_output`].
+    first_output: Option<String>,
+    first_output_notify: bool,
+    urls: UrlTracker,
+    windows: Option<WindowToggle>,
+    throttle: Option<CpuThrottle>,
+    /// Watches the run's memory, if it's capped.
+    caps: Option<CapWatch>,
+    headroom: Option<Headroom>,
+    /// Whether the pending restart was put off for a lack of memory or disk space.
+    restart_deferred: bool,
+    /// The priority the process runs at, if it was set, see [`Supervisor::set_priority`].
+    priority: Option<Priority>,
+    /// Whether the process is paused, see [`Supervisor::pause`].
+    paused: bool,
+    /// Whether the last run exited successfully, see [`Supervisor::succeeded`].
+    succeeded: bool,
+    /// Pauses the process during meetings, see [`Supervisor::set_busy_calendar`].
+    calendar: Option<BusyCalendar>,
+    /// Whether the calendar was busy at the last check.
+    in_meeting: bool,
+    /// Whether the process was paused for the meeting rather than by the user.
+    paused_for_meeting: bool,
+    profile_watcher: Option<ProfileWatcher>,
+    on_logout: OnLogout,
+    /// Whether [`Supervisor::exit`] leaves the process running, see `--detach-on-exit`.
+    detach_on_exit: bool,
+    /// Whether the instance outlives a process that exited on its own, see
+    /// [`Supervisor::set_stay_open`].
+    stay_open: bool,
+    /// Pinged around each run, see `--ping-url`.
+    ping_url: Option<PingUrl>,
+    /// Run around each run, see [`Supervisor::set_hooks`].
+    hooks: Option<Hooks>,
+    subscribers: Option<Subscribers>,
+    /// Called with every event, see [`Supervisor::set_event_callback`].
+    on_event: Option<EventCallback>,
+    maintenance_duration: Duration,
+    /// When maintenance mode runs out, if it's on, see [`Supervisor::start_maintenance`].
+    maintenance: Option<(Instant, DateTime<Local>)>,
+    /// Where the backoff, timeouts, and wakes take the time from.
+    clock: Arc<dyn Clock>,
+    /// Starts the process and its restarts.
+    spawner: Arc<dyn ProcessSpawner>,
+}
+
+impl Supervisor {
+    /// Spawns the command and notifies the user that it started.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The instance name, used in the registry and status reports.
+    /// * `spec` - The command to run.
+    /// * `notifier` - Used for start and exit notifications.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the pre-check fails or the process cannot be spawned (see
+    /// [`spawn_process`]).
+    pub fn start(name: String, spec: CommandSpec, notifier: Notifier) -> anyhow::Result<Self> {
+        Self::start_with(
+            name,
+            spec,
+            notifier,
+            Arc::new(SystemClock),
+            Arc::new(SystemSpawner),
+        )
+    }
+
+    /// Like [`Supervisor::start`], with the time coming from `clock` and the process spawned by
+    /// `spawner`.
+    pub fn start_with(
+        name: String,
+        spec: CommandSpec,
+        mut notifier: Notifier,
+        clock: Arc<dyn Clock>,
+        spawner: Arc<dyn ProcessSpawner>,
+    ) -> anyhow::Result<Self> {
+        notifier.set_instance(&name);
+        pre_check(&spec, &notifier)?;
+        let run = spawn_process(&spec, &*spawner)?;
+        let supervisor = Self::with_run(name, spec, notifier, run, clock, spawner);
+        supervisor.emit(
+            NotifyEvent::Start,
+            "Process started!",
+            &osargs::display(&supervisor.spec.cmd),
+            None,
+        );
+        Ok(supervisor)
+    }
+
+    /// Takes over the run `handover` describes from the trayme that replaced itself with this one,
+    /// see [`Supervisor::hand_over`]. The process goes on as it was, and what it wrote so far is
+    /// read again, so that the log levels, URLs, and progress carry over. trayme waits for the
+    /// process, restarts it, and stops it as it would one it started itself.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the run isn't in the history or its log or pipes cannot be taken
+    /// over.
+    #[cfg(unix)]
+    pub fn adopt(
+        name: String,
+        mut spec: CommandSpec,
+        mut notifier: Notifier,
+        handover: Handover,
+    ) -> anyhow::Result<Self> {
+        notifier.set_instance(&name);
+        let record = RunRecord::load(&handover.run_id)?;
+        let pid = handover.pid;
+        let paused = handover.paused;
+        spec.cmd.clone_from(&handover.cmd);
+        let (stdin, stdout, stderr) = handover.into_pipes()?;
+        let log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&record.log_file)
+            .with_context(|| format!("Failed to open {}", record.log_file.display()))?;
+        let capture = LogCapture::start(log, &record.log_file, stdout, stderr);
+        let run = (ChildProcess::adopt(pid, stdin), capture, record);
+        let mut supervisor = Self::with_run(
+            name,
+            spec,
+            notifier,
+            run,
+            Arc::new(SystemClock),
+            Arc::new(SystemSpawner),
+        );
+        supervisor.paused = paused;
+        info!("Took over PID {pid}");
+        Ok(supervisor)
+    }
+
+    /// The supervisor of the run that was just started or taken over, with every option off.
+    fn with_run(
+        name: String,
+        spec: CommandSpec,
+        notifier: Notifier,
+        (child_proc, capture, record): (ChildProcess, LogCapture, RunRecord),
+        clock: Arc<dyn Clock>,
+        spawner: Arc<dyn ProcessSpawner>,
+    ) -> Self {
+        let output = open_output(&record);
+        let tree = ProcessTree::new(&child_proc, Elevation::of(&spec));
+        let caps = spec.caps.map(|caps| CapWatch::attach(caps, &child_proc));
+        Self {
+            name,
+            spec,
+            notifier,
+            child_proc,
+            tree,
+            capture,
+            record,
+            state: ProcessState::Running,
+            registration: None,
+            stop_strategy: StopStrategy::default(),
+            kill_timeout: STOP_GRACE_PERIOD,
+            output,
+            levels: LevelCounts::default(),
+            health: None,
+            restart_on_unhealthy: false,
+            last_unhealthy_restart: None,
+            restarts: None,
+            fallback: None,
+            chain: None,
+            planned_restarts: None,
+            sleep_watch: None,
+            on_wake: None,
+            wake_check: None,
+            timeout: None,
+            next_restart: None,
+            verbose_exit: false,
+            compress_rotated_logs: false,
+            kill_disabled: false,
+            handover_allowed: false,
+            progress: None,
+            first_output: None,
+            first_output_notify: false,
+            urls: UrlTracker::default(),
+            windows: None,
+            throttle: None,
+            caps,
+            headroom: None,
+            restart_deferred: false,
+            priority: None,
+            paused: false,
+            succeeded: false,
+            calendar: None,
+            in_meeting: false,
+            paused_for_meeting: false,
+            profile_watcher: None,
+            on_logout: OnLogout::default(),
+            detach_on_exit: false,
+            stay_open: false,
+            ping_url: None,
+            hooks: None,
+            subscribers: None,
+            on_event: None,
+            maintenance_duration: DEFAULT_MAINTENANCE_DURATION,
+            maintenance: None,
+            clock,
+            spawner,
+        }
+    }
+
+    /// Renames the instance. Its registry entry, notifications, and event log use the new name
+    /// from now on.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if another running instance has the name, or if the registry entry
+    /// cannot be moved.
+    pub fn rename(&mut self, name: String) -> anyhow::Result<()> {
+        if let Some(registration) = self.registration.as_mut() {
+            registration.rename(&name)?;
+        }
+        info!("Renamed '{}' to '{name}'", self.name);
+        self.notifier.set_instance(&name);
+        self.name = name;
+        Ok(())
+    }
+
+    /// Attaches a registry entry to this instance. It is removed as soon as the process is no
+    /// longer running.
+    pub fn set_registration(&mut self, registration: RegistryGuard) {
+        self.registration = Some(registration);
+    }
+
+    /// Sets how [`Supervisor::kill`] asks the process to stop.
+    pub fn set_stop_strategy(&mut self, strategy: StopStrategy) {
+        self.stop_strategy = strategy;
+    }
+
+    /// Sets how long the process gets to exit after each step of the stop strategy.
+    pub fn set_kill_timeout(&mut self, timeout: Duration) {
+        self.kill_timeout = timeout;
+    }
+
+    /// Watches the output for lines that mark the instance unhealthy.
+    ///
+    /// # Arguments
+    ///
+    /// * `health` - The pattern and threshold to check the output against.
+    /// * `restart` - Whether to restart the process when it becomes unhealthy.
+    pub fn set_health_check(&mut self, health: HealthCheck, restart: bool) {
+        self.health = Some(health);
+        self.restart_on_unhealthy = restart;
+    }
+
+    /// Restarts the process when it exits, as `backoff` decides. The instance keeps running while
+    /// it waits to restart the process.
+    pub fn set_restart_policy(&mut self, backoff: RestartBackoff) {
+        self.restarts = Some(backoff);
+    }
+
+    /// Runs the steps of `chain` one after another as each run, the first of which runs already.
+    pub fn set_chain(&mut self, chain: Chain) {
+        self.chain = Some(chain);
+    }
+
+    /// The step of the chain that runs and how many there are, counting from 1, with the name of
+    /// its program, if the command is a chain.
+    pub fn step(&self) -> Option<(usize, usize, String)> {
+        let (step, total) = self.chain.as_ref()?.position();
+        Some((step, total, self.spec.program_name()))
+    }
+
+    /// Runs the fallback in place of the command once it failed too often in a row. The fallback
+    /// is only started by restarting, so this needs a restart policy.
+    pub fn set_fallback(&mut self, fallback: Fallback) {
+        self.fallback = Some(fallback);
+    }
+
+    /// Restarts the process on the schedules of `planned`, whether it's doing fine or not. A
+    /// restart that comes due while the process is paused or in maintenance mode is put off
+    /// until that's over.
+    pub fn set_planned_restarts(&mut self, planned: PlannedRestarts) {
+        if let Some(next) = planned.next() {
+            info!("Restarting at {next} as planned");
+        }
+        self.planned_restarts = Some(planned);
+    }
+
+    /// Logs when the machine wakes from sleep, and does `on_wake` about the process then. Like
+    /// planned restarts, that's left out while the process is paused or in maintenance mode.
+    pub fn watch_sleep(&mut self, on_wake: Option<WakeAction>) {
+        self.sleep_watch = Some(SleepWatch::new(self.clock.clone()));
+        self.on_wake = on_wake;
+    }
+
+    /// Stops a run once it lasted `timeout`, which counts as a failure for the restart policy.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// How long the current run has left before it times out, with `--timeout`.
+    pub fn time_left(&self) -> Option<Duration> {
+        let timeout = self.timeout?;
+        if self.state != ProcessState::Running {
+            return None;
+        }
+        Some(timeout.saturating_sub(self.uptime()))
+    }
+
+    /// How long the current run has been going.
+    fn uptime(&self) -> Duration {
+        (self.clock.now() - self.record.started_at)
+            .to_std()
+            .unwrap_or_default()
+    }
+
+    /// Stops the process if it ran out of time, then restarts it if the policy says so.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or the run record cannot be saved.
+    fn check_timeout(&mut self) -> anyhow::Result<()> {
+        let Some(timeout) = self.timeout else {
+            return Ok(());
+        };
+        let uptime = self.uptime();
+        if uptime < timeout {
+            return Ok(());
+        }
+        let timeout = humantime::format_duration(timeout);
+        warn!("The command timed out after {timeout}, stopping it");
+        let exit = self.stop_process()?;
+        let limited = self.maintenance.is_none();
+        let decision = self
+            .restarts
+            .as_mut()
+            .map_or(Decision::Stop, |r| r.next(false, uptime, limited));
+        let state = match decision {
+            Decision::RestartIn(_) => ProcessState::Restarting,
+            Decision::GiveUp | Decision::Stop => ProcessState::Killed,
+        };
+        self.finish(state, exit)?;
+        let mut body = format!("Stopped after {timeout}");
+        if let Some(summary) = self.schedule_restart(decision) {
+            body.push('\n');
+            body.push_str(&summary);
+        }
+        self.emit(NotifyEvent::Timeout, "Process timed out", &body, None);
+        Ok(())
+    }
+
+    /// Notifies about the process going over `--max-memory`, then kills or restarts it if
+    /// `--on-limit` says so.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or restarted, or the run record
+    /// cannot be saved.
+    fn check_memory(&mut self) -> anyhow::Result<()> {
+        if !self.is_running() {
+            return Ok(());
+        }
+        let Some(caps) = self.caps.as_mut() else {
+            return Ok(());
+        };
+        let Some(used) = caps.check() else {
+            return Ok(());
+        };
+        let action = caps.action();
+        let limit = self
+            .spec
+            .caps
+            .and_then(|caps| caps.max_memory)
+            .unwrap_or_default();
+        let mut body = format!(
+            "Using {} of {}",
+            usage::format_bytes(used),
+            usage::format_bytes(limit)
+        );
+        warn!("The command went over its memory limit: {body}");
+        match action {
+            LimitAction::Notify => {}
+            LimitAction::Restart => {
+                if let Some(summary) = self.restart_backed_off()? {
+                    body.push('\n');
+                    body.push_str(&summary);
+                }
+            }
+            LimitAction::Kill => {
+                let uptime = self.uptime();
+                let exit = self.stop_process()?;
+                let limited = self.maintenance.is_none();
+                let decision = self
+                    .restarts
+                    .as_mut()
+                    .map_or(Decision::Stop, |r| r.next(false, uptime, limited));
+                let state = match decision {
+                    Decision::RestartIn(_) => ProcessState::Restarting,
+                    Decision::GiveUp | Decision::Stop => ProcessState::Killed,
+                };
+                self.finish(state, exit)?;
+                if let Some(summary) = self.schedule_restart(decision) {
+                    body.push('\n');
+                    body.push_str(&summary);
+                }
+            }
+        }
+        self.emit(
+            NotifyEvent::MemoryLimit,
+            "Memory limit exceeded",
+            &body,
+            None,
+        );
+        Ok(())
+    }
+
+    /// Restarts the process if a planned restart is due.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned for the reasons [`Supervisor::restart`] fails.
+    fn check_planned_restart(&mut self) -> anyhow::Result<()> {
+        if self.paused || self.maintenance.is_some() {
+            return Ok(());
+        }
+        let Some(planned) = self.planned_restarts.as_mut() else {
+            return Ok(());
+        };
+        if !planned.is_due() {
+            return Ok(());
+        }
+        info!("Restarting as planned");
+        self.cycle(NotifyEvent::PlannedRestart, "Scheduled restart")?;
+        if let Some(next) = self
+            .planned_restarts
+            .as_ref()
+            .and_then(PlannedRestarts::next)
+        {
+            info!("Restarting again at {next}");
+        }
+        Ok(())
+    }
+
+    /// Logs a wake from sleep, and restarts or checks on the process as `--on-wake` says. A
+    /// restart that's waiting for its backoff happens right away instead.
+    fn check_sleep(&mut self) -> anyhow::Result<()> {
+        if let Some(result) = self.wake_check.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            self.wake_check = None;
+            match result {
+                Ok(()) => info!("The wake check passed"),
+                Err(e) if self.is_running() => {
+                    warn!("The wake check failed, restarting: {e:#}");
+                    return self.restart_after_wake(
+                        "Restarting after the wake check failed",
+                        format!("{e:#}"),
+                    );
+                }
+                Err(e) => warn!("The wake check failed: {e:#}"),
+            }
+        }
+        let Some(sleep) = self.sleep_watch.as_mut().and_then(SleepWatch::check) else {
+            return Ok(());
+        };
+        info!("{sleep}");
+        if self.paused || self.maintenance.is_some() {
+            return Ok(());
+        }
+        match &self.on_wake {
+            Some(WakeAction::Restart) if self.state == ProcessState::Restarting => {
+                info!("Restarting now that the machine woke");
+                self.next_restart = Some(self.clock.instant());
+            }
+            Some(WakeAction::Restart) if self.is_running() => {
+                info!("Restarting since the machine woke");
+                self.restart_after_wake("Restarting after sleep", sleep.to_string())?;
+            }
+            Some(WakeAction::Check(check)) if self.is_running() => {
+                info!("Checking on the process since the machine woke: {check}");
+                let (check, spec) = (check.clone(), self.spec.clone());
+                let (tx, rx) = mpsc::channel();
+                std::thread::spawn(move || {
+                    let _ = tx.send(precheck::run(&check, &spec));
+                });
+                self.wake_check = Some(rx);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Restarts the process with [`Supervisor::restart_backed_off`] because of a wake, and notifies
+    /// about it with `title` and `body`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or the run record cannot be saved.
+    fn restart_after_wake(&mut self, title: &str, mut body: String) -> anyhow::Result<()> {
+        if let Some(summary) = self.restart_backed_off()? {
+            body.push('\n');
+            body.push_str(&summary);
+        }
+        self.emit(NotifyEvent::Wake, title, &body, None);
+        Ok(())
+    }
+
+    /// Stops the process and restarts it once the restart backoff is over, whatever the restart
+    /// policy, as for a run that failed. Unlike restarting by hand, that doesn't start the backoff
+    /// over, so a process that keeps needing it isn't restarted in a tight loop. Returns what
+    /// happens next, for the notification.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or the run record cannot be saved.
+    fn restart_backed_off(&mut self) -> anyhow::Result<Option<String>> {
+        let uptime = self.uptime();
+        let exit = self.stop_process()?;
+        let limited = self.maintenance.is_none();
+        let decision = self
+            .restarts
+            .get_or_insert_with(|| {
+                RestartBackoff::new(RestartPolicy::Never, restart::DEFAULT_BACKOFF, None)
+            })
+            .next_forced(uptime, limited);
+        let state = match decision {
+            Decision::RestartIn(_) => ProcessState::Restarting,
+            Decision::GiveUp | Decision::Stop => ProcessState::Killed,
+        };
+        self.finish(state, exit)?;
+        Ok(self.schedule_restart(decision))
+    }
+
+    /// Returns `true` while the fallback runs in place of the command.
+    pub fn is_fallback(&self) -> bool {
+        self.fallback.as_ref().is_some_and(Fallback::is_active)
+    }
+
+    /// Returns `false` while the output is over the health check's threshold.
+    pub fn is_healthy(&self) -> bool {
+        match &self.health {
+            Some(health) => health.is_healthy(),
+            None => true,
+        }
+    }
+
+    /// The variables injected into the child's environment on top of the inherited ones.
+    pub fn env_overrides(&self) -> &BTreeMap<String, String> {
+        &self.spec.env_overrides
+    }
+
+    /// The whole environment the current run was started with.
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.record.env
+    }
+
+    /// Replaces the injected variables. The change takes effect on the next restart.
+    pub fn set_env_overrides(&mut self, env: BTreeMap<String, String>) {
+        self.spec.env_overrides = env;
+    }
+
+    /// Shows a notification with the first line the process writes that isn't blank, which is
+    /// often where a server says where it listens.
+    pub fn set_first_output_notify(&mut self, notify: bool) {
+        self.first_output_notify = notify;
+    }
+
+    /// Includes the run's resource usage in exit notifications.
+    pub fn set_verbose_exit(&mut self, verbose: bool) {
+        self.verbose_exit = verbose;
+    }
+
+    /// Compresses log files with gzip after [`Supervisor::rotate_log`] is done with them.
+    pub fn set_compress_rotated_logs(&mut self, compress: bool) {
+        self.compress_rotated_logs = compress;
+    }
+
+    /// Refuses [`ControlCommand::Kill`] and [`ControlCommand::Handover`] requests, so that the
+    /// instance can only be stopped with [`ControlCommand::ForceKill`], which needs an `admin`
+    /// token.
+    pub fn set_kill_disabled(&mut self, disabled: bool) {
+        self.kill_disabled = disabled;
+    }
+
+    /// Lets [`ControlCommand::Handover`] requests replace trayme, which only the instances of the
+    /// `trayme` binary may do.
+    pub fn set_handover_allowed(&mut self, allowed: bool) {
+        self.handover_allowed = allowed;
+    }
+
+    /// Sets how long maintenance mode lasts when it's turned on without a duration.
+    pub fn set_maintenance_duration(&mut self, duration: Duration) {
+        self.maintenance_duration = duration;
+    }
+
+    /// Turns on maintenance mode for `duration`, or the configured duration: health checks, and
+    /// the restarts that come with them, are suspended and no notifications are sent until it
+    /// runs out or is turned off. Events are still recorded in the event log. Turning it on
+    /// again while it's on restarts the countdown.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the duration is too long to count down.
+    pub fn start_maintenance(&mut self, duration: Option<Duration>) -> anyhow::Result<()> {
+        let duration = duration.unwrap_or(self.maintenance_duration);
+        let ends = self.clock.instant().checked_add(duration);
+        let until = chrono::TimeDelta::from_std(duration)
+            .ok()
+            .and_then(|delta| self.clock.now().checked_add_signed(delta));
+        let (Some(ends), Some(until)) = (ends, until) else {
+            bail!(
+                "Maintenance mode can't last {}",
+                humantime::format_duration(duration)
+            );
+        };
+        self.maintenance = Some((ends, until));
+        info!(
+            "Maintenance mode for '{}' until {}",
+            self.name,
+            until.format("%H:%M")
+        );
+        self.emit(
+            NotifyEvent::Maintenance,
+            "Maintenance mode on",
+            &format!(
+                "Health checks and notifications are paused until {}",
+                until.format("%H:%M")
+            ),
+            None,
+        );
+        Ok(())
+    }
+
+    /// Turns off maintenance mode. Output logged during it doesn't count towards the health
+    /// check.
+    pub fn end_maintenance(&mut self) {
+        if self.maintenance.take().is_none() {
+            return;
+        }
+        if let Some(health) = self.health.as_mut() {
+            health.reset();
+        }
+        info!("Maintenance mode for '{}' is over", self.name);
+        self.emit(
+            NotifyEvent::Maintenance,
+            "Maintenance mode off",
+            "Health checks and notifications are back on",
+            None,
+        );
+    }
+
+    /// Turns maintenance mode on with the configured duration, or off if it's on.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the configured duration is too long, see
+    /// [`Supervisor::start_maintenance`].
+    pub fn toggle_maintenance(&mut self) -> anyhow::Result<()> {
+        if self.maintenance.is_some() {
+            self.end_maintenance();
+            Ok(())
+        } else {
+            self.start_maintenance(None)
+        }
+    }
+
+    /// When maintenance mode runs out, if it's on.
+    pub fn maintenance_until(&self) -> Option<DateTime<Local>> {
+        self.maintenance.map(|(_, until)| until)
+    }
+
+    /// Lets the process' windows be shown and hidden. With `start_hidden`, they're hidden as the
+    /// process opens them, and again after every restart while they're hidden.
+    pub fn set_window_control(&mut self, start_hidden: bool) {
+        self.windows = Some(WindowToggle::new(self.child_proc.id(), start_hidden));
+    }
+
+    /// Shows the process' windows that were hidden.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if window control isn't enabled or the windows cannot be shown.
+    pub fn show_window(&mut self) -> anyhow::Result<()> {
+        self.window_toggle()?.show()
+    }
+
+    /// Hides the process' windows.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if window control isn't enabled or the windows cannot be hidden.
+    pub fn hide_window(&mut self) -> anyhow::Result<()> {
+        self.window_toggle()?.hide()
+    }
+
+    /// Shows the process' windows if they're hidden, and hides them otherwise.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if window control isn't enabled or the windows cannot be changed.
+    pub fn toggle_window(&mut self) -> anyhow::Result<()> {
+        self.window_toggle()?.toggle()
+    }
+
+    fn window_toggle(&mut self) -> anyhow::Result<&mut WindowToggle> {
+        if !self.is_running() {
+            bail!("Process is not running");
+        }
+        self.windows
+            .as_mut()
+            .context("Window control is not enabled (see --start-hidden)")
+    }
+
+    /// Keeps the process, and the processes it's restarted as, to `percent` of one CPU. See
+    /// [`CpuThrottle`].
+    pub fn set_cpu_throttle(&mut self, percent: u8) {
+        self.throttle = Some(CpuThrottle::new(percent));
+        self.attach_throttle();
+    }
+
+    /// Runs the process, and the processes it's restarted as, at `priority`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the priority of the running process cannot be changed, in which
+    /// case it's kept as it was.
+    pub fn set_priority(&mut self, priority: Priority) -> anyhow::Result<()> {
+        if self.is_running() {
+            priority.apply(&self.child_proc)?;
+            info!(
+                "Set the priority of PID {} to {priority}",
+                self.child_proc.id()
+            );
+        }
+        self.priority = Some(priority);
+        Ok(())
+    }
+
+    /// The priority the process runs at.
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or_default()
+    }
+
+    fn attach_priority(&mut self) {
+        let Some(priority) = self.priority else {
+            return;
+        };
+        if let Err(e) = priority.apply(&self.child_proc) {
+            warn!("{e:#}, running at the normal priority");
+            self.priority = None;
+        }
+    }
+
+    fn attach_throttle(&mut self) {
+        let Some(throttle) = self.throttle.as_mut() else {
+            return;
+        };
+        if let Err(e) = throttle.attach(&self.child_proc) {
+            warn!("{e:#}, running without --cpu-throttle");
+            self.throttle = None;
+        }
+    }
+
+    /// Pauses the process, e.g. to free the CPU from a heavy job for a while without stopping it.
+    /// The processes it started are paused too if it has a process group of its own.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process isn't running or cannot be paused.
+    pub fn pause(&mut self) -> anyhow::Result<()> {
+        if !self.is_running() {
+            bail!("Process is not running");
+        }
+        if self.paused {
+            return Ok(());
+        }
+        // the throttle would keep continuing it
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.release();
+        }
+        suspend::suspend(&self.child_proc)?;
+        info!("Paused PID {}", self.child_proc.id());
+        self.paused = true;
+        Ok(())
+    }
+
+    /// Lets the process run again after [`Supervisor::pause`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be resumed.
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        suspend::resume(&self.child_proc)?;
+        info!("Resumed PID {}", self.child_proc.id());
+        self.paused = false;
+        self.paused_for_meeting = false;
+        self.attach_throttle();
+        Ok(())
+    }
+
+    /// Pauses the process when a busy event of `calendar` starts, and resumes it when it's over.
+    /// Pausing or resuming it by hand in between is left alone until the next event.
+    pub fn set_busy_calendar(&mut self, calendar: BusyCalendar) {
+        self.calendar = Some(calendar);
+    }
+
+    /// Pauses or resumes the process if a meeting started or ended since the last check.
+    fn check_calendar(&mut self) {
+        let Some(calendar) = self.calendar.as_mut() else {
+            return;
+        };
+        let busy = calendar.busy();
+        if busy.is_some() == self.in_meeting {
+            return;
+        }
+        self.in_meeting = busy.is_some();
+        match busy {
+            Some(busy) if !self.paused => {
+                if let Err(e) = self.pause() {
+                    warn!("Failed to pause for {}: {e:#}", busy.summary);
+                    return;
+                }
+                self.paused_for_meeting = true;
+                self.emit(
+                    NotifyEvent::Calendar,
+                    "Paused for a meeting",
+                    &format!("{} until {}", busy.summary, busy.until.format("%H:%M")),
+                    None,
+                );
+            }
+            None if self.paused_for_meeting => match self.resume() {
+                Ok(()) => self.emit(
+                    NotifyEvent::Calendar,
+                    "Resumed after the meeting",
+                    &osargs::display(&self.spec.cmd),
+                    None,
+                ),
+                Err(e) => warn!("Failed to resume after the meeting: {e:#}"),
+            },
+            _ => {}
+        }
+    }
+
+    /// Applies changes to the instance's profile in the config file while it runs. See
+    /// [`ProfileWatcher`].
+    pub fn set_profile_watcher(&mut self, watcher: ProfileWatcher) {
+        self.profile_watcher = Some(watcher);
+    }
+
+    /// Follows the progress the process reports in its output. See [`ProgressTracker::new`].
+    pub fn set_progress_pattern(&mut self, pattern: Regex) {
+        self.progress = Some(ProgressTracker::new(pattern));
+    }
+
+    /// The progress last reported by the current run, from 0 to 100.
+    pub fn progress(&self) -> Option<f64> {
+        self.progress.as_ref().and_then(ProgressTracker::percent)
+    }
+
+    /// How long the current run will take to reach 100%, going by its progress so far.
+    pub fn eta(&self) -> Option<Duration> {
+        self.progress.as_ref().and_then(ProgressTracker::eta)
+    }
+
+    /// What the tray state follows from, see [`crate::uistate::UiState::of`]. There's no progress
+    /// in it, which the tray rounds for itself.
+    pub fn observation(&self) -> Observation {
+        Observation {
+            process: self.state,
+            healthy: self.is_healthy(),
+            paused: self.paused,
+            stable: self.is_running() && self.uptime() >= restart::STABLE_RUN,
+            clean: self.succeeded,
+            percent: None,
+        }
+    }
+
+    /// The exit code trayme exits with once the instance is finished, see
+    /// [`Termination::exit_code`]: the last run's if the process ended by itself without being
+    /// restarted, and `None` if it was killed or left running.
+    pub fn exit_code(&self) -> Option<i32> {
+        if self.state != ProcessState::Exited {
+            return None;
+        }
+        self.record.termination.map(Termination::exit_code)
+    }
+
+    /// Returns `true` once the process has exited or been killed, and won't be restarted.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state, ProcessState::Running | ProcessState::Restarting)
+    }
+
+    /// Returns `true` once the process is finished and the instance doesn't outlive it, see
+    /// [`Supervisor::set_stay_open`].
+    pub fn is_ended(&self) -> bool {
+        self.is_finished() && !self.outlives(self.state)
+    }
+
+    /// Returns `true` if the last run exited with a successful status, rather than failing or
+    /// being killed.
+    pub fn succeeded(&self) -> bool {
+        self.succeeded
+    }
+
+    /// The temporary directory of the current or last run, if it has one, see `--temp-dir`.
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.record.temp_dir.as_deref()
+    }
+
+    /// Returns `true` while there's a process, rather than one waiting to be restarted.
+    fn is_running(&self) -> bool {
+        self.state == ProcessState::Running
+    }
+
+    /// Checks whether the process has exited, recording and notifying about it if so.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process status cannot be queried or the run record cannot be
+    /// saved.
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        self.check_sleep()?;
+        let now = self.clock.instant();
+        if self.maintenance.is_some_and(|(ends, _)| now >= ends) {
+            self.end_maintenance();
+        }
+        if self.state == ProcessState::Restarting {
+            if self.next_restart.is_some_and(|at| now >= at) {
+                self.restart_automatically();
+            }
+            return Ok(());
+        }
+        self.check_profile();
+        self.check_log_sink();
+        self.scan_output();
+        self.check_health()?;
+        self.check_calendar();
+        self.check_planned_restart()?;
+        self.check_timeout()?;
+        self.check_memory()?;
+        if self.state != ProcessState::Running {
+            return Ok(());
+        }
+        if let Some(windows) = self.windows.as_mut() {
+            if windows.pid() != self.child_proc.id() {
+                windows.retarget(self.child_proc.id());
+            }
+            windows.poll();
+        }
+        if let Some((status, usage)) = usage::try_wait(&mut self.child_proc)? {
+            if self.next_step(status, usage)? {
+                return Ok(());
+            }
+            let uptime = self.uptime();
+            let limited = self.maintenance.is_none();
+            let termination = Termination::of(status);
+            let clean = termination.is_clean();
+            let falls_back = self
+                .fallback
+                .as_mut()
+                .is_some_and(|f| f.observe(clean, uptime));
+            let decision = self.restarts.as_mut().map_or(Decision::Stop, |r| {
+                // the fallback gets a fresh start, whatever the primary command used up
+                if falls_back {
+                    r.reset();
+                }
+                r.next(clean, uptime, limited)
+            });
+            let state = match decision {
+                Decision::RestartIn(_) => ProcessState::Restarting,
+                Decision::GiveUp | Decision::Stop => ProcessState::Exited,
+            };
+            self.finish(state, Some((status, usage)))?;
+            let (event, backtrace) = if clean {
+                info!("Command ended cleanly: {termination}");
+                (NotifyEvent::Exit, None)
+            } else {
+                error!("Command failed: {termination}");
+                (NotifyEvent::Failure, self.find_backtrace())
+            };
+            let mut body = termination.to_string();
+            if let Some(hint) = termination.hint() {
+                body.push('\n');
+                body.push_str(hint);
+            }
+            if let Some(backtrace) = &backtrace {
+                body.push('\n');
+                body.push_str(&backtrace.summary);
+            }
+            if let Some(usage) = self.record.usage.filter(|_| self.verbose_exit) {
+                body.push('\n');
+                body.push_str(&usage.to_string());
+            }
+            if let Some(summary) = self.chain.as_ref().and_then(Chain::summary) {
+                body.push('\n');
+                body.push_str(&summary);
+            }
+            if falls_back {
+                if let Some(fallback) = self.fallback.as_mut() {
+                    fallback.activate(&mut self.spec.cmd);
+                }
+                let summary = format!(
+                    "Switching to the fallback command: {}",
+                    osargs::display(&self.spec.cmd)
+                );
+                warn!("{summary}");
+                body.push('\n');
+                body.push_str(&summary);
+            }
+            if let Some(summary) = self.schedule_restart(decision) {
+                body.push('\n');
+                body.push_str(&summary);
+            }
+            self.emit(event, "Process exited", &body, backtrace.as_ref());
+        }
+        Ok(())
+    }
+
+    /// Starts the next step of the chain if the step that exited with `status` is followed by
+    /// one. Returns `true` if it was, or if it failed to start, in which case the instance ends.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the run record of the step that exited cannot be saved.
+    fn next_step(&mut self, status: ExitStatus, usage: ResourceUsage) -> anyhow::Result<bool> {
+        let success = Termination::of(status).is_clean();
+        let Some(chain) = self.chain.as_mut() else {
+            return Ok(false);
+        };
+        let Some(next) = chain.advance(success).map(<[OsString]>::to_vec) else {
+            return Ok(false);
+        };
+        let (step, total) = chain.position();
+        self.spec.cmd = next;
+        // keeps the registration, the instance goes on with the next step
+        self.finish(ProcessState::Restarting, Some((status, usage)))?;
+        info!(
+            "Starting step {step} of {total}: {}",
+            osargs::display(&self.spec.cmd)
+        );
+        if let Err(e) = self.respawn() {
+            error!("Failed to start step {step} of {total}: {e:#}");
+            self.give_up(ProcessState::Exited);
+            self.emit(
+                NotifyEvent::Failure,
+                &format!("Failed to start step {step} of {total}"),
+                &format!("{e:#}"),
+                None,
+            );
+        }
+        Ok(true)
+    }
+
+    /// Puts the first step of the chain back in place of the command, if it's a chain, so that
+    /// a restart runs all of it again.
+    fn reset_chain(&mut self) {
+        if let Some(chain) = self.chain.as_mut() {
+            self.spec.cmd = chain.reset().to_vec();
+        }
+    }
+
+    /// Carries out what the restart policy decided after the process exited. Returns what
+    /// happens next, for the exit notification.
+    fn schedule_restart(&mut self, decision: Decision) -> Option<String> {
+        let restarts = self.restarts.as_ref()?;
+        match decision {
+            Decision::RestartIn(delay) => {
+                let summary = format!(
+                    "Restarting in {} ({})",
+                    humantime::format_duration(delay),
+                    restarts.describe()
+                );
+                info!("{summary}");
+                self.next_restart = Some(self.clock.instant() + delay);
+                Some(summary)
+            }
+            Decision::GiveUp => {
+                let summary = format!("Gave up after {} restarts", restarts.attempts());
+                warn!("{summary}");
+                Some(summary)
+            }
+            Decision::Stop => None,
+        }
+    }
+
+    /// Starts the process again once the backoff is over. If that fails, it's tried again as
+    /// long as the policy allows, and the instance is finished otherwise.
+    fn restart_automatically(&mut self) {
+        self.next_restart = None;
+        if self.defer_restart() {
+            return;
+        }
+        self.reset_chain();
+        let attempt = self
+            .restarts
+            .as_ref()
+            .map(RestartBackoff::describe)
+            .unwrap_or_default();
+        info!("Restarting '{}' ({attempt})", self.name);
+        let Err(e) = self.respawn() else {
+            self.emit(
+                NotifyEvent::Start,
+                "Process restarted",
+                &format!("{} ({attempt})", osargs::display(&self.spec.cmd)),
+                None,
+            );
+            return;
+        };
+        error!("Failed to restart: {e:#}");
+        let limited = self.maintenance.is_none();
+        let decision = self
+            .restarts
+            .as_mut()
+            .map_or(Decision::Stop, |r| r.next(false, Duration::ZERO, limited));
+        if let Some(summary) = self.schedule_restart(decision) {
+            if decision == Decision::GiveUp {
+                self.give_up(ProcessState::Exited);
+            }
+            self.emit(
+                NotifyEvent::Failure,
+                "Failed to restart",
+                &format!("{e:#}\n{summary}"),
+                None,
+            );
+        }
+    }
+
+    /// Puts the restart off by [`headroom::RECHECK_INTERVAL`] if there isn't the free memory or
+    /// disk space it needs, notifying about it the first time. Returns `true` if it was.
+    fn defer_restart(&mut self) -> bool {
+        let Some(headroom) = self.headroom else {
+            return false;
+        };
+        let shortfall = headroom.shortfall(&self.record.cwd);
+        let Some(shortfall) = shortfall else {
+            self.restart_deferred = false;
+            return false;
+        };
+        self.next_restart = Some(self.clock.instant() + headroom::RECHECK_INTERVAL);
+        if !self.restart_deferred {
+            warn!("Putting the restart off: {shortfall}");
+            self.emit(NotifyEvent::PreCheck, "Restart deferred", &shortfall, None);
+            self.restart_deferred = true;
+        }
+        true
+    }
+
+    /// Ends the instance without a process to stop, e.g. while it waits to restart one.
+    fn give_up(&mut self, state: ProcessState) {
+        self.state = state;
+        self.next_restart = None;
+        if !self.outlives(state) {
+            if let Some(mut registration) = self.registration.take() {
+                registration.remove();
+            }
+        }
+    }
+
+    /// Stops the process. Each step of the stop strategy gets the kill timeout (see
+    /// [`Supervisor::set_kill_timeout`]) to work before falling back to the next one, and the
+    /// process is killed if none of them do.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be killed or the run record cannot be saved.
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        if self.state == ProcessState::Restarting {
+            info!("Not restarting the process anymore");
+            self.give_up(ProcessState::Killed);
+            return Ok(());
+        }
+        if self.outlives(self.state) {
+            info!("Ending the instance");
+            self.give_up(ProcessState::Killed);
+            return Ok(());
+        }
+        if self.is_finished() {
+            return Ok(());
+        }
+        let status = self.stop_process()?;
+        self.finish(ProcessState::Killed, status)
+    }
+
+    /// Publishes every event to the clients subscribed over the control socket, on top of
+    /// notifying and recording it.
+    pub fn set_subscribers(&mut self, subscribers: Subscribers) {
+        self.subscribers = Some(subscribers);
+    }
+
+    /// Calls `on_event` with every event, like the subscribers get it.
+    pub fn set_event_callback(&mut self, on_event: impl Fn(&EventRecord) + 'static) {
+        self.on_event = Some(Box::new(on_event));
+    }
+
+    /// Pings `url` whenever a run starts, succeeds, or fails, starting with the run in progress.
+    pub fn set_ping_url(&mut self, url: PingUrl) {
+        if self.state == ProcessState::Running {
+            url.start();
+        }
+        self.ping_url = Some(url);
+    }
+
+    /// Puts automatic restarts off while there's less free memory or disk space than `headroom`
+    /// needs, see `--require-free-mem`.
+    pub fn set_headroom(&mut self, headroom: Headroom) {
+        self.headroom = Some(headroom);
+    }
+
+    /// Runs `hooks` whenever a run starts, ends, or fails, starting with the run in progress.
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = Some(hooks);
+        if self.state == ProcessState::Running {
+            self.run_hook(HookEvent::Start);
+        }
+    }
+
+    /// Runs the hook for `event` with what there is to know about the current or last run.
+    fn run_hook(&self, event: HookEvent) {
+        let Some(hooks) = &self.hooks else {
+            return;
+        };
+        let context = HookContext {
+            instance: self.name.clone(),
+            run_id: self.record.id.clone(),
+            pid: self.child_proc.id(),
+            log_file: self.record.log_file.clone(),
+            exit_code: self.record.termination.map(Termination::exit_code),
+            exit_status: self.record.exit_status.clone(),
+        };
+        hooks.run(event, &self.spec, &context);
+    }
+
+    /// Sets what [`Supervisor::end_session`] does with the process.
+    pub fn set_on_logout(&mut self, policy: OnLogout) {
+        self.on_logout = policy;
+    }
+
+    /// Makes [`Supervisor::exit`] and [`Supervisor::end_session`] leave the process running,
+    /// whatever the `--on-logout` policy.
+    pub fn set_detach_on_exit(&mut self) {
+        self.detach_on_exit = true;
+        self.on_logout = OnLogout::Detach;
+    }
+
+    /// Keeps the instance registered once the process exited on its own, for a tray that stays
+    /// open to relaunch it, see [`Supervisor::relaunch`]. Killing the instance then ends it.
+    pub fn set_stay_open(&mut self) {
+        self.stay_open = true;
+    }
+
+    /// Whether the instance goes on once the process is in `state`: while it waits to be
+    /// restarted, and after it exited with [`Supervisor::set_stay_open`].
+    fn outlives(&self, state: ProcessState) -> bool {
+        state == ProcessState::Restarting || (self.stay_open && state == ProcessState::Exited)
+    }
+
+    /// Stops the process like [`Supervisor::kill`] because trayme is exiting, e.g. after an
+    /// interrupt, or leaves it running with `--detach-on-exit`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or the run record cannot be saved.
+    pub fn exit(&mut self) -> anyhow::Result<()> {
+        if self.detach_on_exit {
+            self.detach()
+        } else {
+            self.kill()
+        }
+    }
+
+    /// Stops the process or leaves it running, depending on the `--on-logout` policy, because
+    /// the desktop session is ending. trayme should exit afterwards.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped or the run record cannot be saved.
+    pub fn end_session(&mut self) -> anyhow::Result<()> {
+        info!("Session is ending");
+        match self.on_logout {
+            OnLogout::Kill => self.kill(),
+            OnLogout::Detach => self.detach(),
+        }
+    }
+
+    /// Lets go of the process without stopping it, so that it outlives trayme. On Unix its output
+    /// is handed to a relay process first (see [`LogCapture::hand_off`]). trayme should exit
+    /// afterwards.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be resumed or the run record cannot be saved.
+    pub fn detach(&mut self) -> anyhow::Result<()> {
+        if self.state == ProcessState::Restarting {
+            self.give_up(ProcessState::Exited);
+            return Ok(());
+        }
+        if self.is_finished() {
+            return Ok(());
+        }
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.release();
+        }
+        // nothing would resume it once trayme is gone
+        self.resume()?;
+        #[cfg(unix)]
+        if let Err(e) = self.capture.hand_off() {
+            warn!("{e:#}, the output of the process won't be logged anymore");
+        }
+        self.tree.release();
+        info!("Leaving PID {} running", self.child_proc.id());
+        self.state = ProcessState::Detached;
+        if let Some(mut registration) = self.registration.take() {
+            registration.remove();
+        }
+        self.record.detach()
+    }
+
+    /// Hands the instance over to the trayme on disk, which may be newer than this one, without
+    /// stopping the process: this trayme replaces itself with that one, started with the same
+    /// arguments, which takes the run over (see [`Supervisor::adopt`]). `respond` is called right
+    /// before, once nothing but starting the new trayme can fail anymore.
+    ///
+    /// Returns only if the instance couldn't be handed over, in which case it goes on as before.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if handing over isn't allowed (see
+    /// [`Supervisor::set_handover_allowed`]) or possible on this platform, there's no running
+    /// process, its output was handed off, or the trayme on disk cannot be started.
+    pub fn hand_over(&mut self, respond: impl FnOnce()) -> anyhow::Result<Infallible> {
+        if !self.handover_allowed {
+            bail!("This instance can't be handed over");
+        }
+        if !self.is_running() {
+            bail!("There's no running process to hand over");
+        }
+        #[cfg(unix)]
+        return self.exec_handover(respond);
+        #[cfg(not(unix))]
+        {
+            drop(respond);
+            bail!("Instances can only be handed over on Unix")
+        }
+    }
+
+    #[cfg(unix)]
+    fn exec_handover(&mut self, respond: impl FnOnce()) -> anyhow::Result<Infallible> {
+        let handover = Handover::new(
+            &self.child_proc,
+            &self.capture,
+            &self.record,
+            &self.spec.cmd,
+            self.paused,
+        )?;
+        // the new trayme throttles the process itself, and this one's thread goes with it
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.release();
+        }
+        let e = handover.exec(|| {
+            notifyroute::wait_for_pending();
+            respond();
+        });
+        if let Some(throttle) = self.throttle.as_mut() {
+            if let Err(e) = throttle.attach(&self.child_proc) {
+                warn!("{e:#}, the process isn't throttled anymore");
+            }
+        }
+        Err(e)
+    }
+
+    /// Stops the process and starts the command again as a new run. The instance keeps its name
+    /// and registration.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be stopped, or if the pre-check fails or the
+    /// process cannot be spawned again. In the latter cases the instance is finished.
+    pub fn restart(&mut self) -> anyhow::Result<()> {
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.deactivate(&mut self.spec.cmd);
+        }
+        self.cycle(NotifyEvent::Start, "Process restarted")
+    }
+
+    /// Starts the command again as a new run once the process exited, with
+    /// [`Supervisor::set_stay_open`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process is still running, or if the pre-check fails or the
+    /// process cannot be spawned. The instance stays as it was in the latter cases.
+    pub fn relaunch(&mut self) -> anyhow::Result<()> {
+        if !self.is_finished() {
+            bail!("The process is still running");
+        }
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.deactivate(&mut self.spec.cmd);
+        }
+        self.cycle(NotifyEvent::Start, "Process relaunched")
+    }
+
+    /// Stops the process and starts it again as a new run, notifying with `event` and `summary`.
+    fn cycle(&mut self, event: NotifyEvent, summary: &str) -> anyhow::Result<()> {
+        if self.is_running() {
+            let exit = self.stop_process()?;
+            self.tree.kill_remaining();
+            self.capture.finish();
+            self.scan_output();
+            let (status, usage) = with_log_size(exit, &self.capture);
+            self.record.finish(status, usage)?;
+        }
+        // restarting by hand starts the backoff over, and gives the command another chance
+        self.next_restart = None;
+        self.reset_chain();
+        if let Some(restarts) = self.restarts.as_mut() {
+            restarts.reset();
+        }
+        match self.respawn() {
+            Ok(()) => {
+                self.emit(event, summary, &osargs::display(&self.spec.cmd), None);
+                Ok(())
+            }
+            Err(e) => {
+                self.give_up(ProcessState::Exited);
+                Err(e)
+            }
+        }
+    }
+
+    /// The command line the process runs, as [`Supervisor::restart_with`] takes it.
+    pub fn command_line(&self) -> String {
+        if self.spec.shell {
+            osargs::display(&self.spec.cmd)
+        } else {
+            cmdline::join(&self.spec.cmd)
+        }
+    }
+
+    /// Restarts the process with another command line, which is split into arguments like a
+    /// shell would, or handed to the shell as is with `--shell`. The new command is kept for the
+    /// restarts that follow, and takes the place of the primary one if the fallback was running.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the command line is empty or cannot be split, or for the reasons
+    /// [`Supervisor::restart`] fails, in which case the instance is finished.
+    pub fn restart_with(&mut self, line: &str) -> anyhow::Result<()> {
+        let cmd = if self.spec.shell {
+            vec![line.trim().into()]
+        } else {
+            osargs::from_strings(cmdline::split(line).context("Invalid command line")?)
+        };
+        if cmd.first().is_none_or(|program| program.is_empty()) {
+            bail!("The command cannot be empty");
+        }
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.deactivate(&mut self.spec.cmd);
+        }
+        info!("Changing the command to {cmd:?}");
+        self.spec.cmd = cmd;
+        if let Some(registration) = self.registration.as_mut() {
+            if let Err(e) = registration.set_cmd(&self.spec.cmd) {
+                warn!("Failed to update the registry: {e:#}");
+            }
+        }
+        self.restart()
+    }
+
+    /// Runs the pre-check and spawns the command as a new run, starting the run's counters over.
+    fn respawn(&mut self) -> anyhow::Result<()> {
+        let (child_proc, capture, record) = pre_check(&self.spec, &self.notifier)
+            .and_then(|()| spawn_process(&self.spec, &*self.spawner))?;
+        self.tree = ProcessTree::new(&child_proc, Elevation::of(&self.spec));
+        self.caps = self
+            .spec
+            .caps
+            .map(|caps| CapWatch::attach(caps, &child_proc));
+        self.child_proc = child_proc;
+        self.capture = capture;
+        self.output = open_output(&record);
+        self.record = record;
+        self.state = ProcessState::Running;
+        self.paused = false;
+        // a run started during a meeting is paused for it too
+        self.in_meeting = false;
+        self.paused_for_meeting = false;
+        self.levels = LevelCounts::default();
+        if let Some(health) = self.health.as_mut() {
+            health.reset();
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress.reset();
+        }
+        if let Some(planned) = self.planned_restarts.as_mut() {
+            planned.reset();
+        }
+        self.first_output = None;
+        self.urls.reset();
+        self.attach_throttle();
+        self.attach_priority();
+        Ok(())
+    }
+
+    /// Closes the current log file and sends all further output to a new, timestamped one,
+    /// without restarting the process. The old file is compressed in the background if
+    /// [`Supervisor::set_compress_rotated_logs`] was set. Returns the path of the new file.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process is no longer running, the new file cannot be created,
+    /// or the run record cannot be saved.
+    pub fn rotate_log(&mut self) -> anyhow::Result<PathBuf> {
+        if !self.is_running() {
+            bail!("Process is not running");
+        }
+        let new_path = new_log_path(&self.spec.program_name())?;
+        let old_path = self.capture.rotate(&new_path)?;
+        // everything before the rotation is in the old file by now
+        self.scan_output();
+        if let Some(output) = self.output.as_mut() {
+            if let Err(e) = output.reopen(&new_path) {
+                warn!("Failed to follow rotated output: {e}");
+                self.output = None;
+            }
+        }
+        self.record.log_file.clone_from(&new_path);
+        if self.compress_rotated_logs {
+            self.record
+                .rotated_logs
+                .push(capture::compressed_path(&old_path));
+            capture::compress_in_background(old_path);
+        } else {
+            self.record.rotated_logs.push(old_path);
+        }
+        self.record.save()?;
+        Ok(new_path)
+    }
+
+    /// Runs the stop strategy and returns the exit status and resource usage of the process, if
+    /// they could be collected.
+    fn stop_process(&mut self) -> anyhow::Result<Option<(ExitStatus, ResourceUsage)>> {
+        // a paused process can't react to being asked to stop
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.release();
+        }
+        if let Err(e) = self.resume() {
+            warn!("{e:#}, stopping it anyway");
+        }
+        let steps = stop::plan(
+            self.stop_strategy,
+            &self.spec.cmd,
+            self.record.binary.as_deref(),
+            self.spec.elevate,
+        );
+        for step in steps {
+            if step == StopStrategy::Kill {
+                break;
+            }
+            let requested = stop::request_stop(
+                step,
+                &mut self.child_proc,
+                &self.tree,
+                &self.spec.cmd,
+                self.kill_timeout,
+            );
+            if let Err(e) = requested {
+                warn!("{e:#}, falling back");
+                continue;
+            }
+            if let Some(exit) = stop::wait_timeout(&mut self.child_proc, self.kill_timeout)? {
+                info!("Process stopped with {step:?}");
+                return Ok(Some(exit));
+            }
+            warn!("Process still running after {step:?}, falling back");
+        }
+        stop::request_stop(
+            StopStrategy::Kill,
+            &mut self.child_proc,
+            &self.tree,
+            &self.spec.cmd,
+            self.kill_timeout,
+        )?;
+        Ok(usage::wait(&mut self.child_proc).ok())
+    }
+
+    /// Answers a request received over IPC.
+    pub fn handle_request(&mut self, request: ControlRequest) {
+        debug!("Control request: {:?}", request.command);
+        if let ControlCommand::Handover = request.command {
+            self.handle_handover(request);
+            return;
+        }
+        let response = match &request.command {
+            ControlCommand::Status => ControlResponse::Status(self.status()),
+            ControlCommand::Kill if self.kill_disabled => ControlResponse::Error {
+                message:
+                    "Stopping this instance is disabled, use `trayme down --force` to stop it \
+                          anyway"
+                        .to_string(),
+            },
+            ControlCommand::Kill | ControlCommand::ForceKill => match self.kill() {
+                Ok(()) => ControlResponse::Ok,
+                Err(e) => ControlResponse::Error {
+                    message: format!("{e:#}"),
+                },
+            },
+            ControlCommand::Send(line) => match self.send_line(line) {
+                Ok(()) => ControlResponse::Ok,
+                Err(e) => ControlResponse::Error {
+                    message: format!("{e:#}"),
+                },
+            },
+            ControlCommand::StartMaintenance(duration) => match self.start_maintenance(*duration) {
+                Ok(()) => ControlResponse::Status(self.status()),
+                Err(e) => ControlResponse::Error {
+                    message: format!("{e:#}"),
+                },
+            },
+            ControlCommand::EndMaintenance => {
+                self.end_maintenance();
+                ControlResponse::Status(self.status())
+            }
+            ControlCommand::LogLevel(verbosity) => {
+                selflog::set_verbosity(*verbosity);
+                ControlResponse::Ok
+            }
+            ControlCommand::Handover => unreachable!("handed over above"),
+            ControlCommand::RotateLog => match self.rotate_log() {
+                Ok(_) => ControlResponse::Status(self.status()),
+                Err(e) => ControlResponse::Error {
+                    message: format!("{e:#}"),
+                },
+            },
+            // subscriptions are kept by the control server itself
+            ControlCommand::Subscribe => ControlResponse::Error {
+                message: "Not a request".to_string(),
+            },
+        };
+        request.respond(response);
+    }
+
+    /// Hands the instance over for a [`ControlCommand::Handover`] request. The client hears
+    /// back before trayme is replaced, since it wouldn't afterwards.
+    fn handle_handover(&mut self, request: ControlRequest) {
+        if self.kill_disabled {
+            // a trayme on disk that fails to take over would leave the process unsupervised
+            request.respond(ControlResponse::Error {
+                message: "Handing this instance over is disabled, stop it with `trayme down \
+                          --force` instead"
+                    .to_string(),
+            });
+            return;
+        }
+        let mut request = Some(request);
+        let Err(e) = self.hand_over(|| {
+            if let Some(request) = request.take() {
+                request.respond_written(ControlResponse::Ok);
+            }
+        });
+        error!("Failed to hand over: {e:#}");
+        if let Some(request) = request {
+            request.respond(ControlResponse::Error {
+                message: format!("{e:#}"),
+            });
+        }
+    }
+
+    /// Writes a line to the process' stdin.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process is no longer running or has closed its stdin.
+    pub fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        if !self.is_running() {
+            bail!("Process is not running");
+        }
+        let stdin = self
+            .child_proc
+            .stdin
+            .as_mut()
+            .context("Process has no stdin")?;
+        writeln!(stdin, "{line}")
+            .and_then(|()| stdin.flush())
+            .context("Failed to write to stdin")
+    }
+
+    /// Returns a snapshot of this instance for status reports.
+    pub fn status(&self) -> InstanceStatus {
+        InstanceStatus {
+            name: self.name.clone(),
+            cmd: self.spec.cmd.clone(),
+            state: self.state,
+            pid: self.child_proc.id(),
+            run_id: self.record.id.clone(),
+            started_at: self.record.started_at,
+            log_file: self.record.log_file.clone(),
+            exit_status: self.record.exit_status.clone(),
+            termination: self.record.termination,
+            levels: self.levels,
+            healthy: self.is_healthy(),
+            progress: self.progress(),
+            eta_secs: self.eta().map(|eta| eta.as_secs()),
+            maintenance_until: self.maintenance_until(),
+            first_output: self.first_output.clone(),
+            urls: self.urls().to_vec(),
+            paused: self.paused,
+            fallback: self.is_fallback(),
+        }
+    }
+
+    /// The first line the current run wrote that isn't blank, without colors.
+    pub fn first_output(&self) -> Option<&str> {
+        self.first_output.as_deref()
+    }
+
+    /// The URLs the current run printed, e.g. where a dev server or tunnel can be reached.
+    pub fn urls(&self) -> &[String] {
+        self.urls.urls()
+    }
+
+    /// The log levels counted in the output of the current run.
+    pub fn levels(&self) -> LevelCounts {
+        self.levels
+    }
+
+    /// Processes the output written since the last call.
+    fn scan_output(&mut self) {
+        let Some(output) = self.output.as_mut() else {
+            return;
+        };
+        let lines = match output.read_lines() {
+            Ok(lines) => lines,
+            Err(e) => {
+                warn!("Failed to read output: {e}");
+                return;
+            }
+        };
+        for line in lines {
+            if let Some(level) = detect_level(&line) {
+                self.levels.add(level);
+            }
+            if let Some(health) = self.health.as_mut() {
+                health.observe(&line, self.clock.instant());
+            }
+            if let Some(progress) = self.progress.as_mut() {
+                progress.observe(&line);
+            }
+            let plain = strip_ansi(&line);
+            self.urls.observe(&plain);
+            if self.first_output.is_none() {
+                self.latch_first_output(&plain);
+            }
+        }
+    }
+
+    fn latch_first_output(&mut self, plain: &str) {
+        let plain = plain.trim();
+        if plain.is_empty() {
+            return;
+        }
+        if self.first_output_notify {
+            self.emit(NotifyEvent::FirstOutput, "First output", plain, None);
+        }
+        self.first_output = Some(plain.to_string());
+    }
+
+    fn check_log_sink(&self) {
+        for event in self.capture.events() {
+            let body = match event {
+                SinkEvent::FellBack { log, fallback } => format!(
+                    "{} can't be written, the output goes to {} until it can",
+                    log.display(),
+                    fallback.display()
+                ),
+                SinkEvent::Restored { log } => {
+                    format!("The output goes to {} again", log.display())
+                }
+            };
+            self.emit(
+                NotifyEvent::LogFallback,
+                "Log location changed",
+                &body,
+                None,
+            );
+        }
+    }
+
+    fn check_profile(&mut self) {
+        let Some(watcher) = self.profile_watcher.as_mut() else {
+            return;
+        };
+        let change = match watcher.poll() {
+            Ok(Some(change)) => change,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Not reloading the profile of '{}': {e:#}", self.name);
+                return;
+            }
+        };
+        if let Err(e) = self.apply_profile(&change) {
+            warn!("{e:#}");
+            self.emit(
+                NotifyEvent::Reloaded,
+                "Profile not reloaded",
+                &format!("{e:#}"),
+                None,
+            );
+            return;
+        }
+        info!("Reloaded the profile of '{}': {change}", self.name);
+        self.emit(
+            NotifyEvent::Reloaded,
+            "Profile reloaded",
+            &change.to_string(),
+            None,
+        );
+    }
+
+    /// Parses the settings of a changed profile that can change while the process runs, without
+    /// applying any of them yet.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if any of the changed settings is invalid.
+    fn parse_reload(&self, change: &ProfileChange) -> anyhow::Result<Reload> {
+        let profile = &change.profile;
+        let pattern = |pattern: &Option<String>, key| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid {key} pattern"))
+        };
+        let changed = |keys: &[&str]| keys.iter().any(|key| change.applies(key));
+        let mut reload = Reload::default();
+        if changed(&["unhealthy_if", "threshold", "restart_on_unhealthy"]) {
+            let health = pattern(&profile.unhealthy_if, "unhealthy_if")?
+                .map(|pattern| HealthCheck::new(pattern, profile.threshold.unwrap_or_default()));
+            reload.health = Some((health, profile.restart_on_unhealthy));
+        }
+        if changed(&["restart_policy", "restart_backoff", "max_restarts"]) {
+            let initial = match &profile.restart_backoff {
+                Some(backoff) => {
+                    humantime::parse_duration(backoff).context("Invalid restart_backoff")?
+                }
+                None => restart::DEFAULT_BACKOFF,
+            };
+            // as with `--fallback`, which only runs by restarting
+            let policy = profile
+                .restart_policy
+                .or(self.fallback.as_ref().map(|_| RestartPolicy::OnFailure));
+            reload.restarts = Some(
+                policy.map(|policy| RestartBackoff::new(policy, initial, profile.max_restarts)),
+            );
+        }
+        if changed(&["restart_every", "restart_cron"]) {
+            let (every, cron) = profile.planned_restarts()?;
+            // planned from now rather than from the start of the run
+            reload.planned_restarts =
+                Some(PlannedRestarts::with_clock(every, cron, self.clock.clone()));
+        }
+        if self.sleep_watch.is_some() && changed(&["on_wake", "wake_check"]) {
+            reload.on_wake = Some(WakeAction::new(
+                profile.on_wake,
+                profile.wake_check.as_deref(),
+            )?);
+        }
+        if change.applies("progress_regex") {
+            reload.progress =
+                Some(pattern(&profile.progress_regex, "progress_regex")?.map(ProgressTracker::new));
+        }
+        if change.applies("kill_timeout") {
+            reload.kill_timeout = Some(match &profile.kill_timeout {
+                Some(timeout) => {
+                    humantime::parse_duration(timeout).context("Invalid kill_timeout")?
+                }
+                None => STOP_GRACE_PERIOD,
+            });
+        }
+        if change.applies("maintenance_duration") {
+            reload.maintenance_duration = Some(match &profile.maintenance_duration {
+                Some(duration) => {
+                    humantime::parse_duration(duration).context("Invalid maintenance_duration")?
+                }
+                None => DEFAULT_MAINTENANCE_DURATION,
+            });
+        }
+        Ok(reload)
+    }
+
+    /// Applies the settings of a changed profile that can change while the process runs, and
+    /// keeps the ones for the next run in the spec. Either all of them are applied or, if any is
+    /// invalid, none.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if any of the changed settings is invalid.
+    fn apply_profile(&mut self, change: &ProfileChange) -> anyhow::Result<()> {
+        let reload = self.parse_reload(change)?;
+        // nothing below fails, so the profile isn't applied halfway
+        let profile = &change.profile;
+        if let Some((health, restart_on_unhealthy)) = reload.health {
+            self.health = health;
+            self.restart_on_unhealthy = restart_on_unhealthy;
+        }
+        if let Some(restarts) = reload.restarts {
+            self.restarts = restarts;
+        }
+        if let Some(planned_restarts) = reload.planned_restarts {
+            self.planned_restarts = planned_restarts;
+        }
+        if let Some(on_wake) = reload.on_wake {
+            self.on_wake = on_wake;
+        }
+        if let Some(progress) = reload.progress {
+            self.progress = progress;
+        }
+        if let Some(kill_timeout) = reload.kill_timeout {
+            self.kill_timeout = kill_timeout;
+        }
+        if let Some(duration) = reload.maintenance_duration {
+            self.maintenance_duration = duration;
+        }
+        if ["notify_urgency", "notify_sounds", "notify"]
+            .iter()
+            .any(|key| change.applies(key))
+        {
+            let muted = self.notifier.is_muted();
+            self.notifier = profile.notifier();
+            self.notifier.set_instance(&self.name);
+            if muted {
+                self.notifier.mute();
+            }
+        }
+        if change.applies("stop_strategy") {
+            self.stop_strategy = profile.stop_strategy.unwrap_or_default();
+        }
+        if change.applies("priority") {
+            if let Err(e) = self.set_priority(profile.priority.unwrap_or_default()) {
+                warn!("{e:#}");
+            }
+        }
+        if change.applies("cpu_throttle") {
+            match profile.cpu_throttle {
+                Some(percent) => self.set_cpu_throttle(percent),
+                None => self.throttle = None,
+            }
+        }
+        // takes effect on the next restart, like an edit from the Environment dialog
+        let next_run = |key: &str| change.next_run.iter().any(|changed| changed == key);
+        if next_run("cmd") {
+            self.spec.cmd.clone_from(&profile.cmd);
+        }
+        if next_run("cwd") {
+            self.spec.cwd.clone_from(&profile.cwd);
+        }
+        if next_run("env") {
+            self.spec.env_overrides.clone_from(&profile.env);
+        }
+        if next_run("env_provider") {
+            self.spec.env_provider.clone_from(&profile.env_provider);
+        }
+        if next_run("tz") {
+            self.spec.tz.clone_from(&profile.tz);
+        }
+        if next_run("locale") {
+            self.spec.locale.clone_from(&profile.locale);
+        }
+        if next_run("ulimits") {
+            self.spec.ulimits.clone_from(&profile.ulimits);
+        }
+        if next_run("pre_check") {
+            self.spec.pre_check.clone_from(&profile.pre_check);
+        }
+        Ok(())
+    }
+
+    fn check_health(&mut self) -> anyhow::Result<()> {
+        if self.maintenance.is_some() {
+            return Ok(());
+        }
+        let Some(health) = self.health.as_mut() else {
+            return Ok(());
+        };
+        match health.update(self.clock.instant()) {
+            Some(HealthChange::Unhealthy(hits)) => {
+                let window = health.threshold().window;
+                let body = format!(
+                    "{hits} matching lines within {}",
+                    humantime::format_duration(window)
+                );
+                warn!("Instance '{}' is unhealthy: {body}", self.name);
+                self.emit(NotifyEvent::Unhealthy, "Process unhealthy", &body, None);
+                if self.restart_on_unhealthy {
+                    // a process that's unhealthy right after starting would restart in a loop
+                    let recently_restarted = self
+                        .last_unhealthy_restart
+                        .is_some_and(|at| self.clock.instant().duration_since(at) < window);
+                    if recently_restarted {
+                        warn!("Not restarting '{}' again so soon", self.name);
+                    } else {
+                        info!("Restarting unhealthy instance '{}'", self.name);
+                        self.last_unhealthy_restart = Some(self.clock.instant());
+                        self.restart()?;
+                    }
+                }
+            }
+            Some(HealthChange::Recovered) => {
+                info!("Instance '{}' recovered", self.name);
+                self.emit(
+                    NotifyEvent::Recovered,
+                    "Process recovered",
+                    &osargs::display(&self.spec.cmd),
+                    None,
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Notifies the user about an event, unless in maintenance mode, and records it in the event
+    /// log. Starts and ends of runs are pinged with `--ping-url` and run their hooks, maintenance
+    /// mode or not.
+    fn emit(&self, event: NotifyEvent, title: &str, body: &str, backtrace: Option<&Backtrace>) {
+        if let Some(url) = &self.ping_url {
+            match event {
+                NotifyEvent::Start => url.start(),
+                NotifyEvent::Exit => url.success(body),
+                NotifyEvent::Failure | NotifyEvent::Timeout => url.fail(body),
+                _ => {}
+            }
+        }
+        match event {
+            NotifyEvent::Start => self.run_hook(HookEvent::Start),
+            NotifyEvent::Failure | NotifyEvent::Timeout => self.run_hook(HookEvent::Failure),
+            _ => {}
+        }
+        if self.maintenance.is_none() || event == NotifyEvent::Maintenance {
+            let log = LogSource {
+                path: &self.record.log_file,
+                env: &self.record.env,
+            };
+            self.notifier.notify_with_log(event, title, body, Some(log));
+        } else {
+            debug!("Not notifying about {event:?} in maintenance mode");
+        }
+        let record = EventRecord {
+            at: self.clock.now(),
+            instance: self.name.clone(),
+            run_id: self.record.id.clone(),
+            event,
+            message: format!("{title}: {body}"),
+            backtrace: backtrace.map(|backtrace| backtrace.text.clone()),
+        };
+        if let Err(e) = record.append() {
+            warn!("{e:#}");
+        }
+        if let Some(subscribers) = &self.subscribers {
+            subscribers.publish(&record);
+        }
+        if let Some(on_event) = &self.on_event {
+            on_event(&record);
+        }
+    }
+
+    /// Looks for a crash report at the end of the run's log. Only the current log file is
+    /// searched, so a report split by a rotation is cut off.
+    fn find_backtrace(&self) -> Option<Backtrace> {
+        crash::find_in_log(&self.record.log_file)
+            .map_err(|e| warn!("Failed to search the log for a backtrace: {e}"))
+            .ok()
+            .flatten()
+    }
+
+    fn finish(
+        &mut self,
+        state: ProcessState,
+        exit: Option<(ExitStatus, ResourceUsage)>,
+    ) -> anyhow::Result<()> {
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.release();
+        }
+        // nothing the run started outlives it
+        self.tree.kill_remaining();
+        self.capture.finish();
+        self.scan_output();
+        if let Some(line) = self.output.as_mut().and_then(OutputTail::flush) {
+            if let Some(level) = detect_level(&line) {
+                self.levels.add(level);
+            }
+        }
+        self.state = state;
+        self.succeeded = exit.is_some_and(|(status, _)| status.success());
+        if !self.outlives(state) {
+            if let Some(mut registration) = self.registration.take() {
+                registration.remove();
+            }
+        }
+        if let Some((path, cleanup)) = self.record.temp_dir.as_deref().zip(self.spec.temp_dir) {
+            cleanup.clean_up(path, self.succeeded);
+        }
+        let (status, usage) = with_log_size(exit, &self.capture);
+        let saved = self.record.finish(status, usage);
+        self.run_hook(HookEvent::Exit);
+        saved
+    }
+}
+
+/// Fills in how much the run logged, now that it's finished.
+fn with_log_size(
+    exit: Option<(ExitStatus, ResourceUsage)>,
+    capture: &LogCapture,
+) -> (Option<ExitStatus>, Option<ResourceUsage>) {
+    let Some((status, mut usage)) = exit else {
+        return (None, None);
+    };
+    usage.log_bytes = capture.bytes_written();
+    (Some(status), Some(usage))
+}
+
+/// Opens the run's log file for following. Failing to do so only disables output processing, so
+/// it's logged instead of returned.
+fn open_output(record: &RunRecord) -> Option<OutputTail> {
+    OutputTail::open(&record.log_file)
+        .map_err(|e| warn!("Failed to follow output, log levels won't be counted: {e}"))
+        .ok()
+}
+
+/// Returns the file name of `program`, which is what trayme uses to name logs and instances.
+/// Programs given as paths (e.g. ./run.sh) would otherwise produce nested log paths.
+pub fn program_name(program: &str) -> String {
+    Path::new(program).file_name().map_or_else(
+        || program.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// The name of a command line run with `--shell`: every run of characters other than letters,
+/// digits, `-`, and `.` is replaced with `_`, and it's cut to [`MAX_SHELL_NAME`] characters, so
+/// `ls *.txt | wc -l` becomes `ls_.txt_wc_-l`.
+fn shell_program_name(line: &str) -> String {
+    let mut name = String::new();
+    for c in line.chars() {
+        if c.is_alphanumeric() || matches!(c, '-' | '.') {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    let name: String = name.chars().take(MAX_SHELL_NAME).collect();
+    match name.trim_end_matches('_') {
+        "" => "shell".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Picks a new, timestamped log file in the logs directory of `program_name`.
+///
+/// # Errors
+///
+/// An error is returned if the logs directory cannot be determined.
+fn new_log_path(program_name: &str) -> anyhow::Result<PathBuf> {
+    let now_fmt = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S");
+    let logs_dir = layout::program_logs_dir(program_name)?;
+    let mut path = logs_dir.join(format!("{program_name}_{now_fmt}.log"));
+    // restarts and rotations can create several logs within the same second
+    for n in 2.. {
+        if !path.exists() {
+            break;
+        }
+        path = logs_dir.join(format!("{program_name}_{now_fmt}_{n}.log"));
+    }
+    Ok(path)
+}
+
+/// Runs the pre-check of `spec`, if it has one, and notifies the user with its output if it
+/// fails.
+///
+/// # Errors
+///
+/// An error is returned if the pre-check fails.
+fn pre_check(spec: &CommandSpec, notifier: &Notifier) -> anyhow::Result<()> {
+    let Some(check) = &spec.pre_check else {
+        return Ok(());
+    };
+    precheck::run(check, spec)
+        .inspect_err(|e| {
+            error!("Pre-check failed: {e:#}");
+            notifier.notify(NotifyEvent::PreCheck, "Pre-check failed", &format!("{e:#}"));
+        })
+        .with_kind(ErrorKind::Spawn)
+}
+
+/// Spawns the given command in a new process, capturing stdout and stderr into a log file in the
+/// logs directory (see [`LogCapture`]). A [`RunRecord`] describing the run is saved to the history
+/// so that it can be reproduced later. Returns the child process handle, its output capture, and
+/// its run record.
+///
+/// # Arguments
+///
+/// * `spec` - The command to run, along with its optional working directory and environment.
+///   The run record has the command as it was run in the environment provider, if any.
+///
+/// # Errors
+///
+/// If the environment provider cannot be applied, if the log file cannot be created, if the run
+/// record cannot be saved, or if the command fails to spawn, an error is returned.
+fn spawn_process(
+    spec: &CommandSpec,
+    spawner: &dyn ProcessSpawner,
+) -> anyhow::Result<(ChildProcess, LogCapture, RunRecord)> {
+    // checked first, since the error spawn gives is about the program, and a log would be left
+    // behind for a run that never started
+    if let Some(cwd) = spec.cwd.as_ref().filter(|cwd| !cwd.is_dir()) {
+        return Err(anyhow::anyhow!(
+            "Working directory {} doesn't exist",
+            cwd.display()
+        ))
+        .with_kind(ErrorKind::Spawn);
+    }
+    if spec.temp_dir.is_none() {
+        return spawn_logged(spec, None, spawner);
+    }
+    let temp_dir = tempdir::create(&spec.program_name()).with_kind(ErrorKind::Spawn)?;
+    let mut with_temp = spec.clone();
+    with_temp.env_overrides.extend(tempdir::env(&temp_dir));
+    spawn_logged(&with_temp, Some(&temp_dir), spawner)
+        .inspect_err(|_| TempCleanup::Delete.clean_up(&temp_dir, false))
+}
+
+/// Spawns the process of `spec` with its output going to a new log, see [`spawn_process`].
+/// `temp_dir` is recorded as the run's temporary directory.
+fn spawn_logged(
+    spec: &CommandSpec,
+    temp_dir: Option<&Path>,
+    spawner: &dyn ProcessSpawner,
+) -> anyhow::Result<(ChildProcess, LogCapture, RunRecord)> {
+    // named after the command rather than the shell or the wrapper the provider runs it with
+    let output_file = new_log_path(&spec.program_name())?;
+    let shelled;
+    let spec = if spec.shell {
+        shelled = spec.through_shell();
+        &shelled
+    } else if let Some(script) = cfg!(windows).then(|| shebang::apply(spec)).flatten() {
+        shelled = script;
+        &shelled
+    } else {
+        spec
+    };
+    let in_env;
+    let spec = match &spec.env_provider {
+        Some(provider) => {
+            in_env = provider.apply(spec)?;
+            &in_env
+        }
+        None => spec,
+    };
+    let elevated;
+    let spec = if spec.elevate {
+        elevated = elevate::apply(spec)?;
+        &elevated
+    } else {
+        spec
+    };
+    // outside of the elevation, so that its cgroup belongs to the user
+    let capped;
+    let spec = match caps::apply(spec) {
+        Some(spec) => {
+            capped = spec;
+            &capped
+        }
+        None => spec,
+    };
+    let cmd = &spec.cmd;
+    let program = &cmd[0];
+    // TODO: examine if "append" is better than "truncate"
+    let output = capture::open_log(&output_file)?;
+
+    let args = &cmd[1..];
+    info!("Spawning command: {} {args:?}", program.to_string_lossy());
+
+    let mut record = RunRecord::capture(spec, &output_file)?;
+    record.temp_dir = temp_dir.map(Path::to_path_buf);
+    let mut command = process::Command::new(program);
+    command.args(args);
+    if let Some(cwd) = &spec.cwd {
+        command.current_dir(cwd);
+    }
+    if let Some(env) = &spec.env {
+        command.env_clear().envs(env);
+    }
+    command.envs(spec.overrides());
+    limits::apply(&mut command, &spec.ulimits)?;
+    // kept open for the console, see Supervisor::send_line
+    command.stdin(Stdio::piped());
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    spawntrace::trace(spec, &record);
+
+    #[cfg(not(windows))]
+    let mut child_proc = {
+        use std::os::unix::process::CommandExt;
+
+        // so that the processes it starts can be stopped along with it, see ProcessTree
+        command.process_group(0);
+        spawner
+            .spawn(&mut command)
+            .map_err(|e| spawntrace::explain(e, &record))
+            .context("Failed to spawn command")
+            .with_kind(ErrorKind::Spawn)?
+    };
+    #[cfg(windows)]
+    let mut child_proc = {
+        use std::os::windows::process::CommandExt;
+        // https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags#flags
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+        // https://stackoverflow.com/questions/77089431/how-to-run-a-command-without-terminal-in-rust
+        command.creation_flags(CREATE_NO_WINDOW);
+        spawner
+            .spawn(&mut command)
+            .map_err(|e| spawntrace::explain(e, &record))
+            .context("Failed to spawn command")
+            .with_kind(ErrorKind::Spawn)?
+    };
+    let stdout = child_proc.stdout.take().context("Child has no stdout")?;
+    let stderr = child_proc.stderr.take().context("Child has no stderr")?;
+    let capture = LogCapture::start(output, &output_file, stdout, stderr);
+
+    debug!("output piped to: {output_file:?}");
+    record.save()?;
+    info!("Run ID: {}", record.id);
+
+    Ok((child_proc.into(), capture, record))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use chrono::Local;
+
+    use super::{CommandSpec, Supervisor};
+    use crate::{
+        clock::FakeClock,
+        ipc::ProcessState,
+        notify::{FakeNotifier, Notifier, NotifyEvent},
+        restart::{RestartBackoff, RestartPolicy},
+        spawner::FakeSpawner,
+    };
+
+    /// Polls `supervisor` until it's in `state`, which the stand-in process gets to right away.
+    fn poll_until(supervisor: &mut Supervisor, state: ProcessState) {
+        for _ in 0..500 {
+            supervisor.poll().unwrap();
+            if supervisor.status().state == state {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("never got to {state:?}");
+    }
+
+    #[test]
+    fn backs_off_between_restarts() {
+        let spawner = FakeSpawner::exiting_with([1, 1, 0]);
+        let clock = FakeClock::at(Local::now());
+        let desktop = FakeNotifier::default();
+        let mut notifier = Notifier::default();
+        notifier.set_desktop(Arc::new(desktop.clone()));
+        let spec = CommandSpec {
+            cwd: Some(std::env::temp_dir()),
+            env_overrides: [("GREETING".to_string(), "hi".to_string())].into(),
+            ..CommandSpec::of(&["server", "--port=1"])
+        };
+        let mut supervisor = Supervisor::start_with(
+            "test".to_string(),
+            spec,
+            notifier,
+            Arc::new(clock.clone()),
+            Arc::new(spawner.clone()),
+        )
+        .unwrap();
+        supervisor.set_restart_policy(RestartBackoff::new(
+            RestartPolicy::OnFailure,
+            Duration::from_secs(1),
+            None,
+        ));
+
+        poll_until(&mut supervisor, ProcessState::Restarting);
+        supervisor.poll().unwrap();
+        assert_eq!(spawner.spawned().len(), 1, "restarted before the backoff");
+        clock.advance(chrono::Duration::seconds(1));
+        poll_until(&mut supervisor, ProcessState::Restarting);
+        // the second failure in a row waits twice as long
+        clock.advance(chrono::Duration::seconds(1));
+        supervisor.poll().unwrap();
+        assert_eq!(spawner.spawned().len(), 2, "restarted before the backoff");
+        clock.advance(chrono::Duration::seconds(1));
+        poll_until(&mut supervisor, ProcessState::Exited);
+
+        assert_eq!(supervisor.exit_code(), Some(0));
+        let runs = spawner.spawned();
+        assert_eq!(runs.len(), 3);
+        for run in &runs {
+            assert_eq!(run.program, "server");
+            assert_eq!(run.args, ["--port=1"]);
+            assert_eq!(run.cwd.as_deref(), Some(std::env::temp_dir().as_path()));
+            assert!(run.env.contains(&("GREETING".into(), Some("hi".into()))));
+        }
+        let events: Vec<_> = desktop.shown().iter().map(|shown| shown.event).collect();
+        assert_eq!(
+            events
+                .iter()
+                .filter(|&&event| event == NotifyEvent::Failure)
+                .count(),
+            2
+        );
+    }
+}
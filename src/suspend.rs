@@ -0,0 +1,84 @@
+use crate::child::ChildProcess;
+
+/// Pauses `child` until [`resume`] is called. On Unix it's stopped with `SIGSTOP`, along with the
+/// processes it started, which are in its process group (see [`crate::tree::ProcessTree`]). On
+/// Windows every thread of the process itself is suspended.
+///
+/// # Errors
+///
+/// An error is returned if the process cannot be paused, e.g. because it already exited.
+pub fn suspend(child: &ChildProcess) -> anyhow::Result<()> {
+    platform::suspend(child)
+}
+
+/// Lets a process paused with [`suspend`] run again.
+///
+/// # Errors
+///
+/// An error is returned if the process cannot be resumed.
+pub fn resume(child: &ChildProcess) -> anyhow::Result<()> {
+    platform::resume(child)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+
+    use anyhow::Context;
+
+    use crate::child::ChildProcess;
+
+    pub fn suspend(child: &ChildProcess) -> anyhow::Result<()> {
+        signal(child, libc::SIGSTOP).context("Failed to pause the process")
+    }
+
+    pub fn resume(child: &ChildProcess) -> anyhow::Result<()> {
+        signal(child, libc::SIGCONT).context("Failed to resume the process")
+    }
+
+    fn signal(child: &ChildProcess, signal: libc::c_int) -> anyhow::Result<()> {
+        let pid = libc::pid_t::try_from(child.id()).context("PID out of range")?;
+        // SAFETY: kill has no memory safety requirements, and a negative PID signals the group
+        if unsafe { libc::kill(-pid, signal) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::os::windows::io::AsRawHandle;
+
+    use anyhow::bail;
+
+    use crate::child::ChildProcess;
+
+    type Handle = *mut std::ffi::c_void;
+
+    // undocumented, but stable since Windows XP and what Process Explorer and Resource
+    // Monitor use to suspend processes
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn NtSuspendProcess(process: Handle) -> i32;
+        fn NtResumeProcess(process: Handle) -> i32;
+    }
+
+    pub fn suspend(child: &ChildProcess) -> anyhow::Result<()> {
+        // SAFETY: the handle belongs to `child`, which outlives the call
+        let status = unsafe { NtSuspendProcess(child.as_raw_handle().cast()) };
+        if status < 0 {
+            bail!("Failed to pause the process (NTSTATUS {status:#x})");
+        }
+        Ok(())
+    }
+
+    pub fn resume(child: &ChildProcess) -> anyhow::Result<()> {
+        // SAFETY: the handle belongs to `child`, which outlives the call
+        let status = unsafe { NtResumeProcess(child.as_raw_handle().cast()) };
+        if status < 0 {
+            bail!("Failed to resume the process (NTSTATUS {status:#x})");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::Local;
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// The variables that point programs to their temporary directory: `TMPDIR` on Unix, and `TEMP`
+/// and `TMP` on Windows (and for some cross-platform tools elsewhere).
+const TEMP_VARS: &[&str] = &["TMPDIR", "TEMP", "TMP"];
+
+/// What happens to a run's temporary directory once the run is over, see `--temp-dir`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TempCleanup {
+    /// It's deleted.
+    #[default]
+    Delete,
+    /// It's kept if the run failed or was killed, to look into what went wrong.
+    KeepFailed,
+    /// It's kept.
+    Keep,
+}
+
+impl TempCleanup {
+    /// Deletes the temporary directory of a finished run at `path`, unless this says to keep it.
+    /// Failing to do so is only logged.
+    pub fn clean_up(self, path: &Path, succeeded: bool) {
+        let keep = match self {
+            TempCleanup::Delete => false,
+            TempCleanup::KeepFailed => !succeeded,
+            TempCleanup::Keep => true,
+        };
+        if keep {
+            info!("Keeping the temporary directory {}", path.display());
+            return;
+        }
+        match fs::remove_dir_all(path) {
+            Ok(()) => debug!("Deleted the temporary directory {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(
+                "Failed to delete the temporary directory {}: {e}",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Creates a temporary directory of its own for a run of `program`, in the system's temporary
+/// directory.
+///
+/// # Errors
+///
+/// An error is returned if the directory cannot be created.
+pub fn create(program: &str) -> anyhow::Result<PathBuf> {
+    let base = format!(
+        "trayme-{program}-{}-{}",
+        Local::now().format("%Y%m%d%H%M%S"),
+        std::process::id()
+    );
+    let parent = std::env::temp_dir();
+    // restarts can start several runs within the same second
+    for n in 1.. {
+        let path = match n {
+            1 => parent.join(&base),
+            n => parent.join(format!("{base}-{n}")),
+        };
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to create the temporary directory {}",
+                        path.display()
+                    )
+                })
+            }
+        }
+    }
+    unreachable!("there's always another suffix to try")
+}
+
+/// The variables that point the command to the temporary directory at `path`.
+pub fn env(path: &Path) -> impl Iterator<Item = (String, String)> + '_ {
+    TEMP_VARS
+        .iter()
+        .map(move |name| ((*name).to_string(), path.display().to_string()))
+}
@@ -0,0 +1,214 @@
+use std::{fmt, process::ExitStatus};
+
+use serde::{Deserialize, Serialize};
+
+/// How a run ended, as the platform reports it: whether the process exited by itself, was
+/// killed by a signal on Unix, or crashed on Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Termination {
+    /// It exited by itself with this code.
+    Exited { code: i32 },
+    /// It was killed by a signal (Unix).
+    Signaled { signal: i32, core_dumped: bool },
+    /// It ended with an `NTSTATUS` error, e.g. from an unhandled exception (Windows).
+    Crashed { code: u32 },
+}
+
+impl Termination {
+    /// Classifies the exit status of a process.
+    #[cfg(unix)]
+    pub fn of(status: ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Self::Exited { code },
+            (None, Some(signal)) => Self::Signaled {
+                signal,
+                core_dumped: status.core_dumped(),
+            },
+            // only a stopped or continued process has neither, which `wait` doesn't report
+            (None, None) => Self::Exited {
+                code: status.into_raw(),
+            },
+        }
+    }
+
+    /// Classifies the exit status of a process.
+    #[cfg(windows)]
+    pub fn of(status: ExitStatus) -> Self {
+        let code = status.code().unwrap_or_default();
+        // exit codes are u32 on Windows, which std hands out as i32
+        let unsigned = u32::from_ne_bytes(code.to_ne_bytes());
+        // the severity bits of an NTSTATUS error
+        if unsigned >= 0xC000_0000 {
+            Self::Crashed { code: unsigned }
+        } else {
+            Self::Exited { code }
+        }
+    }
+
+    /// Whether the process ended the way it's meant to: it exited with code 0, or was stopped
+    /// with one of the signals anyone asking it to stop sends (`SIGHUP`, `SIGINT`, `SIGTERM`,
+    /// and `SIGPIPE`, as systemd counts them), or with Ctrl+C on Windows. Anything else is a
+    /// failure, which `--restart-policy on-failure` restarts.
+    pub fn is_clean(self) -> bool {
+        match self {
+            Self::Exited { code } => code == 0,
+            #[cfg(unix)]
+            Self::Signaled { signal, .. } => matches!(
+                signal,
+                libc::SIGHUP | libc::SIGINT | libc::SIGTERM | libc::SIGPIPE
+            ),
+            #[cfg(not(unix))]
+            Self::Signaled { .. } => false,
+            Self::Crashed { code } => code == STATUS_CONTROL_C_EXIT,
+        }
+    }
+
+    /// The exit code trayme passes on for this ending, the way shells do: the process' own code,
+    /// 128 plus the signal's number if a signal killed it, or the `NTSTATUS` code of a crash.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Exited { code } => code,
+            Self::Signaled { signal, .. } => 128 + signal,
+            Self::Crashed { code } => i32::from_ne_bytes(code.to_ne_bytes()),
+        }
+    }
+
+    /// What usually causes this ending, for the exit notification, if it says more than the
+    /// code itself.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Self::Signaled { signal, .. } => signal_hint(signal),
+            Self::Crashed { code } => ntstatus(code).map(|(_, hint)| hint),
+            Self::Exited { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Exited { code } => write!(f, "Exited with code {code}"),
+            Self::Signaled {
+                signal,
+                core_dumped,
+            } => {
+                match signal_name(signal) {
+                    Some(name) => write!(f, "Killed by {name} (signal {signal})")?,
+                    None => write!(f, "Killed by signal {signal}")?,
+                }
+                if core_dumped {
+                    write!(f, ", core dumped")?;
+                }
+                Ok(())
+            }
+            Self::Crashed { code } => match ntstatus(code) {
+                Some((name, _)) => write!(f, "Crashed with {name} (0x{code:08X})"),
+                None => write!(f, "Crashed with 0x{code:08X}"),
+            },
+        }
+    }
+}
+
+/// The `NTSTATUS` of a console process ended with Ctrl+C or Ctrl+Break.
+const STATUS_CONTROL_C_EXIT: u32 = 0xC000_013A;
+
+/// The name and usual cause of the `NTSTATUS` codes processes commonly crash with.
+fn ntstatus(code: u32) -> Option<(&'static str, &'static str)> {
+    // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-erref/596a1078-e883-4972-9bbc-49e60bebca55
+    Some(match code {
+        0xC000_0005 => (
+            "STATUS_ACCESS_VIOLATION",
+            "The program read or wrote memory it doesn't own, a bug in it or one of its libraries",
+        ),
+        0xC000_0017 => ("STATUS_NO_MEMORY", "The machine ran out of memory"),
+        0xC000_001D => (
+            "STATUS_ILLEGAL_INSTRUCTION",
+            "The program uses instructions this CPU doesn't have, or is corrupt",
+        ),
+        0xC000_0094 => (
+            "STATUS_INTEGER_DIVIDE_BY_ZERO",
+            "The program divided by zero",
+        ),
+        0xC000_00FD => (
+            "STATUS_STACK_OVERFLOW",
+            "The program ran out of stack, e.g. from endless recursion",
+        ),
+        STATUS_CONTROL_C_EXIT => (
+            "STATUS_CONTROL_C_EXIT",
+            "The program was stopped with Ctrl+C",
+        ),
+        0xC000_0374 => (
+            "STATUS_HEAP_CORRUPTION",
+            "The program corrupted its memory, a bug in it or one of its libraries",
+        ),
+        0xC000_0409 => (
+            "STATUS_STACK_BUFFER_OVERRUN",
+            "The program aborted itself, e.g. a failed check or a Rust panic with panic=abort",
+        ),
+        0xC000_0135 => (
+            "STATUS_DLL_NOT_FOUND",
+            "A DLL the program needs is missing, e.g. a runtime that isn't installed",
+        ),
+        0xC000_0139 => (
+            "STATUS_ENTRYPOINT_NOT_FOUND",
+            "A DLL the program needs is the wrong version",
+        ),
+        _ => return None,
+    })
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    })
+}
+
+#[cfg(not(unix))]
+fn signal_name(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+#[cfg(unix)]
+fn signal_hint(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGKILL => {
+            "Something killed it outright, e.g. the out-of-memory killer (see `dmesg`) or kill -9"
+        }
+        libc::SIGSEGV | libc::SIGBUS => {
+            "The program read or wrote memory it doesn't own, a bug in it or one of its libraries"
+        }
+        libc::SIGABRT => "The program aborted itself, e.g. a failed assertion",
+        libc::SIGILL => "The program uses instructions this CPU doesn't have, or is corrupt",
+        libc::SIGFPE => "The program divided by zero or hit another arithmetic error",
+        libc::SIGXCPU => "The program used up its CPU time limit, see --ulimit",
+        libc::SIGXFSZ => "The program wrote past its file size limit, see --ulimit",
+        libc::SIGPIPE => "The program wrote to a pipe or socket nobody reads anymore",
+        _ => return None,
+    })
+}
+
+#[cfg(not(unix))]
+fn signal_hint(_signal: i32) -> Option<&'static str> {
+    None
+}
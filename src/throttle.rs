@@ -0,0 +1,209 @@
+use crate::child::ChildProcess;
+
+/// Keeps a process to a share of one CPU without cgroups, which macOS and Windows don't have.
+///
+/// On Unix the process is stopped and continued with `SIGSTOP` and `SIGCONT` so that it only
+/// runs for `percent` of every 100 ms. Only the process itself is throttled, not the processes it
+/// starts. On Windows it's put in a job object with a hard CPU rate cap, which its child
+/// processes inherit.
+#[derive(Debug)]
+pub struct CpuThrottle {
+    percent: u8,
+    active: Option<platform::Active>,
+}
+
+impl CpuThrottle {
+    /// Creates a throttle that limits processes to `percent` (1 to 99) of one CPU.
+    pub fn new(percent: u8) -> Self {
+        Self {
+            percent,
+            active: None,
+        }
+    }
+
+    /// Starts throttling `child`, releasing the process throttled before, if any.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the process cannot be throttled on this platform.
+    pub fn attach(&mut self, child: &ChildProcess) -> anyhow::Result<()> {
+        self.release();
+        self.active = Some(platform::Active::start(child, self.percent)?);
+        Ok(())
+    }
+
+    /// Stops throttling and lets the process run freely, e.g. so that it can handle a stop
+    /// request quickly.
+    pub fn release(&mut self) {
+        if let Some(active) = self.active.take() {
+            active.stop();
+        }
+    }
+}
+
+impl Drop for CpuThrottle {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{
+        sync::mpsc::{self, RecvTimeoutError},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use anyhow::Context;
+    use log::{debug, warn};
+
+    use crate::child::ChildProcess;
+
+    /// How long one run-and-pause cycle takes. Short enough that the process doesn't seem to
+    /// hang, long enough that the signals themselves cost next to nothing.
+    pub const PERIOD: Duration = Duration::from_millis(100);
+
+    #[derive(Debug)]
+    pub struct Active {
+        pid: u32,
+        stop: mpsc::Sender<()>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Active {
+        pub fn start(child: &ChildProcess, percent: u8) -> anyhow::Result<Self> {
+            let pid = child.id();
+            let raw_pid = libc::pid_t::try_from(pid).context("PID out of range")?;
+            let running = PERIOD * u32::from(percent) / 100;
+            let paused = PERIOD.saturating_sub(running);
+            let (stop, stopped) = mpsc::channel();
+            let thread = thread::Builder::new()
+                .name("cpu-throttle".to_string())
+                .spawn(move || {
+                    debug!("Throttling PID {pid} to {percent}% CPU");
+                    // false once trayme wants the process back
+                    let wait =
+                        |duration| stopped.recv_timeout(duration) == Err(RecvTimeoutError::Timeout);
+                    while signal(raw_pid, libc::SIGCONT)
+                        && wait(running)
+                        && signal(raw_pid, libc::SIGSTOP)
+                        && wait(paused)
+                    {}
+                    signal(raw_pid, libc::SIGCONT);
+                })
+                .context("Failed to start CPU throttle")?;
+            Ok(Self { pid, stop, thread })
+        }
+
+        pub fn stop(self) {
+            let _ = self.stop.send(());
+            if self.thread.join().is_err() {
+                warn!("CPU throttle of PID {} panicked", self.pid);
+            }
+        }
+    }
+
+    /// Sends `signal` to `pid`, returning `false` once the process is gone.
+    fn signal(pid: libc::pid_t, signal: libc::c_int) -> bool {
+        // SAFETY: kill has no memory safety requirements
+        unsafe { libc::kill(pid, signal) == 0 }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{io, os::windows::io::AsRawHandle, ptr};
+
+    use anyhow::Context;
+    use log::debug;
+
+    use crate::child::ChildProcess;
+
+    type Handle = *mut std::ffi::c_void;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_cpu_rate_control_information
+    const JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION: i32 = 15;
+    const JOB_OBJECT_CPU_RATE_CONTROL_ENABLE: u32 = 0x1;
+    const JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP: u32 = 0x4;
+
+    #[repr(C)]
+    struct CpuRateControl {
+        control_flags: u32,
+        /// The share of all CPUs in hundredths of a percent.
+        cpu_rate: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *const std::ffi::c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(
+            job: Handle,
+            class: i32,
+            info: *const std::ffi::c_void,
+            length: u32,
+        ) -> i32;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    #[derive(Debug)]
+    pub struct Active {
+        pid: u32,
+        job: Handle,
+    }
+
+    impl Active {
+        pub fn start(child: &ChildProcess, percent: u8) -> anyhow::Result<Self> {
+            // the rate is shared by all CPUs, but --cpu-throttle is a share of one of them
+            let cpus = std::thread::available_parallelism().map_or(1, usize::get);
+            let cpus = u32::try_from(cpus).unwrap_or(u32::MAX);
+            let info = CpuRateControl {
+                control_flags: JOB_OBJECT_CPU_RATE_CONTROL_ENABLE
+                    | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+                cpu_rate: (u32::from(percent) * 100 / cpus).max(1),
+            };
+            // SAFETY: all pointers are valid for the duration of the calls, and the job handle is
+            // closed by `stop`
+            unsafe {
+                let job = CreateJobObjectW(ptr::null(), ptr::null());
+                if job.is_null() {
+                    return Err(io::Error::last_os_error())
+                        .context("Failed to create a job object for the CPU throttle");
+                }
+                let active = Self {
+                    pid: child.id(),
+                    job,
+                };
+                if SetInformationJobObject(
+                    job,
+                    JOB_OBJECT_CPU_RATE_CONTROL_INFORMATION,
+                    ptr::addr_of!(info).cast(),
+                    u32::try_from(std::mem::size_of::<CpuRateControl>())?,
+                ) == 0
+                {
+                    return Err(io::Error::last_os_error()).context("Failed to set the CPU rate");
+                }
+                if AssignProcessToJobObject(job, child.as_raw_handle().cast()) == 0 {
+                    return Err(io::Error::last_os_error())
+                        .context("Failed to assign the process to the CPU throttle");
+                }
+                debug!("Throttling PID {} to {percent}% CPU", active.pid);
+                Ok(active)
+            }
+        }
+
+        /// Closes the job object. The process stays in it until it exits, so on Windows the
+        /// limit only goes away with the process.
+        pub fn stop(self) {}
+    }
+
+    impl Drop for Active {
+        fn drop(&mut self) {
+            // SAFETY: the handle was created by CreateJobObjectW and is only closed here
+            unsafe {
+                CloseHandle(self.job);
+            }
+        }
+    }
+}
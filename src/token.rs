@@ -0,0 +1,305 @@
+use std::{
+    fmt::{self, Write as _},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::get_logs_dir;
+
+/// The variable a client's token is read from, e.g. for running `trayme ls` with a read-only
+/// token. Without it, trayme's own clients use the owner secret.
+pub const TOKEN_VAR: &str = "TRAYME_TOKEN";
+
+/// What a token allows over the control socket.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only reading the status, e.g. for a status bar widget.
+    #[default]
+    Read,
+    /// Controlling the process, including killing it and writing to its stdin.
+    Control,
+    /// Everything, including stopping instances started with `--no-kill-menu` with `trayme down
+    /// --force`.
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Control => write!(f, "control"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A token created with `trayme token create`. Only a hash of its secret is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub name: String,
+    pub scope: Scope,
+    pub created_at: DateTime<Local>,
+    /// The SHA-256 of the secret, in hex.
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenFile {
+    #[serde(default)]
+    tokens: Vec<Token>,
+}
+
+/// Creates a token and returns its secret, which is shown to the user once and can't be
+/// recovered afterwards.
+///
+/// # Errors
+///
+/// An error is returned if a token with the name already exists or the token file cannot be
+/// written.
+pub fn create(name: &str, scope: Scope) -> anyhow::Result<String> {
+    let mut file = load()?;
+    if file.tokens.iter().any(|token| token.name == name) {
+        bail!("A token named '{name}' already exists, revoke it first");
+    }
+    let secret = new_secret()?;
+    file.tokens.push(Token {
+        name: name.to_string(),
+        scope,
+        created_at: Local::now(),
+        hash: hash(&secret),
+    });
+    save(&file)?;
+    info!("Created token '{name}' with scope {scope}");
+    Ok(secret)
+}
+
+/// Deletes the token named `name`.
+///
+/// # Errors
+///
+/// An error is returned if there's no such token or the token file cannot be written.
+pub fn revoke(name: &str) -> anyhow::Result<()> {
+    let mut file = load()?;
+    let count = file.tokens.len();
+    file.tokens.retain(|token| token.name != name);
+    if file.tokens.len() == count {
+        bail!("There is no token named '{name}'");
+    }
+    save(&file)
+}
+
+/// The tokens created so far.
+///
+/// # Errors
+///
+/// An error is returned if the token file cannot be read.
+pub fn list() -> anyhow::Result<Vec<Token>> {
+    Ok(load()?.tokens)
+}
+
+/// Decides what a connection presenting `secret` may do. The owner secret allows everything, and
+/// tokens what their scope allows. Returns `None` if the connection may do nothing at all, which
+/// is the case without a secret: trayme's own clients always present one (see [`client_secret`]),
+/// and with `--listen` anyone who can reach the socket could connect.
+///
+/// # Errors
+///
+/// An error is returned if the token file or the owner secret cannot be read.
+pub fn authorize(secret: Option<&str>) -> anyhow::Result<Option<Scope>> {
+    let Some(secret) = secret else {
+        return Ok(None);
+    };
+    Ok(scope_of(secret, &owner_secret()?, &load()?.tokens))
+}
+
+/// What `secret` allows, given the `owner` secret and the `tokens` created so far.
+fn scope_of(secret: &str, owner: &str, tokens: &[Token]) -> Option<Scope> {
+    if secret == owner {
+        return Some(Scope::Admin);
+    }
+    let hash = hash(secret);
+    tokens
+        .iter()
+        .find(|token| token.hash == hash)
+        .map(|token| token.scope)
+}
+
+/// The secret trayme's own clients present: the one in [`TOKEN_VAR`] if it's set, and the owner
+/// secret otherwise.
+///
+/// # Errors
+///
+/// An error is returned if the owner secret cannot be read or created.
+pub fn client_secret() -> anyhow::Result<String> {
+    match std::env::var(TOKEN_VAR) {
+        Ok(secret) if !secret.is_empty() => Ok(secret),
+        _ => owner_secret(),
+    }
+}
+
+/// The secret of the user running trayme, which grants full access to their instances. It's
+/// created on first use in a file only the user can read.
+fn owner_secret() -> anyhow::Result<String> {
+    let path = get_logs_dir()?.join("owner.secret");
+    match std::fs::read_to_string(&path) {
+        Ok(secret) => return Ok(secret.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+    let secret = new_secret()?;
+    // written in full and restricted before it's put in place, so that no one reads it half
+    // written or while others can
+    let temp = path.with_file_name(format!("owner.secret.{}.tmp", std::process::id()));
+    let written = write_private(&temp, &secret).and_then(|()| {
+        // linked rather than renamed, which would replace a secret another process put in place
+        // first and that its clients already use
+        std::fs::hard_link(&temp, &path)
+            .with_context(|| format!("Failed to create {}", path.display()))
+    });
+    let _ = std::fs::remove_file(&temp);
+    match written {
+        Ok(()) => {
+            debug!("Created owner secret at {}", path.display());
+            Ok(secret)
+        }
+        // another trayme process created it first
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::AlreadyExists) =>
+        {
+            Ok(std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+                .trim()
+                .to_string())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `contents` to a new file at `path` that only the user can read.
+fn write_private(path: &std::path::Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    #[cfg(windows)]
+    restrict_to_user(path)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Replaces the permissions `path` inherited from its directory with full access for the user
+/// alone, as `icacls` does.
+#[cfg(windows)]
+fn restrict_to_user(path: &std::path::Path) -> anyhow::Result<()> {
+    let user = std::env::var("USERNAME").context("USERNAME isn't set")?;
+    let status = std::process::Command::new("icacls")
+        .arg(path)
+        .args(["/inheritance:r", "/grant:r"])
+        .arg(format!("{user}:F"))
+        .stdout(std::process::Stdio::null())
+        .status()
+        .context("Failed to run icacls")?;
+    if !status.success() {
+        bail!("icacls failed to restrict {} to {user}", path.display());
+    }
+    Ok(())
+}
+
+fn tokens_path() -> anyhow::Result<PathBuf> {
+    Ok(get_logs_dir()?.join("tokens.toml"))
+}
+
+fn load() -> anyhow::Result<TokenFile> {
+    let path = tokens_path()?;
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenFile::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn save(file: &TokenFile) -> anyhow::Result<()> {
+    let path = tokens_path()?;
+    let contents = toml::to_string(file).context("Failed to serialize tokens")?;
+    std::fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// 32 random bytes in hex.
+fn new_secret() -> anyhow::Result<String> {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).context("Failed to generate a secret")?;
+    Ok(to_hex(&bytes))
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::ControlCommand;
+
+    fn token(name: &str, scope: Scope, secret: &str) -> Token {
+        Token {
+            name: name.to_string(),
+            scope,
+            created_at: Local::now(),
+            hash: hash(secret),
+        }
+    }
+
+    #[test]
+    fn secrets_get_the_scope_of_their_token() {
+        let tokens = [
+            token("bar", Scope::Read, "read-secret"),
+            token("ci", Scope::Control, "control-secret"),
+        ];
+        assert_eq!(scope_of("owner", "owner", &tokens), Some(Scope::Admin));
+        assert_eq!(scope_of("read-secret", "owner", &tokens), Some(Scope::Read));
+        assert_eq!(
+            scope_of("control-secret", "owner", &tokens),
+            Some(Scope::Control)
+        );
+        assert_eq!(scope_of("guess", "owner", &tokens), None);
+        // only the hash is stored, which doesn't work as the secret
+        assert_eq!(scope_of(&tokens[0].hash, "owner", &tokens), None);
+    }
+
+    #[test]
+    fn scopes_allow_the_commands_of_the_scopes_below() {
+        let allows = |scope: Scope, command: ControlCommand| scope >= command.scope();
+        assert!(allows(Scope::Read, ControlCommand::Status));
+        assert!(!allows(Scope::Read, ControlCommand::Kill));
+        assert!(!allows(Scope::Read, ControlCommand::Send("q".to_string())));
+        assert!(allows(Scope::Control, ControlCommand::Subscribe));
+        assert!(allows(Scope::Control, ControlCommand::Kill));
+        assert!(allows(Scope::Control, ControlCommand::Handover));
+        // --no-kill-menu can only be overridden by the owner and admin tokens
+        assert!(!allows(Scope::Control, ControlCommand::ForceKill));
+        assert!(allows(Scope::Admin, ControlCommand::ForceKill));
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::Context;
+use log::debug;
+use tray_icon::{
+    menu::{MenuEvent, MenuEventReceiver},
+    TrayIcon,
+};
+
+#[cfg(test)]
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// What the trays of the trigger modes, such as `--watch-inbox`, show and are told through, so
+/// that they can run without a tray and be tested with a stand-in.
+pub trait TrayBackend {
+    /// Shows `tooltip` on the icon.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the tooltip cannot be set.
+    fn set_tooltip(&mut self, tooltip: &str) -> anyhow::Result<()>;
+
+    /// The ID of the menu item the user clicked since the last call, if any.
+    fn menu_event(&mut self) -> Option<String>;
+}
+
+/// A tray icon and the menu events of its menu.
+pub struct SystemTray {
+    icon: TrayIcon,
+    menu_channel: &'static MenuEventReceiver,
+}
+
+impl SystemTray {
+    pub fn new(icon: TrayIcon) -> Self {
+        Self {
+            icon,
+            menu_channel: MenuEvent::receiver(),
+        }
+    }
+}
+
+impl TrayBackend for SystemTray {
+    fn set_tooltip(&mut self, tooltip: &str) -> anyhow::Result<()> {
+        self.icon
+            .set_tooltip(Some(tooltip))
+            .context("Failed to update tooltip")
+    }
+
+    fn menu_event(&mut self) -> Option<String> {
+        let event = self.menu_channel.try_recv().ok()?;
+        debug!("{event:?}");
+        Some(event.id().0.clone())
+    }
+}
+
+/// No tray at all, for `--headless`: the tooltips are logged and there's no menu to click.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTray;
+
+impl TrayBackend for NoTray {
+    fn set_tooltip(&mut self, tooltip: &str) -> anyhow::Result<()> {
+        debug!("{tooltip}");
+        Ok(())
+    }
+
+    fn menu_event(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// Keeps the tooltips and hands out the menu clicks it was given ahead of time. Clones share
+/// them.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct FakeTray(Arc<Mutex<(Vec<String>, VecDeque<String>)>>);
+
+#[cfg(test)]
+impl FakeTray {
+    /// Makes the next call to [`TrayBackend::menu_event`] return `id`.
+    pub fn click(&self, id: &str) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1
+            .push_back(id.to_string());
+    }
+
+    /// The tooltips shown so far, oldest first.
+    pub fn tooltips(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+            .clone()
+    }
+}
+
+#[cfg(test)]
+impl TrayBackend for FakeTray {
+    fn set_tooltip(&mut self, tooltip: &str) -> anyhow::Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+            .push(tooltip.to_string());
+        Ok(())
+    }
+
+    fn menu_event(&mut self) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1
+            .pop_front()
+    }
+}
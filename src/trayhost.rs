@@ -0,0 +1,123 @@
+use std::{
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+#[cfg(any(windows, all(unix, not(target_os = "macos"))))]
+use std::{sync::mpsc::Sender, thread};
+
+#[cfg(any(windows, all(unix, not(target_os = "macos"))))]
+use log::{debug, info, warn};
+
+/// How often the tray host is checked for a restart.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Notices when the program that shows tray icons restarts: Explorer on Windows, which often
+/// happens after it crashes, and the panel that owns the `StatusNotifierWatcher` on Linux, e.g.
+/// after the display is reconfigured. Tray icons don't always come back by themselves, so the
+/// icon is built again when this says so. macOS keeps menu bar items across restarts, so it's
+/// never reported there.
+pub struct TrayHostWatcher {
+    restarts: Receiver<()>,
+    /// When to try again after a new icon couldn't be built, see [`TrayHostWatcher::retry`].
+    retry_at: Option<Instant>,
+}
+
+impl TrayHostWatcher {
+    /// Starts watching the tray host in the background.
+    pub fn spawn() -> Self {
+        let (sender, restarts) = mpsc::channel();
+        #[cfg(any(windows, all(unix, not(target_os = "macos"))))]
+        thread::spawn(move || watch(&sender));
+        #[cfg(not(any(windows, all(unix, not(target_os = "macos")))))]
+        drop(sender);
+        Self {
+            restarts,
+            retry_at: None,
+        }
+    }
+
+    /// Returns `true` if the tray icon needs to be built again, because the host restarted since
+    /// the last call or an earlier attempt is due to be retried.
+    pub fn restarted(&mut self) -> bool {
+        let mut restarted = false;
+        while self.restarts.try_recv().is_ok() {
+            restarted = true;
+        }
+        if self.retry_at.is_some_and(|at| at <= Instant::now()) {
+            self.retry_at = None;
+            restarted = true;
+        }
+        restarted
+    }
+
+    /// Makes [`TrayHostWatcher::restarted`] return `true` again in a little while, after the
+    /// icon couldn't be built, e.g. because the host wasn't ready for it yet.
+    pub fn retry(&mut self) {
+        self.retry_at = Some(Instant::now() + CHECK_INTERVAL);
+    }
+}
+
+/// Checks the host every [`CHECK_INTERVAL`] until the watcher is dropped, sending on `restarts`
+/// whenever a new one shows up.
+#[cfg(any(windows, all(unix, not(target_os = "macos"))))]
+fn watch(restarts: &Sender<()>) {
+    let mut host = match platform::host() {
+        Ok(host) => host,
+        Err(e) => {
+            warn!("Not watching the tray host, it can't be checked: {e}");
+            return;
+        }
+    };
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+        let current = match platform::host() {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Stopped watching the tray host: {e}");
+                return;
+            }
+        };
+        // the host going away is only worth rebuilding for once a new one is up
+        if current.is_some() && current != host {
+            info!("The tray host restarted, showing the icon again");
+            if restarts.send(()).is_err() {
+                return;
+            }
+        }
+        if current.is_some() {
+            host = current;
+        } else if host.is_some() {
+            debug!("The tray host is gone");
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use crate::display::{self, TRAY_BUS_NAME};
+
+    /// The connection that owns the `StatusNotifierWatcher`, if any.
+    pub fn host() -> std::io::Result<Option<String>> {
+        display::bus_name_owner(TRAY_BUS_NAME)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ptr;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn FindWindowW(class: *const u16, title: *const u16) -> isize;
+    }
+
+    /// The taskbar's window, which is created anew when Explorer restarts, if there is one.
+    #[allow(clippy::unnecessary_wraps)] // matches the other platform
+    pub fn host() -> std::io::Result<Option<isize>> {
+        let class: Vec<u16> = "Shell_TrayWnd\0".encode_utf16().collect();
+        // SAFETY: `class` is a valid, NUL-terminated string for the duration of the call
+        let hwnd = unsafe { FindWindowW(class.as_ptr(), ptr::null()) };
+        Ok((hwnd != 0).then_some(hwnd))
+    }
+}
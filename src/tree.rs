@@ -0,0 +1,377 @@
+use crate::{child::ChildProcess, elevate::Elevation};
+
+/// The child and every process it starts, so that stopping a shell script also stops what the
+/// script ran. On Unix the child leads a process group of its own, which its descendants are in
+/// unless they leave it. On Windows it's put in a job object, which also takes the tree down if
+/// trayme dies.
+#[derive(Debug)]
+pub struct ProcessTree {
+    inner: platform::Tree,
+}
+
+impl ProcessTree {
+    /// Tracks the tree of a child that was just spawned. The tree of an elevated command is
+    /// stopped through `elevation`.
+    pub fn new(child: &ChildProcess, elevation: Option<Elevation>) -> Self {
+        Self {
+            inner: platform::Tree::new(child, elevation),
+        }
+    }
+
+    /// Asks every process in the tree to stop with `SIGTERM` (Unix only).
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the signal cannot be sent.
+    #[cfg(unix)]
+    pub fn terminate(&self) -> anyhow::Result<()> {
+        self.inner.signal(libc::SIGTERM)
+    }
+
+    /// Kills every process in the tree, `child` included.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the tree cannot be killed.
+    pub fn kill(&self, child: &mut ChildProcess) -> anyhow::Result<()> {
+        self.inner.kill(child)
+    }
+
+    /// Kills what's left of the tree after the child exited, e.g. a server a script started in
+    /// the background. Only the first call has an effect, since the group's ID may belong to
+    /// another process later on. The user is only asked to allow killing what's left of an elevated
+    /// tree if something is, and on Windows, what the elevated process started is out of reach
+    /// once it exited.
+    pub fn kill_remaining(&mut self) {
+        self.inner.kill_remaining();
+    }
+
+    /// Lets the tree outlive trayme, for detaching from the process.
+    pub fn release(&mut self) {
+        self.inner.release();
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+
+    use anyhow::Context;
+    use log::debug;
+
+    use crate::{child::ChildProcess, elevate::Elevation};
+
+    #[derive(Debug)]
+    pub struct Tree {
+        /// The ID of the group, which is the child's PID.
+        pgid: libc::pid_t,
+        /// Whether what was left of the group was killed, see `kill_remaining`.
+        finished: bool,
+        /// How the group is signalled if it's root's, see `--elevate`.
+        elevation: Option<Elevation>,
+    }
+
+    impl Tree {
+        pub fn new(child: &ChildProcess, elevation: Option<Elevation>) -> Self {
+            Self {
+                pgid: libc::pid_t::try_from(child.id()).unwrap_or(libc::pid_t::MAX),
+                finished: false,
+                elevation,
+            }
+        }
+
+        pub fn signal(&self, signal: libc::c_int) -> anyhow::Result<()> {
+            if self.finished {
+                return Ok(());
+            }
+            // sudo passes signals on, but SIGKILL would leave the command running without it
+            if let Some(elevation) = &self.elevation {
+                return elevation.signal(self.pgid, signal);
+            }
+            self.signal_directly(signal)
+        }
+
+        fn signal_directly(&self, signal: libc::c_int) -> anyhow::Result<()> {
+            // SAFETY: kill has no memory safety requirements, and a negative PID signals the group
+            if unsafe { libc::kill(-self.pgid, signal) } != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("Failed to signal process group {}", self.pgid));
+            }
+            Ok(())
+        }
+
+        pub fn kill(&self, child: &mut ChildProcess) -> anyhow::Result<()> {
+            match self.signal(libc::SIGKILL) {
+                Ok(()) => Ok(()),
+                // the elevated command can't be killed without the elevation tool
+                Err(e) if self.elevation.is_some() => Err(e),
+                // e.g. the child left the group, so at least it is killed
+                Err(e) => {
+                    debug!("{e:#}");
+                    child.kill().context("Failed to kill child process")
+                }
+            }
+        }
+
+        pub fn kill_remaining(&mut self) {
+            // the user isn't asked to allow killing an elevated group that's empty
+            if self.elevation.is_some() && self.is_empty() {
+                self.finished = true;
+            }
+            // the group is empty most of the time, which fails with ESRCH
+            if !self.finished && self.signal(libc::SIGKILL).is_ok() {
+                debug!("Killed what was left of process group {}", self.pgid);
+            }
+            self.finished = true;
+        }
+
+        /// Whether no process is left in the group, not even one trayme may not signal.
+        fn is_empty(&self) -> bool {
+            // SAFETY: kill has no memory safety requirements, and signal 0 only checks the group
+            let failed = unsafe { libc::kill(-self.pgid, 0) } != 0;
+            failed && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        }
+
+        #[allow(clippy::unused_self)] // the group doesn't die with trayme anyway
+        pub fn release(&mut self) {}
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{io, os::windows::io::AsRawHandle, ptr};
+
+    use anyhow::Context;
+    use log::{debug, warn};
+
+    use crate::{child::ChildProcess, elevate::Elevation};
+
+    type Handle = *mut std::ffi::c_void;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_basic_limit_information
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimits {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        counts: [u64; 6],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimits {
+        basic: BasicLimits,
+        io: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *const std::ffi::c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(
+            job: Handle,
+            class: i32,
+            info: *const std::ffi::c_void,
+            length: u32,
+        ) -> i32;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    #[derive(Debug)]
+    pub struct Tree {
+        /// `None` if the job couldn't be created, in which case only the child is killed.
+        job: Option<Handle>,
+        /// How the elevated process is killed, see `--elevate`. It isn't in the job, and killing
+        /// the child, which waits for it, would leave it running.
+        elevation: Option<Elevation>,
+    }
+
+    impl Tree {
+        pub fn new(child: &ChildProcess, elevation: Option<Elevation>) -> Self {
+            let job = create_job(child)
+                .map_err(|e| warn!("{e:#}, only the process itself will be stopped"))
+                .ok();
+            Self { job, elevation }
+        }
+
+        pub fn kill(&self, child: &mut ChildProcess) -> anyhow::Result<()> {
+            // first, since the elevated process is found as the child's
+            if let Some(elevation) = &self.elevation {
+                elevation.kill_tree(child.id())?;
+            }
+            let Some(job) = self.job else {
+                return child.kill().context("Failed to kill child process");
+            };
+            // SAFETY: the job handle is open until the tree is dropped
+            if unsafe { TerminateJobObject(job, 1) } == 0 {
+                return Err(io::Error::last_os_error()).context("Failed to kill the process tree");
+            }
+            Ok(())
+        }
+
+        pub fn kill_remaining(&mut self) {
+            if let Some(job) = self.job {
+                // SAFETY: the job handle is open until the tree is dropped
+                unsafe {
+                    TerminateJobObject(job, 1);
+                }
+                debug!("Killed what was left of the job");
+            }
+        }
+
+        pub fn release(&mut self) {
+            let Some(job) = self.job else {
+                return;
+            };
+            let limits = ExtendedLimits::default();
+            // SAFETY: the job handle is open and `limits` lives for the duration of the call
+            let cleared = unsafe {
+                SetInformationJobObject(
+                    job,
+                    JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                    ptr::addr_of!(limits).cast(),
+                    u32::try_from(std::mem::size_of::<ExtendedLimits>()).unwrap_or(u32::MAX),
+                )
+            };
+            if cleared == 0 {
+                warn!(
+                    "Failed to let the process outlive trayme: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            if let Some(job) = self.job {
+                // SAFETY: the handle was created by CreateJobObjectW and is only closed here.
+                // Unless the tree was released, this kills whatever is still in the job.
+                unsafe {
+                    CloseHandle(job);
+                }
+            }
+        }
+    }
+
+    /// Creates a job that kills its processes once its last handle is closed, and puts `child`
+    /// in it. Processes the child starts from then on are in it too.
+    fn create_job(child: &ChildProcess) -> anyhow::Result<Handle> {
+        let mut limits = ExtendedLimits::default();
+        limits.basic.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        // SAFETY: all pointers are valid for the duration of the calls, and the job handle is
+        // closed when the tree is dropped
+        unsafe {
+            let job = CreateJobObjectW(ptr::null(), ptr::null());
+            if job.is_null() {
+                return Err(io::Error::last_os_error()).context("Failed to create a job object");
+            }
+            if SetInformationJobObject(
+                job,
+                JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                ptr::addr_of!(limits).cast(),
+                u32::try_from(std::mem::size_of::<ExtendedLimits>())?,
+            ) == 0
+                || AssignProcessToJobObject(job, child.as_raw_handle().cast()) == 0
+            {
+                let err = io::Error::last_os_error();
+                CloseHandle(job);
+                return Err(err).context("Failed to put the process in a job object");
+            }
+            Ok(job)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        io::Read,
+        os::unix::process::{CommandExt, ExitStatusExt},
+        process::{Command, Stdio},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Spawns the shell `script` in a group of its own, as trayme spawns commands. Returns the
+    /// child and a receiver that gets a message once every process holding its stdout is gone,
+    /// which is every process the script started.
+    fn spawn(script: &str) -> (ChildProcess, mpsc::Receiver<()>) {
+        let mut child = Command::new("sh")
+            .args(["-c", script])
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = stdout.read_to_end(&mut Vec::new());
+            let _ = tx.send(());
+        });
+        (child.into(), rx)
+    }
+
+    fn assert_all_gone(gone: &mpsc::Receiver<()>) {
+        assert!(
+            gone.recv_timeout(Duration::from_secs(5)).is_ok(),
+            "a process of the tree is still running"
+        );
+    }
+
+    #[test]
+    fn kills_the_processes_the_child_started() {
+        let (mut child, gone) = spawn("sleep 30 & sleep 30");
+        let tree = ProcessTree::new(&child, None);
+        tree.kill(&mut child).unwrap();
+        let (status, _) = crate::usage::wait(&mut child).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert_all_gone(&gone);
+    }
+
+    #[test]
+    fn terminates_the_group() {
+        let (mut child, gone) = spawn("sleep 30 & wait");
+        let tree = ProcessTree::new(&child, None);
+        tree.terminate().unwrap();
+        let (status, _) = crate::usage::wait(&mut child).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert_all_gone(&gone);
+    }
+
+    #[test]
+    fn kills_what_is_left_once_the_child_exited() {
+        let (mut child, gone) = spawn("sleep 30 &");
+        let mut tree = ProcessTree::new(&child, None);
+        let (status, _) = crate::usage::wait(&mut child).unwrap();
+        assert!(status.success());
+        tree.kill_remaining();
+        assert_all_gone(&gone);
+        // nothing is signalled from then on, since the group's ID may be reused
+        tree.terminate().unwrap();
+    }
+}
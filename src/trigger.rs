@@ -0,0 +1,476 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::OsString,
+    fmt,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use strum::VariantArray;
+use tao::event_loop::ControlFlow;
+use tray_icon::menu::{MenuItem, Submenu};
+
+use crate::{
+    clock::SystemClock,
+    notify::{show_notification, Notifier},
+    osargs, parse,
+    ping::PingUrl,
+    spawner::{ProcessSpawner, SystemSpawner},
+    supervisor::{CommandSpec, Supervisor},
+};
+
+/// The prefix of the menu items that cancel a queued run. It's followed by the run's queue ID.
+const CANCEL_PREFIX: &str = "Cancel/";
+
+/// The menu items shared by the trays of trigger modes such as `trayme clip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VariantArray)]
+pub enum QueueMessage {
+    KillRunning,
+    ClearQueue,
+    ShowLogs,
+    Quit,
+}
+
+impl fmt::Display for QueueMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueMessage::KillRunning => write!(f, "Kill Running"),
+            QueueMessage::ClearQueue => write!(f, "Clear Queue"),
+            QueueMessage::ShowLogs => write!(f, "Show Logs"),
+            QueueMessage::Quit => write!(f, "Quit"),
+        }
+    }
+}
+
+impl FromStr for QueueMessage {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Kill Running" => Ok(QueueMessage::KillRunning),
+            "Clear Queue" => Ok(QueueMessage::ClearQueue),
+            "Show Logs" => Ok(QueueMessage::ShowLogs),
+            "Quit" => Ok(QueueMessage::Quit),
+            _ => Err(strum::ParseError::VariantNotFound),
+        }
+    }
+}
+
+/// A run waiting for a free slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedRun {
+    /// Identifies the run in the queue, e.g. to cancel it from the menu.
+    pub id: u64,
+    /// The trigger's value, e.g. a copied URL.
+    pub value: String,
+}
+
+/// Runs a command template once for every value a trigger produces (e.g. a copied URL), with a
+/// limited number of runs at once. Values that arrive while all slots are taken wait in a queue.
+pub struct RunQueue {
+    /// How the command is run, with the template as its command.
+    spec: CommandSpec,
+    placeholder: &'static str,
+    notifier: Notifier,
+    max_concurrent: NonZeroUsize,
+    pending: VecDeque<QueuedRun>,
+    running: Vec<(String, Supervisor)>,
+    next_id: u64,
+    append_value: bool,
+    /// The values of the runs that finished, and whether they succeeded, if they're kept, see
+    /// [`RunQueue::take_finished`].
+    finished: Option<Vec<(String, bool)>>,
+    /// Pinged around each run, see [`RunQueue::set_ping_url`].
+    ping_url: Option<PingUrl>,
+    spawner: Arc<dyn ProcessSpawner>,
+}
+
+impl RunQueue {
+    /// Creates an empty queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `template` - The command to run. Every occurrence of `placeholder` in its arguments is
+    ///   replaced with the trigger's value (see [`substitute`]).
+    /// * `placeholder` - The placeholder the trigger fills in, e.g. `{clip}`.
+    /// * `notifier` - Used for the start and exit notifications of each run.
+    /// * `max_concurrent` - How many runs may be in progress at once.
+    pub fn new(
+        template: Vec<OsString>,
+        placeholder: &'static str,
+        notifier: Notifier,
+        max_concurrent: NonZeroUsize,
+    ) -> Self {
+        let spec = CommandSpec {
+            cmd: template,
+            cwd: None,
+            env: None,
+            env_overrides: BTreeMap::new(),
+            env_provider: None,
+            tz: None,
+            locale: None,
+            ulimits: Vec::new(),
+            pre_check: None,
+            shell: false,
+            elevate: false,
+            temp_dir: None,
+            interpreters: BTreeMap::new(),
+            caps: None,
+        };
+        Self {
+            spec,
+            placeholder,
+            notifier,
+            max_concurrent,
+            pending: VecDeque::new(),
+            running: Vec::new(),
+            next_id: 0,
+            append_value: true,
+            finished: None,
+            ping_url: None,
+            spawner: Arc::new(SystemSpawner),
+        }
+    }
+
+    /// Spawns the runs with `spawner` instead of starting their commands.
+    #[cfg(test)]
+    pub fn set_spawner(&mut self, spawner: Arc<dyn ProcessSpawner>) {
+        self.spawner = spawner;
+    }
+
+    /// Sets whether the trigger's value is appended to the command when the template has no
+    /// placeholder (see [`substitute`]). On by default.
+    pub fn set_append_value(&mut self, append: bool) {
+        self.append_value = append;
+    }
+
+    /// Runs the command the way `spec` says, e.g. in its working directory and environment, with
+    /// its command as the template.
+    pub fn set_spec(&mut self, spec: CommandSpec) {
+        self.spec = spec;
+    }
+
+    /// Pings `url` whenever a run starts, succeeds, or fails, see `--ping-url`.
+    pub fn set_ping_url(&mut self, url: PingUrl) {
+        self.ping_url = Some(url);
+    }
+
+    /// Keeps the outcome of every run, for [`RunQueue::take_finished`]. Off by default.
+    pub fn set_keep_finished(&mut self) {
+        self.finished.get_or_insert_with(Vec::new);
+    }
+
+    /// The values of the runs that finished since the last call, and whether each succeeded.
+    /// Runs that failed to start count as failed. Always empty unless
+    /// [`RunQueue::set_keep_finished`] was called.
+    pub fn take_finished(&mut self) -> Vec<(String, bool)> {
+        self.finished
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Queues a run for `value`. It starts on the next [`RunQueue::poll`] if a slot is free.
+    pub fn push(&mut self, value: String) {
+        info!("Queued run for '{value}'");
+        self.pending.push_back(QueuedRun {
+            id: self.next_id,
+            value,
+        });
+        self.next_id += 1;
+    }
+
+    /// Checks on the runs in progress and starts queued ones as slots free up. Runs that fail to
+    /// spawn are reported and skipped.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a run's status cannot be queried or recorded.
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        for (_, supervisor) in &mut self.running {
+            supervisor.poll()?;
+        }
+        let finished = &mut self.finished;
+        self.running.retain(|(value, supervisor)| {
+            if !supervisor.is_finished() {
+                return true;
+            }
+            if let Some(finished) = finished.as_mut() {
+                finished.push((value.clone(), supervisor.succeeded()));
+            }
+            false
+        });
+        while self.running.len() < self.max_concurrent.get() {
+            let Some(run) = self.pending.pop_front() else {
+                break;
+            };
+            let cmd = if self.append_value {
+                substitute(&self.spec.cmd, self.placeholder, &run.value)
+            } else {
+                fill(&self.spec.cmd, self.placeholder, &run.value)
+            };
+            let spec = CommandSpec {
+                cmd,
+                ..self.spec.clone()
+            };
+            let name = self.spec.program_name();
+            let started = Supervisor::start_with(
+                name,
+                spec,
+                self.notifier.clone(),
+                Arc::new(SystemClock),
+                self.spawner.clone(),
+            );
+            match started {
+                Ok(mut supervisor) => {
+                    if let Some(url) = &self.ping_url {
+                        supervisor.set_ping_url(url.clone());
+                    }
+                    self.running.push((run.value, supervisor));
+                }
+                Err(e) => {
+                    error!("{e:#}");
+                    show_notification("Failed to start run", &format!("{e:#}"));
+                    if let Some(url) = &self.ping_url {
+                        url.fail(&format!("{e:#}"));
+                    }
+                    if let Some(finished) = self.finished.as_mut() {
+                        finished.push((run.value, false));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The values the runs in progress were started for, oldest first.
+    pub fn running(&self) -> impl Iterator<Item = &str> {
+        self.running.iter().map(|(value, _)| value.as_str())
+    }
+
+    /// The runs waiting for a free slot, in the order they'll start.
+    pub fn pending(&self) -> impl Iterator<Item = &QueuedRun> {
+        self.pending.iter()
+    }
+
+    /// Stops every run in progress. Queued runs start on the next [`RunQueue::poll`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a process cannot be stopped. The other runs are still stopped.
+    pub fn kill_running(&mut self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for (value, mut supervisor) in self.running.drain(..) {
+            if let Err(e) = supervisor.kill() {
+                error!("Failed to kill run for '{value}': {e:#}");
+                result = Err(e).with_context(|| format!("Failed to kill run for '{value}'"));
+            }
+            if let Some(finished) = self.finished.as_mut() {
+                finished.push((value, false));
+            }
+        }
+        result
+    }
+
+    /// Removes a queued run before it starts. Returns `false` if it's no longer queued.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        let Some(index) = self.pending.iter().position(|run| run.id == id) else {
+            return false;
+        };
+        if let Some(run) = self.pending.remove(index) {
+            info!("Cancelled queued run for '{}'", run.value);
+        }
+        true
+    }
+
+    /// Drops every queued run. The runs in progress keep running.
+    pub fn clear(&mut self) {
+        info!("Dropped {} queued runs", self.pending.len());
+        self.pending.clear();
+    }
+
+    /// Drops every queued run and stops the ones in progress.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a process cannot be stopped.
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        self.clear();
+        self.kill_running()
+    }
+
+    /// Carries out a menu action, including the cancel items of [`QueueStatus`]. Returns the
+    /// [`ControlFlow`] for the next iteration of the event loop. Unknown menu items are ignored.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a run cannot be stopped or the logs directory cannot be opened.
+    pub fn handle_menu_event(&mut self, id: &str) -> anyhow::Result<ControlFlow> {
+        if let Some(queue_id) = parse::numbered_menu_id(id, CANCEL_PREFIX) {
+            if !self.cancel(queue_id) {
+                show_notification("Run not cancelled", "It has already started");
+            }
+            return Ok(ControlFlow::Poll);
+        }
+        // a stray event must not take the tray down with it
+        let Ok(msg) = QueueMessage::from_str(id) else {
+            warn!("Ignoring unknown menu item '{id}'");
+            return Ok(ControlFlow::Poll);
+        };
+        match msg {
+            QueueMessage::KillRunning => self.kill_running()?,
+            QueueMessage::ClearQueue => self.clear(),
+            QueueMessage::ShowLogs => {
+                open::that(crate::get_logs_dir()?).context("Failed to open logs dir")?;
+            }
+            QueueMessage::Quit => {
+                self.stop()?;
+                return Ok(ControlFlow::Exit);
+            }
+        }
+        Ok(ControlFlow::Poll)
+    }
+}
+
+/// Menu entries showing what a [`RunQueue`] is doing: a disabled item for the runs in progress,
+/// and a submenu listing the queued runs, where clicking one cancels it.
+pub struct QueueStatus {
+    pub running: MenuItem,
+    pub queued: Submenu,
+    queued_items: Vec<MenuItem>,
+    shown: Option<(Vec<String>, Vec<QueuedRun>)>,
+}
+
+impl QueueStatus {
+    pub fn new() -> Self {
+        Self {
+            running: MenuItem::new("Idle", false, None),
+            queued: Submenu::new("0 queued", false),
+            queued_items: Vec::new(),
+            shown: None,
+        }
+    }
+
+    /// Updates the entries if the queue changed since the last call.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the queued submenu cannot be rebuilt.
+    pub fn update(&mut self, queue: &RunQueue) -> anyhow::Result<()> {
+        let running: Vec<_> = queue.running().map(str::to_string).collect();
+        let pending: Vec<_> = queue.pending().cloned().collect();
+        if let Some((shown_running, shown_pending)) = &self.shown {
+            if *shown_running == running && *shown_pending == pending {
+                return Ok(());
+            }
+        }
+
+        self.running.set_text(match running.as_slice() {
+            [] => "Idle".to_string(),
+            [value] => format!("Running: {value}"),
+            values => format!("{} running", values.len()),
+        });
+
+        for item in self.queued_items.drain(..) {
+            self.queued.remove(&item)?;
+        }
+        for run in &pending {
+            let item = MenuItem::with_id(
+                format!("{CANCEL_PREFIX}{}", run.id),
+                format!("Cancel {}", run.value),
+                true,
+                None,
+            );
+            self.queued.append(&item)?;
+            self.queued_items.push(item);
+        }
+        self.queued.set_text(format!("{} queued", pending.len()));
+        self.queued.set_enabled(!pending.is_empty());
+
+        self.shown = Some((running, pending));
+        Ok(())
+    }
+}
+
+/// Fills a trigger's value into a command template. Every occurrence of `placeholder` in the
+/// arguments is replaced, and the value is appended as the last argument if there are none.
+pub fn substitute(template: &[OsString], placeholder: &str, value: &str) -> Vec<OsString> {
+    if !template
+        .iter()
+        .any(|arg| osargs::contains(arg, placeholder))
+    {
+        return template
+            .iter()
+            .cloned()
+            .chain(std::iter::once(value.into()))
+            .collect();
+    }
+    fill(template, placeholder, value)
+}
+
+/// Replaces every occurrence of `placeholder` in the arguments with `value`.
+pub fn fill(template: &[OsString], placeholder: &str, value: &str) -> Vec<OsString> {
+    template
+        .iter()
+        .map(|arg| osargs::replace(arg, placeholder, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::NotifyUrgency;
+
+    fn strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn substitutes_every_placeholder() {
+        let template = strings(&["yt-dlp", "-o", "{clip}.mp4", "{clip}"]);
+        assert_eq!(
+            substitute(&template, "{clip}", "url"),
+            ["yt-dlp", "-o", "url.mp4", "url"]
+        );
+    }
+
+    #[test]
+    fn appends_without_a_placeholder() {
+        let template = strings(&["open"]);
+        assert_eq!(substitute(&template, "{clip}", "url"), ["open", "url"]);
+        assert_eq!(fill(&template, "{clip}", "url"), ["open"]);
+    }
+
+    #[test]
+    fn queue_messages_round_trip() {
+        for message in QueueMessage::VARIANTS {
+            assert_eq!(message.to_string().parse::<QueueMessage>(), Ok(*message));
+        }
+    }
+
+    #[test]
+    fn cancels_and_clears_queued_runs() {
+        let mut queue = RunQueue::new(
+            strings(&["echo"]),
+            "{clip}",
+            Notifier::new(NotifyUrgency::Normal, []),
+            NonZeroUsize::MIN,
+        );
+        for value in ["a", "b", "c"] {
+            queue.push(value.to_string());
+        }
+        assert!(queue.cancel(1));
+        assert!(!queue.cancel(1));
+        let pending: Vec<_> = queue
+            .pending()
+            .map(|run| (run.id, run.value.as_str()))
+            .collect();
+        assert_eq!(pending, [(0, "a"), (2, "c")]);
+        queue.clear();
+        assert_eq!(queue.pending().count(), 0);
+        assert_eq!(queue.running().count(), 0);
+    }
+}
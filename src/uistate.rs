@@ -0,0 +1,452 @@
+use std::fmt;
+
+use crate::{icon, ipc::ProcessState, TrayMessage};
+
+/// The color of the tray icon while the process is paused.
+const PAUSED_COLOR: [u8; 3] = [0x9e, 0x9e, 0x9e];
+
+/// The color of the tray icon while the process waits to be restarted.
+const RESTARTING_COLOR: [u8; 3] = [0xfb, 0x8c, 0x00];
+
+/// What the supervisor reports about the instance on each tick of the event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // independent facts, which the state is made of
+pub struct Observation {
+    pub process: ProcessState,
+    /// Whether the output is under the `--unhealthy-if` threshold.
+    pub healthy: bool,
+    pub paused: bool,
+    /// Whether the run has lasted long enough to count as stable, see
+    /// [`crate::restart::STABLE_RUN`].
+    pub stable: bool,
+    /// Whether the last run ended cleanly, for a process that exited or waits to be restarted.
+    pub clean: bool,
+    /// The progress the run reported, see `--progress-regex`.
+    pub percent: Option<u8>,
+}
+
+/// The state of an instance as its own tray shows it. Unlike [`crate::state::TrayState`], which
+/// is all that the other instances' status tells, this knows whether the process is paused and
+/// how long it has been up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiState {
+    /// Started, but not up for long enough to count as stable yet.
+    Running,
+    /// Up for long enough to count as stable.
+    Ready,
+    /// Running, but over its `--unhealthy-if` threshold.
+    Unhealthy,
+    /// Exited and waiting to be started again.
+    Restarting,
+    /// Exited with a status that isn't clean.
+    Failed,
+    /// Suspended from the tray, over IPC, or for a busy event of the calendar.
+    Paused,
+    /// Exited cleanly, was killed, or was left running.
+    Stopped,
+}
+
+impl UiState {
+    /// The state an observation puts the tray in. Being paused trumps being unhealthy, since a
+    /// paused process can't get better, and being unhealthy trumps being stable.
+    pub fn of(observation: Observation) -> Self {
+        match observation.process {
+            ProcessState::Running if observation.paused => UiState::Paused,
+            ProcessState::Running if !observation.healthy => UiState::Unhealthy,
+            ProcessState::Running if observation.stable => UiState::Ready,
+            ProcessState::Running => UiState::Running,
+            ProcessState::Restarting => UiState::Restarting,
+            ProcessState::Exited if observation.clean => UiState::Stopped,
+            ProcessState::Exited => UiState::Failed,
+            ProcessState::Killed | ProcessState::Detached => UiState::Stopped,
+        }
+    }
+
+    /// Whether there's a process, which can be paused, restarted, or have its windows toggled.
+    pub fn has_process(self) -> bool {
+        matches!(
+            self,
+            UiState::Running | UiState::Ready | UiState::Unhealthy | UiState::Paused
+        )
+    }
+
+    /// Whether the instance is over, after which the tray exits unless `--stay-open` keeps it
+    /// up.
+    pub fn is_finished(self) -> bool {
+        matches!(self, UiState::Failed | UiState::Stopped)
+    }
+
+    /// A glyph for the state, shown in the tooltip and, with `--status-glyphs`, next to the icon.
+    pub fn glyph(self) -> &'static str {
+        match self {
+            UiState::Running => "○",
+            UiState::Ready => "●",
+            UiState::Unhealthy => "▲",
+            UiState::Restarting => "↻",
+            UiState::Failed => "✖",
+            UiState::Paused => "‖",
+            UiState::Stopped => "■",
+        }
+    }
+
+    /// What the tooltip adds in parentheses, for the states the glyph alone doesn't make clear.
+    pub fn flag(self) -> Option<&'static str> {
+        match self {
+            UiState::Paused => Some("paused"),
+            UiState::Restarting => Some("restarting"),
+            UiState::Running
+            | UiState::Ready
+            | UiState::Unhealthy
+            | UiState::Failed
+            | UiState::Stopped => None,
+        }
+    }
+
+    /// Whether a menu item does anything in this state.
+    pub fn enables(self, msg: TrayMessage) -> bool {
+        match msg {
+            TrayMessage::Restart
+            | TrayMessage::RestartWith
+            | TrayMessage::Detach
+            | TrayMessage::SendInput => self.has_process(),
+            TrayMessage::Pause => self.has_process() && self != UiState::Paused,
+            TrayMessage::Resume => self == UiState::Paused,
+            TrayMessage::ShowWindow | TrayMessage::HideWindow => {
+                self.has_process() && self != UiState::Paused
+            }
+            TrayMessage::Kill => !self.is_finished(),
+            TrayMessage::Relaunch | TrayMessage::Quit => self.is_finished(),
+            TrayMessage::ShowLogs
+            | TrayMessage::RotateLog
+            | TrayMessage::PurgeLogs
+            | TrayMessage::OpenTempDir
+            | TrayMessage::Console
+            | TrayMessage::Environment
+            | TrayMessage::EnvDiff
+            | TrayMessage::Maintenance
+            | TrayMessage::Rename => true,
+        }
+    }
+}
+
+impl fmt::Display for UiState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UiState::Running => write!(f, "starting"),
+            UiState::Ready => write!(f, "running"),
+            UiState::Unhealthy => write!(f, "unhealthy"),
+            UiState::Restarting => write!(f, "restarting"),
+            UiState::Failed => write!(f, "failed"),
+            UiState::Paused => write!(f, "paused"),
+            UiState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Which icon the tray shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconKind {
+    /// The instance's own icon.
+    Default,
+    /// How far along the run is.
+    Progress(u8),
+    /// A round icon of this color.
+    Status([u8; 3]),
+}
+
+impl IconKind {
+    /// Builds the icon, with `default` being the instance's own.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the icon cannot be built.
+    pub fn build(self, default: &tray_icon::Icon) -> anyhow::Result<tray_icon::Icon> {
+        match self {
+            IconKind::Default => Ok(default.clone()),
+            IconKind::Progress(percent) => icon::progress(percent),
+            IconKind::Status(color) => icon::status(color),
+        }
+    }
+}
+
+/// What has to be redrawn after an observation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// The state changed, so the glyph, tooltip, and menu have to follow.
+    pub state: bool,
+    /// The icon changed.
+    pub icon: bool,
+}
+
+/// Follows the observations of an instance and decides what its tray shows, so that the icon,
+/// tooltip, and menu always agree on the state. It's pure: nothing is drawn here.
+#[derive(Debug, Clone)]
+pub struct StateMachine {
+    state: UiState,
+    percent: Option<u8>,
+}
+
+impl StateMachine {
+    /// Starts out with a process that was just started.
+    pub fn new() -> Self {
+        Self {
+            state: UiState::Running,
+            percent: None,
+        }
+    }
+
+    pub fn state(&self) -> UiState {
+        self.state
+    }
+
+    /// Moves to the state of `observation`, and returns what changed.
+    pub fn observe(&mut self, observation: Observation) -> Changes {
+        let icon = self.icon();
+        let state = UiState::of(observation);
+        let changed = state != self.state;
+        self.state = state;
+        self.percent = observation.percent;
+        Changes {
+            state: changed,
+            icon: self.icon() != icon,
+        }
+    }
+
+    /// The icon for the current state: those that need attention get a status icon, and a
+    /// running process shows its progress if it reports any, and its own icon otherwise.
+    pub fn icon(&self) -> IconKind {
+        match self.state {
+            UiState::Unhealthy | UiState::Failed => IconKind::Status(icon::UNHEALTHY_COLOR),
+            UiState::Restarting => IconKind::Status(RESTARTING_COLOR),
+            UiState::Paused => IconKind::Status(PAUSED_COLOR),
+            UiState::Running | UiState::Ready => {
+                self.percent.map_or(IconKind::Default, IconKind::Progress)
+            }
+            UiState::Stopped => IconKind::Default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::VariantArray;
+
+    use super::*;
+
+    const PROCESS_STATES: [ProcessState; 5] = [
+        ProcessState::Running,
+        ProcessState::Exited,
+        ProcessState::Killed,
+        ProcessState::Detached,
+        ProcessState::Restarting,
+    ];
+
+    const STATES: [UiState; 7] = [
+        UiState::Running,
+        UiState::Ready,
+        UiState::Unhealthy,
+        UiState::Restarting,
+        UiState::Failed,
+        UiState::Paused,
+        UiState::Stopped,
+    ];
+
+    /// Every observation there can be, with and without progress.
+    fn observations() -> Vec<Observation> {
+        let mut all = Vec::new();
+        for process in PROCESS_STATES {
+            for bits in 0..16_u8 {
+                for percent in [None, Some(0), Some(50)] {
+                    all.push(Observation {
+                        process,
+                        healthy: bits & 1 != 0,
+                        paused: bits & 2 != 0,
+                        stable: bits & 4 != 0,
+                        clean: bits & 8 != 0,
+                        percent,
+                    });
+                }
+            }
+        }
+        all
+    }
+
+    fn running() -> Observation {
+        Observation {
+            process: ProcessState::Running,
+            healthy: true,
+            paused: false,
+            stable: false,
+            clean: false,
+            percent: None,
+        }
+    }
+
+    #[test]
+    fn every_state_is_reachable() {
+        for state in STATES {
+            assert!(
+                observations().iter().any(|o| UiState::of(*o) == state),
+                "{state:?} is never reached"
+            );
+        }
+    }
+
+    #[test]
+    fn process_state_decides_whether_there_is_a_process() {
+        for observation in observations() {
+            let state = UiState::of(observation);
+            assert_eq!(
+                state.has_process(),
+                observation.process == ProcessState::Running,
+                "{observation:?} gives {state:?}"
+            );
+            assert_eq!(
+                state.is_finished(),
+                matches!(
+                    observation.process,
+                    ProcessState::Exited | ProcessState::Killed | ProcessState::Detached
+                ),
+                "{observation:?} gives {state:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn paused_trumps_unhealthy_trumps_stable() {
+        let paused = Observation {
+            paused: true,
+            healthy: false,
+            stable: true,
+            ..running()
+        };
+        assert_eq!(UiState::of(paused), UiState::Paused);
+        let unhealthy = Observation {
+            paused: false,
+            ..paused
+        };
+        assert_eq!(UiState::of(unhealthy), UiState::Unhealthy);
+        let ready = Observation {
+            healthy: true,
+            ..unhealthy
+        };
+        assert_eq!(UiState::of(ready), UiState::Ready);
+        assert_eq!(UiState::of(running()), UiState::Running);
+    }
+
+    #[test]
+    fn only_unclean_exits_fail() {
+        let exited = Observation {
+            process: ProcessState::Exited,
+            ..running()
+        };
+        assert_eq!(UiState::of(exited), UiState::Failed);
+        let clean = Observation {
+            clean: true,
+            ..exited
+        };
+        assert_eq!(UiState::of(clean), UiState::Stopped);
+        for process in [ProcessState::Killed, ProcessState::Detached] {
+            for clean in [false, true] {
+                let observation = Observation {
+                    process,
+                    clean,
+                    ..running()
+                };
+                assert_eq!(UiState::of(observation), UiState::Stopped);
+            }
+        }
+    }
+
+    #[test]
+    fn glyphs_tell_states_apart() {
+        for (i, a) in STATES.iter().enumerate() {
+            for b in &STATES[i + 1..] {
+                assert_ne!(a.glyph(), b.glyph(), "{a:?} and {b:?}");
+                assert_ne!(a.to_string(), b.to_string(), "{a:?} and {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn menu_never_contradicts_the_state() {
+        for state in STATES {
+            let enabled = |msg| state.enables(msg);
+            // exactly one of pausing and resuming while there's a process, neither without
+            assert_eq!(
+                u8::from(enabled(TrayMessage::Pause)) + u8::from(enabled(TrayMessage::Resume)),
+                u8::from(state.has_process()),
+                "{state:?}"
+            );
+            assert_eq!(
+                enabled(TrayMessage::Kill),
+                !state.is_finished(),
+                "{state:?}"
+            );
+            for msg in [
+                TrayMessage::Restart,
+                TrayMessage::RestartWith,
+                TrayMessage::Detach,
+                TrayMessage::SendInput,
+                TrayMessage::ShowWindow,
+                TrayMessage::HideWindow,
+            ] {
+                if enabled(msg) {
+                    assert!(state.has_process(), "{msg:?} in {state:?}");
+                }
+            }
+            for msg in [TrayMessage::Relaunch, TrayMessage::Quit] {
+                assert_eq!(enabled(msg), state.is_finished(), "{msg:?} in {state:?}");
+            }
+            for msg in TrayMessage::VARIANTS {
+                if enabled(*msg) && msg.is_destructive() {
+                    assert!(!state.is_finished(), "{msg:?} in {state:?}");
+                }
+            }
+        }
+        assert!(!UiState::Restarting.enables(TrayMessage::Restart));
+        assert!(UiState::Restarting.enables(TrayMessage::Kill));
+    }
+
+    #[test]
+    fn icon_follows_the_state() {
+        for observation in observations() {
+            let mut machine = StateMachine::new();
+            machine.observe(observation);
+            let icon = machine.icon();
+            match machine.state() {
+                UiState::Running | UiState::Ready => assert_eq!(
+                    icon,
+                    observation
+                        .percent
+                        .map_or(IconKind::Default, IconKind::Progress),
+                    "{observation:?}"
+                ),
+                UiState::Stopped => assert_eq!(icon, IconKind::Default),
+                state => assert!(
+                    matches!(icon, IconKind::Status(_)),
+                    "{state:?} shows {icon:?}"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn changes_are_reported_once() {
+        for from in observations() {
+            for to in observations() {
+                let mut machine = StateMachine::new();
+                machine.observe(from);
+                let (state, icon) = (machine.state(), machine.icon());
+                let changes = machine.observe(to);
+                assert_eq!(
+                    changes.state,
+                    machine.state() != state,
+                    "{from:?} to {to:?}"
+                );
+                assert_eq!(changes.icon, machine.icon() != icon, "{from:?} to {to:?}");
+                assert_eq!(machine.observe(to), Changes::default(), "{to:?} again");
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::Context;
+use tray_icon::menu::{MenuItem, Submenu};
+
+use crate::qr;
+
+/// The prefix of the IDs of the items in the "Open…" submenu, followed by the URL.
+pub const OPEN_PREFIX: &str = "Open/";
+
+/// The prefix of the IDs of the items in the "Show QR…" submenu, followed by the URL.
+pub const QR_PREFIX: &str = "QR/";
+
+/// How many URLs are kept per run. Once there are more, the oldest ones are dropped.
+const MAX_URLS: usize = 10;
+
+/// Finds the `http` and `https` URLs in a line of output, without the punctuation that usually
+/// follows them in a sentence.
+pub fn find_urls(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line;
+    std::iter::from_fn(move || loop {
+        let start = ["http://", "https://"]
+            .into_iter()
+            .filter_map(|scheme| rest.find(scheme).map(|at| (at, scheme.len())))
+            .min()?;
+        let candidate = &rest[start.0..];
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>' | '`'))
+            .unwrap_or(candidate.len());
+        rest = &candidate[end..];
+        let url = trim_trailing(&candidate[..end]);
+        // a scheme without a host, e.g. in "use http:// or https://"
+        if url.len() > start.1 {
+            return Some(url);
+        }
+    })
+}
+
+/// Trims the punctuation after a URL, keeping closing parentheses that belong to it, as in
+/// Wikipedia links.
+fn trim_trailing(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?', ']', '}']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(inner) if inner.matches('(').count() <= inner.matches(')').count() => inner,
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Servers often say they listen on `0.0.0.0` or `[::]`, meaning every address, which browsers
+/// can't connect to on every platform, so those are opened as `localhost`.
+fn openable(url: &str) -> String {
+    for any in ["0.0.0.0", "[::]"] {
+        for scheme in ["http://", "https://"] {
+            if let Some(rest) = url.strip_prefix(scheme).and_then(|r| r.strip_prefix(any)) {
+                if rest.is_empty() || rest.starts_with([':', '/']) {
+                    return format!("{scheme}localhost{rest}");
+                }
+            }
+        }
+    }
+    url.to_string()
+}
+
+/// The URLs a run printed, in the order they first appeared.
+#[derive(Debug, Clone, Default)]
+pub struct UrlTracker {
+    urls: Vec<String>,
+}
+
+impl UrlTracker {
+    /// Adds the URLs in `line` that haven't been seen yet.
+    pub fn observe(&mut self, line: &str) {
+        for url in find_urls(line).map(openable) {
+            if self.urls.contains(&url) {
+                continue;
+            }
+            if self.urls.len() == MAX_URLS {
+                self.urls.remove(0);
+            }
+            self.urls.push(url);
+        }
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    pub fn reset(&mut self) {
+        self.urls.clear();
+    }
+}
+
+/// The "Open…" and "Show QR…" submenus of the tray, listing the URLs the current run printed.
+/// Clicking one opens it in the browser, or shows a QR code of it for opening it on a phone.
+pub struct UrlMenu {
+    pub submenu: Submenu,
+    pub qr_submenu: Submenu,
+    /// The items of each submenu.
+    items: [Vec<MenuItem>; 2],
+    shown: Vec<String>,
+}
+
+impl UrlMenu {
+    pub fn new() -> Self {
+        Self {
+            submenu: Submenu::new("Open…", false),
+            qr_submenu: Submenu::new("Show QR…", false),
+            items: [Vec::new(), Vec::new()],
+            shown: Vec::new(),
+        }
+    }
+
+    /// Updates the submenu if the URLs changed since the last call.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the submenu cannot be rebuilt.
+    pub fn update(&mut self, urls: &[String]) -> anyhow::Result<()> {
+        if self.shown == urls {
+            return Ok(());
+        }
+        let submenus = [(OPEN_PREFIX, &self.submenu), (QR_PREFIX, &self.qr_submenu)];
+        for ((prefix, submenu), items) in submenus.into_iter().zip(&mut self.items) {
+            for item in items.drain(..) {
+                submenu.remove(&item)?;
+            }
+            for url in urls {
+                let item = MenuItem::with_id(format!("{prefix}{url}"), url, true, None);
+                submenu.append(&item)?;
+                items.push(item);
+            }
+            submenu.set_enabled(!urls.is_empty());
+        }
+        self.shown = urls.to_vec();
+        Ok(())
+    }
+
+    /// Opens the URL of the clicked item in the browser, or shows its QR code. Returns `false`
+    /// if the item isn't in either submenu.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the browser can't be opened or the QR code can't be shown.
+    pub fn handle(&self, id: &str) -> anyhow::Result<bool> {
+        let (url, show_qr) = match (id.strip_prefix(OPEN_PREFIX), id.strip_prefix(QR_PREFIX)) {
+            (Some(url), _) => (url, false),
+            (None, Some(url)) => (url, true),
+            (None, None) => return Ok(false),
+        };
+        // only URLs the process printed are opened, whatever the ID says
+        let Some(index) = self.shown.iter().position(|shown| shown == url) else {
+            return Ok(false);
+        };
+        if show_qr {
+            qr::show(url, index)?;
+        } else {
+            open::that(url).with_context(|| format!("Failed to open {url}"))?;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,461 @@
+use std::{fmt, io, process::ExitStatus, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::child::ChildProcess;
+
+/// The resources a finished run used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// The peak resident set size (working set on Windows) in bytes, if the platform reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_rss_bytes: Option<u64>,
+    /// User plus system CPU time, in milliseconds.
+    pub cpu_time_ms: u64,
+    /// How many bytes the run wrote to its log file.
+    #[serde(default)]
+    pub log_bytes: u64,
+    /// The emulator the process ran under, if it was built for another architecture. Its memory
+    /// and CPU time include the emulator's own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emulation: Option<Emulation>,
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(peak) = self.peak_rss_bytes {
+            write!(f, "peak RSS {}, ", format_bytes(peak))?;
+        }
+        write!(
+            f,
+            "CPU {}, {} logged",
+            humantime::format_duration(Duration::from_millis(self.cpu_time_ms)),
+            format_bytes(self.log_bytes)
+        )?;
+        if let Some(emulation) = self.emulation {
+            write!(f, ", {emulation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How a process built for another architecture was run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Emulation {
+    /// An `x86_64` binary on Apple Silicon, translated by Rosetta 2.
+    Rosetta,
+    /// An x64 or 32-bit x86 binary on Windows on ARM, run by its x86 emulator.
+    X86OnArm,
+}
+
+impl fmt::Display for Emulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Emulation::Rosetta => write!(f, "translated by Rosetta 2"),
+            Emulation::X86OnArm => write!(f, "emulated x86 on ARM"),
+        }
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    #[allow(clippy::cast_precision_loss)] // only used for display
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// How the exit status and resource usage of a child are read on a platform. Each platform has
+/// its own way of reading them, and of telling whether the child was emulated, so they're
+/// implemented per platform instead of with a best-effort common path.
+trait Monitor {
+    /// Reaps `child` if it exited, or waits for it to exit if `block` is set, and reads the
+    /// resources it used. The log size is left at zero, since it isn't known here.
+    fn reap(
+        child: &mut ChildProcess,
+        block: bool,
+    ) -> io::Result<Option<(ExitStatus, ResourceUsage)>>;
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+type Platform = unix::Unix;
+#[cfg(target_os = "macos")]
+type Platform = macos::MacOs;
+#[cfg(windows)]
+type Platform = win::Windows;
+
+/// Like [`std::process::Child::try_wait`], but also collects the resource usage of the child
+/// once it exited. The log size is left at zero, since it isn't known here.
+///
+/// On Unix, the child is reaped with `wait4` instead of through `child`, so `child` must not be
+/// waited on again afterwards.
+///
+/// # Errors
+///
+/// An error is returned if the child's status cannot be queried.
+pub fn try_wait(child: &mut ChildProcess) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    Platform::reap(child, false)
+}
+
+/// Like [`std::process::Child::wait`], but also collects the resource usage of the child. See
+/// [`try_wait`].
+///
+/// # Errors
+///
+/// An error is returned if the child cannot be waited on.
+pub fn wait(child: &mut ChildProcess) -> io::Result<(ExitStatus, ResourceUsage)> {
+    Platform::reap(child, true)?.ok_or_else(|| io::Error::other("child is still running"))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{io, os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use super::ResourceUsage;
+    use crate::child::ChildProcess;
+
+    /// The unit of `ru_maxrss`, which differs between platforms. It's bytes on macOS on both
+    /// Intel and Apple Silicon.
+    #[cfg(target_os = "macos")]
+    const MAXRSS_UNIT: u64 = 1;
+    #[cfg(not(target_os = "macos"))]
+    const MAXRSS_UNIT: u64 = 1024;
+
+    /// Linux and the BSDs report everything through `wait4`.
+    #[cfg(not(target_os = "macos"))]
+    pub struct Unix;
+
+    #[cfg(not(target_os = "macos"))]
+    impl super::Monitor for Unix {
+        fn reap(
+            child: &mut ChildProcess,
+            block: bool,
+        ) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+            wait4(child, block)
+        }
+    }
+
+    pub fn pid(child: &ChildProcess) -> io::Result<libc::pid_t> {
+        libc::pid_t::try_from(child.id()).map_err(|_| io::Error::other("PID out of range"))
+    }
+
+    pub fn wait4(
+        child: &ChildProcess,
+        block: bool,
+    ) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+        let pid = pid(child)?;
+        let options = if block { 0 } else { libc::WNOHANG };
+        let mut status = 0;
+        // SAFETY: rusage is plain old data, so all zeroes is a valid value
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            // SAFETY: both pointers are valid for the duration of the call
+            let reaped = unsafe {
+                libc::wait4(
+                    pid,
+                    std::ptr::addr_of_mut!(status),
+                    options,
+                    std::ptr::addr_of_mut!(rusage),
+                )
+            };
+            match reaped {
+                0 => return Ok(None),
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                _ => break,
+            }
+        }
+        let usage = ResourceUsage {
+            peak_rss_bytes: u64::try_from(rusage.ru_maxrss)
+                .ok()
+                .map(|rss| rss * MAXRSS_UNIT),
+            cpu_time_ms: timeval_ms(rusage.ru_utime) + timeval_ms(rusage.ru_stime),
+            log_bytes: 0,
+            emulation: None,
+        };
+        Ok(Some((ExitStatus::from_raw(status), usage)))
+    }
+
+    fn timeval_ms(time: libc::timeval) -> u64 {
+        let secs = u64::try_from(time.tv_sec).unwrap_or_default();
+        let micros = u64::try_from(time.tv_usec).unwrap_or_default();
+        secs * 1000 + micros / 1000
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::{io, process::ExitStatus};
+
+    use super::{unix, Emulation, ResourceUsage};
+    use crate::child::ChildProcess;
+
+    /// Set in `p_flag` of processes translated by Rosetta 2, see `sys/proc.h`.
+    const P_TRANSLATED: i32 = 0x0002_0000;
+    /// The offset of `p_flag` in `struct kinfo_proc`, after `p_un`, `p_vmspace`, and `p_sigacts`
+    /// of its `kp_proc`. `libc` doesn't define the struct on macOS, and this is all that's
+    /// needed from it.
+    const P_FLAG_OFFSET: usize = 32;
+    /// The size of `struct kinfo_proc` on 64-bit macOS.
+    const KINFO_PROC_SIZE: usize = 648;
+
+    /// `wait4` on macOS, plus whether the child was translated by Rosetta 2. Universal binaries
+    /// run natively, but `x86_64`-only ones are translated on Apple Silicon, which adds the
+    /// translator's memory to theirs.
+    pub struct MacOs;
+
+    impl super::Monitor for MacOs {
+        fn reap(
+            child: &mut ChildProcess,
+            block: bool,
+        ) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+            // the flag can only be read while the process exists, so it's read after it exited
+            // but before it's reaped
+            if !exited(child, block)? {
+                return Ok(None);
+            }
+            let emulation = translated(child).then_some(Emulation::Rosetta);
+            let reaped = unix::wait4(child, true)?;
+            Ok(reaped.map(|(status, usage)| (status, ResourceUsage { emulation, ..usage })))
+        }
+    }
+
+    /// Whether `child` exited, without reaping it.
+    fn exited(child: &ChildProcess, block: bool) -> io::Result<bool> {
+        let pid = unix::pid(child)?;
+        let mut options = libc::WEXITED | libc::WNOWAIT;
+        if !block {
+            options |= libc::WNOHANG;
+        }
+        loop {
+            // SAFETY: siginfo_t is plain old data, so all zeroes is a valid value
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            // SAFETY: info is valid for the duration of the call
+            let result = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid.unsigned_abs(),
+                    std::ptr::addr_of_mut!(info),
+                    options,
+                )
+            };
+            if result == 0 {
+                // with WNOHANG, the PID is left at zero if the child hasn't exited
+                // SAFETY: waitid filled in info
+                return Ok(unsafe { info.si_pid() } != 0);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Whether `child` runs translated by Rosetta 2. Only Apple Silicon translates processes, so
+    /// this is always false on Intel Macs.
+    fn translated(child: &ChildProcess) -> bool {
+        let Ok(pid) = unix::pid(child) else {
+            return false;
+        };
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PID, pid];
+        // u64s, to align the buffer like the struct
+        let mut info = [0_u64; KINFO_PROC_SIZE / 8];
+        let mut size = KINFO_PROC_SIZE;
+        // SAFETY: mib, info, and size are valid for the duration of the call, and size is the
+        // size of info
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                4,
+                info.as_mut_ptr().cast(),
+                std::ptr::addr_of_mut!(size),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 || size < P_FLAG_OFFSET + 4 {
+            return false;
+        }
+        let bytes: Vec<u8> = info.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        let flag = &bytes[P_FLAG_OFFSET..P_FLAG_OFFSET + 4];
+        i32::from_ne_bytes([flag[0], flag[1], flag[2], flag[3]]) & P_TRANSLATED != 0
+    }
+}
+
+#[cfg(windows)]
+mod win {
+    use std::{ffi::c_void, io, os::windows::io::AsRawHandle, process::ExitStatus};
+
+    use super::{Emulation, ResourceUsage};
+    use crate::child::ChildProcess;
+
+    type Bool = i32;
+
+    /// <https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/ne-processthreadsapi-process_information_class>
+    const PROCESS_MACHINE_TYPE_INFO: i32 = 9;
+    const IMAGE_FILE_MACHINE_UNKNOWN: u16 = 0;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+
+    /// <https://learn.microsoft.com/en-us/windows/win32/api/psapi/ns-psapi-process_memory_counters>
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProcessMemoryCounters {
+        cb: u32,
+        page_fault_count: u32,
+        peak_working_set_size: usize,
+        working_set_size: usize,
+        quota_peak_paged_pool_usage: usize,
+        quota_paged_pool_usage: usize,
+        quota_peak_non_paged_pool_usage: usize,
+        quota_non_paged_pool_usage: usize,
+        pagefile_usage: usize,
+        peak_pagefile_usage: usize,
+    }
+
+    /// <https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/ns-processthreadsapi-process_machine_information>
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProcessMachineInformation {
+        process_machine: u16,
+        reserved: u16,
+        machine_attributes: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn GetProcessTimes(
+            process: *mut c_void,
+            creation: *mut FileTime,
+            exit: *mut FileTime,
+            kernel: *mut FileTime,
+            user: *mut FileTime,
+        ) -> Bool;
+        fn K32GetProcessMemoryInfo(
+            process: *mut c_void,
+            counters: *mut ProcessMemoryCounters,
+            cb: u32,
+        ) -> Bool;
+        fn IsWow64Process2(
+            process: *mut c_void,
+            process_machine: *mut u16,
+            native_machine: *mut u16,
+        ) -> Bool;
+        fn GetProcessInformation(
+            process: *mut c_void,
+            class: i32,
+            info: *mut c_void,
+            size: u32,
+        ) -> Bool;
+    }
+
+    /// The process handle stays valid after the process exited, so everything is read from it
+    /// once it did.
+    pub struct Windows;
+
+    impl super::Monitor for Windows {
+        fn reap(
+            child: &mut ChildProcess,
+            block: bool,
+        ) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+            let status = if block {
+                child.wait()?
+            } else {
+                let Some(status) = child.try_wait()? else {
+                    return Ok(None);
+                };
+                status
+            };
+            Ok(Some((status, usage(child))))
+        }
+    }
+
+    /// Reads the usage of a process. This still works after it exited, as long as `child` holds
+    /// its handle.
+    fn usage(child: &ChildProcess) -> ResourceUsage {
+        let handle = child.as_raw_handle();
+        let mut usage = ResourceUsage::default();
+        let (mut creation, mut exit, mut kernel, mut user) = Default::default();
+        // SAFETY: all pointers are valid for the duration of the calls
+        unsafe {
+            let times = GetProcessTimes(
+                handle,
+                std::ptr::addr_of_mut!(creation),
+                std::ptr::addr_of_mut!(exit),
+                std::ptr::addr_of_mut!(kernel),
+                std::ptr::addr_of_mut!(user),
+            );
+            if times != 0 {
+                // FILETIMEs count 100 ns intervals
+                usage.cpu_time_ms = (filetime(&kernel) + filetime(&user)) / 10_000;
+            }
+            let mut counters = ProcessMemoryCounters {
+                cb: std::mem::size_of::<ProcessMemoryCounters>() as u32,
+                ..Default::default()
+            };
+            let cb = counters.cb;
+            if K32GetProcessMemoryInfo(handle, std::ptr::addr_of_mut!(counters), cb) != 0 {
+                usage.peak_rss_bytes = Some(counters.peak_working_set_size as u64);
+            }
+        }
+        if emulated(handle) {
+            usage.emulation = Some(Emulation::X86OnArm);
+        }
+        usage
+    }
+
+    /// Whether the process of `handle` was built for another architecture than the machine's,
+    /// e.g. an x64 binary on Windows on ARM. Its architecture is only reported by Windows 11,
+    /// so the process is assumed to be native on older versions.
+    fn emulated(handle: *mut c_void) -> bool {
+        let (mut process_machine, mut native_machine) = (0, 0);
+        let mut info = ProcessMachineInformation::default();
+        // SAFETY: all pointers are valid for the duration of the calls, and the size is that of
+        // info
+        unsafe {
+            if IsWow64Process2(
+                GetCurrentProcess(),
+                std::ptr::addr_of_mut!(process_machine),
+                std::ptr::addr_of_mut!(native_machine),
+            ) == 0
+            {
+                return false;
+            }
+            if GetProcessInformation(
+                handle,
+                PROCESS_MACHINE_TYPE_INFO,
+                std::ptr::addr_of_mut!(info).cast(),
+                std::mem::size_of::<ProcessMachineInformation>() as u32,
+            ) == 0
+            {
+                return false;
+            }
+        }
+        info.process_machine != IMAGE_FILE_MACHINE_UNKNOWN && info.process_machine != native_machine
+    }
+
+    fn filetime(time: &FileTime) -> u64 {
+        (u64::from(time.high) << 32) | u64::from(time.low)
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    process::Stdio,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tao::{
+    event_loop::{ControlFlow, EventLoop},
+    platform::run_return::EventLoopExtRunReturn,
+};
+use tray_icon::{
+    menu::{IsMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem},
+    TrayIcon,
+};
+
+use crate::{
+    cli::InstanceArgs,
+    exitcode::{ErrorKind, WithKind},
+    headroom,
+    notify::{Notifier, NotifyEvent},
+    precheck,
+    supervisor::CommandSpec,
+    usage::format_bytes,
+    TrayMessage,
+};
+
+/// How long the command waits for its dependencies by default, see `--wait-timeout`.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// How often the dependencies that aren't available yet are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a port has to accept a connection, or a `--wait-for-cmd` command has to exit.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The menu item that starts the command without waiting any longer.
+const START_NOW_ID: &str = "Start Now";
+
+/// A TCP port on a host, as `[HOST:]PORT`. The host defaults to localhost.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortAddr {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for PortAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+            None => ("localhost", s),
+        };
+        let port = port
+            .parse()
+            .map_err(|_| format!("'{port}' is not a port number"))?;
+        if host.is_empty() {
+            return Err(format!("'{s}' has no host before the port"));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PortAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl TryFrom<String> for PortAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PortAddr> for String {
+    fn from(addr: PortAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+/// Something the command needs before it's started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dependency {
+    /// A port accepting connections, see `--wait-for-port`.
+    Port(PortAddr),
+    /// A file that exists, see `--wait-for-file`.
+    File(PathBuf),
+    /// A shell command that succeeds, see `--wait-for-cmd`.
+    Cmd(OsString),
+    /// At least this many bytes of free memory, see `--require-free-mem`.
+    FreeMemory(u64),
+    /// At least this many bytes of free disk space, see `--require-free-disk`.
+    FreeDisk(u64),
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dependency::Port(addr) => write!(f, "port {addr}"),
+            Dependency::File(path) => write!(f, "{}", path.display()),
+            Dependency::Cmd(cmd) => write!(f, "`{}`", cmd.to_string_lossy()),
+            Dependency::FreeMemory(bytes) => write!(f, "{} of free memory", format_bytes(*bytes)),
+            Dependency::FreeDisk(bytes) => write!(f, "{} of free disk space", format_bytes(*bytes)),
+        }
+    }
+}
+
+impl Dependency {
+    /// Whether the dependency is available. Commands run in the working directory and
+    /// environment of `spec`, which is also where disk space is checked.
+    fn is_available(&self, spec: &CommandSpec) -> bool {
+        match self {
+            Dependency::Port(addr) => {
+                let Ok(addrs) = (addr.host.as_str(), addr.port).to_socket_addrs() else {
+                    debug!("{addr} doesn't resolve yet");
+                    return false;
+                };
+                addrs
+                    .into_iter()
+                    .any(|addr| TcpStream::connect_timeout(&addr, CHECK_TIMEOUT).is_ok())
+            }
+            Dependency::File(path) => path.exists(),
+            Dependency::Cmd(cmd) => run_check(cmd, spec),
+            Dependency::FreeMemory(_) | Dependency::FreeDisk(_) => self.shortfall(spec).is_none(),
+        }
+    }
+
+    /// What's missing of free memory or disk space, if this is about them.
+    fn shortfall(&self, spec: &CommandSpec) -> Option<String> {
+        match self {
+            Dependency::FreeMemory(bytes) => headroom::memory_shortfall(*bytes),
+            Dependency::FreeDisk(bytes) => {
+                let cwd = spec.cwd.clone().or_else(|| std::env::current_dir().ok())?;
+                headroom::disk_shortfall(*bytes, &cwd)
+            }
+            Dependency::Port(_) | Dependency::File(_) | Dependency::Cmd(_) => None,
+        }
+    }
+}
+
+/// Runs `cmd` and returns whether it succeeded within [`CHECK_TIMEOUT`].
+fn run_check(cmd: &OsStr, spec: &CommandSpec) -> bool {
+    let child = precheck::spec_shell_command(cmd, spec)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run `{}`: {e}", cmd.to_string_lossy());
+            return false;
+        }
+    };
+    let deadline = Instant::now() + CHECK_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Ok(None) | Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            }
+        }
+    }
+}
+
+/// Waits out the `--delay` of an instance and then for its dependencies before its command is
+/// first started. Restarts don't wait.
+#[derive(Debug)]
+pub struct Waiter {
+    /// When the delay is over, until it is.
+    delay_until: Option<Instant>,
+    /// The dependencies that weren't available yet, in the order they're waited for.
+    pending: Vec<Dependency>,
+    timeout: Duration,
+    /// When waiting for the dependencies started, which is after the delay.
+    started: Instant,
+    last_check: Option<Instant>,
+    /// Whether the start being put off for a lack of memory or disk space was notified about.
+    deferral_notified: bool,
+}
+
+impl Waiter {
+    /// Creates a waiter for the `--delay` and `--wait-for-*` options of `instance`, or returns
+    /// `None` if there's nothing to wait for.
+    pub fn new(instance: &InstanceArgs) -> Option<Self> {
+        let pending: Vec<_> = instance
+            .wait_for_port
+            .iter()
+            .cloned()
+            .map(Dependency::Port)
+            .chain(instance.wait_for_file.iter().cloned().map(Dependency::File))
+            .chain(instance.wait_for_cmd.iter().cloned().map(Dependency::Cmd))
+            .chain(
+                instance
+                    .require_free_mem
+                    .map(|size| Dependency::FreeMemory(size.0)),
+            )
+            .chain(
+                instance
+                    .require_free_disk
+                    .map(|size| Dependency::FreeDisk(size.0)),
+            )
+            .collect();
+        let delay_until = instance.delay.map(|delay| Instant::now() + delay);
+        (delay_until.is_some() || !pending.is_empty()).then(|| Self {
+            delay_until,
+            pending,
+            timeout: instance.wait_timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT),
+            started: Instant::now(),
+            last_check: None,
+            deferral_notified: false,
+        })
+    }
+
+    /// The dependency that's waited for, or `None` once all are available.
+    pub fn waiting_for(&self) -> Option<&Dependency> {
+        self.pending.first()
+    }
+
+    /// What's waited for and for how long, e.g. for the tooltip.
+    pub fn progress(&self) -> String {
+        if let Some(until) = self.delay_until {
+            let left = until.saturating_duration_since(Instant::now());
+            // rounded up, so the countdown doesn't say 0s for its last second
+            let left = Duration::from_secs(left.as_secs() + u64::from(left.subsec_nanos() > 0));
+            return format!("starting in {}", humantime::format_duration(left));
+        }
+        let elapsed = Duration::from_secs(self.started.elapsed().as_secs());
+        match self.waiting_for() {
+            Some(dependency) => format!(
+                "waiting for {dependency}, {} of {}",
+                humantime::format_duration(elapsed),
+                humantime::format_duration(self.timeout)
+            ),
+            None => "ready".to_string(),
+        }
+    }
+
+    /// Checks the dependencies that weren't available yet once the delay is over, unless they
+    /// were within the last [`POLL_INTERVAL`]. Returns `true` once all of them are.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if they still aren't after the timeout.
+    pub fn check(&mut self, spec: &CommandSpec) -> anyhow::Result<bool> {
+        if let Some(until) = self.delay_until {
+            if Instant::now() < until {
+                return Ok(false);
+            }
+            info!("The delay is over");
+            self.delay_until = None;
+            self.started = Instant::now();
+        }
+        if self
+            .last_check
+            .is_some_and(|at| at.elapsed() < POLL_INTERVAL)
+        {
+            return Ok(false);
+        }
+        self.last_check = Some(Instant::now());
+        while let Some(dependency) = self.pending.first() {
+            if !dependency.is_available(spec) {
+                if self.started.elapsed() >= self.timeout {
+                    bail!(
+                        "{dependency} still isn't available after {}",
+                        humantime::format_duration(self.timeout)
+                    );
+                }
+                return Ok(false);
+            }
+            info!("{dependency} is available");
+            self.pending.remove(0);
+        }
+        Ok(true)
+    }
+
+    /// Stops waiting, skipping the rest of the delay and the dependencies that weren't available
+    /// yet.
+    fn start_now(&mut self) {
+        info!("Starting now instead of {}", self.progress());
+        self.delay_until = None;
+        self.pending.clear();
+    }
+
+    /// Notifies that the start is put off, the first time it is for a lack of memory or disk
+    /// space, since that's rarely expected the way waiting for another program is.
+    fn notify_deferral(&mut self, spec: &CommandSpec, notifier: &Notifier) {
+        if self.deferral_notified {
+            return;
+        }
+        let Some(shortfall) = self.waiting_for().and_then(|d| d.shortfall(spec)) else {
+            return;
+        };
+        warn!("Putting the start off: {shortfall}");
+        notifier.notify(NotifyEvent::PreCheck, "Start deferred", &shortfall);
+        self.deferral_notified = true;
+    }
+
+    /// Waits until every dependency is available.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if they still aren't after the timeout, which `notifier` notifies
+    /// about.
+    pub fn wait(&mut self, spec: &CommandSpec, notifier: &Notifier) -> anyhow::Result<()> {
+        if let Some(until) = self.delay_until {
+            info!("Starting {}", self.progress());
+            thread::sleep(until.saturating_duration_since(Instant::now()));
+        }
+        let result = loop {
+            match self.check(spec) {
+                Ok(false) => self.notify_deferral(spec, notifier),
+                result => break result.map(|_| ()),
+            }
+            if let Some(dependency) = self.waiting_for() {
+                debug!("Still waiting for {dependency}");
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        gave_up(result, notifier)
+    }
+
+    /// Waits until every dependency is available while the tray is up, with the progress in its
+    /// tooltip after `tooltip`. A Start Now item is put into `menu` at the position after it until
+    /// then, below the header, which stops waiting. Clicking Kill gives up, and the rest of the menu does nothing yet. Returns
+    /// `false` if the user gave up.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the dependencies still aren't available after the timeout, which
+    /// `notifier` notifies about.
+    pub fn wait_in_tray(
+        &mut self,
+        event_loop: &mut EventLoop<()>,
+        spec: &CommandSpec,
+        notifier: &Notifier,
+        tray: &TrayIcon,
+        tooltip: &str,
+        (menu, position): (&Menu, usize),
+    ) -> anyhow::Result<bool> {
+        let start_now = MenuItem::with_id(START_NOW_ID, START_NOW_ID, true, None);
+        let separator = PredefinedMenuItem::separator();
+        menu.insert_items(&[&start_now as &dyn IsMenuItem, &separator], position)?;
+        let menu_channel = MenuEvent::receiver();
+        let mut result = Ok(true);
+        let mut shown = String::new();
+        event_loop.run_return(|_event, _window, control_flow| {
+            if *control_flow == ControlFlow::Exit {
+                return;
+            }
+            *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(100));
+            if let Ok(event) = menu_channel.try_recv() {
+                if event.id().0 == START_NOW_ID {
+                    self.start_now();
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                if let Ok(TrayMessage::Kill) = TrayMessage::from_str(&event.id().0) {
+                    info!("Gave up {}", self.progress());
+                    result = Ok(false);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                debug!("Ignoring '{}' while waiting", event.id().0);
+            }
+            match self.check(spec) {
+                Ok(false) => self.notify_deferral(spec, notifier),
+                Ok(true) => {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+            }
+            let progress = self.progress();
+            if progress != shown {
+                if let Err(e) = tray.set_tooltip(Some(format!("{tooltip} ({progress})"))) {
+                    warn!("Failed to update tooltip: {e}");
+                }
+                shown = progress;
+            }
+        });
+        menu.remove(&start_now)?;
+        menu.remove(&separator)?;
+        gave_up(result, notifier)
+    }
+}
+
+/// Notifies about the dependency that didn't become available, if one didn't, and marks the
+/// error as the command not starting.
+fn gave_up<T>(result: anyhow::Result<T>, notifier: &Notifier) -> anyhow::Result<T> {
+    result
+        .inspect_err(|e| {
+            error!("{e:#}");
+            notifier.notify(NotifyEvent::PreCheck, "Not started", &format!("{e:#}"));
+        })
+        .with_kind(ErrorKind::Spawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn parses_ports_with_and_without_a_host() {
+        let addr = |s: &str| s.parse::<PortAddr>().map(|addr| addr.to_string());
+        assert_eq!(addr("8080").unwrap(), "localhost:8080");
+        assert_eq!(addr("db:5432").unwrap(), "db:5432");
+        assert_eq!(addr("[::1]:80").unwrap(), "[::1]:80");
+        assert!(addr(":80").is_err());
+        assert!(addr("db:http").is_err());
+        assert!(addr("70000").is_err());
+    }
+
+    #[test]
+    fn checks_whether_dependencies_are_available() {
+        let spec = CommandSpec::of(&["server"]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let open = Dependency::Port(format!("127.0.0.1:{port}").parse().unwrap());
+        assert!(open.is_available(&spec));
+        drop(listener);
+        assert!(!open.is_available(&spec));
+
+        assert!(Dependency::File(std::env::temp_dir()).is_available(&spec));
+        assert!(!Dependency::File(std::env::temp_dir().join("no such file")).is_available(&spec));
+        #[cfg(unix)]
+        {
+            assert!(Dependency::Cmd("true".into()).is_available(&spec));
+            assert!(!Dependency::Cmd("exit 3".into()).is_available(&spec));
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::process::Command;
+
+use anyhow::bail;
+use chrono::{DateTime, Local, TimeDelta};
+use log::{debug, info, warn};
+
+use crate::notify::show_notification;
+
+/// How long before a run the machine is woken, so that it's up by the time the run is due.
+const WAKE_EARLY: TimeDelta = TimeDelta::seconds(30);
+
+/// Wakes the machine from sleep for the next scheduled run, with `--wake`. There is only ever one
+/// wake scheduled per schedule, which is moved along as the runs come due. Where the OS doesn't
+/// let trayme schedule one, the user is told once, and missed runs are caught up on as usual.
+#[derive(Debug)]
+pub struct WakeTimer {
+    /// The name of the OS timer or task, which is the same on every start so that one left
+    /// behind by an earlier trayme is replaced.
+    name: String,
+    /// The run the wake is for and when the machine wakes.
+    armed: Option<(DateTime<Local>, DateTime<Local>)>,
+    /// Whether scheduling a wake failed, in which case it isn't tried again.
+    failed: bool,
+}
+
+impl WakeTimer {
+    /// Creates the timer of the schedule named `name`.
+    pub fn new(name: &str) -> Self {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Self {
+            name: format!("trayme-wake-{name}"),
+            armed: None,
+            failed: false,
+        }
+    }
+
+    /// Makes the machine wake up shortly before `due`, replacing the wake of the previous run.
+    /// `None` only removes that wake, for when no more runs are scheduled.
+    pub fn arm(&mut self, due: Option<DateTime<Local>>) {
+        if self.failed || due == self.armed.map(|(due, _)| due) {
+            return;
+        }
+        self.disarm();
+        let Some(due) = due else {
+            return;
+        };
+        let now = Local::now();
+        let wake_at = (due - WAKE_EARLY).max(now + TimeDelta::seconds(1));
+        match platform::arm(&self.name, wake_at) {
+            Ok(()) => {
+                info!("Waking the machine at {wake_at} for the run due at {due}");
+                self.armed = Some((due, wake_at));
+            }
+            Err(e) => {
+                warn!("Failed to schedule a wake: {e:#}");
+                show_notification(
+                    "Can't wake for scheduled runs",
+                    &format!(
+                        "{e:#}. Runs the machine sleeps through are caught up on according to \
+                         --missed."
+                    ),
+                );
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Removes the scheduled wake, e.g. because the schedule is quit.
+    pub fn disarm(&mut self) {
+        let Some((_, wake_at)) = self.armed.take() else {
+            return;
+        };
+        if let Err(e) = platform::disarm(&self.name, wake_at) {
+            debug!("Failed to remove the wake: {e:#}");
+        }
+    }
+}
+
+/// Runs a command that schedules or removes a wake.
+///
+/// # Errors
+///
+/// An error with what the command printed is returned if it can't be run or fails.
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) => bail!("Failed to run {program}: {e}"),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{program} failed ({}): {}", output.status, stderr.trim());
+    }
+    Ok(())
+}
+
+/// A transient systemd timer with `WakeSystem`, in the user's service manager, or the system's
+/// when trayme runs as root.
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::process::Command;
+
+    use chrono::{DateTime, Local};
+
+    fn systemd(program: &str) -> Command {
+        let mut command = Command::new(program);
+        // SAFETY: geteuid has no preconditions
+        if unsafe { libc::geteuid() } != 0 {
+            command.arg("--user");
+        }
+        command
+    }
+
+    pub fn arm(name: &str, at: DateTime<Local>) -> anyhow::Result<()> {
+        // a timer of the same name may be left from an earlier trayme
+        let _ = systemd("systemctl")
+            .args(["stop", &format!("{name}.timer")])
+            .output();
+        super::run(systemd("systemd-run").args([
+            &format!("--unit={name}"),
+            "--timer-property=WakeSystem=true",
+            "--timer-property=AccuracySec=1s",
+            &format!("--on-calendar={}", at.format("%Y-%m-%d %H:%M:%S")),
+            "true",
+        ]))
+    }
+
+    pub fn disarm(name: &str, _at: DateTime<Local>) -> anyhow::Result<()> {
+        super::run(systemd("systemctl").args(["stop", &format!("{name}.timer")]))
+    }
+}
+
+/// A wake scheduled with `pmset`, which only works when trayme runs as root.
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::process::Command;
+
+    use chrono::{DateTime, Local};
+
+    const PMSET_FORMAT: &str = "%m/%d/%y %H:%M:%S";
+
+    pub fn arm(_name: &str, at: DateTime<Local>) -> anyhow::Result<()> {
+        super::run(Command::new("pmset").args([
+            "schedule",
+            "wake",
+            &at.format(PMSET_FORMAT).to_string(),
+        ]))
+    }
+
+    pub fn disarm(_name: &str, at: DateTime<Local>) -> anyhow::Result<()> {
+        super::run(Command::new("pmset").args([
+            "schedule",
+            "cancel",
+            "wake",
+            &at.format(PMSET_FORMAT).to_string(),
+        ]))
+    }
+}
+
+/// A scheduled task that wakes the computer to run, which does nothing itself.
+#[cfg(windows)]
+mod platform {
+    use std::process::Command;
+
+    use chrono::{DateTime, Local};
+
+    fn powershell(script: &str) -> Command {
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-Command", script]);
+        command
+    }
+
+    pub fn arm(name: &str, at: DateTime<Local>) -> anyhow::Result<()> {
+        let script = format!(
+            "Register-ScheduledTask -Force -TaskName '{name}' \
+             -Action (New-ScheduledTaskAction -Execute 'cmd.exe' -Argument '/c exit') \
+             -Trigger (New-ScheduledTaskTrigger -Once -At '{}') \
+             -Settings (New-ScheduledTaskSettingsSet -WakeToRun) | Out-Null",
+            at.format("%Y-%m-%dT%H:%M:%S")
+        );
+        super::run(&mut powershell(&script))
+    }
+
+    pub fn disarm(name: &str, _at: DateTime<Local>) -> anyhow::Result<()> {
+        super::run(&mut powershell(&format!(
+            "Unregister-ScheduledTask -TaskName '{name}' -Confirm:$false"
+        )))
+    }
+}
@@ -0,0 +1,252 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// How long after the process starts its windows are hidden as they appear, with
+/// `--start-hidden`. GUI apps often open their main window a while after starting.
+const START_HIDDEN_FOR: Duration = Duration::from_secs(15);
+
+/// How often the process' windows are looked for while hiding them at startup.
+const SEARCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shows and hides the top-level windows of a GUI process, found by its PID. Only windows that
+/// were hidden here are shown again, so windows the app keeps hidden itself stay that way.
+#[derive(Debug)]
+pub struct WindowToggle {
+    pid: u32,
+    hidden: Vec<platform::WindowId>,
+    /// Until when new windows are hidden as they appear.
+    hide_until: Option<Instant>,
+    last_search: Option<Instant>,
+}
+
+impl WindowToggle {
+    /// Creates a toggle for the process with `pid`. With `start_hidden`, its windows are hidden
+    /// as they appear for the first [`START_HIDDEN_FOR`].
+    pub fn new(pid: u32, start_hidden: bool) -> Self {
+        Self {
+            pid,
+            hidden: Vec::new(),
+            hide_until: start_hidden.then(|| Instant::now() + START_HIDDEN_FOR),
+            last_search: None,
+        }
+    }
+
+    /// Switches to a new process, e.g. after a restart, hiding its windows if the old one's were.
+    pub fn retarget(&mut self, pid: u32) {
+        let start_hidden = self.is_hidden();
+        *self = Self::new(pid, start_hidden);
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the process' windows were hidden and not shown since.
+    pub fn is_hidden(&self) -> bool {
+        !self.hidden.is_empty() || self.hide_until.is_some()
+    }
+
+    /// Hides windows that appeared since the process started, while it's being started hidden.
+    /// Call this regularly.
+    pub fn poll(&mut self) {
+        let Some(hide_until) = self.hide_until else {
+            return;
+        };
+        if Instant::now() >= hide_until {
+            self.hide_until = None;
+            return;
+        }
+        if self
+            .last_search
+            .is_some_and(|last| last.elapsed() < SEARCH_INTERVAL)
+        {
+            return;
+        }
+        self.last_search = Some(Instant::now());
+        if let Err(e) = self.hide_visible() {
+            warn!("{e:#}");
+            // the platform doesn't support it, so there's no point in trying again
+            self.hide_until = None;
+        }
+    }
+
+    /// Hides all of the process' visible windows.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the windows cannot be found or hidden on this platform.
+    pub fn hide(&mut self) -> anyhow::Result<()> {
+        self.hide_visible()?;
+        if self.hidden.is_empty() {
+            anyhow::bail!("The process has no visible windows");
+        }
+        Ok(())
+    }
+
+    /// Shows the windows that were hidden.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the windows cannot be shown on this platform.
+    pub fn show(&mut self) -> anyhow::Result<()> {
+        self.hide_until = None;
+        for window in self.hidden.drain(..) {
+            platform::set_visible(self.pid, &window, true)?;
+        }
+        Ok(())
+    }
+
+    /// Shows the windows if they're hidden, and hides them otherwise.
+    ///
+    /// # Errors
+    ///
+    /// See [`WindowToggle::show`] and [`WindowToggle::hide`].
+    pub fn toggle(&mut self) -> anyhow::Result<()> {
+        if self.is_hidden() {
+            self.show()
+        } else {
+            self.hide()
+        }
+    }
+
+    fn hide_visible(&mut self) -> anyhow::Result<()> {
+        for window in platform::visible_windows(self.pid)? {
+            debug!("Hiding window {window:?} of PID {}", self.pid);
+            platform::set_visible(self.pid, &window, false)?;
+            if !self.hidden.contains(&window) {
+                self.hidden.push(window);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    /// A window handle.
+    pub type WindowId = isize;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/winuser/nf-winuser-showwindow
+    const SW_HIDE: i32 = 0;
+    const SW_SHOW: i32 = 5;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn EnumWindows(callback: extern "system" fn(isize, isize) -> i32, lparam: isize) -> i32;
+        fn GetWindowThreadProcessId(hwnd: isize, pid: *mut u32) -> u32;
+        fn IsWindowVisible(hwnd: isize) -> i32;
+        fn ShowWindow(hwnd: isize, cmd: i32) -> i32;
+    }
+
+    struct Search {
+        pid: u32,
+        found: Vec<WindowId>,
+    }
+
+    extern "system" fn collect(hwnd: isize, lparam: isize) -> i32 {
+        // SAFETY: lparam is the `Search` passed to EnumWindows below, which outlives the call
+        let search = unsafe { &mut *(lparam as *mut Search) };
+        let mut pid = 0;
+        // SAFETY: hwnd comes from EnumWindows and pid is a valid out pointer
+        let visible = unsafe {
+            GetWindowThreadProcessId(hwnd, &mut pid);
+            IsWindowVisible(hwnd) != 0
+        };
+        if pid == search.pid && visible {
+            search.found.push(hwnd);
+        }
+        1
+    }
+
+    pub fn visible_windows(pid: u32) -> anyhow::Result<Vec<WindowId>> {
+        let mut search = Search {
+            pid,
+            found: Vec::new(),
+        };
+        // SAFETY: `collect` only accesses `search` during the call
+        unsafe {
+            EnumWindows(collect, std::ptr::addr_of_mut!(search) as isize);
+        }
+        Ok(search.found)
+    }
+
+    pub fn set_visible(_pid: u32, window: &WindowId, visible: bool) -> anyhow::Result<()> {
+        // SAFETY: ShowWindow fails harmlessly for windows that no longer exist
+        unsafe {
+            ShowWindow(*window, if visible { SW_SHOW } else { SW_HIDE });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::process::Command;
+
+    use anyhow::{bail, Context};
+
+    /// macOS hides whole apps rather than single windows, so there's only ever one "window".
+    pub type WindowId = ();
+
+    fn system_events(pid: u32, statement: &str) -> anyhow::Result<String> {
+        let process = format!("(first process whose unix id is {pid})");
+        let script = format!(
+            "tell application \"System Events\" to {}",
+            statement.replace("{process}", &process)
+        );
+        let output = Command::new("osascript")
+            .args(["-e", &script])
+            .output()
+            .context("Failed to run osascript")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to control the app's windows: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    pub fn visible_windows(pid: u32) -> anyhow::Result<Vec<WindowId>> {
+        let visible = system_events(pid, "get visible of {process}")?;
+        Ok(if visible == "true" { vec![()] } else { vec![] })
+    }
+
+    pub fn set_visible(pid: u32, _window: &WindowId, visible: bool) -> anyhow::Result<()> {
+        system_events(pid, &format!("set visible of {{process}} to {visible}")).map(|_| ())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::process::Command;
+
+    use anyhow::{bail, Context};
+
+    /// An X11 window ID. Wayland doesn't let clients control other apps' windows, so this only
+    /// works on X11 (including windows of X11 apps on Wayland).
+    pub type WindowId = String;
+
+    fn xdotool(args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("xdotool")
+            .args(args)
+            .output()
+            .context("Failed to run xdotool, which is needed to show and hide windows")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    pub fn visible_windows(pid: u32) -> anyhow::Result<Vec<WindowId>> {
+        if std::env::var_os("DISPLAY").is_none() {
+            bail!("Showing and hiding windows needs an X11 display");
+        }
+        // xdotool exits with 1 when nothing matches
+        let found = xdotool(&["search", "--onlyvisible", "--pid", &pid.to_string()])?;
+        Ok(found.lines().map(str::to_string).collect())
+    }
+
+    pub fn set_visible(_pid: u32, window: &WindowId, visible: bool) -> anyhow::Result<()> {
+        let action = if visible { "windowmap" } else { "windowunmap" };
+        xdotool(&[action, window]).map(|_| ())
+    }
+}
@@ -0,0 +1,382 @@
+//! Runs trayme headless against short shell commands, each test with data and config
+//! directories of its own. Only on Linux, where those directories follow `XDG_*`.
+#![cfg(target_os = "linux")]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The data and config directories of one test, removed once it's done.
+struct Sandbox(PathBuf);
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("trayme-headless-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Runs trayme headless with `args` and waits for it to exit. Notifications have no session
+    /// bus to go to, so none are shown.
+    fn run(&self, args: &[&str]) -> Output {
+        self.trayme().arg("--headless").args(args).output().unwrap()
+    }
+
+    /// Starts trayme headless with `args` without waiting for it.
+    fn start(&self, args: &[&str]) -> Child {
+        self.trayme().arg("--headless").args(args).spawn().unwrap()
+    }
+
+    /// trayme with this sandbox's directories, e.g. for its subcommands.
+    fn trayme(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_trayme"));
+        command
+            .env("XDG_DATA_HOME", self.0.join("data"))
+            .env("XDG_CONFIG_HOME", self.0.join("config"))
+            .env_remove("DBUS_SESSION_BUS_ADDRESS")
+            .env_remove("RUST_LOG")
+            .env_remove("TRAYME_TOKEN");
+        command
+    }
+
+    fn data(&self) -> PathBuf {
+        self.0.join("data").join("trayme")
+    }
+
+    /// How many runs the history has.
+    fn runs(&self) -> usize {
+        files(&self.data().join("history")).len()
+    }
+
+    /// The logs of the runs of `program`, oldest first.
+    fn logs(&self, program: &str) -> Vec<String> {
+        let mut logs = files(&self.data().join("logs").join(program));
+        // later runs in the same second are suffixed with _2, _3, and so on
+        logs.sort_by_key(|path| {
+            let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+            match stem.rsplit_once('_') {
+                Some((started, n)) if n.parse::<u32>().is_ok() => {
+                    (started.to_string(), n.parse().unwrap())
+                }
+                _ => (stem, 1),
+            }
+        });
+        logs.iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+        .unwrap_or_default()
+}
+
+#[test]
+fn passes_on_the_exit_code() {
+    let sandbox = Sandbox::new("exit-code");
+    let output = sandbox.run(&["--", "sh", "-c", "echo hi; exit 3"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(sandbox.runs(), 1);
+    assert_eq!(sandbox.logs("sh"), ["hi\n"]);
+}
+
+#[test]
+fn restarts_failures_until_it_gives_up() {
+    let sandbox = Sandbox::new("give-up");
+    let output = sandbox.run(&[
+        "--restart-policy",
+        "on-failure",
+        "--max-restarts",
+        "2",
+        "--restart-backoff",
+        "10ms",
+        "--",
+        "sh",
+        "-c",
+        "exit 1",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(sandbox.runs(), 3);
+}
+
+#[test]
+fn doesnt_restart_successes_on_failure() {
+    let sandbox = Sandbox::new("on-failure");
+    let output = sandbox.run(&[
+        "--restart-policy",
+        "on-failure",
+        "--restart-backoff",
+        "10ms",
+        "--",
+        "true",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(sandbox.runs(), 1);
+}
+
+#[test]
+fn chains_stop_at_a_failed_step() {
+    let sandbox = Sandbox::new("chain");
+    let output = sandbox.run(&[
+        "--",
+        "sh",
+        "-c",
+        "echo one",
+        "--then",
+        "sh",
+        "-c",
+        "echo two; exit 4",
+        "--then",
+        "sh",
+        "-c",
+        "echo three",
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(sandbox.logs("sh"), ["one\n", "two\n"]);
+}
+
+#[test]
+fn chains_continue_on_failure() {
+    let sandbox = Sandbox::new("chain-continue");
+    sandbox.run(&[
+        "--continue-on-failure",
+        "--",
+        "sh",
+        "-c",
+        "echo one; exit 4",
+        "--then",
+        "sh",
+        "-c",
+        "echo two",
+    ]);
+    assert_eq!(sandbox.logs("sh"), ["one\n", "two\n"]);
+}
+
+#[test]
+fn rejects_empty_steps() {
+    let sandbox = Sandbox::new("empty-step");
+    let output = sandbox.run(&["--", "true", "--then"]);
+    assert!(!output.status.success());
+    assert_eq!(sandbox.runs(), 0);
+}
+
+#[test]
+fn delays_the_start() {
+    let sandbox = Sandbox::new("delay");
+    let started = Instant::now();
+    let output = sandbox.run(&["--delay", "1s", "--", "true"]);
+    assert!(output.status.success());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(sandbox.runs(), 1);
+}
+
+/// A port on localhost that nothing listens on right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Sends `line` to the control socket on `port`, retrying until the instance listens, and returns
+/// the response.
+fn control(port: u16, line: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(e) if Instant::now() > deadline => panic!("The instance never listened: {e}"),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    writeln!(stream, "{line}").unwrap();
+    let mut response = String::new();
+    for line in BufReader::new(stream).lines() {
+        response.push_str(&line.unwrap());
+        response.push('\n');
+    }
+    response
+}
+
+#[test]
+fn control_needs_a_token() {
+    let sandbox = Sandbox::new("token");
+    let port = free_port();
+    let listen = format!("127.0.0.1:{port}");
+    let mut instance = sandbox.start(&[
+        "--name", "guarded", "--listen", &listen, "--", "sleep", "30",
+    ]);
+    let response = control(port, "kill");
+    assert!(response.contains("A token is required"), "{response}");
+    assert!(instance.try_wait().unwrap().is_none());
+    let down = sandbox.trayme().args(["down", "guarded"]).output().unwrap();
+    assert!(down.status.success(), "{down:?}");
+    instance.wait().unwrap();
+}
+
+/// Sends SIGINT to `instance`, as Ctrl+C does, and waits for it to exit.
+fn interrupt(mut instance: Child) {
+    let status = Command::new("kill")
+        .args(["-INT", &instance.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    instance.wait().unwrap();
+}
+
+/// Waits up to 10 seconds for `done` to be true.
+fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn restarts_on_a_schedule() {
+    let sandbox = Sandbox::new("restart-every");
+    let instance = sandbox.start(&[
+        "--restart-every",
+        "1s",
+        "--",
+        "sh",
+        "-c",
+        "echo up; sleep 30",
+    ]);
+    wait_until("the planned restarts", || sandbox.runs() >= 3);
+    interrupt(instance);
+    let logs = sandbox.logs("sh");
+    assert!(logs.len() >= 3, "{logs:?}");
+    assert!(logs.iter().all(|log| log == "up\n"), "{logs:?}");
+}
+
+#[test]
+fn files_away_the_files_of_an_inbox() {
+    let sandbox = Sandbox::new("inbox");
+    let inbox = sandbox.0.join("inbox");
+    fs::create_dir_all(&inbox).unwrap();
+    fs::write(inbox.join("good.txt"), "ok\n").unwrap();
+    fs::write(inbox.join("bad.txt"), "not quite\n").unwrap();
+    let instance = sandbox.start(&[
+        "--watch-inbox",
+        inbox.to_str().unwrap(),
+        "--",
+        "sh",
+        "-c",
+        r#"grep -qx ok "$0""#,
+        "{file}",
+    ]);
+    wait_until("the files to be moved", || {
+        inbox.join("done").join("good.txt").exists()
+            && inbox.join("failed").join("bad.txt").exists()
+    });
+    // a file put in while it's watching is picked up too
+    fs::write(inbox.join("late.txt"), "ok\n").unwrap();
+    wait_until("the late file to be moved", || {
+        inbox.join("done").join("late.txt").exists()
+    });
+    interrupt(instance);
+    assert_eq!(sandbox.runs(), 3);
+    assert!(files(&inbox).iter().all(|path| path.is_dir()));
+}
+
+#[test]
+fn rotates_the_log_on_request() {
+    let sandbox = Sandbox::new("rotate-log");
+    let token = sandbox
+        .trayme()
+        .args(["token", "create", "rotation", "--scope", "control"])
+        .output()
+        .unwrap();
+    assert!(token.status.success(), "{token:?}");
+    let secret = String::from_utf8(token.stdout).unwrap();
+    let port = free_port();
+    let listen = format!("127.0.0.1:{port}");
+    let instance = sandbox.start(&[
+        "--name",
+        "rotated",
+        "--listen",
+        &listen,
+        "--compress-rotated-logs",
+        "--",
+        "sh",
+        "-c",
+        "echo before; read line; echo after; sleep 30",
+    ]);
+    wait_until("the first output", || sandbox.logs("sh") == ["before\n"]);
+
+    let response = control(port, &format!("token {} rotate-log", secret.trim()));
+    assert!(response.contains("\"running\""), "{response}");
+    control(port, &format!("token {} send go", secret.trim()));
+    let logs_dir = sandbox.data().join("logs").join("sh");
+    let find = |extension| {
+        files(&logs_dir)
+            .into_iter()
+            .find(|path| path.extension().is_some_and(|e| e == extension))
+    };
+    wait_until("the old log to be compressed", || find("gz").is_some());
+    wait_until("the output after the rotation", || {
+        find("log").is_some_and(|log| fs::read_to_string(log).unwrap() == "after\n")
+    });
+    interrupt(instance);
+    assert_eq!(files(&logs_dir).len(), 2);
+    assert_eq!(sandbox.runs(), 1);
+}
+
+#[test]
+fn hands_the_process_over_on_self_restart() {
+    let sandbox = Sandbox::new("self-restart");
+    let mut instance = sandbox.start(&[
+        "--name",
+        "upgraded",
+        "--",
+        "sh",
+        "-c",
+        "echo before; read line; echo \"$line\"; sleep 30",
+    ]);
+    wait_until("the first output", || sandbox.logs("sh") == ["before\n"]);
+
+    let handover = sandbox
+        .trayme()
+        .args(["self-restart", "upgraded"])
+        .output()
+        .unwrap();
+    assert!(handover.status.success(), "{handover:?}");
+    assert!(instance.try_wait().unwrap().is_none());
+    // the new trayme writes to the stdin of the process and logs its output
+    let send = sandbox
+        .trayme()
+        .args(["send", "upgraded", "after"])
+        .output()
+        .unwrap();
+    assert!(send.status.success(), "{send:?}");
+    wait_until("the output after the handover", || {
+        sandbox.logs("sh") == ["before\nafter\n"]
+    });
+    let down = sandbox
+        .trayme()
+        .args(["down", "upgraded"])
+        .output()
+        .unwrap();
+    assert!(down.status.success(), "{down:?}");
+    instance.wait().unwrap();
+    assert_eq!(sandbox.runs(), 1);
+}